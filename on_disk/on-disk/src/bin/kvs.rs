@@ -1,10 +1,28 @@
-use clap::{Arg, Command};
+use clap::{Arg, ArgMatches, Command};
+use on_disk::CompactionPolicy;
 use on_disk::KVStore;
 use on_disk::KVStoreError;
 use on_disk::Result;
+use serde::Deserialize;
 use std::env;
+use std::fs;
 use std::process;
 
+// the flags that change the compaction policy
+const COMPACTION_FLAGS: [&str; 4] = [
+    "compaction-bytes",
+    "compaction-ratio",
+    "compaction-interval",
+    "compaction-window",
+];
+
+/// settings of the --config file
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    compaction: CompactionPolicy,
+}
+
 fn main() -> Result<()> {
     let command = Command::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
@@ -12,6 +30,41 @@ fn main() -> Result<()> {
         .about(env!("CARGO_PKG_DESCRIPTION"))
         .disable_help_subcommand(true)
         .subcommand_required(true)
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("FILE")
+                .global(true)
+                .help("JSON settings file, the flags given override it"),
+        )
+        .arg(
+            Arg::new("compaction-bytes")
+                .long("compaction-bytes")
+                .value_name("BYTES")
+                .global(true)
+                .help("Compact once the dead data takes more than this, or none"),
+        )
+        .arg(
+            Arg::new("compaction-ratio")
+                .long("compaction-ratio")
+                .value_name("RATIO")
+                .global(true)
+                .help("Compact once the dead data takes RATIO times the live data"),
+        )
+        .arg(
+            Arg::new("compaction-interval")
+                .long("compaction-interval")
+                .value_name("SECONDS")
+                .global(true)
+                .help("Wait at least this long between compactions"),
+        )
+        .arg(
+            Arg::new("compaction-window")
+                .long("compaction-window")
+                .value_name("HH:MM-HH:MM")
+                .global(true)
+                .help("Only compact in this time of day, in UTC"),
+        )
         .subcommand(
             Command::new("set")
                 .about("Set the value of a string key to a string")
@@ -33,7 +86,7 @@ fn main() -> Result<()> {
                 .arg(Arg::new("KEY").help("A string key").required(true)),
         )
         .get_matches();
-    let mut db = KVStore::open(env::current_dir()?)?;
+    let mut db = KVStore::open_with_policy(env::current_dir()?, compaction_policy(&command)?)?;
     match command.subcommand() {
         Some(("set", args)) => {
             let key = args.get_one::<String>("KEY").unwrap();
@@ -64,3 +117,18 @@ fn main() -> Result<()> {
     }
     Ok(())
 }
+
+/// the compaction policy of the --config file, with the flags given applied over it
+fn compaction_policy(matches: &ArgMatches) -> Result<CompactionPolicy> {
+    let config: Config = match matches.get_one::<String>("config") {
+        Some(path) => serde_json::from_slice(&fs::read(path)?)?,
+        None => Config::default(),
+    };
+    let mut policy = config.compaction;
+    for flag in COMPACTION_FLAGS {
+        if let Some(value) = matches.get_one::<String>(flag) {
+            policy.set(flag, value)?;
+        }
+    }
+    Ok(policy)
+}
@@ -0,0 +1,162 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error::{KVStoreError, Result};
+use std::fmt;
+use std::str::FromStr;
+
+/// the dead bytes that make a compaction due by default
+pub const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+const MINUTES_PER_DAY: u64 = 24 * 60;
+
+/// when the log is compacted
+///
+/// a compaction is due once the dead data passes either threshold, but it only runs
+/// if the last one is at least `min_interval_secs` old and the time of day is in `window`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompactionPolicy {
+    // due once the dead data takes more bytes than this
+    pub dead_bytes: Option<u64>,
+    // due once the dead data takes more than this many times the bytes of the live data
+    pub dead_ratio: Option<f64>,
+    pub min_interval_secs: Option<u64>,
+    pub window: Option<TimeWindow>,
+}
+
+impl Default for CompactionPolicy {
+    /// due after `COMPACTION_THRESHOLD` dead bytes, at any time
+    fn default() -> Self {
+        CompactionPolicy {
+            dead_bytes: Some(COMPACTION_THRESHOLD),
+            dead_ratio: None,
+            min_interval_secs: None,
+            window: None,
+        }
+    }
+}
+
+impl CompactionPolicy {
+    /// whether a log with `dead` and `live` bytes is compacted now
+    ///
+    /// `since_last_secs` is the time since the last compaction, if any ran
+    pub fn should_compact(
+        &self,
+        dead: u64,
+        live: u64,
+        since_last_secs: Option<u64>,
+        minute_of_day: u32,
+    ) -> bool {
+        let due = self.dead_bytes.is_some_and(|bytes| dead > bytes)
+            || self
+                .dead_ratio
+                .is_some_and(|ratio| dead > 0 && dead as f64 > ratio * live as f64);
+        due && self
+            .min_interval_secs
+            .zip(since_last_secs)
+            .is_none_or(|(interval, since_last)| since_last >= interval)
+            && self
+                .window
+                .is_none_or(|window| window.contains(minute_of_day))
+    }
+
+    /// change a setting by the name of its flag without the `--`, `none` unsets any of them
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        let unset = value == "none";
+        match name {
+            "compaction-bytes" => self.dead_bytes = parse_unless(unset, name, value)?,
+            "compaction-ratio" => self.dead_ratio = parse_unless(unset, name, value)?,
+            "compaction-interval" => self.min_interval_secs = parse_unless(unset, name, value)?,
+            "compaction-window" => self.window = parse_unless(unset, name, value)?,
+            _ => return Err(KVStoreError::InvalidSetting(name.to_owned())),
+        }
+        Ok(())
+    }
+}
+
+fn parse_unless<T: FromStr>(unset: bool, name: &str, value: &str) -> Result<Option<T>> {
+    if unset {
+        return Ok(None);
+    }
+    value
+        .parse()
+        .map(Some)
+        .map_err(|_| KVStoreError::InvalidSetting(format!("{} {}", name, value)))
+}
+
+/// the minutes since midnight UTC of a time in seconds since the unix epoch
+pub fn minute_of_day(secs: u64) -> u32 {
+    (secs / 60 % MINUTES_PER_DAY) as u32
+}
+
+/// a time of day range in UTC, written `HH:MM-HH:MM`, it wraps past midnight if it ends first
+///
+/// it may end at `24:00` but not start there, and it is never empty: `00:00-24:00` is the whole day
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeWindow {
+    // minutes since midnight
+    pub start: u32,
+    pub end: u32,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, minute_of_day: u32) -> bool {
+        if self.start <= self.end {
+            self.start <= minute_of_day && minute_of_day < self.end
+        } else {
+            minute_of_day >= self.start || minute_of_day < self.end
+        }
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = KVStoreError;
+
+    fn from_str(window: &str) -> Result<Self> {
+        let invalid = || KVStoreError::InvalidSetting(format!("time window {}", window));
+        let minutes = |time: &str| -> Result<u32> {
+            let (hours, minutes) = time.trim().split_once(':').ok_or_else(invalid)?;
+            let (hours, minutes): (u32, u32) = (
+                hours.parse().map_err(|_| invalid())?,
+                minutes.parse().map_err(|_| invalid())?,
+            );
+            if hours > 24 || minutes >= 60 || u64::from(hours * 60 + minutes) > MINUTES_PER_DAY {
+                return Err(invalid());
+            }
+            Ok(hours * 60 + minutes)
+        };
+        let (start, end) = window.split_once('-').ok_or_else(invalid)?;
+        let (start, end) = (minutes(start)?, minutes(end)?);
+        if u64::from(start) == MINUTES_PER_DAY || start == end {
+            return Err(invalid());
+        }
+        Ok(TimeWindow { start, end })
+    }
+}
+
+impl TryFrom<String> for TimeWindow {
+    type Error = KVStoreError;
+
+    fn try_from(window: String) -> Result<Self> {
+        window.parse()
+    }
+}
+
+impl From<TimeWindow> for String {
+    fn from(window: TimeWindow) -> Self {
+        window.to_string()
+    }
+}
+
+impl fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
@@ -15,6 +15,9 @@ pub enum KVStoreError {
     // Unexpected command type error
     #[fail(display = "Unexpected command type")]
     UnexpectedCommandType,
+    // Unknown setting, or a value it does not take
+    #[fail(display = "Invalid setting {}", _0)]
+    InvalidSetting(String),
 }
 
 impl From<io::Error> for KVStoreError {
@@ -4,23 +4,21 @@ use std::{
     fs::{self, File, OpenOptions},
     io::{self, Read, Seek, Write},
     path::{Path, PathBuf},
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
 use crate::{
     command::Command,
+    compaction::{minute_of_day, CompactionPolicy},
     error::{KVStoreError, Result},
-    reader,
 };
 
-use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
 
 use crate::{
     command::CommandMetaData, reader::BufferReaderWithPosition, writer::BufferWriterWithPosition,
 };
 
-const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
-
 pub struct KVStore {
     // abs path to log files
     db_path: PathBuf,
@@ -34,6 +32,11 @@ pub struct KVStore {
     index_map: BTreeMap<String, CommandMetaData>,
     // size of data in bytes could be delete when compact
     uncompacted: u64,
+    // size of the log files before the current one
+    sealed: u64,
+    // when the uncompacted data is compacted
+    policy: CompactionPolicy,
+    last_compaction: Option<Instant>,
 }
 
 impl KVStore {
@@ -42,6 +45,11 @@ impl KVStore {
     /// load most recent writer
     /// load most recent command into index_map and uncompacted data in bytes
     pub fn open(path: impl Into<PathBuf>) -> Result<KVStore> {
+        KVStore::open_with_policy(path, CompactionPolicy::default())
+    }
+
+    /// open the db, and compact it when `policy` asks for it
+    pub fn open_with_policy(path: impl Into<PathBuf>, policy: CompactionPolicy) -> Result<KVStore> {
         // create dir for files
         let path = path.into();
         fs::create_dir_all(&path)?;
@@ -52,12 +60,14 @@ impl KVStore {
         // get all existing log files
         let existing_file_num_list = sort_file_by_number(&path)?;
         let mut uncompacted = 0_u64;
+        let mut sealed = 0_u64;
         // load all existing file
         for file_num in &existing_file_num_list {
             let mut reader = BufferReaderWithPosition::new(File::open(
                 build_file_path_by_number(&path, file_num.to_owned()),
             )?)?;
             uncompacted += load_uncompacted_data(file_num.to_owned(), &mut reader, &mut index_map)?;
+            sealed += reader.seek(io::SeekFrom::End(0))?;
             readers.insert(file_num.to_owned(), reader);
         }
         // set current_file_num
@@ -71,6 +81,9 @@ impl KVStore {
             current_file_num,
             index_map,
             uncompacted,
+            sealed,
+            policy,
+            last_compaction: None,
         })
     }
 
@@ -103,7 +116,7 @@ impl KVStore {
         // flush the current writer's buffer
         self.writer.flush()?;
         // check if need compact
-        if self.uncompacted > COMPACTION_THRESHOLD {
+        if self.needs_compaction() {
             self.compact()?;
         }
         Ok(())
@@ -156,7 +169,7 @@ impl KVStore {
             self.uncompacted += data_length;
             self.writer.flush()?;
             // check if need compact
-            if self.uncompacted > COMPACTION_THRESHOLD {
+            if self.needs_compaction() {
                 self.compact()?;
             }
             Ok(())
@@ -213,8 +226,21 @@ impl KVStore {
         self.writer = new_file(&self.db_path, self.current_file_num, &mut self.readers)?;
         // reset the uncompated data size
         self.uncompacted = 0_u64;
+        self.sealed = compact_writer.position();
+        self.last_compaction = Some(Instant::now());
         Ok(())
     }
+
+    /// whether the compaction policy asks for a compaction now
+    fn needs_compaction(&self) -> bool {
+        let live = (self.sealed + self.writer.position()).saturating_sub(self.uncompacted);
+        let since_last_secs = self.last_compaction.map(|last| last.elapsed().as_secs());
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs());
+        self.policy
+            .should_compact(self.uncompacted, live, since_last_secs, minute_of_day(now))
+    }
 }
 
 /// Go through the log file
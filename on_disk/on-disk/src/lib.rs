@@ -1,5 +1,7 @@
 mod command;
 pub use command::*;
+mod compaction;
+pub use compaction::*;
 mod error;
 pub use error::*;
 mod kv;
@@ -0,0 +1,144 @@
+use assert_cmd::prelude::*;
+use on_disk::{minute_of_day, CompactionPolicy, KVStore, Result, TimeWindow};
+use std::fs;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tempfile::TempDir;
+
+// Compaction deletes every log file before it, starting with the first one.
+fn compacted(temp_dir: &TempDir) -> bool {
+    !temp_dir.path().join("1.log").exists()
+}
+
+fn overwrite(store: &mut KVStore, times: usize) -> Result<()> {
+    for value in 0..times {
+        store.set("key".to_owned(), value.to_string())?;
+    }
+    Ok(())
+}
+
+#[test]
+fn dead_bytes_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let policy = CompactionPolicy {
+        dead_bytes: Some(500),
+        ..CompactionPolicy::default()
+    };
+    let mut store = KVStore::open_with_policy(temp_dir.path(), policy)?;
+    overwrite(&mut store, 5)?;
+    assert!(!compacted(&temp_dir));
+    overwrite(&mut store, 50)?;
+    assert!(compacted(&temp_dir));
+    assert_eq!(store.get("key".to_owned())?, Some("49".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn dead_ratio_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let policy = CompactionPolicy {
+        dead_bytes: None,
+        dead_ratio: Some(4.0),
+        ..CompactionPolicy::default()
+    };
+    let mut store = KVStore::open_with_policy(temp_dir.path(), policy)?;
+    overwrite(&mut store, 3)?;
+    assert!(!compacted(&temp_dir));
+    overwrite(&mut store, 10)?;
+    assert!(compacted(&temp_dir));
+    Ok(())
+}
+
+#[test]
+fn no_compaction_outside_the_window() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let now = minute_of_day(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+    );
+    let policy = CompactionPolicy {
+        dead_bytes: Some(100),
+        window: Some(TimeWindow {
+            start: (now + 60) % 1440,
+            end: (now + 120) % 1440,
+        }),
+        ..CompactionPolicy::default()
+    };
+    let mut store = KVStore::open_with_policy(temp_dir.path(), policy)?;
+    overwrite(&mut store, 50)?;
+    assert!(!compacted(&temp_dir));
+    Ok(())
+}
+
+#[test]
+fn policy_interval_and_window() {
+    let policy = CompactionPolicy {
+        dead_bytes: Some(10),
+        min_interval_secs: Some(60),
+        window: Some("22:00-02:00".parse().unwrap()),
+        ..CompactionPolicy::default()
+    };
+    assert!(policy.should_compact(11, 0, None, 23 * 60));
+    assert!(policy.should_compact(11, 0, Some(60), 60));
+    assert!(!policy.should_compact(11, 0, Some(59), 60));
+    assert!(!policy.should_compact(11, 0, None, 12 * 60));
+    assert!(!policy.should_compact(10, 0, None, 23 * 60));
+}
+
+#[test]
+fn time_window_may_end_at_midnight() {
+    let day: TimeWindow = "00:00-24:00".parse().unwrap();
+    assert!(day.contains(0));
+    assert!(day.contains(24 * 60 - 1));
+    let evening: TimeWindow = "18:00-24:00".parse().unwrap();
+    assert!(evening.contains(23 * 60));
+    assert!(!evening.contains(0));
+    assert!("24:00-02:00".parse::<TimeWindow>().is_err());
+}
+
+#[test]
+fn empty_time_window_is_rejected() {
+    assert!("03:00-03:00".parse::<TimeWindow>().is_err());
+    assert!("00:00-00:00".parse::<TimeWindow>().is_err());
+    assert!("24:00-24:00".parse::<TimeWindow>().is_err());
+}
+
+// `kvs set` takes the policy from --config, and the flags override it.
+#[test]
+fn cli_compaction_policy() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = temp_dir.path().join("kvs.json");
+    fs::write(&config, r#"{"compaction": {"dead_bytes": 100000000}}"#).unwrap();
+    for value in 0..10 {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["set", "key", &value.to_string()])
+            .args(["--config", config.to_str().unwrap()])
+            .args(["--compaction-bytes", "none", "--compaction-ratio", "2"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    assert!(compacted(&temp_dir));
+}
+
+#[test]
+fn cli_invalid_compaction_policy() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key", "value", "--compaction-window", "noon"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    let config = temp_dir.path().join("kvs.json");
+    fs::write(&config, r#"{"compaction": {"dead_ratio": "half"}}"#).unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key", "--config", config.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
@@ -0,0 +1,149 @@
+//! users file and per-user access control
+//!
+//! the users file is a JSON list of users:
+//!
+//! ```json
+//! [
+//!   {
+//!     "name": "alice",
+//!     "password_hash": "pbkdf2-sha256$<rounds>$<salt>$<pbkdf2 of salt and password>",
+//!     "permissions": [{ "prefix": "app/", "read": true, "write": false }],
+//!     "admin": false
+//!   }
+//! ]
+//! ```
+//!
+//! `kvs-server --hash-password <PASSWORD>` prints a `password_hash`
+
+use pbkdf2::pbkdf2_hmac;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::Result;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+const SALT_LENGTH: usize = 16;
+const HASH_LENGTH: usize = 32;
+const HASH_SCHEME: &str = "pbkdf2-sha256";
+/// the PBKDF2 rounds of a new password hash, the hash keeps them so they can be raised later
+pub const PASSWORD_HASH_ROUNDS: u32 = 600_000;
+/// failed `Request::Auth` a connection may send, the server closes it after the last
+pub const MAX_FAILED_AUTHS: u32 = 3;
+
+/// access granted on every key starting with `prefix`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Permission {
+    pub prefix: String,
+    #[serde(default)]
+    pub read: bool,
+    #[serde(default)]
+    pub write: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    pub name: String,
+    pub password_hash: String,
+    #[serde(default)]
+    pub permissions: Vec<Permission>,
+    // may send the admin requests, like `Request::Compact`
+    #[serde(default)]
+    pub admin: bool,
+}
+
+impl User {
+    /// whether any permission covering `key` grants read access
+    pub fn can_read(&self, key: &str) -> bool {
+        self.permissions
+            .iter()
+            .any(|permission| permission.read && key.starts_with(&permission.prefix))
+    }
+
+    /// whether any permission covering `key` grants write access
+    pub fn can_write(&self, key: &str) -> bool {
+        self.permissions
+            .iter()
+            .any(|permission| permission.write && key.starts_with(&permission.prefix))
+    }
+}
+
+/// users loaded from the users file, by name
+pub struct Users(HashMap<String, User>);
+
+impl Users {
+    pub fn load(path: &Path) -> Result<Users> {
+        let users: Vec<User> = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        Ok(Users::new(users))
+    }
+
+    pub fn new(users: Vec<User>) -> Users {
+        Users(
+            users
+                .into_iter()
+                .map(|user| (user.name.to_owned(), user))
+                .collect(),
+        )
+    }
+
+    /// return the user if the name and password match
+    pub fn authenticate(&self, name: &str, password: &str) -> Option<&User> {
+        self.0
+            .get(name)
+            .filter(|user| verify_password(password, &user.password_hash))
+    }
+}
+
+/// hash `password` with a random salt, in the format of `password_hash`
+pub fn hash_password(password: &str) -> String {
+    hash_password_with_rounds(password, PASSWORD_HASH_ROUNDS)
+}
+
+/// like `hash_password`, with `rounds` of PBKDF2 instead of the default
+pub fn hash_password_with_rounds(password: &str, rounds: u32) -> String {
+    let mut salt = [0_u8; SALT_LENGTH];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let salt = to_hex(&salt);
+    format!(
+        "{}${}${}${}",
+        HASH_SCHEME,
+        rounds,
+        salt,
+        digest(&salt, password, rounds)
+    )
+}
+
+fn verify_password(password: &str, password_hash: &str) -> bool {
+    let mut parts = password_hash.split('$');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(HASH_SCHEME), Some(rounds), Some(salt), Some(hash)) if parts.next().is_none() => {
+            let rounds = match rounds.parse() {
+                Ok(rounds) if rounds > 0 => rounds,
+                _ => return false,
+            };
+            let expected = digest(salt, password, rounds);
+            // compare every byte, so the time taken does not leak the matched length
+            expected.len() == hash.len()
+                && expected
+                    .bytes()
+                    .zip(hash.bytes())
+                    .fold(0, |diff, (a, b)| diff | (a ^ b))
+                    == 0
+        }
+        // a hash of another scheme, or not a hash at all
+        _ => false,
+    }
+}
+
+fn digest(salt: &str, password: &str, rounds: u32) -> String {
+    let mut hash = [0_u8; HASH_LENGTH];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt.as_bytes(), rounds, &mut hash);
+    to_hex(&hash)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
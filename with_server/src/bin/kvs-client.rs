@@ -1,3 +1,272 @@
+use clap::{Arg, ArgAction, Command};
+use std::path::Path;
+use std::process;
+use with_server::{now_millis, KVStoreError, KvsClient, Result, ServerStats, TlsConnector};
+
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+// a leader that changes again while redirecting is given up on
+const MAX_REDIRECTS: usize = 3;
+
 fn main() {
-    println!("Hello, world!");
+    let addr_arg = Arg::new("addr")
+        .long("addr")
+        .value_name("IP-PORT")
+        .help("Server address, or unix:/path/to.sock")
+        .default_value(DEFAULT_ADDR);
+    let matches = Command::new(env!("CARGO_PKG_NAME"))
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Key-value store client")
+        .disable_help_subcommand(true)
+        .subcommand_required(true)
+        .arg(
+            Arg::new("tls-ca")
+                .long("tls-ca")
+                .value_name("PEM-FILE")
+                .help("Connect over TLS, trusting servers signed by this CA")
+                .global(true),
+        )
+        .arg(
+            Arg::new("tls-cert")
+                .long("tls-cert")
+                .value_name("PEM-FILE")
+                .help("Client certificate chain for mutual TLS")
+                .requires_all(["tls-ca", "tls-key"])
+                .global(true),
+        )
+        .arg(
+            Arg::new("tls-key")
+                .long("tls-key")
+                .value_name("PEM-FILE")
+                .help("Private key of the client certificate")
+                .requires("tls-cert")
+                .global(true),
+        )
+        .arg(
+            Arg::new("user")
+                .long("user")
+                .value_name("NAME")
+                .help("Authenticate as this user")
+                .requires("password")
+                .global(true),
+        )
+        .arg(
+            Arg::new("password")
+                .long("password")
+                .value_name("PASSWORD")
+                .help("Password of the user")
+                .requires("user")
+                .global(true),
+        )
+        .subcommand(
+            Command::new("set")
+                .about("Set the value of a string key to a string")
+                .arg(Arg::new("KEY").help("A string key").required(true))
+                .arg(
+                    Arg::new("VALUE")
+                        .help("The value of the string key")
+                        .required(true),
+                )
+                .arg(addr_arg.clone()),
+        )
+        .subcommand(
+            Command::new("get")
+                .about("Get the string value of a given string key")
+                .arg(Arg::new("KEY").help("A string key").required(true))
+                .arg(addr_arg.clone()),
+        )
+        .subcommand(
+            Command::new("incr")
+                .about("Add to the integer value of a key, a missing key counts as 0")
+                .arg(Arg::new("KEY").help("A string key").required(true))
+                .arg(
+                    Arg::new("DELTA")
+                        .help("The amount to add, negative to decrement")
+                        .value_parser(clap::value_parser!(i64))
+                        .allow_negative_numbers(true)
+                        .default_value("1"),
+                )
+                .arg(addr_arg.clone()),
+        )
+        .subcommand(
+            Command::new("stats")
+                .about("Print the state of the server and its engine")
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .help("Print as JSON")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(addr_arg.clone()),
+        )
+        .subcommand(
+            Command::new("admin")
+                .about("Maintenance of the server, as a --user that is an admin")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("compact")
+                        .about("Compact the log now")
+                        .arg(addr_arg.clone()),
+                )
+                .subcommand(
+                    Command::new("flush")
+                        .about("Write everything buffered to disk")
+                        .arg(addr_arg.clone()),
+                )
+                .subcommand(
+                    Command::new("shutdown")
+                        .about("Stop the server")
+                        .arg(addr_arg.clone()),
+                )
+                .subcommand(
+                    Command::new("set-config")
+                        .about("Change a setting while the server runs")
+                        .arg(
+                            Arg::new("NAME")
+                                .help("compaction_threshold, compaction_ratio, ... or slow_log_ms")
+                                .required(true),
+                        )
+                        .arg(Arg::new("VALUE").help("The new value").required(true))
+                        .arg(addr_arg.clone()),
+                ),
+        )
+        .subcommand(
+            Command::new("rm")
+                .about("Remove a given key")
+                .arg(Arg::new("KEY").help("A string key").required(true))
+                .arg(addr_arg),
+        )
+        .get_matches();
+
+    if let Err(err) = run(&matches) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+fn run(matches: &clap::ArgMatches) -> Result<()> {
+    let (name, args) = matches.subcommand().expect("subcommand is required");
+    if name == "stats" {
+        return stats(args);
+    }
+    if name == "admin" {
+        return admin(args);
+    }
+    let mut addr = args.get_one::<String>("addr").unwrap().to_owned();
+    let key = args.get_one::<String>("KEY").unwrap().to_owned();
+    let mut redirects = 0;
+    loop {
+        let mut client = connect(args, &addr)?;
+        let result = match name {
+            "set" => {
+                let value = args.get_one::<String>("VALUE").unwrap().to_owned();
+                client.set(key.to_owned(), value).map(|_| None)
+            }
+            "get" => client.get(key.to_owned()).map(Some),
+            "incr" => {
+                let delta = *args.get_one::<i64>("DELTA").unwrap();
+                client
+                    .incr(key.to_owned(), delta)
+                    .map(|value| Some(Some(value.to_string())))
+            }
+            _ => client.remove(key.to_owned()).map(|_| None),
+        };
+        match result {
+            // a follower or a cluster node sends the request on to its leader
+            Err(KVStoreError::Redirect(leader)) if redirects < MAX_REDIRECTS => {
+                redirects += 1;
+                addr = leader;
+            }
+            Ok(Some(Some(value))) => {
+                println!("{}", value);
+                return Ok(());
+            }
+            Ok(Some(None)) => {
+                println!("Key not found");
+                return Ok(());
+            }
+            Ok(None) => return Ok(()),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// send an admin request
+fn admin(args: &clap::ArgMatches) -> Result<()> {
+    let (name, args) = args.subcommand().expect("subcommand is required");
+    let addr = args.get_one::<String>("addr").unwrap();
+    let mut client = connect(args, addr)?;
+    match name {
+        "compact" => client.compact(),
+        "flush" => client.flush(),
+        "shutdown" => client.shutdown(),
+        _ => client.set_config(
+            args.get_one::<String>("NAME").unwrap().to_owned(),
+            args.get_one::<String>("VALUE").unwrap().to_owned(),
+        ),
+    }
+}
+
+/// print the stats of the server, as JSON if asked
+fn stats(args: &clap::ArgMatches) -> Result<()> {
+    let addr = args.get_one::<String>("addr").unwrap();
+    let stats = connect(args, addr)?.stats()?;
+    if args.get_flag("json") {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+    } else {
+        print_stats(&stats);
+    }
+    Ok(())
+}
+
+fn print_stats(stats: &ServerStats) {
+    let engine = &stats.engine;
+    println!("engine: {}", engine.engine);
+    println!("keys: {}", engine.keys);
+    println!("live bytes: {}", engine.live_bytes);
+    println!("dead bytes: {}", engine.dead_bytes);
+    println!("log files: {}", engine.log_files);
+    println!(
+        "writes: {:?}, {} slowed down, {} stalled",
+        engine.write_stall, engine.slowed_writes, engine.stalled_writes
+    );
+    println!(
+        "compactions: {}, {} bytes reclaimed",
+        engine.compactions, engine.reclaimed_bytes
+    );
+    match &engine.last_compaction {
+        Some(last) => println!(
+            "last compaction: {}s ago, took {}ms, {} bytes reclaimed",
+            now_millis().saturating_sub(last.finished) / 1000,
+            last.duration_ms,
+            last.reclaimed_bytes
+        ),
+        None => println!("last compaction: never"),
+    }
+    println!("connections: {}", stats.connections);
+    println!("uptime: {}s", stats.uptime_secs);
+    println!("ops per second:");
+    for (request, rate) in &stats.ops_per_sec {
+        println!("  {}: {:.2}", request, rate);
+    }
+}
+
+/// connect to `addr` over TLS if asked, and authenticate if credentials are given
+fn connect(args: &clap::ArgMatches, addr: &str) -> Result<KvsClient> {
+    let mut client = match args.get_one::<String>("tls-ca") {
+        Some(ca) => {
+            let identity = args
+                .get_one::<String>("tls-cert")
+                .zip(args.get_one::<String>("tls-key"))
+                .map(|(cert, key)| (Path::new(cert), Path::new(key)));
+            KvsClient::connect_tls(addr, &TlsConnector::new(Path::new(ca), identity)?)?
+        }
+        None => KvsClient::connect_to(addr)?,
+    };
+    if let Some((user, password)) = args
+        .get_one::<String>("user")
+        .zip(args.get_one::<String>("password"))
+    {
+        client.authenticate(user.to_owned(), password.to_owned())?;
+    }
+    Ok(client)
 }
@@ -1,3 +1,573 @@
+use clap::{Arg, Command};
+use log::{error, info};
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use with_server::{
+    hash_password, Address, Backpressure, CompactionPolicy, FollowerEngine, HttpServer, KVStore,
+    KVStoreEngine, KVStoreError, KVStoreOptions, Listener, MemcachedServer, MetricsServer,
+    RaftEngine, Replica, Result, Retention, Server, ShardEngine, SledKVStore, SlowLog, TlsAcceptor,
+    TlsConnector, User, Users, MAX_SEGMENT_BYTES, RAFT_LOG_LIMIT,
+};
+
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+// keeps the last applied log position of a follower, in the data directory
+const REPLICATION_STATE_FILE: &str = "replication.pos";
+// keeps the term, vote and log of a cluster node, in the data directory
+const RAFT_STATE_FILE: &str = "raft.state";
+// keeps the key ranges moved to other servers, in the data directory
+const SHARD_STATE_FILE: &str = "shards.moved";
+// the name of the admin given by --admin-password-hash
+const ADMIN_USER: &str = "admin";
+// the slow requests, in the data directory unless --slow-log-file is given
+const SLOW_LOG_FILE: &str = "slow.log";
+// the flags of the compaction policy, and the names `CompactionPolicy::set` knows them by
+const COMPACTION_FLAGS: [(&str, &str); 4] = [
+    ("compaction-bytes", "compaction_threshold"),
+    ("compaction-ratio", "compaction_ratio"),
+    ("compaction-interval", "compaction_interval_secs"),
+    ("compaction-window", "compaction_window"),
+];
+// the flags of the write backpressure, and the names `Backpressure::set` knows them by
+const BACKPRESSURE_FLAGS: [(&str, &str); 5] = [
+    ("soft-dead-bytes", "soft_dead_bytes"),
+    ("hard-dead-bytes", "hard_dead_bytes"),
+    ("soft-log-files", "soft_log_files"),
+    ("hard-log-files", "hard_log_files"),
+    ("write-slowdown", "write_slowdown_ms"),
+];
+
 fn main() {
-    println!("Hello, world!");
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let matches = Command::new(env!("CARGO_PKG_NAME"))
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Key-value store server")
+        .arg(
+            Arg::new("addr")
+                .long("addr")
+                .value_name("IP-PORT")
+                .help("Address the server listens on, or unix:/path/to.sock")
+                .default_value(DEFAULT_ADDR),
+        )
+        .arg(
+            Arg::new("engine")
+                .long("engine")
+                .value_name("ENGINE-NAME")
+                .help("Storage engine")
+                .value_parser(["kvs", "sled"])
+                .default_value("kvs"),
+        )
+        .arg(
+            Arg::new("keep-versions")
+                .long("keep-versions")
+                .value_name("N")
+                .help("Keep the last N versions of every key (kvs engine)")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("1"),
+        )
+        .arg(
+            Arg::new("version-window")
+                .long("version-window")
+                .value_name("SECONDS")
+                .help("Also keep every version younger than this (kvs engine)")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("FILE")
+                .help("JSON settings file, the flags given override it"),
+        )
+        .arg(
+            Arg::new("compaction-bytes")
+                .long("compaction-bytes")
+                .value_name("BYTES")
+                .help("Compact once the dead records take more than this, or none (kvs engine)"),
+        )
+        .arg(
+            Arg::new("compaction-ratio")
+                .long("compaction-ratio")
+                .value_name("RATIO")
+                .help("Compact once the dead records take RATIO times the live ones (kvs engine)"),
+        )
+        .arg(
+            Arg::new("compaction-interval")
+                .long("compaction-interval")
+                .value_name("SECONDS")
+                .help("Wait at least this long between compactions (kvs engine)"),
+        )
+        .arg(
+            Arg::new("compaction-window")
+                .long("compaction-window")
+                .value_name("HH:MM-HH:MM")
+                .help("Only compact in this time of day, in UTC (kvs engine)"),
+        )
+        .arg(
+            Arg::new("max-segment-bytes")
+                .long("max-segment-bytes")
+                .value_name("BYTES")
+                .help("Go on writing in a new log file once one grows this large (kvs engine)")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("soft-dead-bytes")
+                .long("soft-dead-bytes")
+                .value_name("BYTES")
+                .help("Slow down writes while more dead bytes wait for compaction (kvs engine)"),
+        )
+        .arg(
+            Arg::new("hard-dead-bytes")
+                .long("hard-dead-bytes")
+                .value_name("BYTES")
+                .help("Stall writes until compaction gets the dead bytes under this (kvs engine)"),
+        )
+        .arg(
+            Arg::new("soft-log-files")
+                .long("soft-log-files")
+                .value_name("N")
+                .help("Slow down writes while the log has more files (kvs engine)"),
+        )
+        .arg(
+            Arg::new("hard-log-files")
+                .long("hard-log-files")
+                .value_name("N")
+                .help(
+                    "Stall writes until compaction gets the log under this many files (kvs engine)",
+                ),
+        )
+        .arg(
+            Arg::new("write-slowdown")
+                .long("write-slowdown")
+                .value_name("MILLISECONDS")
+                .help("How long a slowed down write waits (kvs engine)"),
+        )
+        .arg(
+            Arg::new("memcached-addr")
+                .long("memcached-addr")
+                .value_name("IP-PORT")
+                .help("Also serve the memcached text protocol on this address"),
+        )
+        .arg(
+            Arg::new("http-addr")
+                .long("http-addr")
+                .value_name("IP-PORT")
+                .help("Also serve the HTTP/JSON gateway on this address"),
+        )
+        .arg(
+            Arg::new("metrics-addr")
+                .long("metrics-addr")
+                .value_name("IP-PORT")
+                .help("Serve Prometheus metrics at /metrics on this address"),
+        )
+        .arg(
+            Arg::new("slow-log-ms")
+                .long("slow-log-ms")
+                .value_name("MILLISECONDS")
+                .help("Log the requests that take longer than this")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("slow-log-file")
+                .long("slow-log-file")
+                .value_name("FILE")
+                .help("File of the slow log, rotated to FILE.1 when it grows large")
+                .requires("slow-log-ms"),
+        )
+        .arg(
+            Arg::new("tls-cert")
+                .long("tls-cert")
+                .value_name("PEM-FILE")
+                .help("Serve over TLS with this certificate chain")
+                .requires("tls-key"),
+        )
+        .arg(
+            Arg::new("tls-key")
+                .long("tls-key")
+                .value_name("PEM-FILE")
+                .help("Private key of the TLS certificate")
+                .requires("tls-cert"),
+        )
+        .arg(
+            Arg::new("tls-client-ca")
+                .long("tls-client-ca")
+                .value_name("PEM-FILE")
+                .help("Require client certificates signed by this CA (mutual TLS)")
+                .requires("tls-cert"),
+        )
+        .arg(
+            Arg::new("users")
+                .long("users")
+                .value_name("FILE")
+                .help("Users file, clients must authenticate and are limited to their key prefixes")
+                // the memcached and HTTP front-ends have no authentication
+                .conflicts_with_all(["memcached-addr", "http-addr"]),
+        )
+        .arg(
+            Arg::new("admin-password-hash")
+                .long("admin-password-hash")
+                .value_name("HASH")
+                .help("Let user admin with this password_hash send admin requests"),
+        )
+        .arg(
+            Arg::new("hash-password")
+                .long("hash-password")
+                .value_name("PASSWORD")
+                .help("Print the password_hash of PASSWORD for the users file and exit")
+                .exclusive(true),
+        )
+        .arg(
+            Arg::new("replica-of")
+                .long("replica-of")
+                .value_name("IP-PORT")
+                .help("Follow the leader at this address, serve reads and redirect writes"),
+        )
+        .arg(
+            Arg::new("replica-user")
+                .long("replica-user")
+                .value_name("NAME")
+                .help("Authenticate to the leader as this user")
+                .requires_all(["replica-of", "replica-password"]),
+        )
+        .arg(
+            Arg::new("replica-password")
+                .long("replica-password")
+                .value_name("PASSWORD")
+                .help("Password of the replica user")
+                .requires("replica-user"),
+        )
+        .arg(
+            Arg::new("cluster")
+                .long("cluster")
+                .value_name("IP-PORT,...")
+                .help("Run Raft with these nodes, --addr must be one of them")
+                .value_delimiter(',')
+                // nodes neither follow a fixed leader nor authenticate to each other
+                .conflicts_with_all(["replica-of", "users"]),
+        )
+        .arg(
+            Arg::new("shard-ca")
+                .long("shard-ca")
+                .value_name("PEM-FILE")
+                .help("Move keys to servers with certificates signed by this CA, over TLS"),
+        )
+        .arg(
+            Arg::new("shard-user")
+                .long("shard-user")
+                .value_name("NAME")
+                .help("Authenticate to the servers keys are moved to as this user")
+                .requires("shard-password"),
+        )
+        .arg(
+            Arg::new("shard-password")
+                .long("shard-password")
+                .value_name("PASSWORD")
+                .help("Password of the shard user")
+                .requires("shard-user"),
+        )
+        .get_matches();
+
+    if let Some(password) = matches.get_one::<String>("hash-password") {
+        println!("{}", hash_password(password));
+        return;
+    }
+
+    let addr = Address::from(matches.get_one::<String>("addr").unwrap());
+    let engine = matches.get_one::<String>("engine").unwrap().to_owned();
+    let retention = Retention {
+        versions: *matches.get_one::<usize>("keep-versions").unwrap(),
+        window: matches
+            .get_one::<u64>("version-window")
+            .map(|seconds| Duration::from_secs(*seconds)),
+    };
+    let store_options = match store_options(&matches, retention) {
+        Ok(store_options) => store_options,
+        Err(err) => {
+            error!("{}", err);
+            process::exit(1);
+        }
+    };
+    let users = match matches
+        .get_one::<String>("users")
+        .map(|path| Users::load(Path::new(path)))
+    {
+        Some(Ok(users)) => Some(users),
+        Some(Err(err)) => {
+            error!("Cannot load users file: {}", err);
+            process::exit(1);
+        }
+        None => None,
+    };
+    let tls = match (
+        matches.get_one::<String>("tls-cert"),
+        matches.get_one::<String>("tls-key"),
+    ) {
+        (Some(cert), Some(key)) => {
+            let client_ca = matches.get_one::<String>("tls-client-ca").map(Path::new);
+            match TlsAcceptor::new(Path::new(cert), Path::new(key), client_ca) {
+                Ok(tls) => Some(tls),
+                Err(err) => {
+                    error!("{}", err);
+                    process::exit(1);
+                }
+            }
+        }
+        _ => None,
+    };
+    let shard_tls = match matches.get_one::<String>("shard-ca") {
+        Some(ca) => {
+            // the certificate this server serves with identifies it to the others
+            let identity = matches
+                .get_one::<String>("tls-cert")
+                .zip(matches.get_one::<String>("tls-key"))
+                .map(|(cert, key)| (Path::new(cert), Path::new(key)));
+            match TlsConnector::new(Path::new(ca), identity) {
+                Ok(tls) => Some(tls),
+                Err(err) => {
+                    error!("{}", err);
+                    process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
+    let options = Options {
+        addr,
+        tls,
+        users,
+        memcached_addr: matches.get_one::<String>("memcached-addr").cloned(),
+        http_addr: matches.get_one::<String>("http-addr").cloned(),
+        metrics_addr: matches.get_one::<String>("metrics-addr").cloned(),
+        admin_password_hash: matches.get_one::<String>("admin-password-hash").cloned(),
+        slow_log: matches.get_one::<u64>("slow-log-ms").map(|ms| {
+            let path = matches
+                .get_one::<String>("slow-log-file")
+                .map_or_else(|| PathBuf::from(SLOW_LOG_FILE), PathBuf::from);
+            (Duration::from_millis(*ms), path)
+        }),
+        leader: matches.get_one::<String>("replica-of").cloned(),
+        leader_credentials: matches
+            .get_one::<String>("replica-user")
+            .cloned()
+            .zip(matches.get_one::<String>("replica-password").cloned()),
+        cluster: matches
+            .get_many::<String>("cluster")
+            .map(|nodes| nodes.cloned().collect()),
+        shard_tls,
+        shard_credentials: matches
+            .get_one::<String>("shard-user")
+            .cloned()
+            .zip(matches.get_one::<String>("shard-password").cloned()),
+    };
+    info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
+    info!("Storage engine: {}", engine);
+    let listener = match Listener::bind(&options.addr) {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("{}", err);
+            process::exit(1);
+        }
+    };
+    // port 0 picks a free port, the log tells which
+    let bound = listener
+        .local_addr()
+        .unwrap_or_else(|_| options.addr.clone());
+    info!("Listening on {}", bound);
+    if let Address::Unix(path) = &options.addr {
+        // the listener only removes its socket file when dropped, which a signal skips
+        let path = path.to_owned();
+        let handler = ctrlc::set_handler(move || {
+            let _ = fs::remove_file(&path);
+            process::exit(0);
+        });
+        if let Err(err) = handler {
+            error!("Cannot install signal handler: {}", err);
+        }
+    }
+
+    let result = match engine.as_str() {
+        "kvs" => env::current_dir()
+            .map_err(Into::into)
+            .and_then(|path| KVStore::open_with_options(path, store_options))
+            .and_then(|store| run(store, listener, options)),
+        _ => env::current_dir()
+            .map_err(Into::into)
+            .and_then(|path| Ok(sled::open(path)?))
+            .and_then(|db| run(SledKVStore::open(db), listener, options)),
+    };
+    if let Err(err) = result {
+        error!("{}", err);
+        process::exit(1);
+    }
+}
+
+/// settings of the --config file
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    compaction: CompactionPolicy,
+    backpressure: Backpressure,
+    max_segment_bytes: Option<u64>,
+}
+
+/// the kvs engine settings of the --config file, with the flags given applied over them
+fn store_options(matches: &clap::ArgMatches, retention: Retention) -> Result<KVStoreOptions> {
+    let config: Config = match matches.get_one::<String>("config") {
+        Some(path) => serde_json::from_slice(&fs::read(path)?)?,
+        None => Config::default(),
+    };
+    let mut compaction = config.compaction;
+    for (flag, name) in COMPACTION_FLAGS {
+        if let Some(value) = matches.get_one::<String>(flag) {
+            compaction.set(name, value)?;
+        }
+    }
+    let mut backpressure = config.backpressure;
+    for (flag, name) in BACKPRESSURE_FLAGS {
+        if let Some(value) = matches.get_one::<String>(flag) {
+            backpressure.set(name, value)?;
+        }
+    }
+    Ok(KVStoreOptions {
+        retention,
+        compaction,
+        backpressure,
+        max_segment_bytes: matches
+            .get_one::<u64>("max-segment-bytes")
+            .copied()
+            .or(config.max_segment_bytes)
+            .unwrap_or(MAX_SEGMENT_BYTES),
+    })
+}
+
+struct Options {
+    addr: Address,
+    tls: Option<TlsAcceptor>,
+    users: Option<Users>,
+    // addresses of the optional front-ends
+    memcached_addr: Option<String>,
+    http_addr: Option<String>,
+    metrics_addr: Option<String>,
+    admin_password_hash: Option<String>,
+    // threshold and file of the slow log, if it is on
+    slow_log: Option<(Duration, PathBuf)>,
+    // address of the leader, if this server is a follower
+    leader: Option<String>,
+    leader_credentials: Option<(String, String)>,
+    // addresses of every node, if this server is part of a cluster
+    cluster: Option<Vec<String>>,
+    // how to reach the servers keys are moved to
+    shard_tls: Option<TlsConnector>,
+    shard_credentials: Option<(String, String)>,
+}
+
+/// share the engine between all listeners, and follow the leader or join the cluster if any
+fn run<E: KVStoreEngine + Send + 'static>(
+    engine: E,
+    listener: Listener,
+    options: Options,
+) -> Result<()> {
+    let engine = Arc::new(Mutex::new(engine));
+    if let Some(nodes) = options.cluster.clone() {
+        let id = options.addr.to_string();
+        if !nodes.contains(&id) {
+            return Err(KVStoreError::Other(format!(
+                "{} is not one of the cluster nodes",
+                id
+            )));
+        }
+        info!("Joining cluster of {}", nodes.join(", "));
+        let peers = nodes.into_iter().filter(|node| *node != id).collect();
+        let state_path = env::current_dir()?.join(RAFT_STATE_FILE);
+        let engine = RaftEngine::start(engine, id, peers, state_path, RAFT_LOG_LIMIT)?;
+        return serve(engine, listener, options);
+    }
+    match options.leader.clone() {
+        Some(leader) => {
+            info!("Following leader at {}", leader);
+            let state_path = env::current_dir()?.join(REPLICATION_STATE_FILE);
+            let mut replica = Replica::new(Arc::clone(&engine), leader.as_str(), state_path);
+            if let Some((user, password)) = options.leader_credentials.clone() {
+                replica = replica.with_credentials(user, password);
+            }
+            thread::spawn(move || replica.run());
+            serve(FollowerEngine::new(engine, leader), listener, options)
+        }
+        None => {
+            let state_path = env::current_dir()?.join(SHARD_STATE_FILE);
+            let mut shard = ShardEngine::open(engine, state_path)?;
+            if let Some(tls) = options.shard_tls.clone() {
+                shard = shard.with_tls(tls);
+            }
+            if let Some((user, password)) = options.shard_credentials.clone() {
+                shard = shard.with_credentials(user, password);
+            }
+            serve(shard, listener, options)
+        }
+    }
+}
+
+/// serve the engine on `listener`, and on every optional front-end that is given
+fn serve<E: KVStoreEngine + Clone + Send + 'static>(
+    engine: E,
+    listener: Listener,
+    options: Options,
+) -> Result<()> {
+    if let Some(memcached_addr) = options.memcached_addr {
+        info!("Serving memcached protocol on {}", memcached_addr);
+        let memcached = MemcachedServer::new(engine.clone());
+        thread::spawn(move || {
+            if let Err(err) = memcached.start(memcached_addr) {
+                error!("memcached listener stopped: {}", err);
+            }
+        });
+    }
+    if let Some(http_addr) = options.http_addr {
+        info!("Serving HTTP on {}", http_addr);
+        let http = HttpServer::new(engine.clone());
+        thread::spawn(move || {
+            if let Err(err) = http.start(http_addr) {
+                error!("HTTP listener stopped: {}", err);
+            }
+        });
+    }
+    let mut server = Server::new(engine.clone());
+    if let Some(metrics_addr) = options.metrics_addr {
+        info!("Serving metrics on {}", metrics_addr);
+        let counters = Arc::default();
+        let metrics = MetricsServer::new(engine, Arc::clone(&counters));
+        thread::spawn(move || {
+            if let Err(err) = metrics.start(metrics_addr) {
+                error!("metrics listener stopped: {}", err);
+            }
+        });
+        server = server.with_counters(counters);
+    }
+    if let Some((threshold, path)) = options.slow_log {
+        info!(
+            "Logging requests slower than {:?} to {}",
+            threshold,
+            path.display()
+        );
+        server = server.with_slow_log(SlowLog::new(threshold, Some(path))?);
+    }
+    if let Some(password_hash) = options.admin_password_hash {
+        server = server.with_admin(User {
+            name: ADMIN_USER.to_owned(),
+            password_hash,
+            permissions: Vec::new(),
+            admin: true,
+        });
+    }
+    if let Some(users) = options.users {
+        server = server.with_users(users);
+    }
+    match options.tls {
+        Some(tls) => server.with_tls(tls).start_on(listener),
+        None => server.start_on(listener),
+    }
 }
@@ -0,0 +1,326 @@
+use serde::Deserialize;
+use serde_json::de::IoRead;
+use serde_json::Deserializer;
+
+use crate::{
+    retry_backoff, Address, HashRange, KVStoreError, Pipeline, Request, Response, Result,
+    ServerStats, SlowEntry, Stream, TlsConnector, Transaction, Version, WatchEvent,
+    MAX_TRANSACTION_ATTEMPTS,
+};
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/// client of `Server`, keeps one connection open for all requests
+pub struct KvsClient {
+    reader: Deserializer<IoRead<BufReader<Stream>>>,
+    writer: BufWriter<Stream>,
+}
+
+impl KvsClient {
+    /// connect to a TCP address
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        KvsClient::from_stream(Stream::Tcp(TcpStream::connect(addr)?))
+    }
+
+    /// connect to a unix domain socket at `path`
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> Result<Self> {
+        KvsClient::from_stream(Stream::Unix(UnixStream::connect(path)?))
+    }
+
+    /// connect to a TCP address, or to a unix domain socket given as `unix:/path/to.sock`
+    pub fn connect_to<A: Into<Address>>(addr: A) -> Result<Self> {
+        KvsClient::from_stream(Stream::connect(&addr.into())?)
+    }
+
+    /// connect like `connect_to`, and talk TLS over the connection
+    pub fn connect_tls<A: Into<Address>>(addr: A, tls: &TlsConnector) -> Result<Self> {
+        let addr = addr.into();
+        KvsClient::from_stream(tls.connect(&addr, Stream::connect(&addr)?)?)
+    }
+
+    fn from_stream(stream: Stream) -> Result<Self> {
+        Ok(KvsClient {
+            reader: Deserializer::from_reader(BufReader::new(stream.try_clone()?)),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    /// get value by key, None if the key does not exist
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.call(&Request::Get { key })
+    }
+
+    /// set key, value
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.call(&Request::Set { key, value }).map(|_| ())
+    }
+
+    /// remove key
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.call(&Request::Remove { key }).map(|_| ())
+    }
+
+    /// scan pairs whose key starts with `prefix`, ordered by key, at most `limit` if given
+    pub fn scan(&mut self, prefix: String, limit: Option<usize>) -> Result<Vec<(String, String)>> {
+        match self.send(&Request::Scan { prefix, limit })? {
+            Response::Pairs(pairs) => Ok(pairs),
+            _ => Err(KVStoreError::UnexpectedCommandType),
+        }
+    }
+
+    /// move the keys in `ranges` to the server at `target`, return the keys moved
+    ///
+    /// the server keeps serving, and redirects those keys to `target` once they are moved
+    pub fn migrate(&mut self, target: String, ranges: Vec<HashRange>) -> Result<usize> {
+        let moved = self.call(&Request::Migrate { target, ranges })?;
+        moved
+            .and_then(|moved| moved.parse().ok())
+            .ok_or(KVStoreError::UnexpectedCommandType)
+    }
+
+    /// watch every change of `key_or_prefix` and the keys starting with it
+    ///
+    /// the connection only carries the changes from then on
+    pub fn watch(mut self, key_or_prefix: String) -> Result<WatchStream> {
+        self.call(&Request::Watch { key_or_prefix })?;
+        Ok(WatchStream { client: self })
+    }
+
+    /// get the values of `keys` in one round trip, with a result per key in the same order
+    pub fn multi_get(&mut self, keys: Vec<String>) -> Result<Vec<Result<Option<String>>>> {
+        match self.send(&Request::MultiGet { keys })? {
+            Response::Many(responses) => Ok(responses.into_iter().map(result_of).collect()),
+            _ => Err(KVStoreError::UnexpectedCommandType),
+        }
+    }
+
+    /// set every pair in one round trip, with a result per pair in the same order
+    pub fn multi_set(&mut self, pairs: Vec<(String, String)>) -> Result<Vec<Result<()>>> {
+        match self.send(&Request::MultiSet { pairs })? {
+            Response::Many(responses) => Ok(responses
+                .into_iter()
+                .map(|response| result_of(response).map(|_| ()))
+                .collect()),
+            _ => Err(KVStoreError::UnexpectedCommandType),
+        }
+    }
+
+    /// add `delta` to the integer value of key, a missing key counts as 0
+    ///
+    /// return the new value
+    pub fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        let value = self.call(&Request::Incr { key, delta })?;
+        value
+            .and_then(|value| value.parse().ok())
+            .ok_or(KVStoreError::UnexpectedCommandType)
+    }
+
+    /// compact the log of the engine now, only for an admin
+    pub fn compact(&mut self) -> Result<()> {
+        self.call(&Request::Compact).map(|_| ())
+    }
+
+    /// write everything the engine buffers to disk, only for an admin
+    pub fn flush(&mut self) -> Result<()> {
+        self.call(&Request::Flush).map(|_| ())
+    }
+
+    /// stop the server once it answered, only for an admin
+    pub fn shutdown(&mut self) -> Result<()> {
+        self.call(&Request::Shutdown).map(|_| ())
+    }
+
+    /// change a setting of the server or its engine while it runs, only for an admin
+    pub fn set_config(&mut self, name: String, value: String) -> Result<()> {
+        self.call(&Request::SetConfig { name, value }).map(|_| ())
+    }
+
+    /// the state of the server and its engine
+    pub fn stats(&mut self) -> Result<ServerStats> {
+        match self.send(&Request::Stats)? {
+            Response::Stats(stats) => Ok(stats),
+            _ => Err(KVStoreError::UnexpectedCommandType),
+        }
+    }
+
+    /// the last requests slower than the slow log threshold of the server, oldest first
+    ///
+    /// only an admin may read them
+    pub fn slow_log(&mut self) -> Result<Vec<SlowEntry>> {
+        match self.send(&Request::SlowLog)? {
+            Response::SlowLog(entries) => Ok(entries),
+            _ => Err(KVStoreError::UnexpectedCommandType),
+        }
+    }
+
+    /// send requests back-to-back on this connection, without waiting for each response
+    pub fn pipeline(self) -> Pipeline {
+        Pipeline::new(self.reader, self.writer)
+    }
+
+    /// start a transaction, its writes are sent at once by `Transaction::commit`
+    pub fn transaction(&mut self) -> Transaction<'_> {
+        Transaction::new(self)
+    }
+
+    /// run `body` in a transaction and commit it, run it again on a conflict
+    ///
+    /// return KVStoreError::Conflict if every one of `MAX_TRANSACTION_ATTEMPTS` conflicted
+    pub fn transact<T>(
+        &mut self,
+        mut body: impl FnMut(&mut Transaction<'_>) -> Result<T>,
+    ) -> Result<T> {
+        let mut attempt = 1;
+        loop {
+            let mut transaction = self.transaction();
+            let result = body(&mut transaction)?;
+            match transaction.commit() {
+                Err(KVStoreError::Conflict) if attempt < MAX_TRANSACTION_ATTEMPTS => {
+                    thread::sleep(retry_backoff(attempt));
+                    attempt += 1;
+                }
+                committed => return committed.map(|_| result),
+            }
+        }
+    }
+
+    /// get value by key, with the sequence number of the change that made it
+    pub fn get_version(&mut self, key: String) -> Result<Version> {
+        match self.send(&Request::GetVersion { key })? {
+            Response::Versions(mut versions) if versions.len() == 1 => Ok(versions.remove(0)),
+            _ => Err(KVStoreError::UnexpectedCommandType),
+        }
+    }
+
+    /// apply `writes` if every key of `reads` is still at the version read
+    ///
+    /// return KVStoreError::Conflict and change nothing if one of them changed
+    pub fn commit(
+        &mut self,
+        reads: Vec<(String, Version)>,
+        writes: Vec<(String, Option<String>)>,
+    ) -> Result<()> {
+        match self.call(&Request::Commit { reads, writes })?.as_deref() {
+            Some("true") => Ok(()),
+            Some("false") => Err(KVStoreError::Conflict),
+            _ => Err(KVStoreError::UnexpectedCommandType),
+        }
+    }
+
+    /// get value by key as of the change with sequence number `version`
+    pub fn get_at(&mut self, key: String, version: u64) -> Result<Option<String>> {
+        self.call(&Request::GetAt { key, version })
+    }
+
+    /// the versions of key the server keeps, oldest first
+    pub fn history(&mut self, key: String) -> Result<Vec<Version>> {
+        match self.send(&Request::History { key })? {
+            Response::Versions(versions) => Ok(versions),
+            _ => Err(KVStoreError::UnexpectedCommandType),
+        }
+    }
+
+    /// take a snapshot on the server, return its id
+    ///
+    /// the server drops it once it is not used for `lease`
+    pub fn snapshot(&mut self, lease: Duration) -> Result<u64> {
+        let id = self.call(&Request::Snapshot {
+            lease_ms: lease.as_millis() as u64,
+        })?;
+        id.and_then(|id| id.parse().ok())
+            .ok_or(KVStoreError::UnexpectedCommandType)
+    }
+
+    /// get value by key as it was when snapshot `id` was taken
+    pub fn snapshot_get(&mut self, id: u64, key: String) -> Result<Option<String>> {
+        self.call(&Request::SnapshotGet { id, key })
+    }
+
+    /// scan the pairs whose key starts with `prefix` as they were when snapshot `id` was taken
+    pub fn snapshot_scan(
+        &mut self,
+        id: u64,
+        prefix: String,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        match self.send(&Request::SnapshotScan { id, prefix, limit })? {
+            Response::Pairs(pairs) => Ok(pairs),
+            _ => Err(KVStoreError::UnexpectedCommandType),
+        }
+    }
+
+    /// drop snapshot `id` before its lease runs out
+    pub fn release_snapshot(&mut self, id: u64) -> Result<()> {
+        self.call(&Request::ReleaseSnapshot { id }).map(|_| ())
+    }
+
+    /// replay every change after sequence number `seq`, then keep receiving new ones
+    ///
+    /// start with 0 for every change still in the log, or the `seq` of the last event seen
+    pub fn changes_since(mut self, seq: u64) -> Result<WatchStream> {
+        self.call(&Request::ChangesSince { seq })?;
+        Ok(WatchStream { client: self })
+    }
+
+    /// authenticate the connection, required before any other request
+    /// if the server has a users file
+    pub fn authenticate(&mut self, user: String, password: String) -> Result<()> {
+        self.call(&Request::Auth { user, password }).map(|_| ())
+    }
+
+    /// send a request and wait for its value
+    fn call(&mut self, request: &Request) -> Result<Option<String>> {
+        match self.send(request)? {
+            Response::Ok(value) => Ok(value),
+            _ => Err(KVStoreError::UnexpectedCommandType),
+        }
+    }
+
+    /// send a request and wait for its response, errors are turned into `KVStoreError`
+    fn send(&mut self, request: &Request) -> Result<Response> {
+        serde_json::to_writer(&mut self.writer, request)?;
+        self.writer.flush()?;
+        checked(Response::deserialize(&mut self.reader)?)
+    }
+}
+
+/// turn the error responses into `KVStoreError`
+pub(crate) fn checked(response: Response) -> Result<Response> {
+    match response {
+        Response::Err(msg) => Err(KVStoreError::Other(msg)),
+        Response::Error { kind, detail } => Err(KVStoreError::from_kind(&kind, detail)),
+        Response::Denied(msg) => Err(KVStoreError::PermissionDenied(msg)),
+        Response::Redirect(leader) => Err(KVStoreError::Redirect(leader)),
+        response => Ok(response),
+    }
+}
+
+/// the value of a response, like that of one key of a `Response::Many`
+pub(crate) fn result_of(response: Response) -> Result<Option<String>> {
+    match checked(response)? {
+        Response::Ok(value) => Ok(value),
+        _ => Err(KVStoreError::UnexpectedCommandType),
+    }
+}
+
+/// changes pushed by the server, ends when the connection is closed
+pub struct WatchStream {
+    client: KvsClient,
+}
+
+impl Iterator for WatchStream {
+    type Item = Result<WatchEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match Response::deserialize(&mut self.client.reader) {
+            Ok(Response::Event(event)) => Some(Ok(event)),
+            Ok(response) => Some(checked(response).and(Err(KVStoreError::UnexpectedCommandType))),
+            Err(err) if err.is_eof() => None,
+            Err(err) => Some(Err(err.into())),
+        }
+    }
+}
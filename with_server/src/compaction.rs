@@ -0,0 +1,247 @@
+//! when the log of the kvs engine is compacted
+//!
+//! a compaction is due once the dead records pass either threshold, but it only runs
+//! if the last one is at least `min_interval_secs` old and the time of day is in `window`.
+//! writes are held back while the backlog of the compaction passes the limits of `Backpressure`
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KVStoreError, Result};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// the dead bytes that make a compaction due by default
+pub const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+const MINUTES_PER_DAY: u32 = 24 * 60;
+/// how long a write waits past a soft limit of `Backpressure` by default
+pub const WRITE_SLOWDOWN_MS: u64 = 1;
+/// how often a server lets its engine compact between requests
+pub const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompactionPolicy {
+    // due once the dead records take more bytes than this
+    pub dead_bytes: Option<u64>,
+    // due once the dead records take more than this many times the bytes of the live ones
+    pub dead_ratio: Option<f64>,
+    pub min_interval_secs: Option<u64>,
+    pub window: Option<TimeWindow>,
+}
+
+impl Default for CompactionPolicy {
+    /// due after `COMPACTION_THRESHOLD` dead bytes, at any time
+    fn default() -> Self {
+        CompactionPolicy {
+            dead_bytes: Some(COMPACTION_THRESHOLD),
+            dead_ratio: None,
+            min_interval_secs: None,
+            window: None,
+        }
+    }
+}
+
+impl CompactionPolicy {
+    /// whether a log with `dead` and `live` bytes is compacted now
+    ///
+    /// `since_last_secs` is the time since the last compaction, if any ran
+    pub fn should_compact(
+        &self,
+        dead: u64,
+        live: u64,
+        since_last_secs: Option<u64>,
+        minute_of_day: u32,
+    ) -> bool {
+        let due = self.dead_bytes.is_some_and(|bytes| dead > bytes)
+            || self
+                .dead_ratio
+                .is_some_and(|ratio| dead > 0 && dead as f64 > ratio * live as f64);
+        due && self
+            .min_interval_secs
+            .zip(since_last_secs)
+            .is_none_or(|(interval, since_last)| since_last >= interval)
+            && self
+                .window
+                .is_none_or(|window| window.contains(minute_of_day))
+    }
+
+    /// change a setting of `set_config`, `none` unsets any of them
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        let unset = value == "none";
+        match name {
+            "compaction_threshold" => self.dead_bytes = parse_unless(unset, name, value)?,
+            "compaction_ratio" => self.dead_ratio = parse_unless(unset, name, value)?,
+            "compaction_interval_secs" => {
+                self.min_interval_secs = parse_unless(unset, name, value)?
+            }
+            "compaction_window" => self.window = parse_unless(unset, name, value)?,
+            _ => return Err(KVStoreError::Other(format!("unknown setting {}", name))),
+        }
+        Ok(())
+    }
+}
+
+/// how hard writes are held back while compaction falls behind
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum WriteStall {
+    #[default]
+    Normal,
+    // every write waits `write_slowdown_ms` first
+    Slowed,
+    // every write fails until a compaction brings the log back under the limit
+    Stalled,
+}
+
+/// the limits on the compaction backlog past which writes are slowed down, then stalled
+///
+/// every limit is off by default
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Backpressure {
+    pub soft_dead_bytes: Option<u64>,
+    pub hard_dead_bytes: Option<u64>,
+    // limits on the number of log files
+    pub soft_log_files: Option<u64>,
+    pub hard_log_files: Option<u64>,
+    pub write_slowdown_ms: u64,
+}
+
+impl Default for Backpressure {
+    fn default() -> Self {
+        Backpressure {
+            soft_dead_bytes: None,
+            hard_dead_bytes: None,
+            soft_log_files: None,
+            hard_log_files: None,
+            write_slowdown_ms: WRITE_SLOWDOWN_MS,
+        }
+    }
+}
+
+impl Backpressure {
+    /// the names `set` takes
+    pub const SETTINGS: [&'static str; 5] = [
+        "soft_dead_bytes",
+        "hard_dead_bytes",
+        "soft_log_files",
+        "hard_log_files",
+        "write_slowdown_ms",
+    ];
+
+    /// how writes are held back with `dead_bytes` to compact in `log_files` files
+    pub fn state(&self, dead_bytes: u64, log_files: u64) -> WriteStall {
+        let past = |bytes: Option<u64>, files: Option<u64>| {
+            bytes.is_some_and(|bytes| dead_bytes > bytes)
+                || files.is_some_and(|files| log_files > files)
+        };
+        if past(self.hard_dead_bytes, self.hard_log_files) {
+            WriteStall::Stalled
+        } else if past(self.soft_dead_bytes, self.soft_log_files) {
+            WriteStall::Slowed
+        } else {
+            WriteStall::Normal
+        }
+    }
+
+    /// change a setting of `set_config`, `none` turns a limit off
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        let unset = value == "none";
+        match name {
+            "soft_dead_bytes" => self.soft_dead_bytes = parse_unless(unset, name, value)?,
+            "hard_dead_bytes" => self.hard_dead_bytes = parse_unless(unset, name, value)?,
+            "soft_log_files" => self.soft_log_files = parse_unless(unset, name, value)?,
+            "hard_log_files" => self.hard_log_files = parse_unless(unset, name, value)?,
+            "write_slowdown_ms" => {
+                self.write_slowdown_ms = parse_unless(false, name, value)?.unwrap_or_default()
+            }
+            _ => return Err(KVStoreError::Other(format!("unknown setting {}", name))),
+        }
+        Ok(())
+    }
+}
+
+fn parse_unless<T: FromStr>(unset: bool, name: &str, value: &str) -> Result<Option<T>> {
+    if unset {
+        return Ok(None);
+    }
+    value
+        .parse()
+        .map(Some)
+        .map_err(|_| KVStoreError::Other(format!("invalid {} {}", name, value)))
+}
+
+/// a time of day range in UTC, written `HH:MM-HH:MM`, it wraps past midnight if it ends first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeWindow {
+    // minutes since midnight
+    pub start: u32,
+    pub end: u32,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, minute_of_day: u32) -> bool {
+        if self.start <= self.end {
+            self.start <= minute_of_day && minute_of_day < self.end
+        } else {
+            minute_of_day >= self.start || minute_of_day < self.end
+        }
+    }
+}
+
+/// the minutes since midnight UTC of a time in milliseconds since the unix epoch
+pub fn minute_of_day(millis: u64) -> u32 {
+    (millis / 60_000 % MINUTES_PER_DAY as u64) as u32
+}
+
+impl FromStr for TimeWindow {
+    type Err = KVStoreError;
+
+    fn from_str(window: &str) -> Result<Self> {
+        let invalid = || KVStoreError::Other(format!("invalid time window {}", window));
+        let minutes = |time: &str| -> Result<u32> {
+            let (hours, minutes) = time.trim().split_once(':').ok_or_else(invalid)?;
+            let (hours, minutes): (u32, u32) = (
+                hours.parse().map_err(|_| invalid())?,
+                minutes.parse().map_err(|_| invalid())?,
+            );
+            if hours > 24 || minutes >= 60 || hours * 60 + minutes > MINUTES_PER_DAY {
+                return Err(invalid());
+            }
+            Ok(hours * 60 + minutes)
+        };
+        let (start, end) = window.split_once('-').ok_or_else(invalid)?;
+        Ok(TimeWindow {
+            start: minutes(start)?,
+            end: minutes(end)?,
+        })
+    }
+}
+
+impl TryFrom<String> for TimeWindow {
+    type Error = KVStoreError;
+
+    fn try_from(window: String) -> Result<Self> {
+        window.parse()
+    }
+}
+
+impl From<TimeWindow> for String {
+    fn from(window: TimeWindow) -> Self {
+        window.to_string()
+    }
+}
+
+impl fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
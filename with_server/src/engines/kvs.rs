@@ -1,18 +1,31 @@
 //! This is implementation of KVStoreEngine by KVStore DB
 
-use serde::{de::value, Deserialize, Serialize};
+use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
 
-use crate::{KVStoreEngine, Result};
+use super::increment;
+use crate::{
+    minute_of_day, now_millis, Backpressure, CompactionPolicy, CompactionStats, EngineStats,
+    KVStoreEngine, KVStoreError, LogBatch, LogPosition, LogRecord, Result, Retention, Snapshot,
+    Version, WatchEvent, Watcher, Watchers, WriteStall,
+};
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet, VecDeque},
     ffi::OsStr,
-    fs::{self, read, File, OpenOptions},
+    fs::{self, File, OpenOptions},
     io::{self, BufReader, BufWriter, Read, Seek, Write},
     path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
 };
 
-const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+/// the size a log file grows to before the writes go on in the next one, unless set in `KVStoreOptions`
+pub const MAX_SEGMENT_BYTES: u64 = 16 * 1024 * 1024;
+// the oldest files are compacted up to the newest one at least this much dead,
+// else up to the most dead one
+const SEGMENT_GARBAGE_RATIO: f64 = 0.5;
+// one in this many sequence numbers is indexed, reading changes skips at most this many records
+const SEQ_INDEX_INTERVAL: u64 = 64;
 
 pub struct KVStore {
     // path to database
@@ -27,6 +40,155 @@ pub struct KVStore {
     pub index_map: BTreeMap<String, CommandMedaData>,
     // size of uncompacted data in bytes
     pub uncompact: u64,
+    // the uncompacted bytes of every file
+    garbage: HashMap<u64, u64>,
+    // size of the data files before the current one
+    sealed: u64,
+    // the current file is sealed once it grows past it
+    pub max_segment_bytes: u64,
+    // when `uncompact` is compacted
+    pub compaction: CompactionPolicy,
+    // when writes are held back because compaction falls behind
+    pub backpressure: Backpressure,
+    // open watches, notified on every set and remove
+    pub watchers: Watchers,
+    // sequence numbers of the changes still in the log
+    pub history: History,
+    // files read by live snapshots
+    pins: Arc<Mutex<Pins>>,
+    // which older versions of the keys are kept
+    pub retention: Retention,
+    // the kept versions of every key, empty if only the latest value is kept
+    versions: HashMap<String, KeyVersions>,
+    // the sequence number and file of the removals since the db was opened, the keys removed
+    // before are at `forgotten_seq`
+    removed: HashMap<String, (u64, u64)>,
+    forgotten_seq: u64,
+    // compactions run since the db was opened, and the bytes they dropped
+    compactions: u64,
+    reclaimed_bytes: u64,
+    compaction_ms: u64,
+    last_compaction: Option<CompactionStats>,
+    // writes held back since the db was opened
+    slowed_writes: u64,
+    stalled_writes: u64,
+}
+
+/// how `KVStore::open_with_options` opens the db
+#[derive(Debug, Clone, Copy)]
+pub struct KVStoreOptions {
+    pub retention: Retention,
+    pub compaction: CompactionPolicy,
+    pub backpressure: Backpressure,
+    pub max_segment_bytes: u64,
+}
+
+impl Default for KVStoreOptions {
+    fn default() -> Self {
+        KVStoreOptions {
+            retention: Retention::default(),
+            compaction: CompactionPolicy::default(),
+            backpressure: Backpressure::default(),
+            max_segment_bytes: MAX_SEGMENT_BYTES,
+        }
+    }
+}
+
+/// the kept versions of one key, oldest first
+#[derive(Default)]
+struct KeyVersions {
+    // older versions were dropped, nothing is known before the oldest one
+    pruned: bool,
+    list: VecDeque<VersionMetaData>,
+}
+
+impl KeyVersions {
+    /// drop the versions `retention` does not keep, count them in the `garbage` of their files
+    ///
+    /// return their length in bytes
+    fn prune(&mut self, retention: &Retention, now: u64, garbage: &mut HashMap<u64, u64>) -> u64 {
+        let mut pruned = 0;
+        while let Some(oldest) = self.list.front() {
+            if retention.keeps(self.list.len() - 1, oldest.timestamp, now) {
+                break;
+            }
+            pruned += oldest.record.length;
+            *garbage.entry(oldest.record.file_number).or_default() += oldest.record.length;
+            self.list.pop_front();
+            self.pruned = true;
+        }
+        pruned
+    }
+}
+
+/// a version of a key and where its record is
+struct VersionMetaData {
+    seq: u64,
+    timestamp: u64,
+    removed: bool,
+    record: CommandMedaData,
+}
+
+/// the changes still in the log, compaction discards every change before it
+#[derive(Default)]
+pub struct History {
+    // first sequence number not discarded by compaction
+    pub first_seq: u64,
+    // sequence number of the newest change
+    pub last_seq: u64,
+    // sparse index of sequence numbers to the position of their record
+    index: BTreeMap<u64, LogPosition>,
+    // right after the last compaction, the log before it does not tell every change
+    start: LogPosition,
+    // right after the newest change
+    tip: LogPosition,
+    // the tip when a compaction of the oldest files discarded every change, who read up to it
+    // goes on at `start`
+    discarded_tip: Option<LogPosition>,
+}
+
+impl History {
+    fn record(&mut self, seq: u64, record: &CommandMedaData) {
+        // a compacted file repeats older changes
+        if seq <= self.last_seq {
+            return;
+        }
+        self.last_seq = seq;
+        self.tip = record.end();
+        let position = LogPosition {
+            file_number: record.file_number,
+            offset: record.offset,
+        };
+        // the first change of every file is indexed, so is the first one left after the
+        // oldest files are compacted
+        let first_of_file = self
+            .index
+            .values()
+            .next_back()
+            .is_none_or(|last| last.file_number != position.file_number);
+        if first_of_file || seq.is_multiple_of(SEQ_INDEX_INTERVAL) {
+            self.index.insert(seq, position);
+        }
+    }
+
+    fn compacted(&mut self, seq: u64, start: LogPosition) {
+        self.last_seq = self.last_seq.max(seq);
+        self.first_seq = seq + 1;
+        self.index.clear();
+        self.start = start;
+        self.discarded_tip = None;
+    }
+
+    /// forget the changes up to `seq`, the files they were in were compacted
+    ///
+    /// the log goes on at `end` if none of the changes after them are left
+    fn discard(&mut self, seq: u64, end: LogPosition) {
+        self.discarded_tip = (self.last_seq > 0 && seq >= self.last_seq).then_some(self.tip);
+        self.last_seq = self.last_seq.max(seq);
+        self.first_seq = self.first_seq.max(seq + 1);
+        self.index.retain(|&indexed, _| indexed > seq);
+        self.start = self.index.values().next().copied().unwrap_or(end);
+    }
 }
 
 impl KVStore {
@@ -35,24 +197,94 @@ impl KVStore {
     /// load most recent writer
     /// load most recent command into index_map and uncompacted data in bytes
     pub fn open(path: impl Into<PathBuf>) -> Result<KVStore> {
+        KVStore::open_with_retention(path, Retention::default())
+    }
+
+    /// open the db, and keep the older versions of the keys `retention` asks for
+    pub fn open_with_retention(path: impl Into<PathBuf>, retention: Retention) -> Result<KVStore> {
+        KVStore::open_with_options(
+            path,
+            KVStoreOptions {
+                retention,
+                ..KVStoreOptions::default()
+            },
+        )
+    }
+
+    /// open the db with the retention and compaction policy of `options`
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KVStoreOptions) -> Result<KVStore> {
+        let KVStoreOptions {
+            retention,
+            compaction,
+            backpressure,
+            max_segment_bytes,
+        } = options;
         // open existing db by input path
         let path = path.into();
         fs::create_dir_all(&path)?;
         let mut readers: HashMap<u64, BufferReaderWithPosition<File>> = HashMap::new();
         let mut index_map: BTreeMap<String, CommandMedaData> = BTreeMap::new();
+        let mut versions: HashMap<String, KeyVersions> = HashMap::new();
 
         let file_num_list = sort_file_by_number(&path)?;
-        let mut uncompact = 0_u64;
+        let mut garbage: HashMap<u64, u64> = HashMap::new();
+        let mut history = History {
+            first_seq: 1,
+            ..History::default()
+        };
         // load uncompacted data, and update readers' map
         for file_num in &file_num_list {
             let file_path: PathBuf = build_file_path_by_number(&path, file_num.to_owned());
             let mut file = BufferReaderWithPosition::new(File::open(file_path)?)?;
-            uncompact += load_uncompacted_data(file_num.to_owned(), &mut file, &mut index_map)?;
+            let replaced = load_uncompacted_data(
+                file_num.to_owned(),
+                &mut file,
+                &mut index_map,
+                &mut history,
+                &mut garbage,
+                retention.keeps_history().then_some(&mut versions),
+            )?;
+            if let Some(replaced) = replaced {
+                // the files replaced by a finished compaction were left behind by snapshots or a crash
+                let stale: Vec<u64> = readers
+                    .keys()
+                    .filter(|&&num| replaced.contains(num))
+                    .cloned()
+                    .collect();
+                for stale in stale {
+                    readers.remove(&stale);
+                    fs::remove_file(build_file_path_by_number(&path, stale))?;
+                }
+            }
             // insert file into readers's map
             readers.insert(file_num.to_owned(), file);
         }
+        let now = now_millis();
+        for (key, key_versions) in versions.iter_mut() {
+            // a compaction copies the versions it keeps after newer ones
+            key_versions
+                .list
+                .make_contiguous()
+                .sort_by_key(|version| version.seq);
+            // a compaction may have dropped versions before the ones it kept
+            key_versions.pruned = key_versions
+                .list
+                .front()
+                .is_some_and(|oldest| oldest.seq < history.first_seq);
+            key_versions.prune(&retention, now, &mut garbage);
+            match key_versions.list.back().filter(|latest| !latest.removed) {
+                Some(latest) => index_map.insert(key.to_owned(), latest.record.clone()),
+                None => index_map.remove(key),
+            };
+        }
+        let uncompact = garbage.values().sum();
+        let mut sealed = 0;
+        for reader in readers.values_mut() {
+            sealed += reader.seek(io::SeekFrom::End(0))?;
+        }
         let current_file_number = file_num_list.last().unwrap_or(&0) + 1;
         let current_writer = new_file(&path, current_file_number, &mut readers)?;
+        let forgotten_seq = history.last_seq;
         Ok(KVStore {
             db_path: path,
             current_file_number,
@@ -60,119 +292,932 @@ impl KVStore {
             current_writer,
             index_map,
             uncompact,
+            garbage,
+            sealed,
+            max_segment_bytes,
+            compaction,
+            backpressure,
+            watchers: Watchers::default(),
+            history,
+            pins: Arc::default(),
+            retention,
+            versions,
+            removed: HashMap::new(),
+            forgotten_seq,
+            compactions: 0,
+            reclaimed_bytes: 0,
+            compaction_ms: 0,
+            last_compaction: None,
+            slowed_writes: 0,
+            stalled_writes: 0,
         })
     }
 
-    /// compact uncompacted data
+    /// compact every file at once, the compaction policy only compacts the oldest ones
     pub fn compact(&mut self) -> Result<()> {
-        // create a new file to store data after compacted
-        let compact_file_number = self.current_file_number + 1;
+        let started = Instant::now();
+        let bytes_before = self.log_bytes()?;
+        // create new files to store data after compacted, one after another once each is full
+        let first_segment = self.current_file_number + 1;
+        let mut compact_file_number = first_segment;
         let mut compact_writer =
             self::new_file(&self.db_path, compact_file_number, &mut self.readers)?;
-        let mut offset = 0_u64;
-        for command_meta_data in self.index_map.values_mut() {
-            // get the reader file by file number
-            let reader = self
-                .readers
-                .get_mut(&command_meta_data.file_number)
-                .expect("cannot find matched reader");
-            // seek to the command
-            reader.seek(std::io::SeekFrom::Start(command_meta_data.offset))?;
-            // get command
-            let mut command = reader.take(command_meta_data.length);
-            // write into writer
-            io::copy(&mut command, &mut compact_writer)?;
-            // updated the CommandMetaData in index_map by the CommandMetaData in compact file
-            *command_meta_data = CommandMedaData {
-                file_number: compact_file_number,
-                length: compact_writer.position - offset,
-                offset,
-            };
-            // update offset
-            offset = compact_writer.position;
+        if self.retention.keeps_history() {
+            // copy every version the retention keeps, the latest ones are indexed
+            let now = now_millis();
+            for (key, key_versions) in self.versions.iter_mut() {
+                key_versions.prune(&self.retention, now, &mut self.garbage);
+                for version in key_versions.list.iter_mut() {
+                    roll_segment(
+                        &self.db_path,
+                        &mut self.readers,
+                        &mut compact_file_number,
+                        &mut compact_writer,
+                        self.max_segment_bytes,
+                    )?;
+                    version.record = copy_record(
+                        &mut self.readers,
+                        &version.record,
+                        compact_file_number,
+                        &mut compact_writer,
+                    )?;
+                }
+                if let Some(latest) = key_versions.list.back().filter(|latest| !latest.removed) {
+                    self.index_map.insert(key.to_owned(), latest.record.clone());
+                }
+            }
+        } else {
+            for command_meta_data in self.index_map.values_mut() {
+                roll_segment(
+                    &self.db_path,
+                    &mut self.readers,
+                    &mut compact_file_number,
+                    &mut compact_writer,
+                    self.max_segment_bytes,
+                )?;
+                // updated the CommandMetaData in index_map by the CommandMetaData in compact file
+                *command_meta_data = copy_record(
+                    &mut self.readers,
+                    command_meta_data,
+                    compact_file_number,
+                    &mut compact_writer,
+                )?;
+            }
         }
+        // the copied records do not tell what happened before, so history ends here
+        let marker = Record {
+            seq: self.history.last_seq,
+            timestamp: now_millis(),
+            command: Command::CompactedFrom(first_segment),
+        };
+        serde_json::to_writer(&mut compact_writer, &marker)?;
         compact_writer.flush()?;
-        // delete the compacted files
+        // delete the compacted files, but the ones snapshots still read only once they are dropped
+        let mut pins = lock_pins(&self.pins)?;
         let compacted_file_number_list: Vec<u64> = self
             .readers
             .keys()
-            .filter(|&&file_num| file_num < compact_file_number)
+            .filter(|&&file_num| file_num < first_segment)
             .cloned()
             .collect();
         for file_num in compacted_file_number_list {
             self.readers.remove(&file_num);
-            fs::remove_file(build_file_path_by_number(&self.db_path, file_num))?;
+            if pins.counts.contains_key(&file_num) {
+                pins.retired.insert(file_num);
+            } else {
+                fs::remove_file(build_file_path_by_number(&self.db_path, file_num))?;
+            }
         }
+        drop(pins);
         self.current_file_number = compact_file_number + 1;
         self.current_writer =
             self::new_file(&self.db_path, self.current_file_number, &mut self.readers)?;
         self.uncompact = 0_u64;
-        Ok(())
+        self.garbage.clear();
+        self.removed.clear();
+        self.forgotten_seq = marker.seq;
+        self.history.compacted(
+            marker.seq,
+            LogPosition {
+                file_number: compact_file_number,
+                offset: compact_writer.position,
+            },
+        );
+        // the new current file is empty
+        self.sealed = self.log_bytes()?;
+        self.finish_compaction(started, bytes_before)
     }
-}
 
-impl KVStoreEngine for KVStore {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let command = Command::set(key.to_owned(), value);
+    /// compact only the oldest files, up to the ones with the most dead bytes
+    ///
+    /// their live records are copied to the end of the log, then only those files are deleted,
+    /// the changes in the files after them are still read
+    pub fn compact_segments(&mut self) -> Result<()> {
+        let started = Instant::now();
+        let bytes_before = self.log_bytes()?;
+        if self.retention.keeps_history() {
+            let now = now_millis();
+            for key_versions in self.versions.values_mut() {
+                self.uncompact += key_versions.prune(&self.retention, now, &mut self.garbage);
+            }
+        }
+        // the dead records of the current file can only be compacted once it is sealed
+        if self.current_writer.position > 0 {
+            self.seal_current()?;
+        }
+        let chosen = self.oldest_dead_files()?;
+        let Some(&last_chosen) = chosen.last() else {
+            return Ok(());
+        };
+        // the first change of every file is indexed, the ones before the first change of the
+        // files kept are discarded
+        let discarded_seq = self
+            .history
+            .index
+            .iter()
+            .find(|(_, position)| position.file_number > last_chosen)
+            .map_or(self.history.last_seq, |(&seq, _)| seq - 1);
+        let mut live: Vec<CommandMedaData> = if self.retention.keeps_history() {
+            self.versions
+                .values()
+                .flat_map(|key_versions| key_versions.list.iter())
+                .map(|version| version.record.clone())
+                .collect()
+        } else {
+            self.index_map.values().cloned().collect()
+        };
+        live.retain(|record| record.file_number <= last_chosen);
+        // copied in log order, so the versions of a key stay in order
+        live.sort_unstable_by_key(|record| (record.file_number, record.offset));
+        let mut moved = HashMap::new();
+        for record in live {
+            self.roll_if_full()?;
+            let copy = copy_record(
+                &mut self.readers,
+                &record,
+                self.current_file_number,
+                &mut self.current_writer,
+            )?;
+            moved.insert((record.file_number, record.offset), copy);
+        }
+        // a full compaction may have left older files behind for snapshots, they are replaced too
+        let files = sort_file_by_number(&self.db_path)?
+            .into_iter()
+            .filter(|&file_num| file_num <= last_chosen)
+            .collect();
+        let marker = Record {
+            seq: discarded_seq,
+            timestamp: now_millis(),
+            command: Command::CompactedSegments(files),
+        };
         let offset = self.current_writer.position;
-        serde_json::to_writer(&mut self.current_writer, &command)?;
-        let command_length = self.current_writer.position - offset;
-        let old_data = self.index_map.insert(
-            key.to_owned(),
-            CommandMedaData {
+        serde_json::to_writer(&mut self.current_writer, &marker)?;
+        self.current_writer.flush()?;
+        let position = self.current_writer.position;
+        *self.garbage.entry(self.current_file_number).or_default() += position - offset;
+        self.uncompact += position - offset;
+        self.removed
+            .retain(|_, (_, file_num)| *file_num > last_chosen);
+        self.forgotten_seq = self.forgotten_seq.max(marker.seq);
+        self.history.discard(
+            marker.seq,
+            LogPosition {
                 file_number: self.current_file_number,
-                offset,
-                length: command_length,
+                offset: position,
             },
         );
-        self.uncompact += old_data.map(|cmd| cmd.length).unwrap_or(0_u64);
-        self.current_writer.flush()?;
-        if self.uncompact > COMPACTION_THRESHOLD {
-            self.compact()?;
+        let move_record = |record: &mut CommandMedaData| {
+            if let Some(copy) = moved.get(&(record.file_number, record.offset)) {
+                *record = copy.clone();
+            }
+        };
+        self.index_map.values_mut().for_each(&move_record);
+        self.versions
+            .values_mut()
+            .flat_map(|key_versions| key_versions.list.iter_mut())
+            .for_each(|version| move_record(&mut version.record));
+        for file_num in chosen {
+            self.readers.remove(&file_num);
+            fs::remove_file(build_file_path_by_number(&self.db_path, file_num))?;
+            let dead = self.garbage.remove(&file_num).unwrap_or(0);
+            self.uncompact = self.uncompact.saturating_sub(dead);
         }
+        self.sealed = self.log_bytes()? - position;
+        self.finish_compaction(started, bytes_before)
+    }
+
+    /// the oldest sealed files, up to the newest one at least `SEGMENT_GARBAGE_RATIO` dead,
+    /// or else up to the most dead one, oldest first
+    ///
+    /// a newer file is never compacted without the older ones, the `Remove` records it drops
+    /// cannot leave an older `Set` of their key behind
+    ///
+    /// the file a snapshot reads and the ones after it are left alone
+    fn oldest_dead_files(&self) -> Result<Vec<u64>> {
+        let pins = lock_pins(&self.pins)?;
+        let mut sealed: Vec<u64> = self
+            .readers
+            .keys()
+            .filter(|&&file_num| file_num != self.current_file_number)
+            .cloned()
+            .collect();
+        sealed.sort_unstable();
+        let mut candidates = Vec::new();
+        for file_num in sealed {
+            if pins.counts.contains_key(&file_num) {
+                break;
+            }
+            let dead = self.garbage.get(&file_num).copied().unwrap_or(0);
+            let bytes = fs::metadata(build_file_path_by_number(&self.db_path, file_num))?.len();
+            candidates.push((file_num, dead, dead as f64 / bytes.max(1) as f64));
+        }
+        let count = match candidates
+            .iter()
+            .rposition(|&(_, dead, ratio)| dead > 0 && ratio >= SEGMENT_GARBAGE_RATIO)
+        {
+            Some(newest) => newest + 1,
+            None => candidates
+                .iter()
+                .enumerate()
+                .filter(|(_, &(_, dead, _))| dead > 0)
+                .max_by(|(_, (_, _, ratio)), (_, (_, _, other))| ratio.total_cmp(other))
+                .map_or(0, |(most_dead, _)| most_dead + 1),
+        };
+        Ok(candidates
+            .into_iter()
+            .take(count)
+            .map(|(file_num, _, _)| file_num)
+            .collect())
+    }
+
+    /// count a compaction that started at `started` when the log had `bytes_before`
+    fn finish_compaction(&mut self, started: Instant, bytes_before: u64) -> Result<()> {
+        let reclaimed_bytes = bytes_before.saturating_sub(self.log_bytes()?);
+        self.compactions += 1;
+        self.reclaimed_bytes += reclaimed_bytes;
+        let duration_ms = started.elapsed().as_millis() as u64;
+        self.compaction_ms += duration_ms;
+        self.last_compaction = Some(CompactionStats {
+            finished: now_millis(),
+            duration_ms,
+            reclaimed_bytes,
+        });
         Ok(())
     }
 
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        if let Some(command_meta_data) = self.index_map.get(&key) {
-            // get reader by CommandMetaData
+    /// whether the compaction policy asks for a compaction now
+    fn needs_compaction(&self) -> bool {
+        let now = now_millis();
+        let live = (self.sealed + self.current_writer.position).saturating_sub(self.uncompact);
+        let since_last_secs = self
+            .last_compaction
+            .as_ref()
+            .map(|last| now.saturating_sub(last.finished) / 1000);
+        self.compaction
+            .should_compact(self.uncompact, live, since_last_secs, minute_of_day(now))
+    }
+
+    /// how writes are held back by the compaction backlog now
+    pub fn write_stall(&self) -> WriteStall {
+        self.backpressure
+            .state(self.uncompact, self.readers.len() as u64)
+    }
+
+    /// the size of the log files in use
+    fn log_bytes(&self) -> Result<u64> {
+        let mut bytes = 0;
+        for file_num in self.readers.keys() {
+            bytes += fs::metadata(build_file_path_by_number(&self.db_path, *file_num))?.len();
+        }
+        Ok(bytes)
+    }
+
+    /// go on writing in a new file once the current one is full
+    fn roll_if_full(&mut self) -> Result<()> {
+        if self.current_writer.position >= self.max_segment_bytes {
+            self.seal_current()?;
+        }
+        Ok(())
+    }
+
+    /// go on writing in a new file
+    fn seal_current(&mut self) -> Result<()> {
+        self.current_writer.flush()?;
+        self.sealed += self.current_writer.position;
+        self.current_file_number += 1;
+        self.current_writer =
+            self::new_file(&self.db_path, self.current_file_number, &mut self.readers)?;
+        Ok(())
+    }
+
+    /// count a record that is not needed anymore in the garbage of its file
+    fn add_garbage(&mut self, record: &CommandMedaData) {
+        self.uncompact += record.length;
+        add_garbage(&mut self.garbage, record);
+    }
+
+    /// append a command to the current file as the next change
+    fn append(&mut self, command: Command) -> Result<VersionMetaData> {
+        let version = self.write_records(&[command])?.remove(0);
+        self.history.record(version.seq, &version.record);
+        Ok(version)
+    }
+
+    /// write commands to the current file as the next changes with one write, the caller
+    /// flushes
+    ///
+    /// nothing is recorded or indexed, so a failed write leaves the store as it was
+    fn write_records(&mut self, commands: &[Command]) -> Result<Vec<VersionMetaData>> {
+        self.roll_if_full()?;
+        let timestamp = now_millis();
+        let mut buffer = Vec::new();
+        let mut versions = Vec::with_capacity(commands.len());
+        for (seq, command) in (self.history.last_seq + 1..).zip(commands) {
+            let offset = buffer.len() as u64;
+            serde_json::to_writer(
+                &mut buffer,
+                &Record {
+                    seq,
+                    timestamp,
+                    command: command.clone(),
+                },
+            )?;
+            versions.push(VersionMetaData {
+                seq,
+                timestamp,
+                removed: !matches!(command, Command::Set(..)),
+                record: CommandMedaData {
+                    file_number: self.current_file_number,
+                    offset: self.current_writer.position + offset,
+                    length: buffer.len() as u64 - offset,
+                },
+            });
+        }
+        self.current_writer.write_all(&buffer)?;
+        Ok(versions)
+    }
+
+    /// append a `SET` command and index it, return its sequence number, the caller flushes
+    fn write_set(&mut self, key: &str, value: &str) -> Result<u64> {
+        let command = Command::set(key.to_owned(), value.to_owned());
+        let version = self.append(command)?;
+        let seq = version.seq;
+        self.index_set(key, version);
+        Ok(seq)
+    }
+
+    /// index a new value of `key`
+    fn index_set(&mut self, key: &str, version: VersionMetaData) {
+        self.removed.remove(key);
+        let old_data = self
+            .index_map
+            .insert(key.to_owned(), version.record.clone());
+        if self.retention.keeps_history() {
+            self.uncompact += self.add_version(key, version);
+        } else if let Some(old_data) = old_data {
+            self.add_garbage(&old_data);
+        }
+    }
+
+    /// unindex `key`, the removal of `version` is written
+    fn index_remove(&mut self, key: &str, version: VersionMetaData) {
+        let command_meta_data = self.index_map.remove(key);
+        self.removed
+            .insert(key.to_owned(), (version.seq, version.record.file_number));
+        if self.retention.keeps_history() {
+            // the removal is a version too
+            self.uncompact += self.add_version(key, version);
+        } else {
+            if let Some(command_meta_data) = command_meta_data {
+                self.add_garbage(&command_meta_data);
+            }
+            // add the remove command into uncompact data
+            self.add_garbage(&version.record);
+        }
+    }
+
+    /// keep a new version of `key`, return the length in bytes of the versions it pushes out
+    fn add_version(&mut self, key: &str, version: VersionMetaData) -> u64 {
+        let key_versions = self.versions.entry(key.to_owned()).or_default();
+        key_versions.list.push_back(version);
+        key_versions.prune(&self.retention, now_millis(), &mut self.garbage)
+    }
+
+    /// the kept versions of `key`, or an error if only the latest value is kept
+    fn versions_of(&self, key: &str) -> Result<Option<&KeyVersions>> {
+        if !self.retention.keeps_history() {
+            return Err(KVStoreError::Other(
+                "the store keeps no versions, open it with a retention policy".to_owned(),
+            ));
+        }
+        Ok(self.versions.get(key))
+    }
+
+    /// records from `from` on that are `wanted`, at most `limit`, each with the position after it
+    ///
+    /// the copies a compaction made of older changes are skipped
+    fn read_records(
+        &mut self,
+        from: LogPosition,
+        limit: usize,
+        wanted: impl Fn(&Record) -> bool,
+    ) -> Result<Vec<(LogPosition, Record)>> {
+        // new changes are in the order of their sequence numbers, a change numbered before the
+        // newest one so far is a copy, so reading starts at the indexed change before `from`
+        let (mut newest, mut position) = match self
+            .history
+            .index
+            .iter()
+            .rev()
+            .find(|(_, position)| **position <= from)
+        {
+            Some((&seq, &position)) => (seq - 1, position),
+            None => (self.history.first_seq - 1, from),
+        };
+        let mut records = Vec::new();
+        while records.len() < limit {
+            let file_number = position.file_number;
+            let end = self.file_length(file_number)?;
+            let start = position.offset;
             let reader = self
                 .readers
-                .get_mut(&command_meta_data.file_number)
+                .get_mut(&file_number)
                 .expect("cannot find matched reader");
-            // seek to command position
-            reader.seek(io::SeekFrom::Start(command_meta_data.offset))?;
-            // get the data
-            let data = reader.take(command_meta_data.length);
-            if let Command::Set(_, value) = serde_json::from_reader(data)? {
-                Ok(Some(value))
-            } else {
-                Err(crate::KVStoreError::UnexpectedCommandType)
+            reader.seek(io::SeekFrom::Start(start))?;
+            let mut commands =
+                Deserializer::from_reader(reader.take(end - start)).into_iter::<Record>();
+            while records.len() < limit {
+                match commands.next() {
+                    Some(record) => {
+                        let record = record?;
+                        let before = position;
+                        position.offset = start + commands.byte_offset() as u64;
+                        if record.command.is_change() && record.seq > 0 {
+                            if record.seq <= newest {
+                                continue;
+                            }
+                            newest = record.seq;
+                        }
+                        if before >= from && wanted(&record) {
+                            records.push((position, record));
+                        }
+                    }
+                    None => break,
+                }
+            }
+            if position.offset < end {
+                break;
+            }
+            // go on with the next file, if the end of this one is reached
+            match self.readers.keys().filter(|&&num| num > file_number).min() {
+                Some(&next) => {
+                    position = LogPosition {
+                        file_number: next,
+                        offset: 0,
+                    }
+                }
+                None => break,
             }
-        } else {
-            Ok(None)
+        }
+        Ok(records)
+    }
+
+    /// length of the data file, only the flushed part of the current file counts
+    fn file_length(&mut self, file_number: u64) -> Result<u64> {
+        if file_number == self.current_file_number {
+            return Ok(self.current_writer.position);
+        }
+        let reader = self
+            .readers
+            .get_mut(&file_number)
+            .expect("cannot find matched reader");
+        Ok(reader.seek(io::SeekFrom::End(0))?)
+    }
+}
+
+impl KVStoreEngine for KVStore {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        let seq = self.write_set(&key, &value)?;
+        self.current_writer.flush()?;
+        self.watchers.notify(seq, &key, Some(&value));
+        if self.needs_compaction() {
+            self.compact_segments()?;
+        }
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.index_map.get(&key) {
+            Some(command_meta_data) => read_value(&mut self.readers, command_meta_data).map(Some),
+            None => Ok(None),
         }
     }
 
     fn remove(&mut self, key: String) -> Result<()> {
         if self.index_map.contains_key(&key) {
-            let command_meta_data = self.index_map.remove(&key);
-            self.uncompact += command_meta_data.map(|cmd| cmd.length).unwrap_or(0);
             // create and write the Remove command into current writer file
-            let command = Command::rm(key);
-            let offset = self.current_writer.position;
-            serde_json::to_writer(&mut self.current_writer, &command)?;
-            let data_length = self.current_writer.position - offset;
-            // add the remove command into uncompact data
-            self.uncompact += data_length;
+            let command = Command::rm(key.to_owned());
+            let version = self.append(command)?;
+            let seq = version.seq;
+            self.index_remove(&key, version);
             self.current_writer.flush()?;
-            if self.uncompact > COMPACTION_THRESHOLD {
-                self.compact()?;
+            self.watchers.notify(seq, &key, None);
+            if self.needs_compaction() {
+                self.compact_segments()?;
             }
             Ok(())
         } else {
-            Err(crate::KVStoreError::KeyNotFound)
+            Err(KVStoreError::KeyNotFound)
+        }
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        // the store is only reachable through `&mut self`, so get and write cannot interleave
+        if self.get(key.to_owned())? != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => self.set(key, value)?,
+            None if expected.is_some() => self.remove(key)?,
+            None => {}
+        }
+        Ok(true)
+    }
+
+    fn stats(&mut self) -> Result<EngineStats> {
+        let bytes = self.log_bytes()?;
+        Ok(EngineStats {
+            engine: "kvs".to_owned(),
+            keys: self.index_map.len() as u64,
+            live_bytes: bytes.saturating_sub(self.uncompact),
+            dead_bytes: self.uncompact,
+            log_files: self.readers.len() as u64,
+            compactions: self.compactions,
+            reclaimed_bytes: self.reclaimed_bytes,
+            compaction_ms: self.compaction_ms,
+            last_compaction: self.last_compaction.clone(),
+            write_stall: self.write_stall(),
+            slowed_writes: self.slowed_writes,
+            stalled_writes: self.stalled_writes,
+        })
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        KVStore::compact(self)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.current_writer.flush()?;
+        self.current_writer.writer.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    fn set_config(&mut self, name: &str, value: &str) -> Result<()> {
+        if Backpressure::SETTINGS.contains(&name) {
+            return self.backpressure.set(name, value);
+        }
+        self.compaction.set(name, value)
+    }
+
+    fn compactions(&mut self) -> Result<u64> {
+        Ok(self.compactions)
+    }
+
+    // a stalled write fails until `maintain` or the next compaction catches up
+    fn hold_back_write(&mut self) -> Result<Duration> {
+        match self.write_stall() {
+            WriteStall::Normal => Ok(Duration::ZERO),
+            WriteStall::Slowed => {
+                self.slowed_writes += 1;
+                Ok(Duration::from_millis(self.backpressure.write_slowdown_ms))
+            }
+            WriteStall::Stalled => {
+                self.stalled_writes += 1;
+                Err(KVStoreError::WriteStalled(format!(
+                    "{} dead bytes in {} log files",
+                    self.uncompact,
+                    self.readers.len()
+                )))
+            }
+        }
+    }
+
+    // writes past a hard limit are stalled until the dead bytes are compacted, whatever the
+    // policy says
+    fn maintain(&mut self) -> Result<()> {
+        let stalled =
+            |store: &KVStore| store.uncompact > 0 && store.write_stall() == WriteStall::Stalled;
+        if self.needs_compaction() || stalled(self) {
+            self.compact_segments()?;
+        }
+        // the oldest files may not hold enough of the dead bytes to let writes through
+        if stalled(self) {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn multi_get(&mut self, keys: Vec<String>) -> Result<Vec<Result<Option<String>>>> {
+        // read in file order, so the readers seek forward instead of back and forth
+        let mut lookups: Vec<(usize, CommandMedaData)> = keys
+            .iter()
+            .enumerate()
+            .filter_map(|(position, key)| Some((position, self.index_map.get(key)?.clone())))
+            .collect();
+        lookups.sort_unstable_by_key(|(_, meta)| (meta.file_number, meta.offset));
+        let mut results: Vec<Result<Option<String>>> = keys.iter().map(|_| Ok(None)).collect();
+        for (position, command_meta_data) in lookups {
+            results[position] = read_value(&mut self.readers, &command_meta_data).map(Some);
+        }
+        Ok(results)
+    }
+
+    fn multi_set(&mut self, pairs: Vec<(String, String)>) -> Result<Vec<Result<()>>> {
+        // one flush for every pair
+        let results: Vec<Result<u64>> = pairs
+            .iter()
+            .map(|(key, value)| self.write_set(key, value))
+            .collect();
+        self.current_writer.flush()?;
+        for ((key, value), result) in pairs.iter().zip(&results) {
+            if let Ok(seq) = result {
+                self.watchers.notify(*seq, key, Some(value));
+            }
+        }
+        if self.needs_compaction() {
+            self.compact_segments()?;
         }
+        Ok(results
+            .into_iter()
+            .map(|result| result.map(|_| ()))
+            .collect())
+    }
+
+    fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        // nothing can write between the get and the set
+        let value = increment(&key, self.get(key.to_owned())?.as_deref(), delta)?;
+        self.set(key, value.to_string())?;
+        Ok(value)
+    }
+
+    fn get_version(&mut self, key: String) -> Result<Version> {
+        if let Some(latest) = self.versions.get(&key).and_then(|kept| kept.list.back()) {
+            let (seq, timestamp) = (latest.seq, latest.timestamp);
+            let value = match latest.removed {
+                true => None,
+                false => Some(read_value(&mut self.readers, &latest.record.clone())?),
+            };
+            return Ok(Version {
+                seq,
+                timestamp,
+                value,
+            });
+        }
+        match self.index_map.get(&key) {
+            Some(command_meta_data) => {
+                let record = read_record(&mut self.readers, &command_meta_data.clone())?;
+                match record.command {
+                    Command::Set(_, value) => Ok(Version {
+                        seq: record.seq,
+                        timestamp: record.timestamp,
+                        value: Some(value),
+                    }),
+                    _ => Err(KVStoreError::UnexpectedCommandType),
+                }
+            }
+            None => Ok(Version {
+                seq: self
+                    .removed
+                    .get(&key)
+                    .map_or(self.forgotten_seq, |&(seq, _)| seq),
+                timestamp: 0,
+                value: None,
+            }),
+        }
+    }
+
+    fn commit(
+        &mut self,
+        reads: Vec<(String, Version)>,
+        writes: Vec<(String, Option<String>)>,
+    ) -> Result<bool> {
+        // like compare_and_swap, nothing can write between the checks and the writes, the
+        // sequence numbers tell a key that changed and changed back
+        for (key, read) in reads {
+            if self.get_version(key)?.seq != read.seq {
+                return Ok(false);
+            }
+        }
+        // removing a key that is not there is no change
+        let mut present = HashMap::new();
+        let mut commands = Vec::new();
+        for (key, value) in writes {
+            let there = *present
+                .entry(key.to_owned())
+                .or_insert_with(|| self.index_map.contains_key(&key));
+            match value {
+                Some(value) => {
+                    present.insert(key.to_owned(), true);
+                    commands.push(Command::set(key, value));
+                }
+                None if there => {
+                    present.insert(key.to_owned(), false);
+                    commands.push(Command::rm(key));
+                }
+                None => {}
+            }
+        }
+        if commands.is_empty() {
+            return Ok(true);
+        }
+        // every write is in the log before any of them is indexed or sent on
+        let versions = self.write_records(&commands)?;
+        self.current_writer.flush()?;
+        for (command, version) in commands.into_iter().zip(versions) {
+            self.history.record(version.seq, &version.record);
+            let seq = version.seq;
+            match command {
+                Command::Set(key, value) => {
+                    self.index_set(&key, version);
+                    self.watchers.notify(seq, &key, Some(&value));
+                }
+                Command::Remove(key) => {
+                    self.index_remove(&key, version);
+                    self.watchers.notify(seq, &key, None);
+                }
+                _ => {}
+            }
+        }
+        if self.needs_compaction() {
+            self.compact_segments()?;
+        }
+        Ok(true)
+    }
+
+    fn scan(&mut self, prefix: String, limit: Option<usize>) -> Result<Vec<(String, String)>> {
+        // index_map is ordered, so matched keys are a contiguous range starting at `prefix`
+        let keys: Vec<String> = self
+            .index_map
+            .range(prefix.to_owned()..)
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(&prefix))
+            .take(limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect();
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(value) = self.get(key.to_owned())? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn watch(&mut self, prefix: String) -> Result<Watcher> {
+        Ok(self.watchers.subscribe(prefix))
+    }
+
+    fn get_at(&mut self, key: String, version: u64) -> Result<Option<String>> {
+        let key_versions = match self.versions_of(&key)? {
+            Some(key_versions) => key_versions,
+            None => return Ok(None),
+        };
+        match key_versions
+            .list
+            .iter()
+            .rev()
+            .find(|kept| kept.seq <= version)
+        {
+            Some(kept) if kept.removed => Ok(None),
+            Some(kept) => {
+                let record = kept.record.clone();
+                read_value(&mut self.readers, &record).map(Some)
+            }
+            None => match key_versions.list.front() {
+                Some(oldest) if key_versions.pruned => {
+                    Err(KVStoreError::HistoryCompacted(oldest.seq))
+                }
+                _ => Ok(None),
+            },
+        }
+    }
+
+    fn history(&mut self, key: String) -> Result<Vec<Version>> {
+        let kept: Vec<(u64, u64, Option<CommandMedaData>)> = match self.versions_of(&key)? {
+            Some(key_versions) => key_versions
+                .list
+                .iter()
+                .map(|kept| {
+                    let record = (!kept.removed).then(|| kept.record.clone());
+                    (kept.seq, kept.timestamp, record)
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        kept.into_iter()
+            .map(|(seq, timestamp, record)| {
+                let value = match record {
+                    Some(record) => Some(read_value(&mut self.readers, &record)?),
+                    None => None,
+                };
+                Ok(Version {
+                    seq,
+                    timestamp,
+                    value,
+                })
+            })
+            .collect()
+    }
+
+    fn snapshot(&mut self) -> Result<Snapshot> {
+        let mut pins = lock_pins(&self.pins)?;
+        let mut readers = HashMap::new();
+        for command_meta_data in self.index_map.values() {
+            let file_number = command_meta_data.file_number;
+            if let Entry::Vacant(entry) = readers.entry(file_number) {
+                let file_path = build_file_path_by_number(&self.db_path, file_number);
+                entry.insert(BufferReaderWithPosition::new(File::open(file_path)?)?);
+                *pins.counts.entry(file_number).or_default() += 1;
+            }
+        }
+        Ok(Snapshot::from(KVSnapshot {
+            db_path: self.db_path.to_owned(),
+            index_map: self.index_map.clone(),
+            readers,
+            pins: Arc::clone(&self.pins),
+        }))
+    }
+
+    fn read_log(&mut self, from: LogPosition, limit: usize) -> Result<LogBatch> {
+        let mut position = from;
+        let known = self.readers.contains_key(&from.file_number)
+            && from >= self.history.start
+            && from.offset <= self.file_length(from.file_number)?;
+        if self.history.discarded_tip == Some(from) {
+            // the follower read every change the compaction discarded
+            position = self.history.start;
+        } else if !known {
+            if from != LogPosition::default() || self.history.first_seq > 1 {
+                // the position was compacted away, or the log lacks changes, ship everything instead
+                return Ok(LogBatch::Snapshot {
+                    pairs: self.scan(String::new(), None)?,
+                    next: LogPosition {
+                        file_number: self.current_file_number,
+                        offset: self.current_writer.position,
+                    },
+                });
+            }
+            // a new follower replays the whole log
+            position = LogPosition {
+                file_number: self.readers.keys().min().cloned().unwrap_or(0),
+                offset: 0,
+            };
+        }
+
+        let records = self
+            .read_records(position, limit, |record| record.command.is_change())?
+            .into_iter()
+            .filter_map(|(position, record)| {
+                let record = match record.command.into_change()? {
+                    (key, Some(value)) => LogRecord::Set(key, value),
+                    (key, None) => LogRecord::Remove(key),
+                };
+                Some((position, record))
+            })
+            .collect();
+        Ok(LogBatch::Records(records))
+    }
+
+    fn changes_since(&mut self, seq: u64, limit: usize) -> Result<Vec<WatchEvent>> {
+        if seq + 1 < self.history.first_seq {
+            return Err(KVStoreError::HistoryCompacted(self.history.first_seq));
+        }
+        let start = match self.history.index.range(..=seq + 1).next_back() {
+            Some((_, position)) => *position,
+            None => match self.history.index.values().next() {
+                Some(position) => *position,
+                None => return Ok(Vec::new()),
+            },
+        };
+        let records = self.read_records(start, limit, |record| {
+            record.seq > seq && record.command.is_change()
+        })?;
+        Ok(records
+            .into_iter()
+            .filter_map(|(_, record)| {
+                let (key, value) = record.command.into_change()?;
+                Some(WatchEvent {
+                    seq: record.seq,
+                    key,
+                    value,
+                })
+            })
+            .collect())
     }
 }
 
@@ -191,7 +1236,6 @@ fn new_file(
         OpenOptions::new()
             .create(true)
             .append(true)
-            .write(true)
             .open(&file_path)?,
     )?;
     readers.insert(
@@ -207,44 +1251,270 @@ fn new_file(
 ///
 /// remove the `SET` CommandMetaData by `Remove` Command, and count how many `SET` command and data and `Remove` command itself can be compacted
 ///
-/// return data in bytes that can be compacted in next compact process
+/// the bytes that can be compacted are counted in `garbage` by file, unless `versions` are kept,
+/// then only the versions the retention drops are
+///
+/// record the sequence numbers of the changes in `history`
+///
+/// a finished compaction holds every live record of the files it replaced, the keys only found
+/// in those are dropped
+///
+/// return the files replaced by a compaction the file finishes, if it does
 fn load_uncompacted_data(
     file_number: u64,
     file: &mut BufferReaderWithPosition<File>,
     index_map: &mut BTreeMap<String, CommandMedaData>,
-) -> Result<u64> {
-    let mut data_in_bytes = 0_u64;
+    history: &mut History,
+    garbage: &mut HashMap<u64, u64>,
+    mut versions: Option<&mut HashMap<String, KeyVersions>>,
+) -> Result<Option<Replaced>> {
+    let mut replaced = None;
     // read from begining
     let mut old_offset = file.seek(std::io::SeekFrom::Start(0))?;
-    // read and load the file into Iterator<Command>
-    let mut commands = Deserializer::from_reader(file).into_iter::<Command>();
+    // read and load the file into Iterator<Record>
+    let mut commands = Deserializer::from_reader(file).into_iter::<Record>();
 
-    while let Some(command) = commands.next() {
+    while let Some(record) = commands.next() {
         let new_offset = commands.byte_offset() as u64;
-        match command? {
+        let record = record?;
+        let command_meta_data = CommandMedaData {
+            file_number,
+            offset: old_offset,
+            length: new_offset - old_offset,
+        };
+        if record.command.is_change() && record.seq > 0 {
+            history.record(record.seq, &command_meta_data);
+        }
+        if let (Some(versions), Some(key)) = (versions.as_deref_mut(), record.command.key()) {
+            let version = VersionMetaData {
+                seq: record.seq,
+                timestamp: record.timestamp,
+                removed: !matches!(record.command, Command::Set(..)),
+                record: command_meta_data.clone(),
+            };
+            versions
+                .entry(key.to_owned())
+                .or_default()
+                .list
+                .push_back(version);
+        }
+        let keeps_versions = versions.is_some();
+        match record.command {
             Command::Set(key, _) => {
-                let old_data = index_map.insert(
-                    key,
-                    CommandMedaData {
-                        file_number,
-                        offset: old_offset,
-                        length: new_offset - old_offset,
-                    },
-                );
+                let old_data = index_map.insert(key, command_meta_data);
                 // add the length of prev `set` with the same input key command as uncompacted data
-                data_in_bytes += old_data.map(|cmd| cmd.length).unwrap_or(0);
+                if let Some(old_data) = old_data.filter(|_| !keeps_versions) {
+                    add_garbage(garbage, &old_data);
+                }
             }
             Command::Remove(key) => {
                 let old_data = index_map.remove(&key);
-                // add the removed `set` with input key command as uncompacted data
-                data_in_bytes += old_data.map(|cmd| cmd.length).unwrap_or(0);
-                // add the `remove` command itself as uncompacted data
-                data_in_bytes += new_offset - old_offset;
+                if !keeps_versions {
+                    // add the removed `set` with input key command as uncompacted data
+                    if let Some(old_data) = old_data {
+                        add_garbage(garbage, &old_data);
+                    }
+                    // add the `remove` command itself as uncompacted data
+                    add_garbage(garbage, &command_meta_data);
+                }
+            }
+            Command::CompactedFrom(first_segment) => {
+                index_map
+                    .retain(|_, command_meta_data| command_meta_data.file_number >= first_segment);
+                if let Some(versions) = versions.as_deref_mut() {
+                    versions.retain(|_, key_versions| {
+                        let list = &mut key_versions.list;
+                        list.retain(|version| version.record.file_number >= first_segment);
+                        !list.is_empty()
+                    });
+                }
+                // only the marker itself is left to compact
+                garbage.clear();
+                add_garbage(garbage, &command_meta_data);
+                history.compacted(record.seq, command_meta_data.end());
+                replaced = Some(Replaced::Before(first_segment));
+            }
+            Command::CompactedSegments(files) => {
+                // the files are still there if the compaction did not get to delete them
+                index_map
+                    .retain(|_, command_meta_data| !files.contains(&command_meta_data.file_number));
+                if let Some(versions) = versions.as_deref_mut() {
+                    versions.retain(|_, key_versions| {
+                        let list = &mut key_versions.list;
+                        list.retain(|version| !files.contains(&version.record.file_number));
+                        !list.is_empty()
+                    });
+                }
+                for replaced_file in &files {
+                    garbage.remove(replaced_file);
+                }
+                add_garbage(garbage, &command_meta_data);
+                history.discard(record.seq, command_meta_data.end());
+                replaced = Some(Replaced::Files(files));
             }
         }
         old_offset = new_offset;
     }
-    Ok(data_in_bytes)
+    Ok(replaced)
+}
+
+/// count a record in the garbage of its file
+fn add_garbage(garbage: &mut HashMap<u64, u64>, record: &CommandMedaData) {
+    *garbage.entry(record.file_number).or_default() += record.length;
+}
+
+/// the files a finished compaction replaced
+enum Replaced {
+    // every file before the first one the compaction wrote
+    Before(u64),
+    // the files an incremental compaction picked
+    Files(Vec<u64>),
+}
+
+impl Replaced {
+    fn contains(&self, file_number: u64) -> bool {
+        match self {
+            Replaced::Before(first_segment) => file_number < *first_segment,
+            Replaced::Files(files) => files.contains(&file_number),
+        }
+    }
+}
+
+/// move on to the next file once the one `writer` writes is full, return if it did
+fn roll_segment(
+    dir_path: &Path,
+    readers: &mut HashMap<u64, BufferReaderWithPosition<File>>,
+    file_number: &mut u64,
+    writer: &mut BuffferWriterWithPosition<File>,
+    max_segment_bytes: u64,
+) -> Result<bool> {
+    if writer.position < max_segment_bytes {
+        return Ok(false);
+    }
+    writer.flush()?;
+    *file_number += 1;
+    *writer = new_file(dir_path, *file_number, readers)?;
+    Ok(true)
+}
+
+/// copy a record to the end of the compacted file, return where it is there
+fn copy_record(
+    readers: &mut HashMap<u64, BufferReaderWithPosition<File>>,
+    command_meta_data: &CommandMedaData,
+    compact_file_number: u64,
+    compact_writer: &mut BuffferWriterWithPosition<File>,
+) -> Result<CommandMedaData> {
+    // get the reader file by file number
+    let reader = readers
+        .get_mut(&command_meta_data.file_number)
+        .expect("cannot find matched reader");
+    // seek to the command
+    reader.seek(io::SeekFrom::Start(command_meta_data.offset))?;
+    // get command
+    let mut command = reader.take(command_meta_data.length);
+    // write into writer
+    let offset = compact_writer.position;
+    io::copy(&mut command, compact_writer)?;
+    Ok(CommandMedaData {
+        file_number: compact_file_number,
+        length: compact_writer.position - offset,
+        offset,
+    })
+}
+
+/// read the value of a `SET` command
+fn read_value(
+    readers: &mut HashMap<u64, BufferReaderWithPosition<File>>,
+    command_meta_data: &CommandMedaData,
+) -> Result<String> {
+    match read_record(readers, command_meta_data)?.command {
+        Command::Set(_, value) => Ok(value),
+        _ => Err(KVStoreError::UnexpectedCommandType),
+    }
+}
+
+fn read_record(
+    readers: &mut HashMap<u64, BufferReaderWithPosition<File>>,
+    command_meta_data: &CommandMedaData,
+) -> Result<Record> {
+    // get reader by CommandMetaData
+    let reader = readers
+        .get_mut(&command_meta_data.file_number)
+        .expect("cannot find matched reader");
+    // seek to command position
+    reader.seek(io::SeekFrom::Start(command_meta_data.offset))?;
+    // get the data
+    let data = reader.take(command_meta_data.length);
+    Ok(serde_json::from_reader(data)?)
+}
+
+/// the pairs of a `KVStore` at the moment the snapshot was taken
+pub struct KVSnapshot {
+    db_path: PathBuf,
+    index_map: BTreeMap<String, CommandMedaData>,
+    // readers of the files `index_map` points into, pinned until the snapshot is dropped
+    readers: HashMap<u64, BufferReaderWithPosition<File>>,
+    pins: Arc<Mutex<Pins>>,
+}
+
+impl KVSnapshot {
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        match self.index_map.get(key) {
+            Some(command_meta_data) => read_value(&mut self.readers, command_meta_data).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn scan(&mut self, prefix: &str, limit: Option<usize>) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for (key, command_meta_data) in self
+            .index_map
+            .range(prefix.to_owned()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .take(limit.unwrap_or(usize::MAX))
+        {
+            pairs.push((
+                key.to_owned(),
+                read_value(&mut self.readers, command_meta_data)?,
+            ));
+        }
+        Ok(pairs)
+    }
+}
+
+impl Drop for KVSnapshot {
+    fn drop(&mut self) {
+        let mut pins = match self.pins.lock() {
+            Ok(pins) => pins,
+            Err(_) => return,
+        };
+        for file_number in self.readers.keys() {
+            let count = pins.counts.entry(*file_number).or_default();
+            *count = count.saturating_sub(1);
+            if *count > 0 {
+                continue;
+            }
+            pins.counts.remove(file_number);
+            if pins.retired.remove(file_number) {
+                // only a disk error leaves it behind, and the next open deletes it
+                let _ = fs::remove_file(build_file_path_by_number(&self.db_path, *file_number));
+            }
+        }
+    }
+}
+
+/// data files read by snapshots
+#[derive(Default)]
+struct Pins {
+    // snapshots reading each file
+    counts: HashMap<u64, usize>,
+    // compacted files kept for snapshots, deleted with the last one
+    retired: HashSet<u64>,
+}
+
+fn lock_pins(pins: &Mutex<Pins>) -> Result<MutexGuard<'_, Pins>> {
+    pins.lock()
+        .map_err(|_| KVStoreError::Other("snapshot pins lock poisoned".to_owned()))
 }
 
 /// build file path
@@ -293,7 +1563,7 @@ impl<R: Read + Seek> Seek for BufferReaderWithPosition<R> {
 
 impl<R: Read + Seek> BufferReaderWithPosition<R> {
     fn new(mut inner: R) -> Result<Self> {
-        let position = inner.seek(std::io::SeekFrom::Current(0))?;
+        let position = inner.stream_position()?;
         Ok(Self {
             reader: BufReader::new(inner),
             position,
@@ -328,7 +1598,7 @@ impl<W: Write + Seek> Seek for BuffferWriterWithPosition<W> {
 
 impl<W: Write + Seek> BuffferWriterWithPosition<W> {
     fn new(mut inner: W) -> Result<Self> {
-        let position = inner.seek(std::io::SeekFrom::Current(0))?;
+        let position = inner.stream_position()?;
         Ok(Self {
             writer: BufWriter::new(inner),
             position,
@@ -337,18 +1607,48 @@ impl<W: Write + Seek> BuffferWriterWithPosition<W> {
 }
 
 /// command's meta data, offset of a command and length of the command/command with data
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct CommandMedaData {
     file_number: u64,
     offset: u64,
     length: u64,
 }
 
+impl CommandMedaData {
+    /// the position right after the command
+    fn end(&self) -> LogPosition {
+        LogPosition {
+            file_number: self.file_number,
+            offset: self.offset + self.length,
+        }
+    }
+}
+
+/// a command in the log, with the sequence number of the change
 #[derive(Deserialize, Serialize)]
+struct Record {
+    // 0 in logs written before changes were numbered
+    #[serde(default)]
+    seq: u64,
+    // milliseconds since the unix epoch
+    #[serde(default)]
+    timestamp: u64,
+    #[serde(flatten)]
+    command: Command,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
 enum Command {
     Set(String, String),
     Remove(String),
+    // last record of a full compaction, the changes up to its sequence number are gone
+    // the compaction wrote every file from this number on to the one it ends
+    CompactedFrom(u64),
+    // last record of an incremental compaction, the live records of these files were copied
+    // before it and the files deleted, the changes up to its sequence number are gone
+    CompactedSegments(Vec<u64>),
 }
+
 impl Command {
     fn set(key: String, value: String) -> Command {
         Command::Set(key, value)
@@ -357,4 +1657,27 @@ impl Command {
     fn rm(key: String) -> Command {
         Command::Remove(key)
     }
+
+    fn key(&self) -> Option<&str> {
+        match self {
+            Command::Set(key, _) | Command::Remove(key) => Some(key),
+            Command::CompactedFrom(_) | Command::CompactedSegments(_) => None,
+        }
+    }
+
+    fn is_change(&self) -> bool {
+        !matches!(
+            self,
+            Command::CompactedFrom(_) | Command::CompactedSegments(_)
+        )
+    }
+
+    /// the key and its new value, None if it was removed
+    fn into_change(self) -> Option<(String, Option<String>)> {
+        match self {
+            Command::Set(key, value) => Some((key, Some(value))),
+            Command::Remove(key) => Some((key, None)),
+            Command::CompactedFrom(_) | Command::CompactedSegments(_) => None,
+        }
+    }
 }
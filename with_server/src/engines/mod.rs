@@ -1,4 +1,11 @@
-use crate::Result;
+use crate::{
+    EngineStats, HashRange, KVStoreError, LogBatch, LogPosition, RaftMessage, RaftReply, Result,
+    Snapshot, Version, WatchEvent, Watcher,
+};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
 pub trait KVStoreEngine {
     /// set key, value
     ///
@@ -14,7 +21,326 @@ pub trait KVStoreEngine {
     ///
     /// return KVStoreError::KeyNotFound if the key does not exsits
     fn remove(&mut self, key: String) -> Result<()>;
+
+    /// compare and swap the value of key
+    ///
+    /// `expected` None means the key must not exist, `new` None means remove the key
+    ///
+    /// return false (and change nothing) if the current value does not match `expected`
+    fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool>;
+
+    /// get the values of `keys`, with a result per key in the same order
+    fn multi_get(&mut self, keys: Vec<String>) -> Result<Vec<Result<Option<String>>>> {
+        Ok(keys.into_iter().map(|key| self.get(key)).collect())
+    }
+
+    /// set every pair, with a result per pair in the same order
+    fn multi_set(&mut self, pairs: Vec<(String, String)>) -> Result<Vec<Result<()>>> {
+        Ok(pairs
+            .into_iter()
+            .map(|(key, value)| self.set(key, value))
+            .collect())
+    }
+
+    /// add `delta` to the integer value of key, a missing key counts as 0
+    ///
+    /// return the new value, or KVStoreError::NotAnInteger if the value is not an integer
+    ///
+    /// the default retries compare_and_swap until no other write gets in between
+    fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        loop {
+            let current = self.get(key.to_owned())?;
+            let value = increment(&key, current.as_deref(), delta)?;
+            if self.compare_and_swap(key.to_owned(), current, Some(value.to_string()))? {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// the value of key with the sequence number of the change that made it, for `commit`
+    ///
+    /// the default numbers nothing, the seq is 0
+    fn get_version(&mut self, key: String) -> Result<Version> {
+        Ok(Version {
+            seq: 0,
+            timestamp: 0,
+            value: self.get(key)?,
+        })
+    }
+
+    /// commit a transaction: apply `writes` at once if every key of `reads` is still at the
+    /// version `get_version` read, an engine that numbers no changes compares the values
+    ///
+    /// a value None means the key does not exist, or is removed by the write
+    ///
+    /// return false (and change nothing) if another write got in between
+    fn commit(
+        &mut self,
+        reads: Vec<(String, Version)>,
+        writes: Vec<(String, Option<String>)>,
+    ) -> Result<bool>;
+
+    /// scan key-value pairs whose key starts with `prefix`, ordered by key
+    ///
+    /// return at most `limit` pairs if it is given
+    fn scan(&mut self, prefix: String, limit: Option<usize>) -> Result<Vec<(String, String)>>;
+
+    /// watch every set and remove of the keys starting with `prefix`
+    fn watch(&mut self, prefix: String) -> Result<Watcher>;
+
+    /// value of key as of the change with sequence number `version`
+    ///
+    /// return None if the key did not exist then, KVStoreError::HistoryCompacted if that
+    /// version is no longer kept
+    fn get_at(&mut self, _key: String, _version: u64) -> Result<Option<String>> {
+        Err(KVStoreError::Other(
+            "the engine keeps no versions".to_owned(),
+        ))
+    }
+
+    /// the kept versions of key, oldest first
+    fn history(&mut self, _key: String) -> Result<Vec<Version>> {
+        Err(KVStoreError::Other(
+            "the engine keeps no versions".to_owned(),
+        ))
+    }
+
+    /// a read-only view of the pairs as they are now, later writes are not seen by it
+    ///
+    /// the default copies every pair
+    fn snapshot(&mut self) -> Result<Snapshot> {
+        Ok(Snapshot::from_pairs(self.scan(String::new(), None)?))
+    }
+
+    /// the size of the data and how compaction went
+    ///
+    /// the default only counts the keys
+    fn stats(&mut self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            engine: "unknown".to_owned(),
+            keys: self.scan(String::new(), None)?.len() as u64,
+            ..EngineStats::default()
+        })
+    }
+
+    /// compact the log now, instead of waiting for it to grow
+    fn compact(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// write everything buffered to disk
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// change a setting while the engine runs
+    fn set_config(&mut self, name: &str, _value: &str) -> Result<()> {
+        Err(KVStoreError::Other(format!("unknown setting {}", name)))
+    }
+
+    /// how many times the engine compacted its log, cheaper than `stats`
+    fn compactions(&mut self) -> Result<u64> {
+        Ok(0)
+    }
+
+    /// how long a client write waits first while compaction falls behind, see
+    /// `slow_down_write`, or KVStoreError::WriteStalled if it cannot be made now
+    ///
+    /// the engine's own write methods are never held back, so replicated and applied
+    /// writes go through whatever the backlog
+    fn hold_back_write(&mut self) -> Result<Duration> {
+        Ok(Duration::ZERO)
+    }
+
+    /// the work the engine does between requests, like the compactions its policy asks for
+    fn maintain(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// read at most `limit` log records written after `from`, to ship them to followers
+    ///
+    /// return a snapshot of all pairs if `from` is no longer in the log
+    fn read_log(&mut self, _from: LogPosition, _limit: usize) -> Result<LogBatch> {
+        Err(KVStoreError::Other(
+            "the engine has no log to replicate".to_owned(),
+        ))
+    }
+
+    /// read at most `limit` changes with a sequence number after `seq`, oldest first
+    ///
+    /// return KVStoreError::HistoryCompacted if compaction discarded some of them
+    fn changes_since(&mut self, _seq: u64, _limit: usize) -> Result<Vec<WatchEvent>> {
+        Err(KVStoreError::Other(
+            "the engine keeps no history of changes".to_owned(),
+        ))
+    }
+
+    /// copy the keys in `ranges` to the server at `target`, then redirect them there
+    ///
+    /// return the number of keys moved
+    fn migrate(&mut self, _target: String, _ranges: Vec<HashRange>) -> Result<usize> {
+        Err(KVStoreError::Other(
+            "the server does not migrate keys".to_owned(),
+        ))
+    }
+
+    /// handle a message from another node of the cluster
+    fn raft(&mut self, _message: RaftMessage) -> Result<RaftReply> {
+        Err(KVStoreError::Other(
+            "the server is not in cluster mode".to_owned(),
+        ))
+    }
+}
+
+/// share one engine between several listeners, every call holds the lock
+impl<E: KVStoreEngine> KVStoreEngine for Arc<Mutex<E>> {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        lock(self)?.set(key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        lock(self)?.get(key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        lock(self)?.remove(key)
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        lock(self)?.compare_and_swap(key, expected, new)
+    }
+
+    fn multi_get(&mut self, keys: Vec<String>) -> Result<Vec<Result<Option<String>>>> {
+        lock(self)?.multi_get(keys)
+    }
+
+    fn multi_set(&mut self, pairs: Vec<(String, String)>) -> Result<Vec<Result<()>>> {
+        lock(self)?.multi_set(pairs)
+    }
+
+    fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        lock(self)?.incr(key, delta)
+    }
+
+    fn get_version(&mut self, key: String) -> Result<Version> {
+        lock(self)?.get_version(key)
+    }
+
+    fn commit(
+        &mut self,
+        reads: Vec<(String, Version)>,
+        writes: Vec<(String, Option<String>)>,
+    ) -> Result<bool> {
+        lock(self)?.commit(reads, writes)
+    }
+
+    fn scan(&mut self, prefix: String, limit: Option<usize>) -> Result<Vec<(String, String)>> {
+        lock(self)?.scan(prefix, limit)
+    }
+
+    fn watch(&mut self, prefix: String) -> Result<Watcher> {
+        lock(self)?.watch(prefix)
+    }
+
+    fn get_at(&mut self, key: String, version: u64) -> Result<Option<String>> {
+        lock(self)?.get_at(key, version)
+    }
+
+    fn history(&mut self, key: String) -> Result<Vec<Version>> {
+        lock(self)?.history(key)
+    }
+
+    fn snapshot(&mut self) -> Result<Snapshot> {
+        lock(self)?.snapshot()
+    }
+
+    fn stats(&mut self) -> Result<EngineStats> {
+        lock(self)?.stats()
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        lock(self)?.compact()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        lock(self)?.flush()
+    }
+
+    fn set_config(&mut self, name: &str, value: &str) -> Result<()> {
+        lock(self)?.set_config(name, value)
+    }
+
+    fn compactions(&mut self) -> Result<u64> {
+        lock(self)?.compactions()
+    }
+
+    fn hold_back_write(&mut self) -> Result<Duration> {
+        lock(self)?.hold_back_write()
+    }
+
+    fn maintain(&mut self) -> Result<()> {
+        lock(self)?.maintain()
+    }
+
+    fn read_log(&mut self, from: LogPosition, limit: usize) -> Result<LogBatch> {
+        lock(self)?.read_log(from, limit)
+    }
+
+    fn changes_since(&mut self, seq: u64, limit: usize) -> Result<Vec<WatchEvent>> {
+        lock(self)?.changes_since(seq, limit)
+    }
+
+    fn migrate(&mut self, target: String, ranges: Vec<HashRange>) -> Result<usize> {
+        lock(self)?.migrate(target, ranges)
+    }
+
+    fn raft(&mut self, message: RaftMessage) -> Result<RaftReply> {
+        lock(self)?.raft(message)
+    }
+}
+
+/// `value` of key plus `delta`, a missing value counts as 0
+pub(crate) fn increment(key: &str, value: Option<&str>, delta: i64) -> Result<i64> {
+    let current = match value {
+        Some(value) => value
+            .trim()
+            .parse::<i64>()
+            .map_err(|_| KVStoreError::NotAnInteger(key.to_owned()))?,
+        None => 0,
+    };
+    current
+        .checked_add(delta)
+        .ok_or_else(|| KVStoreError::Other(format!("incrementing {} overflows", key)))
+}
+
+/// wait as long as `engine` asks before a client write, or fail if writes are stalled
+///
+/// called before the write, the lock of a shared engine is not held while waiting
+pub fn slow_down_write<E: KVStoreEngine>(engine: &mut E) -> Result<()> {
+    let delay = engine.hold_back_write()?;
+    if !delay.is_zero() {
+        thread::sleep(delay);
+    }
+    Ok(())
+}
+
+fn lock<E>(engine: &Mutex<E>) -> Result<std::sync::MutexGuard<'_, E>> {
+    engine
+        .lock()
+        .map_err(|_| KVStoreError::Other("engine lock poisoned".to_owned()))
 }
 
 mod kvs;
+pub use kvs::{KVSnapshot, KVStore, KVStoreOptions, MAX_SEGMENT_BYTES};
 mod seld;
+pub use seld::SledKVStore;
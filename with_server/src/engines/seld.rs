@@ -2,6 +2,8 @@
 
 use super::KVStoreEngine;
 use crate::error::{KVStoreError, Result};
+use crate::{EngineStats, Version, Watcher};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
 use sled::{Db, Tree};
 
 #[derive(Clone)]
@@ -36,4 +38,93 @@ impl KVStoreEngine for SledKVStore {
         tree.flush()?;
         Ok(())
     }
+
+    fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let tree: &Tree = &self.0;
+        let swapped = tree
+            .compare_and_swap(
+                key,
+                expected.map(String::into_bytes),
+                new.map(String::into_bytes),
+            )?
+            .is_ok();
+        tree.flush()?;
+        Ok(swapped)
+    }
+
+    // sled reclaims space in the background, flushing is what can be asked of it
+    fn compact(&mut self) -> Result<()> {
+        self.flush()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.0.flush()?;
+        Ok(())
+    }
+
+    fn stats(&mut self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            engine: "sled".to_owned(),
+            keys: self.0.len() as u64,
+            live_bytes: self.0.size_on_disk()?,
+            ..EngineStats::default()
+        })
+    }
+
+    fn commit(
+        &mut self,
+        reads: Vec<(String, Version)>,
+        writes: Vec<(String, Option<String>)>,
+    ) -> Result<bool> {
+        let tree: &Tree = &self.0;
+        // sled numbers no changes, the values are compared, and it retries the closure itself
+        // if another transaction touches the same keys
+        let result = tree.transaction(|tx| {
+            for (key, read) in &reads {
+                let value = tx.get(key)?;
+                if value.as_deref() != read.value.as_ref().map(String::as_bytes) {
+                    return Err(ConflictableTransactionError::Abort(()));
+                }
+            }
+            for (key, value) in &writes {
+                match value {
+                    Some(value) => tx.insert(key.as_bytes(), value.as_bytes())?,
+                    None => tx.remove(key.as_bytes())?,
+                };
+            }
+            Ok(())
+        });
+        match result {
+            Ok(()) => {
+                tree.flush()?;
+                Ok(true)
+            }
+            Err(TransactionError::Abort(())) => Ok(false),
+            Err(TransactionError::Storage(err)) => Err(err.into()),
+        }
+    }
+
+    fn watch(&mut self, prefix: String) -> Result<Watcher> {
+        let tree: &Tree = &self.0;
+        Ok(Watcher::from_sled(tree.watch_prefix(prefix)))
+    }
+
+    fn scan(&mut self, prefix: String, limit: Option<usize>) -> Result<Vec<(String, String)>> {
+        let tree: &Tree = &self.0;
+        tree.scan_prefix(prefix)
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|pair| {
+                let (key, value) = pair?;
+                Ok((
+                    String::from_utf8(key.to_vec())?,
+                    String::from_utf8(value.to_vec())?,
+                ))
+            })
+            .collect()
+    }
 }
@@ -1,3 +1,5 @@
+// `failure_derive` expands its impls inside an anonymous const
+#![allow(non_local_definitions)]
 use failure::Fail;
 use std::io;
 use std::string::FromUtf8Error;
@@ -13,12 +15,36 @@ pub enum KVStoreError {
     // Sled DB error
     #[fail(display = "sled error: {}", _0)]
     Sled(#[cause] sled::Error),
+    // TLS error
+    #[fail(display = "TLS error: {}", _0)]
+    Tls(#[cause] rustls::Error),
     // Key or value is invalid UTF-8 sequence
     #[fail(display = "UTF-8 error: {}", _0)]
     Utf8(#[cause] FromUtf8Error),
     // Removing non-existent key error
     #[fail(display = "Key not found")]
     KeyNotFound,
+    // Not authenticated or no permission for the key
+    #[fail(display = "Permission denied: {}", _0)]
+    PermissionDenied(String),
+    // Writes must go to the leader at this address
+    #[fail(display = "Not the leader, redirect to {}", _0)]
+    Redirect(String),
+    // Changes before this sequence number were discarded by compaction
+    #[fail(
+        display = "Changes before sequence number {} were discarded by compaction",
+        _0
+    )]
+    HistoryCompacted(u64),
+    // Incrementing a key whose value is not an integer
+    #[fail(display = "Value of {} is not an integer", _0)]
+    NotAnInteger(String),
+    // A transaction read a key another write changed before it committed
+    #[fail(display = "Transaction conflict, a key it read was changed")]
+    Conflict,
+    // Compaction cannot bring the log back under the hard limit of the backpressure
+    #[fail(display = "Writes stalled, compaction is behind: {}", _0)]
+    WriteStalled(String),
     // Invalid Command type error
     #[fail(display = "Unexpected command type")]
     UnexpectedCommandType,
@@ -27,6 +53,65 @@ pub enum KVStoreError {
     Other(String),
 }
 
+impl KVStoreError {
+    /// the name of the variant, errors are counted by it
+    pub fn kind(&self) -> &'static str {
+        match self {
+            KVStoreError::Io(_) => "io",
+            KVStoreError::Serde(_) => "serde",
+            KVStoreError::Sled(_) => "sled",
+            KVStoreError::Tls(_) => "tls",
+            KVStoreError::Utf8(_) => "utf8",
+            KVStoreError::KeyNotFound => "key_not_found",
+            KVStoreError::PermissionDenied(_) => "permission_denied",
+            KVStoreError::Redirect(_) => "redirect",
+            KVStoreError::HistoryCompacted(_) => "history_compacted",
+            KVStoreError::NotAnInteger(_) => "not_an_integer",
+            KVStoreError::Conflict => "conflict",
+            KVStoreError::WriteStalled(_) => "write_stalled",
+            KVStoreError::UnexpectedCommandType => "unexpected_command_type",
+            KVStoreError::Other(_) => "other",
+        }
+    }
+
+    /// what the variant holds, a server sends it with the kind so the client can rebuild it
+    pub fn detail(&self) -> String {
+        match self {
+            KVStoreError::PermissionDenied(detail)
+            | KVStoreError::Redirect(detail)
+            | KVStoreError::NotAnInteger(detail)
+            | KVStoreError::WriteStalled(detail)
+            | KVStoreError::Other(detail) => detail.clone(),
+            KVStoreError::HistoryCompacted(seq) => seq.to_string(),
+            KVStoreError::KeyNotFound
+            | KVStoreError::Conflict
+            | KVStoreError::UnexpectedCommandType => String::new(),
+            // the cause stays on the server, only its message goes
+            err => err.to_string(),
+        }
+    }
+
+    /// the error a server sent as `kind` and `detail`
+    ///
+    /// errors with a cause, like io, come back as `Other` with their message
+    pub fn from_kind(kind: &str, detail: String) -> KVStoreError {
+        match kind {
+            "key_not_found" => KVStoreError::KeyNotFound,
+            "permission_denied" => KVStoreError::PermissionDenied(detail),
+            "redirect" => KVStoreError::Redirect(detail),
+            "history_compacted" => match detail.parse() {
+                Ok(seq) => KVStoreError::HistoryCompacted(seq),
+                Err(_) => KVStoreError::Other(detail),
+            },
+            "not_an_integer" => KVStoreError::NotAnInteger(detail),
+            "conflict" => KVStoreError::Conflict,
+            "write_stalled" => KVStoreError::WriteStalled(detail),
+            "unexpected_command_type" => KVStoreError::UnexpectedCommandType,
+            _ => KVStoreError::Other(detail),
+        }
+    }
+}
+
 impl From<io::Error> for KVStoreError {
     fn from(err: io::Error) -> Self {
         KVStoreError::Io(err)
@@ -45,6 +130,12 @@ impl From<sled::Error> for KVStoreError {
     }
 }
 
+impl From<rustls::Error> for KVStoreError {
+    fn from(err: rustls::Error) -> Self {
+        KVStoreError::Tls(err)
+    }
+}
+
 impl From<FromUtf8Error> for KVStoreError {
     fn from(err: FromUtf8Error) -> Self {
         KVStoreError::Utf8(err)
@@ -0,0 +1,130 @@
+//! HTTP/JSON gateway on top of KVStoreEngine
+//!
+//! `GET /kv/{key}`, `PUT /kv/{key}` (value in body), `DELETE /kv/{key}`,
+//! `GET /kv?prefix=&limit=` and `GET /health`
+
+use log::error;
+use serde_json::json;
+use tiny_http::{Header, Method, Request as HttpRequest, Response as HttpResponse};
+
+use crate::{slow_down_write, KVStoreEngine, KVStoreError, Result};
+use std::net::{TcpListener, ToSocketAddrs};
+
+pub struct HttpServer<E: KVStoreEngine> {
+    pub engine: E,
+}
+
+impl<E: KVStoreEngine> HttpServer<E> {
+    /// `new` create a http server
+    pub fn new(engine: E) -> Self {
+        HttpServer { engine }
+    }
+
+    pub fn start<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        self.start_on(TcpListener::bind(addr)?)
+    }
+
+    /// serve on a listener that is already bound, like `start`
+    pub fn start_on(mut self, listener: TcpListener) -> Result<()> {
+        let server = tiny_http::Server::from_listener(listener, None)
+            .map_err(|err| KVStoreError::Other(err.to_string()))?;
+        for mut request in server.incoming_requests() {
+            let (status, body) = self.serve(&mut request);
+            let mut response = HttpResponse::from_string(body.to_string()).with_status_code(status);
+            if let Ok(header) = Header::from_bytes("Content-Type", "application/json") {
+                response.add_header(header);
+            }
+            if let Err(err) = request.respond(response) {
+                error!("Error on serving http client: {}", err)
+            }
+        }
+        Ok(())
+    }
+
+    /// route the request, return status code and JSON body
+    fn serve(&mut self, request: &mut HttpRequest) -> (u16, serde_json::Value) {
+        let url = request.url().to_owned();
+        let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+        let result = match (request.method(), path) {
+            (Method::Get, "/health") => Ok((200, json!({ "status": "ok" }))),
+            (Method::Get, "/kv") => self.list(query),
+            (method, path) => match path.strip_prefix("/kv/").map(percent_decode) {
+                Some(Some(key)) => match method {
+                    Method::Get => self.engine.get(key.to_owned()).map(|value| match value {
+                        Some(value) => (200, json!({ "key": key, "value": value })),
+                        None => (404, json!({ "error": "Key not found" })),
+                    }),
+                    Method::Put => read_body(request)
+                        .and_then(|value| {
+                            slow_down_write(&mut self.engine)?;
+                            self.engine.set(key.to_owned(), value)
+                        })
+                        .map(|_| (200, json!({ "key": key }))),
+                    Method::Delete => slow_down_write(&mut self.engine)
+                        .and_then(|_| self.engine.remove(key.to_owned()))
+                        .map(|_| (200, json!({ "key": key }))),
+                    _ => Ok((405, json!({ "error": "Method not allowed" }))),
+                },
+                Some(None) => Ok((400, json!({ "error": "Invalid key encoding" }))),
+                None => Ok((404, json!({ "error": "Not found" }))),
+            },
+        };
+        result.unwrap_or_else(|err| (status_of(&err), json!({ "error": err.to_string() })))
+    }
+
+    /// `GET /kv?prefix=&limit=`
+    fn list(&mut self, query: &str) -> Result<(u16, serde_json::Value)> {
+        let mut prefix = String::new();
+        let mut limit = None;
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            // `+` stands for a space only in the query
+            match (name, percent_decode(&value.replace('+', " "))) {
+                ("prefix", Some(value)) => prefix = value,
+                ("limit", Some(value)) => match value.parse::<usize>() {
+                    Ok(value) => limit = Some(value),
+                    Err(_) => return Ok((400, json!({ "error": "Invalid limit" }))),
+                },
+                _ => return Ok((400, json!({ "error": "Invalid query" }))),
+            }
+        }
+        let pairs = self.engine.scan(prefix, limit)?;
+        let items: Vec<serde_json::Value> = pairs
+            .into_iter()
+            .map(|(key, value)| json!({ "key": key, "value": value }))
+            .collect();
+        Ok((200, json!(items)))
+    }
+}
+
+/// http status code of an engine error
+fn status_of(err: &KVStoreError) -> u16 {
+    match err {
+        KVStoreError::KeyNotFound => 404,
+        KVStoreError::Utf8(_) => 400,
+        _ => 500,
+    }
+}
+
+fn read_body(request: &mut HttpRequest) -> Result<String> {
+    let mut body = Vec::new();
+    request.as_reader().read_to_end(&mut body)?;
+    Ok(String::from_utf8(body)?)
+}
+
+/// decode `%XX` escapes of a path segment or query value
+fn percent_decode(input: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(input.len());
+    let mut iter = input.bytes();
+    while let Some(byte) = iter.next() {
+        match byte {
+            b'%' => {
+                let hex = [iter.next()?, iter.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
@@ -1,13 +1,48 @@
+mod auth;
+pub use auth::*;
 mod client;
 pub use client::*;
+mod transaction;
+pub use transaction::*;
+mod pipeline;
+pub use pipeline::*;
+mod sharding;
+pub use sharding::*;
+mod migration;
+pub use migration::*;
 mod error;
 pub use error::*;
 mod response;
-pub use response::*;
 mod server;
 pub use server::*;
 mod network;
 pub use network::*;
+mod transport;
+pub use transport::*;
+mod tls;
+pub use tls::*;
+mod watch;
+pub use watch::*;
+mod snapshot;
+pub use snapshot::*;
+mod mvcc;
+pub use mvcc::*;
+mod stats;
+pub use stats::*;
+mod compaction;
+pub use compaction::*;
+mod slowlog;
+pub use slowlog::*;
+mod replication;
+pub use replication::*;
+mod raft;
+pub use raft::*;
+mod http;
+pub use http::*;
+mod metrics;
+pub use metrics::*;
+mod memcached;
+pub use memcached::*;
 
 mod engines;
 pub use engines::*;
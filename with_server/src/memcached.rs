@@ -0,0 +1,399 @@
+//! memcached ASCII protocol front-end on top of KVStoreEngine
+//!
+//! supports get/gets, set/add/replace, cas, delete, stats, version and quit.
+//! every item is stored as a JSON encoded `MemcachedItem`, so flags, exptime and the
+//! cas unique live alongside the data. values written by other front-ends are served
+//! with flags 0 and never expire.
+
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::{slow_down_write, KVStoreEngine, KVStoreError, Result};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// exptime greater than 30 days is an absolute unix timestamp, otherwise it is relative
+const RELATIVE_EXPTIME_LIMIT: i64 = 60 * 60 * 24 * 30;
+/// the largest data block of an item, like the default of memcached
+pub const MAX_ITEM_BYTES: usize = 1024 * 1024;
+
+/// item layout stored in the engine
+#[derive(Debug, Serialize, Deserialize)]
+struct MemcachedItem {
+    flags: u32,
+    // absolute unix time in seconds, 0 means never expire
+    exptime: u64,
+    cas: u64,
+    data: String,
+}
+
+impl MemcachedItem {
+    /// decode the stored value, fall back to a plain value set by other front-ends
+    fn decode(raw: &str) -> MemcachedItem {
+        serde_json::from_str(raw).unwrap_or_else(|_| {
+            let mut hasher = DefaultHasher::new();
+            raw.hash(&mut hasher);
+            MemcachedItem {
+                flags: 0,
+                exptime: 0,
+                cas: hasher.finish(),
+                data: raw.to_owned(),
+            }
+        })
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.exptime != 0 && self.exptime <= now
+    }
+}
+
+/// counters reported by the `stats` command, shared by all connections
+struct MemcachedStats {
+    started: u64,
+    next_cas: AtomicU64,
+    curr_connections: AtomicU64,
+    total_connections: AtomicU64,
+    cmd_get: AtomicU64,
+    cmd_set: AtomicU64,
+    get_hits: AtomicU64,
+    get_misses: AtomicU64,
+}
+
+pub struct MemcachedServer<E: KVStoreEngine> {
+    pub engine: E,
+    stats: Arc<MemcachedStats>,
+}
+
+impl<E: KVStoreEngine + Clone + Send + 'static> MemcachedServer<E> {
+    /// `new` create a memcached server
+    pub fn new(engine: E) -> Self {
+        let started = unix_now();
+        let stats = MemcachedStats {
+            started,
+            // seed cas uniques by time, so they do not repeat after a restart
+            next_cas: AtomicU64::new(started << 20),
+            curr_connections: AtomicU64::new(0),
+            total_connections: AtomicU64::new(0),
+            cmd_get: AtomicU64::new(0),
+            cmd_set: AtomicU64::new(0),
+            get_hits: AtomicU64::new(0),
+            get_misses: AtomicU64::new(0),
+        };
+        MemcachedServer {
+            engine,
+            stats: Arc::new(stats),
+        }
+    }
+
+    /// accept connections, each connection is served in its own thread
+    pub fn start<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        self.start_on(TcpListener::bind(addr)?)
+    }
+
+    /// serve on a listener that is already bound, like `start`
+    pub fn start_on(self, listener: TcpListener) -> Result<()> {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let mut connection = MemcachedServer {
+                        engine: self.engine.clone(),
+                        stats: Arc::clone(&self.stats),
+                    };
+                    thread::spawn(move || {
+                        connection
+                            .stats
+                            .curr_connections
+                            .fetch_add(1, Ordering::SeqCst);
+                        connection
+                            .stats
+                            .total_connections
+                            .fetch_add(1, Ordering::SeqCst);
+                        if let Err(err) = connection.serve(stream) {
+                            error!("Error on serving memcached client: {}", err)
+                        }
+                        connection
+                            .stats
+                            .curr_connections
+                            .fetch_sub(1, Ordering::SeqCst);
+                    });
+                }
+                Err(err) => error!("Connection failed: {}", err),
+            }
+        }
+        Ok(())
+    }
+
+    /// serve commands until the client quits or closes the connection
+    fn serve(&mut self, stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let args: Vec<&str> = line.split_whitespace().collect();
+            let response = match args.split_first() {
+                Some((&"get", keys)) if !keys.is_empty() => self.retrieve(keys, false)?,
+                Some((&"gets", keys)) if !keys.is_empty() => self.retrieve(keys, true)?,
+                Some((&cmd @ ("set" | "add" | "replace" | "cas"), args)) => {
+                    self.stats.cmd_set.fetch_add(1, Ordering::SeqCst);
+                    match StorageArgs::parse(cmd, args) {
+                        Some(args) if args.bytes > MAX_ITEM_BYTES => {
+                            // the block is skipped, not read into memory, so the next command
+                            // line is where it is expected
+                            skip_data_block(&mut reader, args.bytes)?;
+                            if args.noreply {
+                                continue;
+                            }
+                            "SERVER_ERROR object too large for cache".to_owned()
+                        }
+                        Some(args) => {
+                            let data = read_data_block(&mut reader, args.bytes)?;
+                            let reply = match data {
+                                Some(data) => self.store(cmd, &args, data),
+                                None => "CLIENT_ERROR bad data chunk".to_owned(),
+                            };
+                            if args.noreply {
+                                continue;
+                            }
+                            reply
+                        }
+                        None => "CLIENT_ERROR bad command line format".to_owned(),
+                    }
+                }
+                Some((&"delete", [key])) => self.delete(key),
+                Some((&"delete", [key, "noreply"])) => {
+                    self.delete(key);
+                    continue;
+                }
+                Some((&"stats", [])) => self.report_stats(),
+                Some((&"version", [])) => format!("VERSION {}", env!("CARGO_PKG_VERSION")),
+                Some((&"quit", [])) => return Ok(()),
+                _ => "ERROR".to_owned(),
+            };
+            writer.write_all(response.as_bytes())?;
+            writer.write_all(b"\r\n")?;
+            writer.flush()?;
+        }
+    }
+
+    /// `get` and `gets`, missing and expired keys are skipped
+    fn retrieve(&mut self, keys: &[&str], with_cas: bool) -> Result<String> {
+        let mut response = String::new();
+        for &key in keys {
+            self.stats.cmd_get.fetch_add(1, Ordering::SeqCst);
+            let item = match self.load(key) {
+                Ok(item) => item,
+                Err(err) => return Ok(format!("SERVER_ERROR {}", err)),
+            };
+            match item {
+                Some((_, item)) => {
+                    self.stats.get_hits.fetch_add(1, Ordering::SeqCst);
+                    response.push_str(&format!("VALUE {} {} {}", key, item.flags, item.data.len()));
+                    if with_cas {
+                        response.push_str(&format!(" {}", item.cas));
+                    }
+                    response.push_str(&format!("\r\n{}\r\n", item.data));
+                }
+                None => {
+                    self.stats.get_misses.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        }
+        response.push_str("END");
+        Ok(response)
+    }
+
+    /// `set`, `add`, `replace` and `cas`, return the reply line
+    fn store(&mut self, cmd: &str, args: &StorageArgs, data: String) -> String {
+        let item = MemcachedItem {
+            flags: args.flags,
+            exptime: args.exptime,
+            cas: self.stats.next_cas.fetch_add(1, Ordering::SeqCst),
+            data,
+        };
+        let key = args.key.to_owned();
+        let result = slow_down_write(&mut self.engine)
+            .and_then(|_| Ok(serde_json::to_string(&item)?))
+            .and_then(|new| match cmd {
+                "set" => self.engine.set(key, new).map(|_| "STORED"),
+                "add" => self.load(&key).and_then(|_| {
+                    // `load` drops an expired item, so it counts as missing here
+                    let stored = self.engine.compare_and_swap(key, None, Some(new))?;
+                    Ok(if stored { "STORED" } else { "NOT_STORED" })
+                }),
+                "replace" => loop {
+                    match self.load(&key)? {
+                        Some((raw, _)) => {
+                            let new = Some(new.to_owned());
+                            if self
+                                .engine
+                                .compare_and_swap(key.to_owned(), Some(raw), new)?
+                            {
+                                break Ok("STORED");
+                            }
+                        }
+                        None => break Ok("NOT_STORED"),
+                    }
+                },
+                _ => match self.load(&key)? {
+                    Some((raw, current)) if Some(current.cas) == args.cas_unique => {
+                        let stored = self.engine.compare_and_swap(key, Some(raw), Some(new))?;
+                        Ok(if stored { "STORED" } else { "EXISTS" })
+                    }
+                    Some(_) => Ok("EXISTS"),
+                    None => Ok("NOT_FOUND"),
+                },
+            });
+        match result {
+            Ok(reply) => reply.to_owned(),
+            Err(err) => format!("SERVER_ERROR {}", err),
+        }
+    }
+
+    fn delete(&mut self, key: &str) -> String {
+        let result = self.load(key).and_then(|item| match item {
+            Some(_) => {
+                slow_down_write(&mut self.engine)?;
+                self.engine.remove(key.to_owned())
+            }
+            None => Err(KVStoreError::KeyNotFound),
+        });
+        match result {
+            Ok(_) => "DELETED".to_owned(),
+            Err(KVStoreError::KeyNotFound) => "NOT_FOUND".to_owned(),
+            Err(err) => format!("SERVER_ERROR {}", err),
+        }
+    }
+
+    fn report_stats(&self) -> String {
+        let now = unix_now();
+        let counters = [
+            ("curr_connections", &self.stats.curr_connections),
+            ("total_connections", &self.stats.total_connections),
+            ("cmd_get", &self.stats.cmd_get),
+            ("cmd_set", &self.stats.cmd_set),
+            ("get_hits", &self.stats.get_hits),
+            ("get_misses", &self.stats.get_misses),
+        ];
+        let mut response = format!(
+            "STAT pid {}\r\nSTAT uptime {}\r\nSTAT time {}\r\nSTAT version {}\r\n",
+            std::process::id(),
+            now - self.stats.started,
+            now,
+            env!("CARGO_PKG_VERSION")
+        );
+        for (name, counter) in counters {
+            response.push_str(&format!(
+                "STAT {} {}\r\n",
+                name,
+                counter.load(Ordering::SeqCst)
+            ));
+        }
+        response.push_str("END");
+        response
+    }
+
+    /// load the raw stored value and its decoded item
+    ///
+    /// an expired item is removed (unless it changed meanwhile) and reported as missing
+    fn load(&mut self, key: &str) -> Result<Option<(String, MemcachedItem)>> {
+        match self.engine.get(key.to_owned())? {
+            Some(raw) => {
+                let item = MemcachedItem::decode(&raw);
+                if item.is_expired(unix_now()) {
+                    self.engine
+                        .compare_and_swap(key.to_owned(), Some(raw), None)?;
+                    Ok(None)
+                } else {
+                    Ok(Some((raw, item)))
+                }
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// arguments of a storage command line
+/// `<command> <key> <flags> <exptime> <bytes> [<cas unique>] [noreply]`
+struct StorageArgs<'a> {
+    key: &'a str,
+    flags: u32,
+    exptime: u64,
+    bytes: usize,
+    cas_unique: Option<u64>,
+    noreply: bool,
+}
+
+impl<'a> StorageArgs<'a> {
+    fn parse(cmd: &str, args: &[&'a str]) -> Option<StorageArgs<'a>> {
+        let (fixed, rest) = match cmd {
+            "cas" if args.len() >= 5 => args.split_at(5),
+            "set" | "add" | "replace" if args.len() >= 4 => args.split_at(4),
+            _ => return None,
+        };
+        let noreply = match rest {
+            [] => false,
+            ["noreply"] => true,
+            _ => return None,
+        };
+        Some(StorageArgs {
+            key: fixed[0],
+            flags: fixed[1].parse().ok()?,
+            exptime: absolute_exptime(fixed[2].parse().ok()?),
+            bytes: fixed[3].parse().ok()?,
+            cas_unique: match fixed.get(4) {
+                Some(cas) => Some(cas.parse().ok()?),
+                None => None,
+            },
+            noreply,
+        })
+    }
+}
+
+/// read `<data block>\r\n`, return None if the block is malformed or not UTF-8
+fn read_data_block<R: Read>(reader: &mut R, bytes: usize) -> Result<Option<String>> {
+    let Some(length) = bytes.checked_add(2) else {
+        return Ok(None);
+    };
+    let mut block = vec![0_u8; length];
+    reader.read_exact(&mut block)?;
+    if !block.ends_with(b"\r\n") {
+        return Ok(None);
+    }
+    block.truncate(bytes);
+    Ok(String::from_utf8(block).ok())
+}
+
+/// read past `<data block>\r\n` without keeping it
+fn skip_data_block<R: Read>(reader: &mut R, bytes: usize) -> Result<()> {
+    let length = (bytes as u64).saturating_add(2);
+    io::copy(&mut reader.take(length), &mut io::sink())?;
+    Ok(())
+}
+
+/// convert the exptime of the protocol into an absolute unix time
+fn absolute_exptime(exptime: i64) -> u64 {
+    match exptime {
+        0 => 0,
+        // negative exptime expires the item immediately
+        exptime if exptime < 0 => 1,
+        exptime if exptime <= RELATIVE_EXPTIME_LIMIT => unix_now() + exptime as u64,
+        exptime => exptime as u64,
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,216 @@
+//! Prometheus metrics of a server: `GET /metrics` in the text exposition format
+//!
+//! the requests and errors are those counted by the `Server` sharing the `Counters`,
+//! the rest is read from the engine on every scrape
+
+use log::error;
+use tiny_http::{Header, Method, Response as HttpResponse};
+
+use crate::{Counters, KVStoreEngine, KVStoreError, Result, LATENCY_BUCKETS};
+use std::net::{TcpListener, ToSocketAddrs};
+use std::sync::Arc;
+
+pub struct MetricsServer<E: KVStoreEngine> {
+    pub engine: E,
+    counters: Arc<Counters>,
+}
+
+impl<E: KVStoreEngine> MetricsServer<E> {
+    /// `new` create a metrics server, `counters` are those given to `Server::with_counters`
+    pub fn new(engine: E, counters: Arc<Counters>) -> Self {
+        MetricsServer { engine, counters }
+    }
+
+    pub fn start<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        self.start_on(TcpListener::bind(addr)?)
+    }
+
+    /// serve on a listener that is already bound, like `start`
+    pub fn start_on(mut self, listener: TcpListener) -> Result<()> {
+        let server = tiny_http::Server::from_listener(listener, None)
+            .map_err(|err| KVStoreError::Other(err.to_string()))?;
+        for request in server.incoming_requests() {
+            let (status, body) = match (request.method(), request.url()) {
+                (Method::Get, "/metrics") => match self.render() {
+                    Ok(body) => (200, body),
+                    Err(err) => (500, format!("{}\n", err)),
+                },
+                _ => (404, "Not found\n".to_owned()),
+            };
+            let mut response = HttpResponse::from_string(body).with_status_code(status);
+            if let Ok(header) =
+                Header::from_bytes("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+            {
+                response.add_header(header);
+            }
+            if let Err(err) = request.respond(response) {
+                error!("Error on serving metrics client: {}", err)
+            }
+        }
+        Ok(())
+    }
+
+    /// every metric in the text exposition format
+    pub fn render(&mut self) -> Result<String> {
+        let mut out = String::new();
+        let requests = self.counters.requests()?;
+
+        header(
+            &mut out,
+            "kvs_requests_total",
+            "counter",
+            "Requests received",
+        );
+        for (request, counts) in &requests {
+            sample(
+                &mut out,
+                "kvs_requests_total",
+                &label("request", request),
+                counts.total,
+            );
+        }
+
+        header(
+            &mut out,
+            "kvs_request_duration_seconds",
+            "histogram",
+            "Time to answer a request",
+        );
+        for (request, counts) in &requests {
+            let latency = &counts.latency;
+            if latency.count == 0 {
+                continue;
+            }
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(&latency.buckets) {
+                cumulative += count;
+                let labels = format!("request=\"{}\",le=\"{}\"", request, bound);
+                sample(
+                    &mut out,
+                    "kvs_request_duration_seconds_bucket",
+                    &labels,
+                    cumulative,
+                );
+            }
+            let labels = format!("request=\"{}\",le=\"+Inf\"", request);
+            sample(
+                &mut out,
+                "kvs_request_duration_seconds_bucket",
+                &labels,
+                latency.count,
+            );
+            let labels = label("request", request);
+            sample(
+                &mut out,
+                "kvs_request_duration_seconds_sum",
+                &labels,
+                latency.sum,
+            );
+            sample(
+                &mut out,
+                "kvs_request_duration_seconds_count",
+                &labels,
+                latency.count,
+            );
+        }
+
+        header(&mut out, "kvs_errors_total", "counter", "Errors answered");
+        for (kind, count) in self.counters.errors()? {
+            sample(&mut out, "kvs_errors_total", &label("kind", kind), count);
+        }
+
+        let stats = self.engine.stats()?;
+        let gauges = [
+            (
+                "kvs_compactions_total",
+                "counter",
+                "Compactions run",
+                stats.compactions as f64,
+            ),
+            (
+                "kvs_compaction_reclaimed_bytes_total",
+                "counter",
+                "Bytes dropped by compactions",
+                stats.reclaimed_bytes as f64,
+            ),
+            (
+                "kvs_compaction_duration_seconds_total",
+                "counter",
+                "Time spent compacting",
+                stats.compaction_ms as f64 / 1000.0,
+            ),
+            (
+                "kvs_log_files",
+                "gauge",
+                "Log files in use",
+                stats.log_files as f64,
+            ),
+            (
+                "kvs_index_keys",
+                "gauge",
+                "Keys in the index",
+                stats.keys as f64,
+            ),
+            (
+                "kvs_live_bytes",
+                "gauge",
+                "Bytes of the records still read",
+                stats.live_bytes as f64,
+            ),
+            (
+                "kvs_dead_bytes",
+                "gauge",
+                "Bytes the next compaction drops",
+                stats.dead_bytes as f64,
+            ),
+            (
+                "kvs_write_stall",
+                "gauge",
+                "How writes are held back, 0 not, 1 slowed down, 2 stalled",
+                stats.write_stall as u8 as f64,
+            ),
+            (
+                "kvs_slowed_writes_total",
+                "counter",
+                "Writes slowed down by the compaction backlog",
+                stats.slowed_writes as f64,
+            ),
+            (
+                "kvs_stalled_writes_total",
+                "counter",
+                "Writes stalled by the compaction backlog",
+                stats.stalled_writes as f64,
+            ),
+            (
+                "kvs_connections",
+                "gauge",
+                "Open client connections",
+                self.counters.connections() as f64,
+            ),
+        ];
+        for (name, kind, help, value) in gauges {
+            header(&mut out, name, kind, help);
+            sample(&mut out, name, "", value);
+        }
+        Ok(out)
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    out.push_str(&format!(
+        "# HELP {} {}\n# TYPE {} {}\n",
+        name, help, name, kind
+    ));
+}
+
+fn label(name: &str, value: &str) -> String {
+    format!("{}=\"{}\"", name, value)
+}
+
+fn sample(out: &mut String, name: &str, labels: &str, value: impl std::fmt::Display) {
+    if labels.is_empty() {
+        out.push_str(&format!("{} {}\n", name, value));
+    } else {
+        out.push_str(&format!("{}{{{}}} {}\n", name, labels, value));
+    }
+}
@@ -0,0 +1,450 @@
+//! online migration of key ranges from one server to another
+//!
+//! the keys of the ranges are copied to the target while they are still served here.
+//! writes to them go on meanwhile and are remembered, and copied again in rounds. the
+//! last of them are copied under the ownership lock, and the ranges are flipped to moved
+//! under the same lock, so the target never misses a write. the moved ranges are saved,
+//! their keys are redirected to the target from then on, also after a restart. a failed
+//! migration removes what it copied from the target, the keys stay here
+
+use log::{error, info};
+
+use crate::raft::write_synced;
+use crate::{
+    EngineStats, HashRange, KVStoreEngine, KVStoreError, KvsClient, LogBatch, LogPosition, Result,
+    Snapshot, TlsConnector, Version, WatchEvent, Watcher,
+};
+use std::collections::HashSet;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// keys written during a copy are copied again without the lock until at most this
+/// many are left, which are copied under the lock
+const FINAL_DELTA_KEYS: usize = 100;
+/// rounds of copying the written keys again, the migration fails if the writes keep up
+const MAX_DELTA_ROUNDS: usize = 10;
+
+struct Migration {
+    target: String,
+    ranges: Vec<HashRange>,
+    // keys written since the copy started, they are copied again
+    written: HashSet<String>,
+}
+
+#[derive(Default)]
+struct Ownership {
+    in_flight: Vec<Migration>,
+    // ranges handed over, with the server that owns them now
+    moved: Vec<(HashRange, String)>,
+}
+
+impl Ownership {
+    fn moved_to(&self, key: &str) -> Option<&str> {
+        self.moved
+            .iter()
+            .find(|(range, _)| range.contains_key(key))
+            .map(|(_, target)| target.as_str())
+    }
+
+    /// remember that `key` was written, if it is migrating
+    fn written(&mut self, key: &str) {
+        let migration = self
+            .in_flight
+            .iter_mut()
+            .find(|migration| migration.ranges.iter().any(|range| range.contains_key(key)));
+        if let Some(migration) = migration {
+            migration.written.insert(key.to_owned());
+        }
+    }
+
+    /// the keys written since the last call, for the migration of `ranges` to `target`
+    fn take_written(&mut self, target: &str, ranges: &[HashRange]) -> Vec<String> {
+        self.in_flight
+            .iter_mut()
+            .find(|migration| migration.target == target && migration.ranges == ranges)
+            .map_or_else(Vec::new, |migration| {
+                mem::take(&mut migration.written).into_iter().collect()
+            })
+    }
+}
+
+/// engine of one shard: redirects moved keys, copies migrating keys to their target
+pub struct ShardEngine<E: KVStoreEngine> {
+    engine: E,
+    ownership: Arc<Mutex<Ownership>>,
+    // where the moved ranges are kept
+    state_path: PathBuf,
+    // how to reach the target of a migration
+    tls: Option<TlsConnector>,
+    credentials: Option<(String, String)>,
+}
+
+impl<E: KVStoreEngine + Clone> Clone for ShardEngine<E> {
+    fn clone(&self) -> Self {
+        ShardEngine {
+            engine: self.engine.clone(),
+            ownership: Arc::clone(&self.ownership),
+            state_path: self.state_path.clone(),
+            tls: self.tls.clone(),
+            credentials: self.credentials.clone(),
+        }
+    }
+}
+
+impl<E: KVStoreEngine> ShardEngine<E> {
+    /// serve `engine`, with the ranges moved away saved in `state_path`
+    pub fn open(engine: E, state_path: impl Into<PathBuf>) -> Result<Self> {
+        let state_path = state_path.into();
+        let moved = load_moved(&state_path)?;
+        Ok(ShardEngine {
+            engine,
+            ownership: Arc::new(Mutex::new(Ownership {
+                in_flight: Vec::new(),
+                moved,
+            })),
+            state_path,
+            tls: None,
+            credentials: None,
+        })
+    }
+
+    /// `with_tls` connect to the target of a migration over TLS
+    pub fn with_tls(mut self, tls: TlsConnector) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// `with_credentials` authenticate to the target of a migration as `user`
+    pub fn with_credentials(mut self, user: String, password: String) -> Self {
+        self.credentials = Some((user, password));
+        self
+    }
+
+    fn connect(&self, target: &str) -> Result<KvsClient> {
+        let mut client = match &self.tls {
+            Some(tls) => KvsClient::connect_tls(target, tls)?,
+            None => KvsClient::connect_to(target)?,
+        };
+        if let Some((user, password)) = &self.credentials {
+            client.authenticate(user.to_owned(), password.to_owned())?;
+        }
+        Ok(client)
+    }
+
+    /// keys of this shard in `ranges`
+    fn keys_in(&mut self, ranges: &[HashRange]) -> Result<Vec<String>> {
+        Ok(self
+            .engine
+            .scan(String::new(), None)?
+            .into_iter()
+            .map(|(key, _)| key)
+            .filter(|key| ranges.iter().any(|range| range.contains_key(key)))
+            .collect())
+    }
+
+    /// copy the keys of `ranges` and move the ranges to `target`, return the keys copied
+    ///
+    /// if it fails, the keys it copied are removed from the target again
+    fn hand_over(&mut self, target: &str, ranges: &[HashRange]) -> Result<usize> {
+        let mut copied = HashSet::new();
+        let result = self
+            .connect(target)
+            .and_then(|mut client| self.copy_and_move(&mut client, target, ranges, &mut copied));
+        if result.is_err() && !copied.is_empty() {
+            if let Err(err) = self
+                .connect(target)
+                .and_then(|mut client| undo(&mut client, &copied))
+            {
+                error!("Cannot remove the keys copied to {}: {}", target, err);
+            }
+        }
+        result
+    }
+
+    /// writes only wait for the last written keys to be copied
+    fn copy_and_move(
+        &mut self,
+        client: &mut KvsClient,
+        target: &str,
+        ranges: &[HashRange],
+        copied: &mut HashSet<String>,
+    ) -> Result<usize> {
+        let keys = self.keys_in(ranges)?;
+        copy(&mut self.engine, client, &keys, copied)?;
+        for _ in 0..MAX_DELTA_ROUNDS {
+            let mut ownership = lock(&self.ownership)?;
+            let written = ownership.take_written(target, ranges);
+            if written.len() <= FINAL_DELTA_KEYS {
+                copy(&mut self.engine, client, &written, copied)?;
+                let mut moved = ownership.moved.clone();
+                moved.extend(ranges.iter().map(|range| (*range, target.to_owned())));
+                save_moved(&self.state_path, &moved)?;
+                // from now on the keys are redirected
+                ownership.moved = moved;
+                return Ok(keys.len());
+            }
+            drop(ownership);
+            copy(&mut self.engine, client, &written, copied)?;
+        }
+        Err(KVStoreError::Other(format!(
+            "writes to the keys moving to {} kept up with {} rounds of copying",
+            target, MAX_DELTA_ROUNDS
+        )))
+    }
+}
+
+impl<E: KVStoreEngine> KVStoreEngine for ShardEngine<E> {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        // the lock is held until the write is done, so the hand over cannot miss it
+        let mut ownership = owned(&self.ownership, &key)?;
+        ownership.written(&key);
+        self.engine.set(key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        drop(owned(&self.ownership, &key)?);
+        self.engine.get(key)
+    }
+
+    fn get_version(&mut self, key: String) -> Result<Version> {
+        drop(owned(&self.ownership, &key)?);
+        self.engine.get_version(key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        let mut ownership = owned(&self.ownership, &key)?;
+        ownership.written(&key);
+        self.engine.remove(key)
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let mut ownership = owned(&self.ownership, &key)?;
+        let swapped = self
+            .engine
+            .compare_and_swap(key.to_owned(), expected, new)?;
+        if swapped {
+            ownership.written(&key);
+        }
+        Ok(swapped)
+    }
+
+    fn multi_get(&mut self, keys: Vec<String>) -> Result<Vec<Result<Option<String>>>> {
+        // moved keys are redirected one by one, the others are read at once
+        let ownership = lock(&self.ownership)?;
+        let targets: Vec<Option<String>> = keys
+            .iter()
+            .map(|key| ownership.moved_to(key).map(str::to_owned))
+            .collect();
+        drop(ownership);
+        let here = keys
+            .into_iter()
+            .zip(&targets)
+            .filter(|(_, target)| target.is_none())
+            .map(|(key, _)| key)
+            .collect();
+        let mut found = self.engine.multi_get(here)?.into_iter();
+        Ok(targets
+            .into_iter()
+            .map(|target| match target {
+                Some(target) => Err(KVStoreError::Redirect(target)),
+                None => found.next().unwrap_or(Ok(None)),
+            })
+            .collect())
+    }
+
+    fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        let mut ownership = owned(&self.ownership, &key)?;
+        ownership.written(&key);
+        self.engine.incr(key, delta)
+    }
+
+    fn commit(
+        &mut self,
+        reads: Vec<(String, Version)>,
+        writes: Vec<(String, Option<String>)>,
+    ) -> Result<bool> {
+        let mut ownership = lock(&self.ownership)?;
+        // every key must still be here, a transaction is not split between servers
+        let read_keys = reads.iter().map(|(key, _)| key);
+        for key in read_keys.chain(writes.iter().map(|(key, _)| key)) {
+            if let Some(target) = ownership.moved_to(key) {
+                return Err(KVStoreError::Redirect(target.to_owned()));
+            }
+        }
+        let keys: Vec<String> = writes.iter().map(|(key, _)| key.to_owned()).collect();
+        let committed = self.engine.commit(reads, writes)?;
+        if committed {
+            for key in keys {
+                ownership.written(&key);
+            }
+        }
+        Ok(committed)
+    }
+
+    fn scan(&mut self, prefix: String, limit: Option<usize>) -> Result<Vec<(String, String)>> {
+        let ownership = lock(&self.ownership)?;
+        // moved keys may not be deleted yet
+        let mut pairs: Vec<(String, String)> = self
+            .engine
+            .scan(prefix, None)?
+            .into_iter()
+            .filter(|(key, _)| ownership.moved_to(key).is_none())
+            .collect();
+        if let Some(limit) = limit {
+            pairs.truncate(limit);
+        }
+        Ok(pairs)
+    }
+
+    fn watch(&mut self, prefix: String) -> Result<Watcher> {
+        self.engine.watch(prefix)
+    }
+
+    fn get_at(&mut self, key: String, version: u64) -> Result<Option<String>> {
+        drop(owned(&self.ownership, &key)?);
+        self.engine.get_at(key, version)
+    }
+
+    fn history(&mut self, key: String) -> Result<Vec<Version>> {
+        drop(owned(&self.ownership, &key)?);
+        self.engine.history(key)
+    }
+
+    fn snapshot(&mut self) -> Result<Snapshot> {
+        self.engine.snapshot()
+    }
+
+    fn stats(&mut self) -> Result<EngineStats> {
+        self.engine.stats()
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        self.engine.compact()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.engine.flush()
+    }
+
+    fn set_config(&mut self, name: &str, value: &str) -> Result<()> {
+        self.engine.set_config(name, value)
+    }
+
+    fn compactions(&mut self) -> Result<u64> {
+        self.engine.compactions()
+    }
+
+    fn hold_back_write(&mut self) -> Result<Duration> {
+        self.engine.hold_back_write()
+    }
+
+    fn maintain(&mut self) -> Result<()> {
+        self.engine.maintain()
+    }
+
+    fn read_log(&mut self, from: LogPosition, limit: usize) -> Result<LogBatch> {
+        self.engine.read_log(from, limit)
+    }
+
+    fn changes_since(&mut self, seq: u64, limit: usize) -> Result<Vec<WatchEvent>> {
+        self.engine.changes_since(seq, limit)
+    }
+
+    fn migrate(&mut self, target: String, ranges: Vec<HashRange>) -> Result<usize> {
+        lock(&self.ownership)?.in_flight.push(Migration {
+            target: target.to_owned(),
+            ranges: ranges.clone(),
+            written: HashSet::new(),
+        });
+        info!("Migrating {} ranges to {}", ranges.len(), target);
+        let copied = self.hand_over(&target, &ranges);
+        lock(&self.ownership)?
+            .in_flight
+            .retain(|migration| migration.target != target || migration.ranges != ranges);
+        let copied = copied?;
+
+        // the ranges are redirected, so their keys can be deleted here
+        for key in self.keys_in(&ranges)? {
+            match self.engine.remove(key) {
+                Ok(()) | Err(KVStoreError::KeyNotFound) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        info!("Migrated {} keys to {}", copied, target);
+        Ok(copied)
+    }
+}
+
+/// copy the current state of `keys` to the target, removed keys are removed there
+///
+/// the keys set on the target are added to `copied`
+fn copy<E: KVStoreEngine>(
+    engine: &mut E,
+    client: &mut KvsClient,
+    keys: &[String],
+    copied: &mut HashSet<String>,
+) -> Result<()> {
+    for key in keys {
+        match engine.get(key.to_owned())? {
+            Some(value) => {
+                let sent = client.set(key.to_owned(), value);
+                // a write that failed on the way may still have been done
+                if !matches!(sent, Err(KVStoreError::PermissionDenied(_))) {
+                    copied.insert(key.to_owned());
+                }
+                sent?
+            }
+            // the key may never have been copied
+            None if client.get(key.to_owned())?.is_some() => client.remove(key.to_owned())?,
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+/// remove the `copied` keys from the target of a failed migration, as many as it can
+fn undo(client: &mut KvsClient, copied: &HashSet<String>) -> Result<()> {
+    let mut result = Ok(());
+    for key in copied {
+        match client.remove(key.to_owned()) {
+            Ok(()) | Err(KVStoreError::KeyNotFound) => {}
+            Err(err) => result = result.and(Err(err)),
+        }
+    }
+    result
+}
+
+fn load_moved(path: &Path) -> Result<Vec<(HashRange, String)>> {
+    match fs::read(path) {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn save_moved(path: &Path, moved: &[(HashRange, String)]) -> Result<()> {
+    write_synced(path, &serde_json::to_vec(moved)?)
+}
+
+fn lock(ownership: &Mutex<Ownership>) -> Result<MutexGuard<'_, Ownership>> {
+    ownership
+        .lock()
+        .map_err(|_| KVStoreError::Other("ownership lock poisoned".to_owned()))
+}
+
+/// lock the ownership, and redirect if `key` was moved
+fn owned<'a>(ownership: &'a Mutex<Ownership>, key: &str) -> Result<MutexGuard<'a, Ownership>> {
+    let ownership = lock(ownership)?;
+    match ownership.moved_to(key) {
+        Some(target) => Err(KVStoreError::Redirect(target.to_owned())),
+        None => Ok(ownership),
+    }
+}
@@ -0,0 +1,57 @@
+//! versioned values: an engine can keep older values of a key next to the latest one
+//!
+//! a version is known by the sequence number of the change that wrote it
+
+use serde::{Deserialize, Serialize};
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// a value a key held, or its removal if `value` is None
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Version {
+    pub seq: u64,
+    // milliseconds since the unix epoch, 0 if it was written before versions were kept
+    pub timestamp: u64,
+    pub value: Option<String>,
+}
+
+/// which older versions of a key compaction keeps
+///
+/// a version is kept if it is one of the last `versions`, or younger than `window`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    pub versions: usize,
+    pub window: Option<Duration>,
+}
+
+impl Default for Retention {
+    /// only the latest value
+    fn default() -> Self {
+        Retention {
+            versions: 1,
+            window: None,
+        }
+    }
+}
+
+impl Retention {
+    /// if anything but the latest value is kept
+    pub fn keeps_history(&self) -> bool {
+        self.versions > 1 || self.window.is_some()
+    }
+
+    /// if the version written at `timestamp` is kept when `newer` versions follow it
+    pub fn keeps(&self, newer: usize, timestamp: u64, now: u64) -> bool {
+        newer < self.versions
+            || self
+                .window
+                .is_some_and(|window| now.saturating_sub(timestamp) <= window.as_millis() as u64)
+    }
+}
+
+/// milliseconds since the unix epoch
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
@@ -1,14 +1,239 @@
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    HashRange, LogBatch, LogPosition, RaftMessage, RaftReply, ServerStats, SlowEntry, Version,
+    WatchEvent,
+};
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+    // a response per key, in the same order
+    MultiGet {
+        keys: Vec<String>,
+    },
+    MultiSet {
+        pairs: Vec<(String, String)>,
+    },
+    // add `delta` to the integer value of `key`, the response holds the new value
+    Incr {
+        key: String,
+        delta: i64,
+    },
+    // value of `key` as of the change with sequence number `version`
+    GetAt {
+        key: String,
+        version: u64,
+    },
+    // the kept versions of `key`, oldest first
+    History {
+        key: String,
+    },
+    // the value of `key` with the sequence number of its change, the response is one version
+    GetVersion {
+        key: String,
+    },
+    // apply `writes` if every key of `reads` is still at the version read, a value None is no key
+    Commit {
+        reads: Vec<(String, Version)>,
+        writes: Vec<(String, Option<String>)>,
+    },
+    // pairs whose key starts with `prefix`, ordered by key
+    Scan {
+        prefix: String,
+        limit: Option<usize>,
+    },
+    Auth {
+        user: String,
+        password: String,
+    },
+    // take a snapshot, it is dropped once unused for `lease_ms`, the response holds its id
+    Snapshot {
+        lease_ms: u64,
+    },
+    // `Get` and `Scan` of the pairs in a snapshot
+    SnapshotGet {
+        id: u64,
+        key: String,
+    },
+    SnapshotScan {
+        id: u64,
+        prefix: String,
+        limit: Option<usize>,
+    },
+    ReleaseSnapshot {
+        id: u64,
+    },
+    // push every change of the matching keys, the connection then only carries `Response::Event`
+    Watch {
+        key_or_prefix: String,
+    },
+    // replay every change after sequence number `seq`, then push new ones like `Watch`
+    ChangesSince {
+        seq: u64,
+    },
+    // follow the log from `from`, the connection then only carries `Response::Log`
+    Replicate {
+        from: LogPosition,
+    },
+    // admin requests, only for a user that is an admin
+    // compact the log of the engine
+    Compact,
+    // write everything buffered to disk
+    Flush,
+    // stop accepting connections once answered, the server returns from `start`
+    Shutdown,
+    // change a setting of the server or its engine while it runs
+    SetConfig {
+        name: String,
+        value: String,
+    },
+    // the state of the server and its engine
+    Stats,
+    // the last requests slower than the slow log threshold, oldest first, they tell the
+    // keys of every user
+    SlowLog,
+    // between the nodes of a cluster
+    Raft(RaftMessage),
+    // a pipelined request, its response is `Response::Tagged` with the same id
+    Tagged {
+        id: u64,
+        request: Box<Request>,
+    },
+    // copy the keys in `ranges` to the server at `target`, then redirect them there
+    Migrate {
+        target: String,
+        ranges: Vec<HashRange>,
+    },
+}
+
+impl Request {
+    /// the name of the variant, requests are counted by it
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::Get { .. } => "Get",
+            Request::Set { .. } => "Set",
+            Request::Remove { .. } => "Remove",
+            Request::MultiGet { .. } => "MultiGet",
+            Request::MultiSet { .. } => "MultiSet",
+            Request::Incr { .. } => "Incr",
+            Request::GetAt { .. } => "GetAt",
+            Request::History { .. } => "History",
+            Request::GetVersion { .. } => "GetVersion",
+            Request::Commit { .. } => "Commit",
+            Request::Scan { .. } => "Scan",
+            Request::Auth { .. } => "Auth",
+            Request::Snapshot { .. } => "Snapshot",
+            Request::SnapshotGet { .. } => "SnapshotGet",
+            Request::SnapshotScan { .. } => "SnapshotScan",
+            Request::ReleaseSnapshot { .. } => "ReleaseSnapshot",
+            Request::Watch { .. } => "Watch",
+            Request::ChangesSince { .. } => "ChangesSince",
+            Request::Replicate { .. } => "Replicate",
+            Request::Compact => "Compact",
+            Request::Flush => "Flush",
+            Request::Shutdown => "Shutdown",
+            Request::SetConfig { .. } => "SetConfig",
+            Request::Stats => "Stats",
+            Request::SlowLog => "SlowLog",
+            Request::Raft(_) => "Raft",
+            Request::Migrate { .. } => "Migrate",
+            Request::Tagged { .. } => "Tagged",
+        }
+    }
+
+    /// whether only an admin may send the request
+    pub fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            Request::Compact
+                | Request::Flush
+                | Request::Shutdown
+                | Request::SetConfig { .. }
+                | Request::Migrate { .. }
+                | Request::SlowLog
+        )
+    }
+
+    /// whether the request changes keys, it waits while the engine slows writes down
+    pub fn is_write(&self) -> bool {
+        match self {
+            Request::Set { .. }
+            | Request::Remove { .. }
+            | Request::MultiSet { .. }
+            | Request::Incr { .. } => true,
+            Request::Commit { writes, .. } => !writes.is_empty(),
+            Request::Tagged { request, .. } => request.is_write(),
+            _ => false,
+        }
+    }
+
+    /// the key or prefix the request is about, if it is about one
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Request::Get { key }
+            | Request::Set { key, .. }
+            | Request::Remove { key }
+            | Request::Incr { key, .. }
+            | Request::GetAt { key, .. }
+            | Request::History { key }
+            | Request::GetVersion { key }
+            | Request::SnapshotGet { key, .. } => Some(key),
+            Request::Scan { prefix, .. } | Request::SnapshotScan { prefix, .. } => Some(prefix),
+            Request::Watch { key_or_prefix } => Some(key_or_prefix),
+            Request::MultiGet { keys } => keys.first().map(String::as_str),
+            Request::MultiSet { pairs } => pairs.first().map(|(key, _)| key.as_str()),
+            Request::Commit { writes, .. } => writes.first().map(|(key, _)| key.as_str()),
+            Request::Tagged { request, .. } => request.key(),
+            _ => None,
+        }
+    }
+
+    /// bytes of the values the request writes
+    pub fn value_bytes(&self) -> u64 {
+        let bytes = match self {
+            Request::Set { value, .. } => value.len(),
+            Request::MultiSet { pairs } => pairs.iter().map(|(_, value)| value.len()).sum(),
+            Request::Commit { writes, .. } => writes
+                .iter()
+                .filter_map(|(_, value)| value.as_ref())
+                .map(String::len)
+                .sum(),
+            Request::Tagged { request, .. } => return request.value_bytes(),
+            _ => 0,
+        };
+        bytes as u64
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Response {
     Ok(Option<String>),
+    Pairs(Vec<(String, String)>),
+    // a response per key of `MultiGet` or `MultiSet`
+    Many(Vec<Response>),
     Err(String),
+    // an error of the engine, `KVStoreError::from_kind` turns it back into the same variant
+    Error { kind: String, detail: String },
+    // the connection is not authenticated, or the user lacks the permission
+    Denied(String),
+    // this server does not take writes, send them to the leader at this address
+    Redirect(String),
+    Log(LogBatch),
+    Event(WatchEvent),
+    Versions(Vec<Version>),
+    Stats(ServerStats),
+    SlowLog(Vec<SlowEntry>),
+    Raft(RaftReply),
+    // the response to `Request::Tagged` with this id
+    Tagged { id: u64, response: Box<Response> },
 }
@@ -0,0 +1,264 @@
+//! pipelining: many requests on one connection without waiting for each response
+//!
+//! every request is sent as `Request::Tagged` with an id, and the server echoes the id in
+//! `Response::Tagged`, so responses are matched to their requests in whatever order they come
+
+use serde::Deserialize;
+use serde_json::de::IoRead;
+use serde_json::Deserializer;
+
+use crate::client::{checked, result_of};
+use crate::{KVStoreError, Request, Response, Result, Stream};
+use std::collections::{HashMap, HashSet};
+use std::io::{BufReader, BufWriter, Write};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread;
+
+/// how many bytes of requests are sent before waiting for their responses
+///
+/// responses are read as they come, so the server never waits on the client, this only
+/// bounds how far the client runs ahead of the server
+pub const MAX_PIPELINE_BYTES: usize = 4 * 1024 * 1024;
+
+/// a connection sending requests back-to-back, see `KvsClient::pipeline`
+///
+/// requests are buffered until a handle is waited for or `flush` is called,
+/// a thread reads the responses and keeps them until their handles wait for them
+pub struct Pipeline {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    writer: Mutex<Writer>,
+    responses: Mutex<Responses>,
+    // signalled when a response arrives or the reader stops
+    changed: Condvar,
+}
+
+struct Writer {
+    stream: BufWriter<Stream>,
+    next_id: u64,
+}
+
+struct Responses {
+    // size of each request sent whose response was not read yet
+    in_flight: HashMap<u64, usize>,
+    in_flight_bytes: usize,
+    // responses read before their handle waited for them
+    arrived: HashMap<u64, Response>,
+    // requests whose handle was dropped, their responses are discarded
+    abandoned: HashSet<u64>,
+    // why the reader stopped, once it did
+    closed: Option<String>,
+}
+
+/// the response to a pipelined request, it is read by `wait`
+pub struct Pending<T> {
+    id: Option<u64>,
+    shared: Arc<Shared>,
+    map: fn(Response) -> Result<T>,
+}
+
+impl Pipeline {
+    pub(crate) fn new(
+        reader: Deserializer<IoRead<BufReader<Stream>>>,
+        writer: BufWriter<Stream>,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            writer: Mutex::new(Writer {
+                stream: writer,
+                next_id: 0,
+            }),
+            responses: Mutex::new(Responses {
+                in_flight: HashMap::new(),
+                in_flight_bytes: 0,
+                arrived: HashMap::new(),
+                abandoned: HashSet::new(),
+                closed: None,
+            }),
+            changed: Condvar::new(),
+        });
+        let weak = Arc::downgrade(&shared);
+        thread::spawn(move || receive(reader, weak));
+        Pipeline { shared }
+    }
+
+    /// get value by key, None if the key does not exist
+    pub fn get(&mut self, key: String) -> Result<Pending<Option<String>>> {
+        self.request(Request::Get { key }, result_of)
+    }
+
+    /// set key, value
+    pub fn set(&mut self, key: String, value: String) -> Result<Pending<()>> {
+        self.request(Request::Set { key, value }, |response| {
+            result_of(response).map(|_| ())
+        })
+    }
+
+    /// remove key
+    pub fn remove(&mut self, key: String) -> Result<Pending<()>> {
+        self.request(Request::Remove { key }, |response| {
+            result_of(response).map(|_| ())
+        })
+    }
+
+    /// add `delta` to the integer value of key, the handle gives the new value
+    pub fn incr(&mut self, key: String, delta: i64) -> Result<Pending<i64>> {
+        self.request(Request::Incr { key, delta }, |response| {
+            result_of(response)?
+                .and_then(|value| value.parse().ok())
+                .ok_or(KVStoreError::UnexpectedCommandType)
+        })
+    }
+
+    /// send any request, the handle gives its response
+    ///
+    /// requests that take over the connection, like `Request::Watch`, cannot be pipelined
+    pub fn send(&mut self, request: Request) -> Result<Pending<Response>> {
+        self.request(request, checked)
+    }
+
+    /// send the buffered requests
+    pub fn flush(&mut self) -> Result<()> {
+        lock(&self.shared.writer)?.stream.flush()?;
+        Ok(())
+    }
+
+    fn request<T>(
+        &mut self,
+        request: Request,
+        map: fn(Response) -> Result<T>,
+    ) -> Result<Pending<T>> {
+        let mut writer = lock(&self.shared.writer)?;
+        let id = writer.next_id;
+        writer.next_id += 1;
+        let tagged = Request::Tagged {
+            id,
+            request: Box::new(request),
+        };
+        let bytes = serde_json::to_vec(&tagged)?;
+        if !lock(&self.shared.responses)?.fits(bytes.len()) {
+            // the server only answers what it was sent
+            writer.stream.flush()?;
+        }
+        self.shared.wait_for(|responses| {
+            responses.fits(bytes.len()).then(|| {
+                responses.in_flight.insert(id, bytes.len());
+                responses.in_flight_bytes += bytes.len();
+            })
+        })?;
+        writer.stream.write_all(&bytes)?;
+        Ok(Pending {
+            id: Some(id),
+            shared: Arc::clone(&self.shared),
+            map,
+        })
+    }
+}
+
+impl<T> Pending<T> {
+    /// send the buffered requests and wait for this response
+    pub fn wait(mut self) -> Result<T> {
+        let id = self.id.take().ok_or(KVStoreError::UnexpectedCommandType)?;
+        lock(&self.shared.writer)?.stream.flush()?;
+        let response = self
+            .shared
+            .wait_for(|responses| responses.arrived.remove(&id))?;
+        (self.map)(response)
+    }
+}
+
+impl<T> Drop for Pending<T> {
+    fn drop(&mut self) {
+        if let (Some(id), Ok(mut responses)) = (self.id, lock(&self.shared.responses)) {
+            if responses.arrived.remove(&id).is_none() {
+                responses.abandoned.insert(id);
+            }
+        }
+    }
+}
+
+impl Shared {
+    /// wait until `ready` gives something, or the reader stopped
+    fn wait_for<T>(&self, mut ready: impl FnMut(&mut Responses) -> Option<T>) -> Result<T> {
+        let mut responses = lock(&self.responses)?;
+        loop {
+            if let Some(value) = ready(&mut responses) {
+                return Ok(value);
+            }
+            if let Some(reason) = &responses.closed {
+                return Err(KVStoreError::Other(format!(
+                    "pipeline connection closed: {}",
+                    reason
+                )));
+            }
+            responses = self
+                .changed
+                .wait(responses)
+                .map_err(|_| KVStoreError::Other("pipeline lock poisoned".to_owned()))?;
+        }
+    }
+}
+
+impl Drop for Shared {
+    /// the server answers what is left and closes the connection, which stops the reader
+    fn drop(&mut self) {
+        if let Ok(writer) = self.writer.get_mut() {
+            let _ = writer.stream.flush();
+            let _ = writer.stream.get_ref().shutdown_write();
+        }
+    }
+}
+
+impl Responses {
+    /// whether a request of `bytes` can be sent now, a request larger than the budget
+    /// goes alone
+    fn fits(&self, bytes: usize) -> bool {
+        self.in_flight_bytes == 0 || self.in_flight_bytes + bytes <= MAX_PIPELINE_BYTES
+    }
+
+    /// keep a response until its handle waits for it
+    fn arrive(&mut self, id: u64, response: Response) {
+        if let Some(bytes) = self.in_flight.remove(&id) {
+            self.in_flight_bytes -= bytes;
+        }
+        if !self.abandoned.remove(&id) {
+            self.arrived.insert(id, response);
+        }
+    }
+}
+
+/// read responses until the connection closes or the pipeline is gone
+fn receive(mut reader: Deserializer<IoRead<BufReader<Stream>>>, shared: Weak<Shared>) {
+    let reason = loop {
+        let response = Response::deserialize(&mut reader);
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        let Ok(mut responses) = lock(&shared.responses) else {
+            return;
+        };
+        match response {
+            Ok(Response::Tagged { id, response }) => responses.arrive(id, *response),
+            // an untagged error is about the connection, not one request
+            Ok(response) => match checked(response) {
+                Err(err) => break err.to_string(),
+                Ok(_) => break KVStoreError::UnexpectedCommandType.to_string(),
+            },
+            Err(err) => break err.to_string(),
+        }
+        shared.changed.notify_all();
+    };
+    if let Some(shared) = shared.upgrade() {
+        if let Ok(mut responses) = lock(&shared.responses) {
+            responses.closed = Some(reason);
+        }
+        shared.changed.notify_all();
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> Result<MutexGuard<'_, T>> {
+    mutex
+        .lock()
+        .map_err(|_| KVStoreError::Other("pipeline lock poisoned".to_owned()))
+}
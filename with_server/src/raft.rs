@@ -0,0 +1,1124 @@
+//! cluster mode: the nodes run Raft to agree on one log of writes, the engine is the state machine
+//!
+//! a node is known by the address it serves clients on. the leader serves every request,
+//! the other nodes redirect clients to it. a write is answered once a majority of the
+//! nodes has it in their log and the leader has applied it to its engine
+//!
+//! the raft state (term, vote and where the snapshot is) is kept in one file, the log since
+//! the last snapshot in another one next to it, new entries are appended to it. both are
+//! synced before a message is answered. the engine itself is the snapshot: applied entries
+//! are dropped from the log once there are more than `log_limit` of them, and a node missing
+//! dropped entries is sent every pair
+//!
+//! the leader answers a read once a majority of the nodes answered a heartbeat sent after the
+//! read came in, so a leader cut off from the others does not answer with stale values
+
+use log::{error, info};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::de::IoRead;
+use serde_json::{Deserializer, StreamDeserializer};
+
+use crate::client::checked;
+use crate::{
+    replace_all, Address, EngineStats, KVStoreEngine, KVStoreError, Request, Response, Result,
+    Snapshot, Stream, Version, WatchEvent, Watcher,
+};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// applied entries kept in the log before they are dropped
+pub const RAFT_LOG_LIMIT: usize = 1000;
+/// the leader sends entries, or an empty heartbeat, to every node this often
+pub const RAFT_HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+/// a node starts an election after hearing nothing from a leader for a random time in this range
+const ELECTION_TIMEOUT_MIN: Duration = Duration::from_millis(300);
+const ELECTION_TIMEOUT_MAX: Duration = Duration::from_millis(600);
+/// how often election timeouts are checked
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+/// a node that does not answer within this time is treated as down
+const RPC_TIMEOUT: Duration = Duration::from_secs(1);
+/// a write not committed within this time is answered with an error
+const PROPOSAL_TIMEOUT: Duration = Duration::from_secs(5);
+/// most entries sent in one `AppendEntries`
+const APPEND_BATCH_SIZE: usize = 256;
+
+/// a write, as stored in the raft log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RaftCommand {
+    // appended by a new leader, commits the entries left by earlier terms
+    Noop,
+    Set(String, String),
+    Remove(String),
+    CompareAndSwap {
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    },
+    Commit {
+        reads: Vec<(String, Option<String>)>,
+        writes: Vec<(String, Option<String>)>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RaftEntry {
+    pub term: u64,
+    pub command: RaftCommand,
+}
+
+/// messages between the nodes of a cluster
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RaftMessage {
+    RequestVote {
+        term: u64,
+        candidate: String,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    AppendEntries {
+        term: u64,
+        leader: String,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<RaftEntry>,
+        leader_commit: u64,
+    },
+    // the entries the node needs were dropped: every pair, as of entry `last_index`
+    InstallSnapshot {
+        term: u64,
+        leader: String,
+        last_index: u64,
+        last_term: u64,
+        pairs: Vec<(String, String)>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RaftReply {
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    // on success, the last entry known to match the leader
+    // on failure, the entry to retry from
+    Append {
+        term: u64,
+        success: bool,
+        match_index: u64,
+    },
+}
+
+/// the part of the state that survives a restart, along with the entries in the log file
+#[derive(Default, Serialize, Deserialize)]
+struct SavedState {
+    term: u64,
+    voted_for: Option<String>,
+    // the last entry dropped from the log, its effect is in the engine
+    snapshot_index: u64,
+    snapshot_term: u64,
+    // the last entry applied to the engine
+    applied: u64,
+}
+
+enum Role {
+    Follower,
+    Candidate {
+        votes: HashSet<String>,
+    },
+    Leader {
+        next_index: HashMap<String, u64>,
+        match_index: HashMap<String, u64>,
+        // the last read round each node answered a heartbeat of
+        acked_round: HashMap<String, u64>,
+    },
+}
+
+struct RaftState<E> {
+    engine: E,
+    saved: SavedState,
+    // entries after the snapshot of `saved`
+    entries: Vec<RaftEntry>,
+    // the log file, new entries are appended to it
+    log: File,
+    // counted up by every read, the heartbeats sent after it confirm the leader for it
+    read_round: u64,
+    role: Role,
+    leader: Option<String>,
+    commit_index: u64,
+    election_deadline: Instant,
+    // entries proposed on this node, and the results of those already applied
+    pending: HashSet<u64>,
+    results: HashMap<u64, Result<bool>>,
+}
+
+impl<E> RaftState<E> {
+    fn last_index(&self) -> u64 {
+        self.saved.snapshot_index + self.entries.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(self.saved.snapshot_term, |entry| entry.term)
+    }
+
+    /// term of the entry at `index`, None if it was dropped or does not exist yet
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.saved.snapshot_index {
+            return Some(self.saved.snapshot_term);
+        }
+        self.entry(index).map(|entry| entry.term)
+    }
+
+    fn entry(&self, index: u64) -> Option<&RaftEntry> {
+        let offset = index.checked_sub(self.saved.snapshot_index + 1)?;
+        self.entries.get(offset as usize)
+    }
+
+    fn reset_election_timer(&mut self) {
+        let timeout = rand::thread_rng().gen_range(ELECTION_TIMEOUT_MIN..ELECTION_TIMEOUT_MAX);
+        self.election_deadline = Instant::now() + timeout;
+    }
+
+    /// become a follower, in `term` if it is newer than the current one
+    fn step_down(&mut self, term: u64) {
+        if term > self.saved.term {
+            self.saved.term = term;
+            self.saved.voted_for = None;
+            self.leader = None;
+        }
+        self.role = Role::Follower;
+    }
+
+    /// error to answer clients with on a node that is not the leader
+    fn check_leader(&self) -> Result<()> {
+        match (&self.role, &self.leader) {
+            (Role::Leader { .. }, _) => Ok(()),
+            (_, Some(leader)) => Err(KVStoreError::Redirect(leader.to_owned())),
+            (_, None) => Err(KVStoreError::Other(
+                "no leader elected yet, retry later".to_owned(),
+            )),
+        }
+    }
+}
+
+struct Raft<E> {
+    id: String,
+    peers: Vec<String>,
+    state_path: PathBuf,
+    log_limit: usize,
+    state: Mutex<RaftState<E>>,
+    // signaled whenever the log, the commit index or the role changes
+    changed: Condvar,
+}
+
+impl<E: KVStoreEngine> Raft<E> {
+    fn lock(&self) -> Result<MutexGuard<'_, RaftState<E>>> {
+        self.state.lock().map_err(|_| poisoned())
+    }
+
+    fn majority(&self) -> usize {
+        let nodes = self.peers.len() + 1;
+        nodes / 2 + 1
+    }
+
+    /// keep the term, the vote and where the snapshot is, the entries are in the log file
+    fn save_state(&self, state: &RaftState<E>) -> Result<()> {
+        write_synced(&self.state_path, &serde_json::to_vec(&state.saved)?)
+    }
+
+    /// append the entries from `from` on to the log file
+    ///
+    /// on load, an entry replaces the one at its index and every one after it
+    fn append_log(&self, state: &mut RaftState<E>, from: u64) -> Result<()> {
+        let mut data = Vec::new();
+        for index in from..=state.last_index() {
+            if let Some(entry) = state.entry(index) {
+                serde_json::to_writer(&mut data, &(index, entry))?;
+                data.push(b'\n');
+            }
+        }
+        state.log.write_all(&data)?;
+        state.log.sync_data()?;
+        Ok(())
+    }
+
+    /// write the log file again with only the entries after the snapshot
+    fn rewrite_log(&self, state: &mut RaftState<E>) -> Result<()> {
+        state.log = write_log(&log_path(&self.state_path), &state.saved, &state.entries)?;
+        Ok(())
+    }
+
+    /// start elections whenever the leader stays silent
+    fn tick(&self) {
+        loop {
+            thread::sleep(TICK_INTERVAL);
+            let mut state = match self.lock() {
+                Ok(state) => state,
+                Err(_) => return,
+            };
+            let leading = matches!(state.role, Role::Leader { .. });
+            if !leading && Instant::now() >= state.election_deadline {
+                if let Err(err) = self.start_election(&mut state) {
+                    error!("Election failed: {}", err);
+                }
+                self.changed.notify_all();
+            }
+        }
+    }
+
+    fn start_election(&self, state: &mut RaftState<E>) -> Result<()> {
+        state.saved.term += 1;
+        state.saved.voted_for = Some(self.id.to_owned());
+        state.leader = None;
+        state.role = Role::Candidate {
+            votes: HashSet::from([self.id.to_owned()]),
+        };
+        state.reset_election_timer();
+        self.save_state(state)?;
+        info!("Starting election for term {}", state.saved.term);
+        self.count_votes(state)
+    }
+
+    fn count_votes(&self, state: &mut RaftState<E>) -> Result<()> {
+        match &state.role {
+            Role::Candidate { votes } if votes.len() >= self.majority() => {
+                self.become_leader(state)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn become_leader(&self, state: &mut RaftState<E>) -> Result<()> {
+        info!("Elected leader for term {}", state.saved.term);
+        let next = state.last_index() + 1;
+        state.role = Role::Leader {
+            next_index: self
+                .peers
+                .iter()
+                .map(|peer| (peer.to_owned(), next))
+                .collect(),
+            match_index: self.peers.iter().map(|peer| (peer.to_owned(), 0)).collect(),
+            acked_round: self.peers.iter().map(|peer| (peer.to_owned(), 0)).collect(),
+        };
+        state.leader = Some(self.id.to_owned());
+        let term = state.saved.term;
+        state.entries.push(RaftEntry {
+            term,
+            command: RaftCommand::Noop,
+        });
+        self.append_log(state, next)?;
+        self.advance_commit(state)
+    }
+
+    /// commit the last entry of the current term that a majority has, then apply
+    fn advance_commit(&self, state: &mut RaftState<E>) -> Result<()> {
+        if let Role::Leader { match_index, .. } = &state.role {
+            // entries of earlier terms are only committed along with one of the current term
+            let mut index = state.last_index();
+            while index > state.commit_index && state.term_at(index) == Some(state.saved.term) {
+                let replicas = 1 + match_index.values().filter(|&&m| m >= index).count();
+                if replicas >= self.majority() {
+                    state.commit_index = index;
+                    break;
+                }
+                index -= 1;
+            }
+        }
+        self.apply(state)
+    }
+
+    /// apply committed entries to the engine, and drop them from the log past the limit
+    fn apply(&self, state: &mut RaftState<E>) -> Result<()> {
+        if state.saved.applied >= state.commit_index {
+            return Ok(());
+        }
+        while state.saved.applied < state.commit_index {
+            let index = state.saved.applied + 1;
+            let command = match state.entry(index) {
+                Some(entry) => entry.command.clone(),
+                None => break,
+            };
+            let result = apply_command(&mut state.engine, command);
+            match &result {
+                // a remove of a missing key changes nothing, on every node alike
+                Ok(_) | Err(KVStoreError::KeyNotFound) => {}
+                // any other error is this node's own, the entry is applied again next time
+                Err(err) => {
+                    error!("Cannot apply entry {}, will retry: {}", index, err);
+                    break;
+                }
+            }
+            state.saved.applied = index;
+            if state.pending.remove(&index) {
+                state.results.insert(index, result);
+            }
+        }
+        let applied = (state.saved.applied - state.saved.snapshot_index) as usize;
+        let dropped = applied > self.log_limit;
+        if dropped {
+            state.saved.snapshot_term = state.term_at(state.saved.applied).unwrap_or_default();
+            state.entries.drain(..applied);
+            state.saved.snapshot_index = state.saved.applied;
+        }
+        // the state first, the entries the log file still has before the snapshot are skipped
+        self.save_state(state)?;
+        if dropped {
+            self.rewrite_log(state)?;
+        }
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /// answer a message from another node
+    fn handle(&self, message: RaftMessage) -> Result<RaftReply> {
+        let mut state = self.lock()?;
+        let reply = match message {
+            RaftMessage::RequestVote {
+                term,
+                candidate,
+                last_log_index,
+                last_log_term,
+            } => self.request_vote(&mut state, term, candidate, (last_log_term, last_log_index)),
+            RaftMessage::AppendEntries {
+                term,
+                leader,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => self.append_entries(
+                &mut state,
+                term,
+                leader,
+                (prev_log_index, prev_log_term),
+                entries,
+                leader_commit,
+            ),
+            RaftMessage::InstallSnapshot {
+                term,
+                leader,
+                last_index,
+                last_term,
+                pairs,
+            } => self.install_snapshot(&mut state, term, leader, (last_index, last_term), pairs),
+        };
+        self.changed.notify_all();
+        reply
+    }
+
+    /// `last_log` is the (term, index) of the last entry of the candidate
+    fn request_vote(
+        &self,
+        state: &mut RaftState<E>,
+        term: u64,
+        candidate: String,
+        last_log: (u64, u64),
+    ) -> Result<RaftReply> {
+        if term > state.saved.term {
+            state.step_down(term);
+        }
+        // only a candidate with every committed entry can win
+        let granted = term == state.saved.term
+            && state
+                .saved
+                .voted_for
+                .as_ref()
+                .is_none_or(|voted_for| *voted_for == candidate)
+            && last_log >= (state.last_term(), state.last_index());
+        if granted {
+            state.saved.voted_for = Some(candidate);
+            state.reset_election_timer();
+        }
+        self.save_state(state)?;
+        Ok(RaftReply::Vote {
+            term: state.saved.term,
+            granted,
+        })
+    }
+
+    /// `prev` is the (index, term) of the entry right before `entries`
+    fn append_entries(
+        &self,
+        state: &mut RaftState<E>,
+        term: u64,
+        leader: String,
+        prev: (u64, u64),
+        mut entries: Vec<RaftEntry>,
+        leader_commit: u64,
+    ) -> Result<RaftReply> {
+        if term < state.saved.term {
+            return Ok(self.rejected(state));
+        }
+        self.follow(state, term, leader)?;
+        let (mut prev_index, mut prev_term) = prev;
+        // entries up to the snapshot are committed, so they match the leader already
+        if prev_index < state.saved.snapshot_index {
+            let skip = state.saved.snapshot_index - prev_index;
+            if skip as usize >= entries.len() {
+                return Ok(RaftReply::Append {
+                    term,
+                    success: true,
+                    match_index: prev_index + entries.len() as u64,
+                });
+            }
+            entries.drain(..skip as usize);
+            prev_index = state.saved.snapshot_index;
+            prev_term = state.saved.snapshot_term;
+        }
+        if state.term_at(prev_index) != Some(prev_term) {
+            return Ok(RaftReply::Append {
+                term,
+                success: false,
+                match_index: prev_index.saturating_sub(1).min(state.last_index()),
+            });
+        }
+
+        let match_index = prev_index + entries.len() as u64;
+        let mut first_changed = None;
+        for (index, entry) in (prev_index + 1..).zip(entries) {
+            match state.term_at(index) {
+                Some(existing) if existing == entry.term => continue,
+                // a conflicting entry was never committed, drop it and everything after it
+                Some(_) => {
+                    let offset = index - state.saved.snapshot_index - 1;
+                    state.entries.truncate(offset as usize);
+                }
+                None => {}
+            }
+            state.entries.push(entry);
+            first_changed.get_or_insert(index);
+        }
+        if let Some(first_changed) = first_changed {
+            self.append_log(state, first_changed)?;
+        }
+        state.commit_index = state.commit_index.max(leader_commit.min(match_index));
+        self.apply(state)?;
+        Ok(RaftReply::Append {
+            term,
+            success: true,
+            match_index,
+        })
+    }
+
+    /// `last` is the (index, term) of the last entry included in `pairs`
+    fn install_snapshot(
+        &self,
+        state: &mut RaftState<E>,
+        term: u64,
+        leader: String,
+        last: (u64, u64),
+        pairs: Vec<(String, String)>,
+    ) -> Result<RaftReply> {
+        if term < state.saved.term {
+            return Ok(self.rejected(state));
+        }
+        self.follow(state, term, leader)?;
+        let (last_index, last_term) = last;
+        if last_index > state.saved.applied {
+            info!(
+                "Installing snapshot of {} keys at {}",
+                pairs.len(),
+                last_index
+            );
+            replace_all(&mut state.engine, pairs)?;
+            // keep the entries after the snapshot if the log agrees with it
+            if state.term_at(last_index) == Some(last_term) {
+                let dropped = last_index - state.saved.snapshot_index;
+                state.entries.drain(..dropped as usize);
+            } else {
+                state.entries.clear();
+            }
+            state.saved.snapshot_index = last_index;
+            state.saved.snapshot_term = last_term;
+            state.saved.applied = last_index;
+            state.commit_index = state.commit_index.max(last_index);
+            self.save_state(state)?;
+            self.rewrite_log(state)?;
+        }
+        Ok(RaftReply::Append {
+            term,
+            success: true,
+            match_index: last_index,
+        })
+    }
+
+    /// accept the sender of a message of the current term as the leader
+    fn follow(&self, state: &mut RaftState<E>, term: u64, leader: String) -> Result<()> {
+        let newer = term > state.saved.term;
+        state.step_down(term);
+        state.leader = Some(leader);
+        state.reset_election_timer();
+        if newer {
+            self.save_state(state)?;
+        }
+        Ok(())
+    }
+
+    fn rejected(&self, state: &RaftState<E>) -> RaftReply {
+        RaftReply::Append {
+            term: state.saved.term,
+            success: false,
+            match_index: state.last_index(),
+        }
+    }
+
+    /// send vote requests or entries to `peer` for as long as the node runs
+    fn send_to(&self, peer: &str) {
+        let mut client: Option<PeerClient> = None;
+        loop {
+            let (term, round, message) = {
+                let mut state = match self.lock() {
+                    Ok(state) => state,
+                    Err(_) => return,
+                };
+                match self.message_for(&mut state, peer) {
+                    Some(message) => (state.saved.term, state.read_round, message),
+                    None => {
+                        let _ = self.changed.wait_timeout(state, RAFT_HEARTBEAT_INTERVAL);
+                        continue;
+                    }
+                }
+            };
+
+            let connected = match client.take() {
+                Some(connected) => Ok(connected),
+                None => PeerClient::connect(peer),
+            };
+            let reply = connected.and_then(|mut connected| {
+                let reply = connected.call(message)?;
+                client = Some(connected);
+                Ok(reply)
+            });
+            let mut state = match self.lock() {
+                Ok(state) => state,
+                Err(_) => return,
+            };
+            if let Ok(reply) = reply {
+                if let Err(err) = self.handle_reply(&mut state, peer, (term, round), reply) {
+                    error!("Cannot handle the reply of {}: {}", peer, err);
+                }
+            }
+            // wait for the next heartbeat, unless there are more entries to send or a read
+            // waits for a heartbeat sent after it
+            // if the node is down, the connection is retried on the next heartbeat
+            let waited_for = self.has_more_for(&state, peer) || state.read_round != round;
+            if !waited_for || client.is_none() {
+                let _ = self.changed.wait_timeout(state, RAFT_HEARTBEAT_INTERVAL);
+            }
+        }
+    }
+
+    /// the message `peer` needs next, if any
+    fn message_for(&self, state: &mut RaftState<E>, peer: &str) -> Option<RaftMessage> {
+        let term = state.saved.term;
+        match &state.role {
+            Role::Follower => None,
+            Role::Candidate { votes } if votes.contains(peer) => None,
+            Role::Candidate { .. } => Some(RaftMessage::RequestVote {
+                term,
+                candidate: self.id.to_owned(),
+                last_log_index: state.last_index(),
+                last_log_term: state.last_term(),
+            }),
+            Role::Leader { next_index, .. } => {
+                let next = next_index.get(peer).copied().unwrap_or(1);
+                if next <= state.saved.snapshot_index {
+                    // the engine has every applied entry, so it is a snapshot as of `applied`
+                    let applied = state.saved.applied;
+                    let pairs = match state.engine.scan(String::new(), None) {
+                        Ok(pairs) => pairs,
+                        Err(err) => {
+                            error!("Cannot snapshot the engine: {}", err);
+                            return None;
+                        }
+                    };
+                    return Some(RaftMessage::InstallSnapshot {
+                        term,
+                        leader: self.id.to_owned(),
+                        last_index: applied,
+                        last_term: state.term_at(applied).unwrap_or_default(),
+                        pairs,
+                    });
+                }
+                let entries = (next..=state.last_index())
+                    .take(APPEND_BATCH_SIZE)
+                    .filter_map(|index| state.entry(index).cloned())
+                    .collect();
+                Some(RaftMessage::AppendEntries {
+                    term,
+                    leader: self.id.to_owned(),
+                    prev_log_index: next - 1,
+                    prev_log_term: state.term_at(next - 1).unwrap_or_default(),
+                    entries,
+                    leader_commit: state.commit_index,
+                })
+            }
+        }
+    }
+
+    /// `sent` is the term and the read round of the message replied to
+    fn handle_reply(
+        &self,
+        state: &mut RaftState<E>,
+        peer: &str,
+        sent: (u64, u64),
+        reply: RaftReply,
+    ) -> Result<()> {
+        let (sent_term, sent_round) = sent;
+        let term = match reply {
+            RaftReply::Vote { term, .. } | RaftReply::Append { term, .. } => term,
+        };
+        if term > state.saved.term {
+            state.step_down(term);
+            return self.save_state(state);
+        }
+        // the reply is to a message of an earlier term
+        if sent_term != state.saved.term {
+            return Ok(());
+        }
+        match reply {
+            RaftReply::Vote { granted: true, .. } => {
+                if let Role::Candidate { votes } = &mut state.role {
+                    votes.insert(peer.to_owned());
+                }
+                self.count_votes(state)
+            }
+            RaftReply::Vote { granted: false, .. } => Ok(()),
+            RaftReply::Append {
+                success,
+                match_index: matched,
+                ..
+            } => {
+                if let Role::Leader {
+                    next_index,
+                    match_index,
+                    acked_round,
+                } = &mut state.role
+                {
+                    // any answer of this term tells the node still follows this leader
+                    let acked = acked_round.entry(peer.to_owned()).or_insert(0);
+                    if sent_round > *acked {
+                        *acked = sent_round;
+                        self.changed.notify_all();
+                    }
+                    let next = next_index.entry(peer.to_owned()).or_insert(1);
+                    if success {
+                        let known = match_index.entry(peer.to_owned()).or_insert(0);
+                        *known = (*known).max(matched);
+                        *next = *known + 1;
+                    } else {
+                        *next = (matched + 1).min(next.saturating_sub(1)).max(1);
+                    }
+                }
+                if success {
+                    self.advance_commit(state)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn has_more_for(&self, state: &RaftState<E>, peer: &str) -> bool {
+        match &state.role {
+            Role::Leader { next_index, .. } => next_index
+                .get(peer)
+                .is_some_and(|&next| next <= state.last_index()),
+            _ => false,
+        }
+    }
+
+    /// append a write to the log, and wait until it is applied
+    fn propose(&self, command: RaftCommand) -> Result<bool> {
+        let mut state = self.lock()?;
+        state.check_leader()?;
+        let term = state.saved.term;
+        state.entries.push(RaftEntry { term, command });
+        let index = state.last_index();
+        state.pending.insert(index);
+        self.append_log(&mut state, index)?;
+        self.advance_commit(&mut state)?;
+        self.changed.notify_all();
+
+        let deadline = Instant::now() + PROPOSAL_TIMEOUT;
+        loop {
+            if let Some(result) = state.results.remove(&index) {
+                return result;
+            }
+            let now = Instant::now();
+            // a new leader may or may not keep the entry
+            if state.saved.term != term || now >= deadline {
+                state.pending.remove(&index);
+                return Err(KVStoreError::Other(
+                    "the write was not committed in time, it may or may not take effect".to_owned(),
+                ));
+            }
+            state = self
+                .changed
+                .wait_timeout(state, deadline - now)
+                .map_err(|_| poisoned())?
+                .0;
+        }
+    }
+
+    /// read from the engine of the leader, once it made sure it still is the leader
+    ///
+    /// the commit index is the read index once an entry of the term is committed, then a
+    /// majority has to answer a heartbeat sent after the read came in, and the engine has to
+    /// have applied every entry up to the read index
+    fn read<T>(&self, read: impl FnOnce(&mut E) -> Result<T>) -> Result<T> {
+        let mut state = self.lock()?;
+        state.check_leader()?;
+        let term = state.saved.term;
+        state.read_round += 1;
+        let round = state.read_round;
+        self.changed.notify_all();
+        let mut read_index = None;
+        let deadline = Instant::now() + RPC_TIMEOUT;
+        loop {
+            state.check_leader()?;
+            if state.saved.term != term {
+                return Err(KVStoreError::Other(
+                    "the leader changed during the read, retry later".to_owned(),
+                ));
+            }
+            if read_index.is_none() && state.term_at(state.commit_index) == Some(term) {
+                read_index = Some(state.commit_index);
+            }
+            let ready = read_index.is_some_and(|read_index| state.saved.applied >= read_index);
+            if ready && self.confirmed(&state, round) {
+                return read(&mut state.engine);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(KVStoreError::Other(
+                    "a majority did not confirm the leader in time, retry later".to_owned(),
+                ));
+            }
+            state = self
+                .changed
+                .wait_timeout(state, deadline - now)
+                .map_err(|_| poisoned())?
+                .0;
+        }
+    }
+
+    /// whether a majority, the leader with it, answered a heartbeat of read round `round`
+    fn confirmed(&self, state: &RaftState<E>, round: u64) -> bool {
+        match &state.role {
+            Role::Leader { acked_round, .. } => {
+                let acked = acked_round.values().filter(|&&acked| acked >= round);
+                1 + acked.count() >= self.majority()
+            }
+            _ => false,
+        }
+    }
+
+    /// read from the engine of this node, which may lag behind the leader
+    fn local<T>(&self, read: impl FnOnce(&mut E) -> Result<T>) -> Result<T> {
+        read(&mut self.lock()?.engine)
+    }
+}
+
+fn apply_command<E: KVStoreEngine>(engine: &mut E, command: RaftCommand) -> Result<bool> {
+    match command {
+        RaftCommand::Noop => Ok(true),
+        RaftCommand::Set(key, value) => engine.set(key, value).map(|_| true),
+        RaftCommand::Remove(key) => engine.remove(key).map(|_| true),
+        RaftCommand::CompareAndSwap { key, expected, new } => {
+            engine.compare_and_swap(key, expected, new)
+        }
+        RaftCommand::Commit { reads, writes } => {
+            // every node numbers its changes apart, so the values read are compared, then
+            // committed at the versions they are at on this node
+            let mut versions = Vec::with_capacity(reads.len());
+            for (key, read) in reads {
+                let version = engine.get_version(key.to_owned())?;
+                if version.value != read {
+                    return Ok(false);
+                }
+                versions.push((key, version));
+            }
+            engine.commit(versions, writes)
+        }
+    }
+}
+
+/// the log file next to the state file at `state_path`
+fn log_path(state_path: &Path) -> PathBuf {
+    state_path.with_extension("log")
+}
+
+/// read the entries after the snapshot of `saved` from the log file at `path`
+///
+/// an entry replaces the one at its index and every one after it, like a conflicting entry
+/// from a leader. a last entry cut short by a crash was never acknowledged, it is dropped
+fn load_log(path: &Path, saved: &SavedState) -> Result<Vec<RaftEntry>> {
+    let mut entries = Vec::new();
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(entries),
+        Err(err) => return Err(err.into()),
+    };
+    let records = Deserializer::from_reader(BufReader::new(file)).into_iter::<(u64, RaftEntry)>();
+    for record in records {
+        let (index, entry) = match record {
+            Ok(record) => record,
+            Err(err) if err.is_eof() => break,
+            Err(err) => return Err(err.into()),
+        };
+        // the state was saved with a newer snapshot before the log was written again
+        let Some(offset) = index.checked_sub(saved.snapshot_index + 1) else {
+            continue;
+        };
+        if offset as usize > entries.len() {
+            return Err(KVStoreError::Other(format!(
+                "the raft log misses the entries before {}",
+                index
+            )));
+        }
+        entries.truncate(offset as usize);
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// write the log file at `path` with the entries after the snapshot of `saved`, return it
+/// open to append to
+fn write_log(path: &Path, saved: &SavedState, entries: &[RaftEntry]) -> Result<File> {
+    let mut data = Vec::new();
+    for (index, entry) in (saved.snapshot_index + 1..).zip(entries) {
+        serde_json::to_writer(&mut data, &(index, entry))?;
+        data.push(b'\n');
+    }
+    write_synced(path, &data)?;
+    Ok(OpenOptions::new().append(true).open(path)?)
+}
+
+/// replace the file at `path` with `data` through a temporary file, both synced with their
+/// directory, so a crash leaves either the old file or the new one
+pub(crate) fn write_synced(path: &Path, data: &[u8]) -> Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(data)?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+fn poisoned() -> KVStoreError {
+    KVStoreError::Other("raft state lock poisoned".to_owned())
+}
+
+/// connection to another node of the cluster
+struct PeerClient {
+    writer: BufWriter<Stream>,
+    replies: StreamDeserializer<'static, IoRead<BufReader<Stream>>, Response>,
+}
+
+impl PeerClient {
+    fn connect(peer: &str) -> Result<PeerClient> {
+        let stream = Stream::connect(&Address::from(peer))?;
+        stream.set_read_timeout(Some(RPC_TIMEOUT))?;
+        Ok(PeerClient {
+            writer: BufWriter::new(stream.try_clone()?),
+            replies: Deserializer::from_reader(BufReader::new(stream)).into_iter(),
+        })
+    }
+
+    fn call(&mut self, message: RaftMessage) -> Result<RaftReply> {
+        serde_json::to_writer(&mut self.writer, &Request::Raft(message))?;
+        self.writer.flush()?;
+        let response = self
+            .replies
+            .next()
+            .ok_or_else(|| KVStoreError::Other("node closed the connection".to_owned()))??;
+        match checked(response)? {
+            Response::Raft(reply) => Ok(reply),
+            _ => Err(KVStoreError::UnexpectedCommandType),
+        }
+    }
+}
+
+/// engine of a cluster node: writes go through the raft log, the leader serves everything
+pub struct RaftEngine<E: KVStoreEngine> {
+    raft: Arc<Raft<E>>,
+}
+
+impl<E: KVStoreEngine> Clone for RaftEngine<E> {
+    fn clone(&self) -> Self {
+        RaftEngine {
+            raft: Arc::clone(&self.raft),
+        }
+    }
+}
+
+impl<E: KVStoreEngine + Send + 'static> RaftEngine<E> {
+    /// load the raft state kept at `state_path`, and join the cluster
+    ///
+    /// `id` is the address this node serves clients on, `peers` those of the other nodes
+    pub fn start(
+        engine: E,
+        id: String,
+        peers: Vec<String>,
+        state_path: impl Into<PathBuf>,
+        log_limit: usize,
+    ) -> Result<Self> {
+        let state_path = state_path.into();
+        let saved: SavedState = match fs::read(&state_path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => SavedState::default(),
+            Err(err) => return Err(err.into()),
+        };
+        let entries = load_log(&log_path(&state_path), &saved)?;
+        // written again, without what a crash cut short or the snapshot holds
+        let log = write_log(&log_path(&state_path), &saved, &entries)?;
+        let mut state = RaftState {
+            engine,
+            commit_index: saved.applied,
+            saved,
+            entries,
+            log,
+            read_round: 0,
+            role: Role::Follower,
+            leader: None,
+            election_deadline: Instant::now(),
+            pending: HashSet::new(),
+            results: HashMap::new(),
+        };
+        state.reset_election_timer();
+        let raft = Arc::new(Raft {
+            id,
+            peers,
+            state_path,
+            log_limit,
+            state: Mutex::new(state),
+            changed: Condvar::new(),
+        });
+
+        let ticker = Arc::clone(&raft);
+        thread::spawn(move || ticker.tick());
+        for peer in raft.peers.clone() {
+            let sender = Arc::clone(&raft);
+            thread::spawn(move || sender.send_to(&peer));
+        }
+        Ok(RaftEngine { raft })
+    }
+}
+
+impl<E: KVStoreEngine> RaftEngine<E> {
+    /// address of the current leader, if one is known
+    pub fn leader(&self) -> Option<String> {
+        self.raft.lock().ok()?.leader.clone()
+    }
+}
+
+impl<E: KVStoreEngine> KVStoreEngine for RaftEngine<E> {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.raft.propose(RaftCommand::Set(key, value)).map(|_| ())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.raft.read(|engine| engine.get(key))
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.raft.propose(RaftCommand::Remove(key)).map(|_| ())
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.raft
+            .propose(RaftCommand::CompareAndSwap { key, expected, new })
+    }
+
+    fn multi_get(&mut self, keys: Vec<String>) -> Result<Vec<Result<Option<String>>>> {
+        self.raft.read(|engine| engine.multi_get(keys))
+    }
+
+    fn commit(
+        &mut self,
+        reads: Vec<(String, Version)>,
+        writes: Vec<(String, Option<String>)>,
+    ) -> Result<bool> {
+        let reads = reads
+            .into_iter()
+            .map(|(key, read)| (key, read.value))
+            .collect();
+        self.raft.propose(RaftCommand::Commit { reads, writes })
+    }
+
+    fn scan(&mut self, prefix: String, limit: Option<usize>) -> Result<Vec<(String, String)>> {
+        self.raft.read(|engine| engine.scan(prefix, limit))
+    }
+
+    fn watch(&mut self, prefix: String) -> Result<Watcher> {
+        self.raft.read(|engine| engine.watch(prefix))
+    }
+
+    fn get_at(&mut self, key: String, version: u64) -> Result<Option<String>> {
+        self.raft.read(|engine| engine.get_at(key, version))
+    }
+
+    fn history(&mut self, key: String) -> Result<Vec<Version>> {
+        self.raft.read(|engine| engine.history(key))
+    }
+
+    fn snapshot(&mut self) -> Result<Snapshot> {
+        self.raft.read(|engine| engine.snapshot())
+    }
+
+    // every node tells about its own engine
+    fn stats(&mut self) -> Result<EngineStats> {
+        self.raft.local(|engine| engine.stats())
+    }
+
+    // maintenance is up to every node, it changes no pair
+    fn compact(&mut self) -> Result<()> {
+        self.raft.local(|engine| engine.compact())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.raft.local(|engine| engine.flush())
+    }
+
+    fn set_config(&mut self, name: &str, value: &str) -> Result<()> {
+        self.raft.local(|engine| engine.set_config(name, value))
+    }
+
+    fn compactions(&mut self) -> Result<u64> {
+        self.raft.local(|engine| engine.compactions())
+    }
+
+    fn hold_back_write(&mut self) -> Result<Duration> {
+        self.raft.local(|engine| engine.hold_back_write())
+    }
+
+    fn maintain(&mut self) -> Result<()> {
+        self.raft.local(|engine| engine.maintain())
+    }
+
+    fn changes_since(&mut self, seq: u64, limit: usize) -> Result<Vec<WatchEvent>> {
+        self.raft.read(|engine| engine.changes_since(seq, limit))
+    }
+
+    fn raft(&mut self, message: RaftMessage) -> Result<RaftReply> {
+        self.raft.handle(message)
+    }
+}
@@ -0,0 +1,296 @@
+//! leader-follower replication by shipping the append-only log of the leader
+//!
+//! a follower sends `Request::Replicate` with the position it applied up to, the leader
+//! answers with a stream of `Response::Log` batches read from its log files, and keeps
+//! the connection open to ship new records as they are written
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+
+use crate::client::checked;
+use crate::raft::write_synced;
+use crate::{
+    Address, EngineStats, KVStoreEngine, KVStoreError, Request, Response, Result, Snapshot, Stream,
+    Version, WatchEvent, Watcher,
+};
+use std::fs;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+/// most records shipped in one batch
+pub const REPLICATION_BATCH_SIZE: usize = 1024;
+/// how often the leader checks its log for new records
+pub const REPLICATION_POLL_INTERVAL: Duration = Duration::from_millis(20);
+/// the leader ships an empty batch when idle for this long, so followers notice a dead leader
+pub const REPLICATION_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// the follower reconnects if nothing arrives for this long
+const REPLICATION_TIMEOUT: Duration = Duration::from_secs(5);
+/// the follower waits this long before reconnecting to the leader
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// position in the log of the leader, right after a record
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct LogPosition {
+    pub file_number: u64,
+    pub offset: u64,
+}
+
+/// a mutation as written in the log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum LogRecord {
+    Set(String, String),
+    Remove(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum LogBatch {
+    // records in log order, each with the position right after it
+    Records(Vec<(LogPosition, LogRecord)>),
+    // the requested position was compacted away: every live pair, and where to go on
+    Snapshot {
+        pairs: Vec<(String, String)>,
+        next: LogPosition,
+    },
+}
+
+/// engine of a follower: serves reads, rejects writes with a redirect to the leader
+#[derive(Clone)]
+pub struct FollowerEngine<E: KVStoreEngine> {
+    engine: E,
+    leader: String,
+}
+
+impl<E: KVStoreEngine> FollowerEngine<E> {
+    pub fn new(engine: E, leader: String) -> Self {
+        FollowerEngine { engine, leader }
+    }
+}
+
+impl<E: KVStoreEngine> KVStoreEngine for FollowerEngine<E> {
+    fn set(&mut self, _key: String, _value: String) -> Result<()> {
+        Err(KVStoreError::Redirect(self.leader.to_owned()))
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.engine.get(key)
+    }
+
+    fn remove(&mut self, _key: String) -> Result<()> {
+        Err(KVStoreError::Redirect(self.leader.to_owned()))
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        _key: String,
+        _expected: Option<String>,
+        _new: Option<String>,
+    ) -> Result<bool> {
+        Err(KVStoreError::Redirect(self.leader.to_owned()))
+    }
+
+    fn multi_get(&mut self, keys: Vec<String>) -> Result<Vec<Result<Option<String>>>> {
+        self.engine.multi_get(keys)
+    }
+
+    fn multi_set(&mut self, _pairs: Vec<(String, String)>) -> Result<Vec<Result<()>>> {
+        Err(KVStoreError::Redirect(self.leader.to_owned()))
+    }
+
+    fn incr(&mut self, _key: String, _delta: i64) -> Result<i64> {
+        Err(KVStoreError::Redirect(self.leader.to_owned()))
+    }
+
+    fn commit(
+        &mut self,
+        _reads: Vec<(String, Version)>,
+        _writes: Vec<(String, Option<String>)>,
+    ) -> Result<bool> {
+        Err(KVStoreError::Redirect(self.leader.to_owned()))
+    }
+
+    fn scan(&mut self, prefix: String, limit: Option<usize>) -> Result<Vec<(String, String)>> {
+        self.engine.scan(prefix, limit)
+    }
+
+    // the writes the follower applies are watched like any other
+    fn watch(&mut self, prefix: String) -> Result<Watcher> {
+        self.engine.watch(prefix)
+    }
+
+    fn get_at(&mut self, key: String, version: u64) -> Result<Option<String>> {
+        self.engine.get_at(key, version)
+    }
+
+    fn history(&mut self, key: String) -> Result<Vec<Version>> {
+        self.engine.history(key)
+    }
+
+    fn snapshot(&mut self) -> Result<Snapshot> {
+        self.engine.snapshot()
+    }
+
+    fn stats(&mut self) -> Result<EngineStats> {
+        self.engine.stats()
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        self.engine.compact()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.engine.flush()
+    }
+
+    fn set_config(&mut self, name: &str, value: &str) -> Result<()> {
+        self.engine.set_config(name, value)
+    }
+
+    fn compactions(&mut self) -> Result<u64> {
+        self.engine.compactions()
+    }
+
+    fn hold_back_write(&mut self) -> Result<Duration> {
+        self.engine.hold_back_write()
+    }
+
+    fn maintain(&mut self) -> Result<()> {
+        self.engine.maintain()
+    }
+
+    fn changes_since(&mut self, seq: u64, limit: usize) -> Result<Vec<WatchEvent>> {
+        self.engine.changes_since(seq, limit)
+    }
+}
+
+/// follows a leader, applies its log to the local engine
+pub struct Replica<E: KVStoreEngine> {
+    engine: E,
+    leader: Address,
+    // file keeping the last applied position, to resume after a restart
+    state_path: PathBuf,
+    credentials: Option<(String, String)>,
+}
+
+impl<E: KVStoreEngine> Replica<E> {
+    pub fn new<A: Into<Address>>(engine: E, leader: A, state_path: impl Into<PathBuf>) -> Self {
+        Replica {
+            engine,
+            leader: leader.into(),
+            state_path: state_path.into(),
+            credentials: None,
+        }
+    }
+
+    /// `with_credentials` authenticate to a leader that has a users file
+    pub fn with_credentials(mut self, user: String, password: String) -> Self {
+        self.credentials = Some((user, password));
+        self
+    }
+
+    /// follow the leader forever, reconnect whenever the connection is lost
+    pub fn run(mut self) {
+        loop {
+            if let Err(err) = self.follow() {
+                error!("Replication from {} stopped: {}", self.leader, err);
+            }
+            thread::sleep(RECONNECT_INTERVAL);
+        }
+    }
+
+    /// connect to the leader and apply its log until the connection is lost
+    pub fn follow(&mut self) -> Result<()> {
+        let mut position = self.load_position()?;
+        let stream = Stream::connect(&self.leader)?;
+        stream.set_read_timeout(Some(REPLICATION_TIMEOUT))?;
+        let mut writer = BufWriter::new(stream.try_clone()?);
+        let mut responses =
+            Deserializer::from_reader(BufReader::new(stream)).into_iter::<Response>();
+        let mut call = |request: &Request| -> Result<Response> {
+            serde_json::to_writer(&mut writer, request)?;
+            writer.flush()?;
+            Ok(responses.next().ok_or_else(closed)??)
+        };
+
+        if let Some((user, password)) = self.credentials.clone() {
+            expect_ok(call(&Request::Auth { user, password })?)?;
+        }
+        info!("Replicating from {} at {:?}", self.leader, position);
+        let mut response = call(&Request::Replicate { from: position })?;
+        loop {
+            match response {
+                Response::Log(LogBatch::Records(records)) => {
+                    for (next, record) in records {
+                        self.apply(record)?;
+                        position = next;
+                    }
+                }
+                Response::Log(LogBatch::Snapshot { pairs, next }) => {
+                    info!("Resynchronizing {} keys from {}", pairs.len(), self.leader);
+                    replace_all(&mut self.engine, pairs)?;
+                    position = next;
+                }
+                response => expect_ok(response)?,
+            }
+            self.save_position(position)?;
+            response = responses.next().ok_or_else(closed)??;
+        }
+    }
+
+    fn apply(&mut self, record: LogRecord) -> Result<()> {
+        match record {
+            LogRecord::Set(key, value) => self.engine.set(key, value),
+            // the record may be applied twice after a restart
+            LogRecord::Remove(key) => match self.engine.remove(key) {
+                Err(KVStoreError::KeyNotFound) => Ok(()),
+                result => result,
+            },
+        }
+    }
+
+    fn load_position(&self) -> Result<LogPosition> {
+        match fs::read(&self.state_path) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(LogPosition::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// synced with its directory, so a crash leaves the old position or the new one
+    fn save_position(&self, position: LogPosition) -> Result<()> {
+        write_synced(&self.state_path, &serde_json::to_vec(&position)?)
+    }
+}
+
+/// replace the contents of `engine` with `pairs`, ordered by key
+pub fn replace_all<E: KVStoreEngine>(engine: &mut E, pairs: Vec<(String, String)>) -> Result<()> {
+    let mut stale: Vec<String> = engine
+        .scan(String::new(), None)?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    stale.retain(|key| pairs.binary_search_by(|(other, _)| other.cmp(key)).is_err());
+    for key in stale {
+        engine.remove(key)?;
+    }
+    for (key, value) in pairs {
+        engine.set(key, value)?;
+    }
+    Ok(())
+}
+
+fn expect_ok(response: Response) -> Result<()> {
+    match checked(response)? {
+        Response::Ok(_) => Ok(()),
+        response => Err(KVStoreError::Other(format!(
+            "unexpected response from leader: {:?}",
+            response
+        ))),
+    }
+}
+
+fn closed() -> KVStoreError {
+    KVStoreError::Other("leader closed the connection".to_owned())
+}
@@ -1,61 +1,649 @@
-use log::error;
-use serde::Deserialize;
+use log::{error, info};
 use serde_json::Deserializer;
 
+use crate::Address;
+use crate::Counters;
 use crate::KVStoreEngine;
+use crate::Listener;
 use crate::Request;
 use crate::Response;
 use crate::Result;
+use crate::Stream;
+use crate::TlsAcceptor;
+use crate::{lock_snapshot, Snapshots, SNAPSHOT_EXPIRY_INTERVAL};
+use crate::{now_millis, SlowEntry, SlowLog};
+use crate::{slow_down_write, MAINTENANCE_INTERVAL};
+use crate::{KVStoreError, LogBatch, LogPosition};
+use crate::{User, Users, MAX_FAILED_AUTHS};
+use crate::{CHANGES_BATCH_SIZE, CHANGES_POLL_INTERVAL, WATCH_HEARTBEAT_INTERVAL};
+use crate::{REPLICATION_BATCH_SIZE, REPLICATION_HEARTBEAT_INTERVAL, REPLICATION_POLL_INTERVAL};
 use std::io::BufReader;
 use std::io::BufWriter;
-use std::net::TcpStream;
+use std::io::Write;
 use std::net::{TcpListener, ToSocketAddrs};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
 
 pub struct Server<E: KVStoreEngine> {
     pub engine: E,
+    // wrap every accepted connection into TLS if it is set
+    tls: Option<TlsAcceptor>,
+    // require `Request::Auth` and check permissions if it is set
+    users: Option<Arc<Users>>,
+    // the admins that are not in `users`
+    admins: Option<Arc<Users>>,
+    // snapshots taken by the clients of every connection
+    snapshots: Arc<Mutex<Snapshots>>,
+    // requests and connections of every connection
+    counters: Arc<Counters>,
+    // keep the slow requests of every connection if it is set
+    slow_log: Option<Arc<SlowLog>>,
+    // set by `Request::Shutdown`, the listener at `listen_addr` stops accepting then
+    shutdown: Arc<AtomicBool>,
+    listen_addr: Option<Address>,
 }
 
-impl<E: KVStoreEngine> Server<E> {
+impl<E: KVStoreEngine + Clone + Send + 'static> Server<E> {
     /// `new` create a server
     pub fn new(engine: E) -> Self {
-        Server { engine }
+        Server {
+            engine,
+            tls: None,
+            users: None,
+            admins: None,
+            snapshots: Arc::default(),
+            counters: Arc::default(),
+            slow_log: None,
+            shutdown: Arc::default(),
+            listen_addr: None,
+        }
+    }
+
+    /// `with_tls` serve every connection over TLS
+    pub fn with_tls(mut self, tls: TlsAcceptor) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// `with_users` require every connection to authenticate as one of `users`
+    pub fn with_users(mut self, users: Users) -> Self {
+        self.users = Some(Arc::new(users));
+        self
+    }
+
+    /// `with_admin` accept the credential of `admin` for the admin requests
+    ///
+    /// with no users file, the other requests still need no authentication
+    pub fn with_admin(mut self, admin: User) -> Self {
+        let admin = User {
+            admin: true,
+            ..admin
+        };
+        self.admins = Some(Arc::new(Users::new(vec![admin])));
+        self
+    }
+
+    /// `with_counters` count the requests in `counters`, to share them with a `MetricsServer`
+    pub fn with_counters(mut self, counters: Arc<Counters>) -> Self {
+        self.counters = counters;
+        self
+    }
+
+    /// `with_slow_log` keep the requests slower than the threshold of `slow_log`
+    pub fn with_slow_log(mut self, slow_log: SlowLog) -> Self {
+        self.slow_log = Some(Arc::new(slow_log));
+        self
+    }
+
+    /// listen on a TCP address
+    ///
+    /// each connection is served in its own thread
+    ///
+    /// return once a `Request::Shutdown` is answered
+    pub fn start<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        self.start_on(TcpListener::bind(addr)?)
+    }
+
+    /// listen on a unix domain socket at `path`, like `start`
+    pub fn start_unix<P: AsRef<Path>>(self, path: P) -> Result<()> {
+        self.start_on(Listener::bind(&Address::Unix(path.as_ref().to_owned()))?)
     }
 
-    pub fn start<A: ToSocketAddrs>(mut self, addr: A) -> Result<()> {
-        let listener = TcpListener::bind(addr)?;
-        for stream in listener.incoming() {
-            match (stream) {
+    /// serve the connections of a listener that is already bound, like `start`
+    pub fn start_on<L: Into<Listener>>(self, listener: L) -> Result<()> {
+        let listener = listener.into();
+        let addr = listener.local_addr()?;
+        // release what the snapshots of clients that went away hold
+        let snapshots = Arc::clone(&self.snapshots);
+        thread::spawn(move || loop {
+            thread::sleep(SNAPSHOT_EXPIRY_INTERVAL);
+            match lock(&snapshots) {
+                Ok(mut snapshots) => snapshots.expire(),
+                Err(_) => return,
+            }
+        });
+        // compact when no write comes to ask for it, stalled writes wait for this
+        let mut engine = self.engine.clone();
+        let shutdown = Arc::clone(&self.shutdown);
+        thread::spawn(move || {
+            while !shutdown.load(Ordering::SeqCst) {
+                thread::sleep(MAINTENANCE_INTERVAL);
+                if let Err(err) = engine.maintain() {
+                    error!("Maintenance failed: {}", err);
+                }
+            }
+        });
+        loop {
+            let accepted = listener.accept();
+            if self.shutdown.load(Ordering::SeqCst) {
+                info!("Shutting down");
+                return Ok(());
+            }
+            match accepted {
                 Ok(stream) => {
-                    if let Err(err) = self.serve(stream) {
-                        error!("Error on serving client: {}", err)
-                    }
+                    let mut connection = Server {
+                        engine: self.engine.clone(),
+                        tls: self.tls.clone(),
+                        users: self.users.clone(),
+                        admins: self.admins.clone(),
+                        snapshots: Arc::clone(&self.snapshots),
+                        counters: Arc::clone(&self.counters),
+                        slow_log: self.slow_log.clone(),
+                        shutdown: Arc::clone(&self.shutdown),
+                        listen_addr: Some(addr.clone()),
+                    };
+                    thread::spawn(move || {
+                        connection.counters.connected();
+                        let stream = match &connection.tls {
+                            Some(tls) => tls.accept(stream),
+                            None => Ok(stream),
+                        };
+                        if let Err(err) = stream.and_then(|stream| connection.serve(stream)) {
+                            error!("Error on serving client: {}", err)
+                        }
+                        connection.counters.disconnected();
+                    });
                 }
                 Err(err) => error!("Connection failed: {}", err),
             }
         }
+    }
+
+    /// serve requests until the client closes the connection
+    fn serve(&mut self, stream: Stream) -> Result<()> {
+        let client = stream.peer_addr();
+        let reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        let requests = Deserializer::from_reader(reader).into_iter::<Request>();
+        // the user this connection authenticated as
+        let mut user: Option<User> = None;
+        let mut failed_auths = 0;
+
+        for request in requests {
+            // pipelined requests are answered in order, tagged with their id
+            let (id, request) = match request? {
+                Request::Tagged { id, request } => (Some(id), *request),
+                request => (None, request),
+            };
+            let name = request.name();
+            let started = Instant::now();
+            self.counters.record(name)?;
+            // what the slow log tells of the request, known before it is served
+            let slow = match &self.slow_log {
+                Some(_) => Some((
+                    SlowEntry {
+                        timestamp: 0,
+                        request: name.to_owned(),
+                        key: request.key().map(str::to_owned),
+                        value_bytes: request.value_bytes(),
+                        duration_us: 0,
+                        client: client.to_owned(),
+                        compacted: false,
+                    },
+                    self.engine.compactions()?,
+                )),
+                None => None,
+            };
+            let response = match self.check_access(&mut user, &request) {
+                Some(denied) => {
+                    self.counters.error("permission_denied");
+                    if let Request::Auth { .. } = request {
+                        failed_auths += 1;
+                    }
+                    denied
+                }
+                None => match request {
+                    // the connection is handed over to replication until the follower leaves
+                    Request::Replicate { from } if id.is_none() => {
+                        return self.replicate(from, &mut writer)
+                    }
+                    Request::Watch { key_or_prefix } if id.is_none() => {
+                        return self.watch(key_or_prefix, &mut writer)
+                    }
+                    Request::ChangesSince { seq } if id.is_none() => {
+                        return self.changes_since(seq, &mut writer)
+                    }
+                    request => self.respond(request),
+                },
+            };
+            let elapsed = started.elapsed();
+            self.counters.observe(name, elapsed)?;
+            if let Some((entry, compactions)) = slow {
+                self.log_slow(entry, compactions, elapsed);
+            }
+            let response = match id {
+                Some(id) => Response::Tagged {
+                    id,
+                    response: Box::new(response),
+                },
+                None => response,
+            };
+            serde_json::to_writer(&mut writer, &response)?;
+            writer.flush()?;
+            // guessing passwords takes a new connection every few tries
+            if failed_auths >= MAX_FAILED_AUTHS {
+                info!(
+                    "Closing connection of {} after {} failed logins",
+                    client, failed_auths
+                );
+                return Ok(());
+            }
+            if self.shutdown.load(Ordering::SeqCst) {
+                // wake the listener, it stops accepting once it sees the flag
+                if let Some(addr) = &self.listen_addr {
+                    let _ = Stream::connect(addr);
+                }
+                return Ok(());
+            }
+        }
         Ok(())
     }
 
-    fn serve(&mut self, stream: TcpStream) -> Result<()> {
-        let reader = BufReader::new(&stream);
-        let writer = BufWriter::new(&stream);
-        let request = Request::deserialize(&mut Deserializer::from_reader(reader))?;
+    /// change a setting of the server, or else of the engine
+    fn set_config(&mut self, name: &str, value: &str) -> Result<()> {
+        match (name, &self.slow_log) {
+            ("slow_log_ms", Some(slow_log)) => {
+                let ms = value
+                    .parse()
+                    .map_err(|_| KVStoreError::Other(format!("invalid slow_log_ms {}", value)))?;
+                slow_log.set_threshold(Duration::from_millis(ms));
+                Ok(())
+            }
+            _ => self.engine.set_config(name, value),
+        }
+    }
 
-        let response = match request {
-            Request::Get { key } => match self.engine.get(key) {
-                Ok(value) => Response::Ok(value),
-                Err(err) => Response::Err(format!("{}", err)),
+    /// keep a request in the slow log if it took longer than the threshold
+    ///
+    /// `compactions` is the count of the engine before the request
+    fn log_slow(&mut self, mut entry: SlowEntry, compactions: u64, elapsed: Duration) {
+        let slow_log = match &self.slow_log {
+            Some(slow_log) if elapsed >= slow_log.threshold() => Arc::clone(slow_log),
+            _ => return,
+        };
+        entry.timestamp = now_millis();
+        entry.duration_us = elapsed.as_micros() as u64;
+        entry.compacted = self
+            .engine
+            .compactions()
+            .is_ok_and(|count| count != compactions);
+        if let Err(err) = slow_log.record(entry) {
+            error!("Cannot write the slow log: {}", err);
+        }
+    }
+
+    /// serve a request that is answered with one response
+    fn respond(&mut self, request: Request) -> Response {
+        if request.is_write() {
+            if let Err(err) = slow_down_write(&mut self.engine) {
+                return response_of(&self.counters, Err(err));
+            }
+        }
+        match request {
+            Request::Get { key } => response_of(&self.counters, self.engine.get(key)),
+            Request::Set { key, value } => {
+                response_of(&self.counters, self.engine.set(key, value).map(|_| None))
+            }
+            Request::Remove { key } => {
+                response_of(&self.counters, self.engine.remove(key).map(|_| None))
+            }
+            Request::MultiGet { keys } => match self.engine.multi_get(keys) {
+                Ok(results) => Response::Many(
+                    results
+                        .into_iter()
+                        .map(|result| response_of(&self.counters, result))
+                        .collect(),
+                ),
+                Err(err) => response_of(&self.counters, Err(err)),
+            },
+            Request::MultiSet { pairs } => match self.engine.multi_set(pairs) {
+                Ok(results) => Response::Many(
+                    results
+                        .into_iter()
+                        .map(|result| response_of(&self.counters, result.map(|_| None)))
+                        .collect(),
+                ),
+                Err(err) => response_of(&self.counters, Err(err)),
+            },
+            Request::Incr { key, delta } => response_of(
+                &self.counters,
+                self.engine
+                    .incr(key, delta)
+                    .map(|value| Some(value.to_string())),
+            ),
+            Request::Commit { reads, writes } => response_of(
+                &self.counters,
+                self.engine
+                    .commit(reads, writes)
+                    .map(|committed| Some(committed.to_string())),
+            ),
+            Request::GetAt { key, version } => {
+                response_of(&self.counters, self.engine.get_at(key, version))
+            }
+            Request::History { key } => match self.engine.history(key) {
+                Ok(versions) => Response::Versions(versions),
+                Err(err) => response_of(&self.counters, Err(err)),
+            },
+            Request::GetVersion { key } => match self.engine.get_version(key) {
+                Ok(version) => Response::Versions(vec![version]),
+                Err(err) => response_of(&self.counters, Err(err)),
+            },
+            Request::Scan { prefix, limit } => match self.engine.scan(prefix, limit) {
+                Ok(pairs) => Response::Pairs(pairs),
+                Err(err) => response_of(&self.counters, Err(err)),
+            },
+            Request::Auth { .. } => Response::Ok(None),
+            Request::Snapshot { lease_ms } => {
+                let snapshot = self.snapshot(lease_ms);
+                response_of(&self.counters, snapshot)
+            }
+            Request::SnapshotGet { id, key } => response_of(
+                &self.counters,
+                lock(&self.snapshots)
+                    .and_then(|mut snapshots| snapshots.get(id))
+                    .and_then(|snapshot| lock_snapshot(&snapshot)?.get(&key)),
+            ),
+            Request::SnapshotScan { id, prefix, limit } => match lock(&self.snapshots)
+                .and_then(|mut snapshots| snapshots.get(id))
+                .and_then(|snapshot| lock_snapshot(&snapshot)?.scan(&prefix, limit))
+            {
+                Ok(pairs) => Response::Pairs(pairs),
+                Err(err) => response_of(&self.counters, Err(err)),
             },
-            Request::Set { key, value } => match self.engine.set(key, value) {
-                Ok(_) => Response::Ok(None),
-                Err(err) => Response::Err(format!("{}", err)),
+            Request::ReleaseSnapshot { id } => response_of(
+                &self.counters,
+                lock(&self.snapshots)
+                    .map(|mut snapshots| snapshots.remove(id))
+                    .map(|_| None),
+            ),
+            // streams take over the connection, they cannot be pipelined
+            Request::Replicate { .. }
+            | Request::Watch { .. }
+            | Request::ChangesSince { .. }
+            | Request::Tagged { .. } => Response::Err("request cannot be pipelined".to_owned()),
+            Request::Migrate { target, ranges } => response_of(
+                &self.counters,
+                self.engine
+                    .migrate(target, ranges)
+                    .map(|moved| Some(moved.to_string())),
+            ),
+            Request::Compact => response_of(&self.counters, self.engine.compact().map(|_| None)),
+            Request::Flush => response_of(&self.counters, self.engine.flush().map(|_| None)),
+            // nothing buffered is lost, the connections still open are dropped with the process
+            Request::Shutdown => {
+                let flushed = self.engine.flush();
+                if flushed.is_ok() {
+                    self.shutdown.store(true, Ordering::SeqCst);
+                }
+                response_of(&self.counters, flushed.map(|_| None))
+            }
+            Request::SetConfig { name, value } => {
+                let result = self.set_config(&name, &value);
+                response_of(&self.counters, result.map(|_| None))
+            }
+            Request::Stats => match self
+                .engine
+                .stats()
+                .and_then(|engine| self.counters.report(engine))
+            {
+                Ok(stats) => Response::Stats(stats),
+                Err(err) => response_of(&self.counters, Err(err)),
             },
-            Request::Remove { key } => match self.engine.remove(key) {
-                Ok(_) => Response::Ok(None),
-                Err(err) => Response::Err(format!("{}", err)),
+            Request::SlowLog => match &self.slow_log {
+                Some(slow_log) => match slow_log.recent() {
+                    Ok(entries) => Response::SlowLog(entries),
+                    Err(err) => response_of(&self.counters, Err(err)),
+                },
+                None => response_of(
+                    &self.counters,
+                    Err(KVStoreError::Other("the slow log is off".to_owned())),
+                ),
             },
+            Request::Raft(message) => match self.engine.raft(message) {
+                Ok(reply) => Response::Raft(reply),
+                Err(err) => {
+                    self.counters.error(err.kind());
+                    error_response(err)
+                }
+            },
+        }
+    }
+
+    /// take a snapshot kept for `lease_ms` after its last use, return its id
+    fn snapshot(&mut self, lease_ms: u64) -> Result<Option<String>> {
+        let snapshot = self.engine.snapshot()?;
+        let id = lock(&self.snapshots)?.insert(snapshot, Duration::from_millis(lease_ms));
+        Ok(Some(id.to_string()))
+    }
+
+    /// ship the log from `from` to a follower, then keep shipping new records
+    fn replicate<W: Write>(&mut self, from: LogPosition, writer: &mut W) -> Result<()> {
+        let mut position = from;
+        let mut idle = Duration::ZERO;
+        loop {
+            let batch = match self.engine.read_log(position, REPLICATION_BATCH_SIZE) {
+                Ok(batch) => batch,
+                Err(err) => {
+                    serde_json::to_writer(&mut *writer, &error_response(err))?;
+                    writer.flush()?;
+                    return Ok(());
+                }
+            };
+            let shipped = match &batch {
+                LogBatch::Records(records) => {
+                    if let Some((last, _)) = records.last() {
+                        position = *last;
+                    }
+                    !records.is_empty()
+                }
+                LogBatch::Snapshot { next, .. } => {
+                    position = *next;
+                    true
+                }
+            };
+            if shipped || idle >= REPLICATION_HEARTBEAT_INTERVAL {
+                serde_json::to_writer(&mut *writer, &Response::Log(batch))?;
+                writer.flush()?;
+                idle = Duration::ZERO;
+            }
+            if !shipped {
+                thread::sleep(REPLICATION_POLL_INTERVAL);
+                idle += REPLICATION_POLL_INTERVAL;
+            }
+        }
+    }
+
+    /// push every change under `prefix` until the client leaves
+    fn watch<W: Write>(&mut self, prefix: String, writer: &mut W) -> Result<()> {
+        let mut watcher = match self.engine.watch(prefix) {
+            Ok(watcher) => watcher,
+            Err(err) => {
+                serde_json::to_writer(&mut *writer, &response_of(&self.counters, Err(err)))?;
+                writer.flush()?;
+                return Ok(());
+            }
         };
-        serde_json::to_writer(writer, &response)?;
-        Ok(())
+        // the client knows changes are watched from here on
+        serde_json::to_writer(&mut *writer, &Response::Ok(None))?;
+        writer.flush()?;
+        loop {
+            match watcher.next_timeout(WATCH_HEARTBEAT_INTERVAL)? {
+                Some(event) => serde_json::to_writer(&mut *writer, &Response::Event(event))?,
+                // whitespace between responses is skipped, writing it only fails if the client left
+                None => writer.write_all(b"\n")?,
+            }
+            writer.flush()?;
+        }
+    }
+
+    /// replay the changes after `seq`, then push new ones until the client leaves
+    fn changes_since<W: Write>(&mut self, mut seq: u64, writer: &mut W) -> Result<()> {
+        let mut started = false;
+        let mut idle = Duration::ZERO;
+        loop {
+            let changes = match self.engine.changes_since(seq, CHANGES_BATCH_SIZE) {
+                Ok(changes) => changes,
+                // also if the client fell behind compaction while tailing
+                Err(err) => {
+                    serde_json::to_writer(&mut *writer, &response_of(&self.counters, Err(err)))?;
+                    writer.flush()?;
+                    return Ok(());
+                }
+            };
+            if !started {
+                // the client knows the replay is on its way
+                serde_json::to_writer(&mut *writer, &Response::Ok(None))?;
+                writer.flush()?;
+                started = true;
+            }
+            if changes.is_empty() {
+                thread::sleep(CHANGES_POLL_INTERVAL);
+                idle += CHANGES_POLL_INTERVAL;
+                if idle < WATCH_HEARTBEAT_INTERVAL {
+                    continue;
+                }
+                writer.write_all(b"\n")?;
+            }
+            for change in changes {
+                seq = change.seq;
+                serde_json::to_writer(&mut *writer, &Response::Event(change))?;
+            }
+            writer.flush()?;
+            idle = Duration::ZERO;
+        }
+    }
+
+    /// authenticate `Request::Auth` and check the permission of other requests
+    ///
+    /// return the response to send instead of serving the request, if any
+    fn check_access(&self, user: &mut Option<User>, request: &Request) -> Option<Response> {
+        if let Request::Auth {
+            user: name,
+            password,
+        } = request
+        {
+            if self.users.is_none() && self.admins.is_none() {
+                return None;
+            }
+            *user = [&self.users, &self.admins]
+                .into_iter()
+                .flatten()
+                .find_map(|users| users.authenticate(name, password))
+                .cloned();
+            return match user {
+                Some(_) => None,
+                None => Some(Response::Denied("invalid user or password".to_owned())),
+            };
+        }
+        if request.is_admin() {
+            return match user {
+                Some(user) if user.admin => None,
+                Some(user) => Some(Response::Denied(format!("{} is not an admin", user.name))),
+                None => Some(Response::Denied("admin credential required".to_owned())),
+            };
+        }
+        // without a users file, anyone may send the other requests
+        self.users.as_ref()?;
+        let user = match user {
+            Some(user) => user,
+            None => return Some(Response::Denied("authentication required".to_owned())),
+        };
+        let allowed = match request {
+            Request::Get { key }
+            | Request::GetAt { key, .. }
+            | Request::History { key }
+            | Request::GetVersion { key } => user.can_read(key),
+            // every key under `prefix` must be readable
+            Request::Scan { prefix, .. } => user.can_read(prefix),
+            Request::Watch { key_or_prefix } => user.can_read(key_or_prefix),
+            Request::ChangesSince { .. } => user.can_read(""),
+            Request::Set { key, .. } | Request::Remove { key } | Request::Incr { key, .. } => {
+                user.can_write(key)
+            }
+            Request::MultiGet { keys } => keys.iter().all(|key| user.can_read(key)),
+            Request::MultiSet { pairs } => pairs.iter().all(|(key, _)| user.can_write(key)),
+            Request::Commit { reads, writes } => {
+                reads.iter().all(|(key, _)| user.can_read(key))
+                    && writes.iter().all(|(key, _)| user.can_write(key))
+            }
+            Request::Auth { .. } => true,
+            // it tells no key or value
+            Request::Stats => true,
+            // checked above
+            Request::Compact
+            | Request::Flush
+            | Request::Shutdown
+            | Request::SetConfig { .. }
+            | Request::Migrate { .. }
+            | Request::SlowLog => true,
+            // only one level of tags, it is refused when served
+            Request::Tagged { .. } => true,
+            // a snapshot holds every key, reading it is checked like reading the store
+            Request::Snapshot { .. } | Request::ReleaseSnapshot { .. } => true,
+            Request::SnapshotGet { key, .. } => user.can_read(key),
+            Request::SnapshotScan { prefix, .. } => user.can_read(prefix),
+            // a follower gets every key
+            Request::Replicate { .. } => user.can_read(""),
+            // another node of the cluster writes every key
+            Request::Raft(_) => user.can_write(""),
+        };
+        if allowed {
+            None
+        } else {
+            Some(Response::Denied(format!("{} has no access", user.name)))
+        }
+    }
+}
+
+fn lock(snapshots: &Mutex<Snapshots>) -> Result<MutexGuard<'_, Snapshots>> {
+    snapshots
+        .lock()
+        .map_err(|_| KVStoreError::Other("snapshots lock poisoned".to_owned()))
+}
+
+/// response to an engine call, a follower redirects writes to its leader
+///
+/// errors are counted by kind
+fn response_of(counters: &Counters, result: Result<Option<String>>) -> Response {
+    match result {
+        Ok(value) => Response::Ok(value),
+        Err(err) => {
+            counters.error(err.kind());
+            error_response(err)
+        }
+    }
+}
+
+/// the response of an error, the client turns it back into the same `KVStoreError`
+fn error_response(err: KVStoreError) -> Response {
+    match err {
+        KVStoreError::Redirect(leader) => Response::Redirect(leader),
+        err => Response::Error {
+            kind: err.kind().to_owned(),
+            detail: err.detail(),
+        },
     }
 }
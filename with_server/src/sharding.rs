@@ -0,0 +1,355 @@
+//! client-side sharding: every key lives on one of several servers, chosen by consistent hashing
+//!
+//! each server is placed on a hash ring at `VIRTUAL_NODES` points, and a key belongs to the
+//! server of the first point at or after the hash of the key. adding or removing a server
+//! only moves the keys next to its points, about 1/n of all keys
+//!
+//! `add_node_and_migrate` moves those keys to the new server while the others keep serving
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{KVStoreError, KvsClient, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::thread;
+
+/// points on the ring per server, more points spread keys more evenly
+pub const VIRTUAL_NODES: usize = 160;
+
+/// keys whose hash is in (start, end], wrapping around the ring if `start >= end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HashRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl HashRange {
+    pub fn contains(&self, hash: u64) -> bool {
+        if self.start < self.end {
+            self.start < hash && hash <= self.end
+        } else {
+            self.start < hash || hash <= self.end
+        }
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.contains(key_hash(key))
+    }
+}
+
+/// client of several `Server`s, each holding a share of the keys
+///
+/// connections are opened on first use, and reopened after an error
+pub struct ShardedClient {
+    nodes: Vec<String>,
+    // point on the ring -> server address
+    ring: BTreeMap<u64, String>,
+    clients: HashMap<String, KvsClient>,
+    // user and password every connection authenticates with
+    credentials: Option<(String, String)>,
+}
+
+impl ShardedClient {
+    /// `new` shard keys across the servers at `nodes`
+    pub fn new<S: Into<String>>(nodes: impl IntoIterator<Item = S>) -> Self {
+        let mut client = ShardedClient {
+            nodes: Vec::new(),
+            ring: BTreeMap::new(),
+            clients: HashMap::new(),
+            credentials: None,
+        };
+        for node in nodes {
+            client.add_node(node);
+        }
+        client
+    }
+
+    /// `with_credentials` authenticate every connection as `user`
+    ///
+    /// moving keys with `add_node_and_migrate` needs an admin
+    pub fn with_credentials(mut self, user: String, password: String) -> Self {
+        self.credentials = Some((user, password));
+        self.clients.clear();
+        self
+    }
+
+    /// addresses of the servers, in the order they were added
+    pub fn nodes(&self) -> &[String] {
+        &self.nodes
+    }
+
+    /// add a server, it takes over a share of the keys of the others
+    pub fn add_node<S: Into<String>>(&mut self, node: S) {
+        let node = node.into();
+        if self.nodes.contains(&node) {
+            return;
+        }
+        for point in 0..VIRTUAL_NODES {
+            self.ring
+                .insert(key_hash(&format!("{}#{}", node, point)), node.to_owned());
+        }
+        self.nodes.push(node);
+    }
+
+    /// remove a server, its keys go to the servers next to it on the ring
+    pub fn remove_node(&mut self, node: &str) {
+        self.nodes.retain(|other| other != node);
+        self.ring.retain(|_, other| other != node);
+        self.clients.remove(node);
+    }
+
+    /// add a server, and move the keys it takes over from the others to it
+    ///
+    /// the other servers keep serving while their keys are copied, return the keys moved
+    pub fn add_node_and_migrate<S: Into<String>>(&mut self, node: S) -> Result<usize> {
+        let node = node.into();
+        if self.nodes.contains(&node) {
+            return Ok(0);
+        }
+        let mut ring = self.ring.clone();
+        let points: Vec<u64> = (0..VIRTUAL_NODES)
+            .map(|point| key_hash(&format!("{}#{}", node, point)))
+            .collect();
+        for point in &points {
+            ring.insert(*point, node.to_owned());
+        }
+        // each point takes the keys between the point before it and itself
+        let mut ranges: HashMap<String, Vec<HashRange>> = HashMap::new();
+        for &end in &points {
+            let start = ring
+                .range(..end)
+                .next_back()
+                .or_else(|| ring.iter().next_back())
+                .map_or(end, |(start, _)| *start);
+            if let Some(owner) = self.node_at(end) {
+                ranges
+                    .entry(owner.to_owned())
+                    .or_default()
+                    .push(HashRange { start, end });
+            }
+        }
+        let mut moved = 0;
+        for (source, ranges) in ranges {
+            moved += self.call(&source, |client| {
+                client.migrate(node.to_owned(), ranges.clone())
+            })?;
+        }
+        self.add_node(node);
+        Ok(moved)
+    }
+
+    /// address of the server `key` belongs to, None if there is no server
+    pub fn node_for(&self, key: &str) -> Option<&str> {
+        self.node_at(key_hash(key))
+    }
+
+    /// the server of the first point at or after `hash`
+    fn node_at(&self, hash: u64) -> Option<&str> {
+        self.ring
+            .range(hash..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, node)| node.as_str())
+    }
+
+    /// get value by key from the server it belongs to
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let node = self.shard(&key)?;
+        self.call(&node, |client| client.get(key.to_owned()))
+    }
+
+    /// set key, value on the server it belongs to
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let node = self.shard(&key)?;
+        self.call(&node, |client| client.set(key.to_owned(), value.to_owned()))
+    }
+
+    /// remove key from the server it belongs to
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let node = self.shard(&key)?;
+        self.call(&node, |client| client.remove(key.to_owned()))
+    }
+
+    /// get the values of `keys`, in the same order, with one `MultiGet` per server in parallel
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut values = vec![None; keys.len()];
+        let mut work: HashMap<String, Vec<(usize, String)>> = HashMap::new();
+        for (position, key) in keys.into_iter().enumerate() {
+            work.entry(self.shard(&key)?)
+                .or_default()
+                .push((position, key));
+        }
+        let found = self.fan_out(work, |client, keys| {
+            client.multi_get(keys.into_iter().map(|(_, key)| key).collect())
+        })?;
+        for ((position, _), value) in found {
+            values[position] = value;
+        }
+        Ok(values)
+    }
+
+    /// set every pair, with one `MultiSet` per server in parallel
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut work: HashMap<String, Vec<(String, String)>> = HashMap::new();
+        for (key, value) in pairs {
+            work.entry(self.shard(&key)?)
+                .or_default()
+                .push((key, value));
+        }
+        self.fan_out(work, |client, pairs| client.multi_set(pairs))?;
+        Ok(())
+    }
+
+    /// scan every server for keys starting with `prefix`, merged in key order
+    pub fn scan(&mut self, prefix: String, limit: Option<usize>) -> Result<Vec<(String, String)>> {
+        let work = self
+            .nodes
+            .iter()
+            .map(|node| (node.to_owned(), vec![prefix.to_owned()]))
+            .collect();
+        let mut pairs: Vec<(String, String)> = self
+            .fan_out(work, |client, prefixes| {
+                prefixes
+                    .into_iter()
+                    .map(|prefix| client.scan(prefix, limit).map(Ok))
+                    .collect()
+            })?
+            .into_iter()
+            .flat_map(|(_, pairs)| pairs)
+            .collect();
+        pairs.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        if let Some(limit) = limit {
+            pairs.truncate(limit);
+        }
+        Ok(pairs)
+    }
+
+    fn shard(&self, key: &str) -> Result<String> {
+        self.node_for(key)
+            .map(str::to_owned)
+            .ok_or_else(|| KVStoreError::Other("no server to shard keys across".to_owned()))
+    }
+
+    fn client(&mut self, node: &str) -> Result<&mut KvsClient> {
+        if !self.clients.contains_key(node) {
+            let mut client = KvsClient::connect_to(node)?;
+            if let Some((user, password)) = &self.credentials {
+                client.authenticate(user.to_owned(), password.to_owned())?;
+            }
+            self.clients.insert(node.to_owned(), client);
+        }
+        Ok(self.clients.get_mut(node).expect("connected above"))
+    }
+
+    /// run `request` on the server at `node`
+    ///
+    /// a server that handed the key over to another one redirects there
+    fn call<T>(&mut self, node: &str, request: impl Fn(&mut KvsClient) -> Result<T>) -> Result<T> {
+        let result = request(self.client(node)?);
+        self.forget_broken(node, &result);
+        match result {
+            Err(KVStoreError::Redirect(owner)) => {
+                let result = request(self.client(&owner)?);
+                self.forget_broken(&owner, &result);
+                result
+            }
+            result => result,
+        }
+    }
+
+    /// run `request` on every server that has items, one thread per server
+    ///
+    /// `request` returns a result per item, in the same order. like `call`, items a server
+    /// handed over to another one are sent again there
+    fn fan_out<I: Clone + Send, T: Send>(
+        &mut self,
+        work: HashMap<String, Vec<I>>,
+        request: impl Fn(&mut KvsClient, Vec<I>) -> PerItem<T> + Sync,
+    ) -> Result<Vec<(I, T)>> {
+        let mut done = Vec::new();
+        let mut redirected: HashMap<String, Vec<I>> = HashMap::new();
+        for (item, result) in self.in_parallel(work, &request)? {
+            match result {
+                Ok(value) => done.push((item, value)),
+                Err(KVStoreError::Redirect(owner)) => {
+                    redirected.entry(owner).or_default().push(item)
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        for (owner, items) in redirected {
+            let results = self.call(&owner, |client| request(client, items.clone()))?;
+            for (item, result) in items.into_iter().zip(results) {
+                done.push((item, result?));
+            }
+        }
+        Ok(done)
+    }
+
+    /// run `request` on every server that has items, one thread per server, the result of each item
+    ///
+    /// a server that redirects the whole request is sent it again at the owner
+    fn in_parallel<I: Clone + Send, T: Send>(
+        &mut self,
+        mut work: HashMap<String, Vec<I>>,
+        request: &(impl Fn(&mut KvsClient, Vec<I>) -> PerItem<T> + Sync),
+    ) -> Result<Vec<(I, Result<T>)>> {
+        for node in work.keys() {
+            self.client(node)?;
+        }
+        let results: Vec<(String, Vec<I>, PerItem<T>)> = thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .clients
+                .iter_mut()
+                .filter_map(|(node, client)| {
+                    let items = work.remove(node)?;
+                    let sent = items.clone();
+                    Some((
+                        node.to_owned(),
+                        items,
+                        scope.spawn(move || request(client, sent)),
+                    ))
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|(node, items, handle)| {
+                    let result = handle.join().unwrap_or_else(|_| {
+                        Err(KVStoreError::Other(format!("request to {} panicked", node)))
+                    });
+                    (node, items, result)
+                })
+                .collect()
+        });
+        let mut done = Vec::new();
+        for (node, items, result) in results {
+            self.forget_broken(&node, &result);
+            let results = match result {
+                Err(KVStoreError::Redirect(owner)) => {
+                    self.call(&owner, |client| request(client, items.clone()))?
+                }
+                result => result?,
+            };
+            done.extend(items.into_iter().zip(results));
+        }
+        Ok(done)
+    }
+
+    /// drop the connection to `node` if it failed, the next request reconnects
+    fn forget_broken<T>(&mut self, node: &str, result: &Result<T>) {
+        if let Err(KVStoreError::Io(_)) | Err(KVStoreError::Serde(_)) = result {
+            self.clients.remove(node);
+        }
+    }
+}
+
+/// the result of a request with many items, and of each item in the same order
+type PerItem<T> = Result<Vec<Result<T>>>;
+
+/// position of `key` on the ring, stable across processes and platforms
+pub fn key_hash(key: &str) -> u64 {
+    let digest = Sha256::digest(key.as_bytes());
+    let mut bytes = [0_u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(bytes)
+}
@@ -0,0 +1,113 @@
+//! requests slower than a threshold, kept in memory and appended to a file
+//!
+//! the file holds a JSON entry per line, and is rotated to `<file>.1` once it grows
+//! past `SLOW_LOG_MAX_BYTES`
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KVStoreError, Result};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+/// how many of the last slow requests `Request::SlowLog` returns
+pub const SLOW_LOG_RECENT: usize = 128;
+/// the size the slow log file is rotated at
+pub const SLOW_LOG_MAX_BYTES: u64 = 16 * 1024 * 1024;
+
+/// a request that took longer than the threshold
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SlowEntry {
+    // milliseconds since the unix epoch, when the request was answered
+    pub timestamp: u64,
+    // the `Request` variant
+    pub request: String,
+    // the key or prefix, if the request has one
+    pub key: Option<String>,
+    // bytes of the values written
+    pub value_bytes: u64,
+    pub duration_us: u64,
+    pub client: String,
+    // the engine compacted its log while serving the request
+    pub compacted: bool,
+}
+
+/// the slow requests of every connection of a server
+pub struct SlowLog {
+    // in microseconds, it can be changed while the server runs
+    threshold: AtomicU64,
+    state: Mutex<SlowLogState>,
+}
+
+struct SlowLogState {
+    recent: VecDeque<SlowEntry>,
+    file: Option<(PathBuf, File)>,
+}
+
+impl SlowLog {
+    /// keep the requests slower than `threshold`, and append them to `path` if given
+    pub fn new(threshold: Duration, path: Option<PathBuf>) -> Result<Self> {
+        let file = match path {
+            Some(path) => {
+                let file = open(&path)?;
+                Some((path, file))
+            }
+            None => None,
+        };
+        Ok(SlowLog {
+            threshold: AtomicU64::new(threshold.as_micros() as u64),
+            state: Mutex::new(SlowLogState {
+                recent: VecDeque::new(),
+                file,
+            }),
+        })
+    }
+
+    pub fn threshold(&self) -> Duration {
+        Duration::from_micros(self.threshold.load(Ordering::Relaxed))
+    }
+
+    pub fn set_threshold(&self, threshold: Duration) {
+        self.threshold
+            .store(threshold.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// keep an entry, the oldest kept one is dropped once there are `SLOW_LOG_RECENT`
+    pub fn record(&self, entry: SlowEntry) -> Result<()> {
+        let mut state = lock(&self.state)?;
+        if let Some((path, file)) = &mut state.file {
+            serde_json::to_writer(&mut *file, &entry)?;
+            file.write_all(b"\n")?;
+            if file.metadata()?.len() >= SLOW_LOG_MAX_BYTES {
+                let mut rotated = path.clone().into_os_string();
+                rotated.push(".1");
+                fs::rename(&*path, rotated)?;
+                *file = open(path)?;
+            }
+        }
+        if state.recent.len() == SLOW_LOG_RECENT {
+            state.recent.pop_front();
+        }
+        state.recent.push_back(entry);
+        Ok(())
+    }
+
+    /// the last slow requests, oldest first
+    pub fn recent(&self) -> Result<Vec<SlowEntry>> {
+        Ok(lock(&self.state)?.recent.iter().cloned().collect())
+    }
+}
+
+fn open(path: &PathBuf) -> Result<File> {
+    Ok(OpenOptions::new().create(true).append(true).open(path)?)
+}
+
+fn lock(state: &Mutex<SlowLogState>) -> Result<MutexGuard<'_, SlowLogState>> {
+    state
+        .lock()
+        .map_err(|_| KVStoreError::Other("slow log lock poisoned".to_owned()))
+}
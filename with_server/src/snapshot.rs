@@ -0,0 +1,118 @@
+//! point-in-time snapshots: read-only views of an engine, frozen at the moment they were taken
+//!
+//! over the network a snapshot is known by an id, and dropped once its lease runs out
+//! without being used
+
+use crate::{KVSnapshot, KVStoreError, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// the longest lease a client can ask for
+pub const MAX_SNAPSHOT_LEASE: Duration = Duration::from_secs(60 * 60);
+/// how often the server drops the snapshots whose lease ran out
+pub const SNAPSHOT_EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+enum Source {
+    // a copy of every pair, for engines without native snapshots
+    Pairs(BTreeMap<String, String>),
+    KVStore(KVSnapshot),
+}
+
+/// the pairs of an engine at one moment, later writes are not seen
+pub struct Snapshot {
+    source: Source,
+}
+
+impl Snapshot {
+    pub fn from_pairs(pairs: impl IntoIterator<Item = (String, String)>) -> Self {
+        Snapshot {
+            source: Source::Pairs(pairs.into_iter().collect()),
+        }
+    }
+
+    /// get value by key as it was when the snapshot was taken
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        match &mut self.source {
+            Source::Pairs(pairs) => Ok(pairs.get(key).cloned()),
+            Source::KVStore(snapshot) => snapshot.get(key),
+        }
+    }
+
+    /// scan key-value pairs whose key starts with `prefix` as they were, ordered by key
+    pub fn scan(&mut self, prefix: &str, limit: Option<usize>) -> Result<Vec<(String, String)>> {
+        match &mut self.source {
+            Source::Pairs(pairs) => Ok(pairs
+                .range(prefix.to_owned()..)
+                .take_while(|(key, _)| key.starts_with(prefix))
+                .take(limit.unwrap_or(usize::MAX))
+                .map(|(key, value)| (key.to_owned(), value.to_owned()))
+                .collect()),
+            Source::KVStore(snapshot) => snapshot.scan(prefix, limit),
+        }
+    }
+}
+
+impl From<KVSnapshot> for Snapshot {
+    fn from(snapshot: KVSnapshot) -> Self {
+        Snapshot {
+            source: Source::KVStore(snapshot),
+        }
+    }
+}
+
+struct Lease {
+    snapshot: Arc<Mutex<Snapshot>>,
+    duration: Duration,
+    expires: Instant,
+}
+
+/// the snapshots clients of a server hold, by id
+#[derive(Default)]
+pub struct Snapshots {
+    next_id: u64,
+    leases: HashMap<u64, Lease>,
+}
+
+impl Snapshots {
+    /// keep `snapshot` until it is not used for `lease`, return its id
+    pub fn insert(&mut self, snapshot: Snapshot, lease: Duration) -> u64 {
+        self.next_id += 1;
+        let duration = lease.min(MAX_SNAPSHOT_LEASE);
+        self.leases.insert(
+            self.next_id,
+            Lease {
+                snapshot: Arc::new(Mutex::new(snapshot)),
+                duration,
+                expires: Instant::now() + duration,
+            },
+        );
+        self.next_id
+    }
+
+    /// the snapshot of `id`, its lease starts over
+    pub fn get(&mut self, id: u64) -> Result<Arc<Mutex<Snapshot>>> {
+        self.expire();
+        let lease = self.leases.get_mut(&id).ok_or_else(|| {
+            KVStoreError::Other(format!("snapshot {} does not exist or expired", id))
+        })?;
+        lease.expires = Instant::now() + lease.duration;
+        Ok(Arc::clone(&lease.snapshot))
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        self.leases.remove(&id);
+    }
+
+    /// drop the snapshots whose lease ran out
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        self.leases.retain(|_, lease| lease.expires > now);
+    }
+}
+
+pub fn lock_snapshot(snapshot: &Mutex<Snapshot>) -> Result<MutexGuard<'_, Snapshot>> {
+    snapshot
+        .lock()
+        .map_err(|_| KVStoreError::Other("snapshot lock poisoned".to_owned()))
+}
@@ -0,0 +1,223 @@
+//! what a running server tells about itself, see `Request::Stats`
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KVStoreError, Result, WriteStall};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// how many seconds the request rates are averaged over
+pub const RATE_WINDOW_SECS: u64 = 10;
+/// upper bounds of the request latency buckets, in seconds
+pub const LATENCY_BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+
+/// the state of an engine
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EngineStats {
+    pub engine: String,
+    pub keys: u64,
+    // bytes of the records still read, and of those the next compaction drops
+    pub live_bytes: u64,
+    pub dead_bytes: u64,
+    pub log_files: u64,
+    pub compactions: u64,
+    // bytes dropped and time taken by every compaction so far
+    pub reclaimed_bytes: u64,
+    pub compaction_ms: u64,
+    pub last_compaction: Option<CompactionStats>,
+    // how writes are held back now, and how many were slowed down or stalled so far
+    #[serde(default)]
+    pub write_stall: WriteStall,
+    #[serde(default)]
+    pub slowed_writes: u64,
+    #[serde(default)]
+    pub stalled_writes: u64,
+}
+
+/// one run of the compaction
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompactionStats {
+    // milliseconds since the unix epoch
+    pub finished: u64,
+    pub duration_ms: u64,
+    pub reclaimed_bytes: u64,
+}
+
+/// the response to `Request::Stats`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerStats {
+    pub engine: EngineStats,
+    // requests per second over the last `RATE_WINDOW_SECS`, by `Request` variant
+    pub ops_per_sec: BTreeMap<String, f64>,
+    pub connections: u64,
+    pub uptime_secs: u64,
+}
+
+/// counters shared by every connection of a server
+#[derive(Debug)]
+pub struct Counters {
+    started: Instant,
+    connections: AtomicU64,
+    requests: Mutex<BTreeMap<&'static str, RequestCounts>>,
+    // errors answered, by `KVStoreError::kind`
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+/// the requests of one `Request` variant
+#[derive(Debug, Default, Clone)]
+pub struct RequestCounts {
+    pub total: u64,
+    rate: Rate,
+    // requests that take over the connection are not timed
+    pub latency: Histogram,
+}
+
+/// how many observations fell in each of `LATENCY_BUCKETS`, the last one is above them all
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    pub buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: [0; LATENCY_BUCKETS.len() + 1],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+/// requests counted by the second they came in, for the last `RATE_WINDOW_SECS`
+#[derive(Debug, Default, Clone)]
+struct Rate {
+    seconds: VecDeque<(u64, u64)>,
+}
+
+impl Rate {
+    fn record(&mut self, second: u64) {
+        match self.seconds.back_mut() {
+            Some((last, count)) if *last == second => *count += 1,
+            _ => self.seconds.push_back((second, 1)),
+        }
+        self.expire(second);
+    }
+
+    fn expire(&mut self, second: u64) {
+        while let Some((first, _)) = self.seconds.front() {
+            if first + RATE_WINDOW_SECS > second {
+                break;
+            }
+            self.seconds.pop_front();
+        }
+    }
+}
+
+impl Default for Counters {
+    fn default() -> Self {
+        Counters {
+            started: Instant::now(),
+            connections: AtomicU64::new(0),
+            requests: Mutex::default(),
+            errors: Mutex::default(),
+        }
+    }
+}
+
+impl Counters {
+    pub fn connected(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn disconnected(&self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn connections(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// count a request by the name of its `Request` variant
+    pub fn record(&self, request: &'static str) -> Result<()> {
+        let second = self.started.elapsed().as_secs();
+        let mut requests = lock(&self.requests)?;
+        let counts = requests.entry(request).or_default();
+        counts.total += 1;
+        counts.rate.record(second);
+        Ok(())
+    }
+
+    /// time how long a request took to answer
+    pub fn observe(&self, request: &'static str, latency: Duration) -> Result<()> {
+        lock(&self.requests)?
+            .entry(request)
+            .or_default()
+            .latency
+            .observe(latency.as_secs_f64());
+        Ok(())
+    }
+
+    /// count an error answered to a client
+    pub fn error(&self, kind: &'static str) {
+        if let Ok(mut errors) = lock(&self.errors) {
+            *errors.entry(kind).or_default() += 1;
+        }
+    }
+
+    /// the counts of every `Request` variant seen so far
+    pub fn requests(&self) -> Result<BTreeMap<&'static str, RequestCounts>> {
+        Ok(lock(&self.requests)?.clone())
+    }
+
+    /// the errors answered so far, by kind
+    pub fn errors(&self) -> Result<BTreeMap<&'static str, u64>> {
+        Ok(lock(&self.errors)?.clone())
+    }
+
+    /// the stats of the server serving `engine`
+    pub fn report(&self, engine: EngineStats) -> Result<ServerStats> {
+        let uptime = self.started.elapsed();
+        let second = uptime.as_secs();
+        // a server younger than the window has not seen a full one
+        let window = uptime.as_secs_f64().clamp(1.0, RATE_WINDOW_SECS as f64);
+        let mut requests = lock(&self.requests)?;
+        let ops_per_sec = requests
+            .iter_mut()
+            .map(|(name, counts)| {
+                counts.rate.expire(second);
+                let count: u64 = counts.rate.seconds.iter().map(|(_, count)| count).sum();
+                (name.to_string(), count as f64 / window)
+            })
+            .collect();
+        Ok(ServerStats {
+            engine,
+            ops_per_sec,
+            connections: self.connections(),
+            uptime_secs: second,
+        })
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> Result<MutexGuard<'_, T>> {
+    mutex
+        .lock()
+        .map_err(|_| KVStoreError::Other("stats lock poisoned".to_owned()))
+}
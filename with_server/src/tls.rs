@@ -0,0 +1,320 @@
+//! optional TLS on top of any transport, with optional client certificate verification
+
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection};
+use rustls::{Connection, Error as RustlsError};
+
+use crate::{Address, KVStoreError, Result, Stream};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, TryLockError};
+use std::time::Duration;
+
+/// bytes read from the transport at a time, about one record
+const RECEIVE_BUFFER_SIZE: usize = 16 * 1024;
+/// how often a thread waiting for another one's read checks whether that read gave up
+const RECEIVE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// wraps accepted streams into TLS sessions
+#[derive(Clone)]
+pub struct TlsAcceptor(Arc<ServerConfig>);
+
+impl TlsAcceptor {
+    /// load the server certificate chain and private key (PEM)
+    ///
+    /// if `client_ca` is given, clients must present a certificate signed by it
+    pub fn new(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<TlsAcceptor> {
+        let provider = provider();
+        let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()?;
+        let builder = match client_ca {
+            Some(client_ca) => {
+                let verifier = WebPkiClientVerifier::builder_with_provider(
+                    Arc::new(load_roots(client_ca)?),
+                    provider,
+                )
+                .build()
+                .map_err(|err| RustlsError::General(err.to_string()))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder.with_single_cert(load_certs(cert)?, load_key(key)?)?;
+        Ok(TlsAcceptor(Arc::new(config)))
+    }
+
+    /// start a server side session, the handshake runs on first read or write
+    pub fn accept(&self, stream: Stream) -> Result<Stream> {
+        let connection = ServerConnection::new(Arc::clone(&self.0))?;
+        let session = TlsStream::new(connection.into(), stream)?;
+        Ok(Stream::Tls(Arc::new(session)))
+    }
+}
+
+/// wraps connected streams into TLS sessions
+#[derive(Clone)]
+pub struct TlsConnector(Arc<ClientConfig>);
+
+impl TlsConnector {
+    /// trust servers whose certificate is signed by `ca` (PEM)
+    ///
+    /// `identity` is the client certificate chain and private key, for mutual TLS
+    pub fn new(ca: &Path, identity: Option<(&Path, &Path)>) -> Result<TlsConnector> {
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(load_roots(ca)?);
+        let config = match identity {
+            Some((cert, key)) => {
+                builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        Ok(TlsConnector(Arc::new(config)))
+    }
+
+    /// start a client side session, the server certificate must match the host of `addr`
+    pub fn connect(&self, addr: &Address, stream: Stream) -> Result<Stream> {
+        let host = match addr {
+            Address::Tcp(addr) => addr
+                .rsplit_once(':')
+                .map_or(addr.as_str(), |(host, _)| host)
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_owned(),
+            Address::Unix(_) => "localhost".to_owned(),
+        };
+        let server_name =
+            ServerName::try_from(host).map_err(|err| RustlsError::General(err.to_string()))?;
+        let connection = ClientConnection::new(Arc::clone(&self.0), server_name)?;
+        let session = TlsStream::new(connection.into(), stream)?;
+        Ok(Stream::Tls(Arc::new(session)))
+    }
+}
+
+/// an established TLS session, sends close_notify when dropped
+///
+/// one thread can read while another writes: the session is only locked to decrypt
+/// or encrypt records, never while blocked on the transport. the handshake runs on
+/// whichever side needs it first
+///
+/// a session whose handshake did not finish is dropped silently, flushing it would
+/// block on reading the rest of the handshake
+pub struct TlsStream {
+    session: Mutex<Connection>,
+    /// signalled whenever records were received, for threads waiting on another reader
+    received: Condvar,
+    /// the transport, for its address and timeouts
+    transport: Stream,
+    /// held while reading from the transport, so records are decrypted in order
+    reader: Mutex<Stream>,
+    /// held while writing to the transport, so records are sent in order
+    writer: Mutex<Stream>,
+}
+
+impl TlsStream {
+    fn new(session: Connection, transport: Stream) -> Result<TlsStream> {
+        Ok(TlsStream {
+            session: Mutex::new(session),
+            received: Condvar::new(),
+            reader: Mutex::new(transport.try_clone()?),
+            writer: Mutex::new(transport.try_clone()?),
+            transport,
+        })
+    }
+
+    /// the transport the session runs on
+    pub fn get_ref(&self) -> &Stream {
+        &self.transport
+    }
+
+    /// read plaintext, blocking until some arrives
+    pub fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut read = 0;
+        self.receive_until(|session| match session.reader().read(buf) {
+            Ok(n) => {
+                read = n;
+                Ok(true)
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(err),
+        })?;
+        Ok(read)
+    }
+
+    /// encrypt `buf` and send it, finishing the handshake first if needed
+    pub fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        if lock(&self.session)?.is_handshaking() {
+            self.receive_until(|session| Ok(!session.is_handshaking()))?;
+        }
+        let mut transport = lock(&self.writer)?;
+        let written = lock(&self.session)?.writer().write(buf)?;
+        self.send_records(&mut transport)?;
+        drop(transport);
+        self.send()?;
+        Ok(written)
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        let mut transport = lock(&self.writer)?;
+        self.send_records(&mut transport)?;
+        transport.flush()
+    }
+
+    /// send close_notify and shut down the writing half of the transport
+    pub fn shutdown_write(&self) -> Result<()> {
+        {
+            let mut session = lock(&self.session)?;
+            if !session.is_handshaking() {
+                session.send_close_notify();
+            }
+        }
+        self.flush()?;
+        self.transport.shutdown_write()
+    }
+
+    /// read records from the transport until `done` holds for the session
+    ///
+    /// while another thread is reading, wait for the records it receives instead
+    fn receive_until(
+        &self,
+        mut done: impl FnMut(&mut Connection) -> io::Result<bool>,
+    ) -> io::Result<()> {
+        let mut incoming = vec![0; RECEIVE_BUFFER_SIZE];
+        loop {
+            let mut transport = match self.reader.try_lock() {
+                Ok(transport) => transport,
+                Err(TryLockError::WouldBlock) => {
+                    let mut session = lock(&self.session)?;
+                    if done(&mut session)? {
+                        return Ok(());
+                    }
+                    // the timeout covers a reader that gives up without receiving anything
+                    let _ = self
+                        .received
+                        .wait_timeout(session, RECEIVE_POLL_INTERVAL)
+                        .map_err(|_| poisoned())?;
+                    continue;
+                }
+                Err(TryLockError::Poisoned(_)) => return Err(poisoned()),
+            };
+            if done(&mut *lock(&self.session)?)? {
+                return Ok(());
+            }
+            // the handshake messages we owe the peer before it sends anything more
+            self.send()?;
+            let n = transport.read(&mut incoming)?;
+            let processed = self.process(&incoming[..n]);
+            drop(transport);
+            self.received.notify_all();
+            // replies and alerts produced by the records
+            self.send()?;
+            processed?;
+            if n == 0 && !done(&mut *lock(&self.session)?)? {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed during the TLS handshake",
+                ));
+            }
+        }
+    }
+
+    /// hand received bytes to the session, no bytes is the end of the transport
+    fn process(&self, mut incoming: &[u8]) -> io::Result<()> {
+        let mut session = lock(&self.session)?;
+        loop {
+            session.read_tls(&mut incoming)?;
+            session
+                .process_new_packets()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            if incoming.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// send the records the session has queued, unless another thread is sending,
+    /// which then sends them once it is done
+    fn send(&self) -> io::Result<()> {
+        loop {
+            let mut transport = match self.writer.try_lock() {
+                Ok(transport) => transport,
+                Err(TryLockError::WouldBlock) => return Ok(()),
+                Err(TryLockError::Poisoned(_)) => return Err(poisoned()),
+            };
+            self.send_records(&mut transport)?;
+            drop(transport);
+            // records queued while the other thread found the writer taken
+            if !lock(&self.session)?.wants_write() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// the session is only locked to take the records, not while they are written
+    fn send_records(&self, transport: &mut Stream) -> io::Result<()> {
+        loop {
+            let mut records = Vec::new();
+            {
+                let mut session = lock(&self.session)?;
+                while session.wants_write() {
+                    session.write_tls(&mut records)?;
+                }
+            }
+            if records.is_empty() {
+                return Ok(());
+            }
+            transport.write_all(&records)?;
+        }
+    }
+}
+
+impl Drop for TlsStream {
+    fn drop(&mut self) {
+        match self.session.get_mut() {
+            Ok(session) if !session.is_handshaking() => session.send_close_notify(),
+            _ => return,
+        }
+        let _ = self.flush();
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> io::Result<MutexGuard<'_, T>> {
+    mutex.lock().map_err(|_| poisoned())
+}
+
+fn poisoned() -> io::Error {
+    io::Error::other("TLS session lock poisoned")
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|err| pem_error(path, err))
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|err| pem_error(path, err))
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+fn pem_error(path: &Path, err: rustls::pki_types::pem::Error) -> KVStoreError {
+    KVStoreError::Tls(RustlsError::General(format!(
+        "cannot load {}: {}",
+        path.display(),
+        err
+    )))
+}
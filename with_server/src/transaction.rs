@@ -0,0 +1,77 @@
+//! optimistic transactions: reads go to the server, writes are buffered until commit
+//!
+//! the commit sends every key read with the version it had, and the server applies the
+//! writes only if none of them changed since, even if changed back. otherwise nothing is
+//! written, and the transaction can be run again on fresh values
+
+use rand::Rng;
+
+use crate::{KvsClient, Result, Version};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// how many times `KvsClient::transact` runs a transaction that keeps conflicting
+pub const MAX_TRANSACTION_ATTEMPTS: u32 = 10;
+/// the longest wait before running a conflicting transaction again
+pub const MAX_TRANSACTION_BACKOFF: Duration = Duration::from_millis(100);
+
+/// a random wait before the next attempt, growing with every conflict
+///
+/// transactions that conflicted with each other are spread out, so one of them wins
+pub fn retry_backoff(attempt: u32) -> Duration {
+    let ceiling = Duration::from_millis(1 << attempt.min(16)).min(MAX_TRANSACTION_BACKOFF);
+    ceiling.mul_f64(rand::thread_rng().gen::<f64>())
+}
+
+/// a transaction on one connection, see `KvsClient::transaction`
+pub struct Transaction<'a> {
+    client: &'a mut KvsClient,
+    // every key read, with the version the server had
+    reads: BTreeMap<String, Version>,
+    // buffered writes, None removes the key
+    writes: BTreeMap<String, Option<String>>,
+}
+
+impl<'a> Transaction<'a> {
+    pub fn new(client: &'a mut KvsClient) -> Self {
+        Transaction {
+            client,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// get value by key, as written by this transaction or read before
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        if let Some(read) = self.reads.get(&key) {
+            return Ok(read.value.clone());
+        }
+        let read = self.client.get_version(key.to_owned())?;
+        let value = read.value.clone();
+        self.reads.insert(key, read);
+        Ok(value)
+    }
+
+    /// set key, value on commit
+    pub fn set(&mut self, key: String, value: String) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// remove key on commit, if it exists then
+    pub fn remove(&mut self, key: String) {
+        self.writes.insert(key, None);
+    }
+
+    /// apply the writes if no key read was changed since
+    ///
+    /// return KVStoreError::Conflict and change nothing otherwise
+    pub fn commit(self) -> Result<()> {
+        self.client.commit(
+            self.reads.into_iter().collect(),
+            self.writes.into_iter().collect(),
+        )
+    }
+}
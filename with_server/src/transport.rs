@@ -0,0 +1,217 @@
+//! transports shared by Server and KvsClient: TCP and unix domain socket, optionally with TLS
+//!
+//! an address of the form `unix:/path/to.sock` is a unix domain socket,
+//! anything else is resolved as a TCP address
+
+use crate::{KVStoreError, Result, TlsStream};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+const UNIX_PREFIX: &str = "unix:";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl From<&str> for Address {
+    fn from(addr: &str) -> Self {
+        match addr.strip_prefix(UNIX_PREFIX) {
+            Some(path) => Address::Unix(PathBuf::from(path)),
+            None => Address::Tcp(addr.to_owned()),
+        }
+    }
+}
+
+impl From<String> for Address {
+    fn from(addr: String) -> Self {
+        Address::from(addr.as_str())
+    }
+}
+
+impl From<&String> for Address {
+    fn from(addr: &String) -> Self {
+        Address::from(addr.as_str())
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Self {
+        Address::Tcp(addr.to_string())
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(addr) => write!(f, "{}", addr),
+            Address::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+/// a connected stream of either transport
+///
+/// clones of a TLS stream share its session
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    Tls(Arc<TlsStream>),
+}
+
+impl Stream {
+    pub fn connect(addr: &Address) -> Result<Stream> {
+        Ok(match addr {
+            Address::Tcp(addr) => Stream::Tcp(TcpStream::connect(addr.as_str())?),
+            Address::Unix(path) => Stream::Unix(UnixStream::connect(path)?),
+        })
+    }
+
+    pub fn try_clone(&self) -> Result<Stream> {
+        Ok(match self {
+            Stream::Tcp(stream) => Stream::Tcp(stream.try_clone()?),
+            Stream::Unix(stream) => Stream::Unix(stream.try_clone()?),
+            Stream::Tls(stream) => Stream::Tls(Arc::clone(stream)),
+        })
+    }
+
+    /// the address of the other end, for logs
+    pub fn peer_addr(&self) -> String {
+        match self {
+            Stream::Tcp(stream) => stream
+                .peer_addr()
+                .map_or_else(|_| "unknown".to_owned(), |addr| addr.to_string()),
+            // the client end of a unix socket has no path
+            Stream::Unix(_) => "unix".to_owned(),
+            Stream::Tls(stream) => stream.get_ref().peer_addr(),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout)?,
+            Stream::Unix(stream) => stream.set_read_timeout(timeout)?,
+            Stream::Tls(stream) => stream.get_ref().set_read_timeout(timeout)?,
+        }
+        Ok(())
+    }
+
+    /// stop writing, the other end reads to the end of the stream and reading goes on
+    pub fn shutdown_write(&self) -> Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(Shutdown::Write)?,
+            Stream::Unix(stream) => stream.shutdown(Shutdown::Write)?,
+            Stream::Tls(stream) => stream.shutdown_write()?,
+        }
+        Ok(())
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Unix(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// a bound listener of either transport
+///
+/// the socket file of a unix listener is removed when the listener is dropped
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    pub fn bind(addr: &Address) -> Result<Listener> {
+        Ok(match addr {
+            Address::Tcp(addr) => Listener::Tcp(TcpListener::bind(addr.as_str())?),
+            Address::Unix(path) => {
+                remove_stale_socket(path)?;
+                Listener::Unix(UnixListener::bind(path)?, path.to_owned())
+            }
+        })
+    }
+
+    /// the address clients connect to
+    pub fn local_addr(&self) -> Result<Address> {
+        Ok(match self {
+            Listener::Tcp(listener) => Address::from(listener.local_addr()?),
+            Listener::Unix(_, path) => Address::Unix(path.to_owned()),
+        })
+    }
+
+    pub fn accept(&self) -> Result<Stream> {
+        Ok(match self {
+            Listener::Tcp(listener) => Stream::Tcp(listener.accept()?.0),
+            Listener::Unix(listener, _) => Stream::Unix(listener.accept()?.0),
+        })
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// remove the socket file left by a server that did not shut down cleanly
+///
+/// a socket that still accepts connections belongs to a live server and is kept
+pub fn remove_stale_socket(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if UnixStream::connect(path).is_ok() {
+                return Err(KVStoreError::Io(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is in use", path.display()),
+                )));
+            }
+            fs::remove_file(path)?;
+            Ok(())
+        }
+        Ok(_) => Err(KVStoreError::Io(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ))),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
@@ -0,0 +1,95 @@
+//! watching keys: a stream of every set and remove of the keys under a prefix
+//!
+//! `Request::ChangesSince` streams the same events starting from any change still in the
+//! log, so a client can resume from the `seq` of the last event it got
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KVStoreError, Result};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+/// the server sends nothing more often than this to a watching client, to find out if it left
+pub const WATCH_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// changes read from the log at a time
+pub const CHANGES_BATCH_SIZE: usize = 1024;
+/// how often the server looks for new changes once it has sent every one
+pub const CHANGES_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// a key was set to `value`, or removed if `value` is None
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WatchEvent {
+    // sequence number of the change in the store, 0 if the engine numbers no changes
+    pub seq: u64,
+    pub key: String,
+    pub value: Option<String>,
+}
+
+// sequence number of the change, the key and its new value, None if it was removed
+type Change = (u64, String, Option<String>);
+
+enum Source {
+    Channel(Receiver<Change>),
+    Sled(sled::Subscriber),
+}
+
+/// changes of the keys under one prefix, in the order they happened
+pub struct Watcher {
+    source: Source,
+}
+
+impl Watcher {
+    pub fn from_sled(subscriber: sled::Subscriber) -> Watcher {
+        Watcher {
+            source: Source::Sled(subscriber),
+        }
+    }
+
+    /// wait at most `timeout` for the next change, None if there was none
+    pub fn next_timeout(&mut self, timeout: Duration) -> Result<Option<WatchEvent>> {
+        let change = match &mut self.source {
+            Source::Channel(receiver) => receiver.recv_timeout(timeout),
+            Source::Sled(subscriber) => subscriber.next_timeout(timeout).map(|event| match event {
+                sled::Event::Insert { key, value } => (0, lossy(&key), Some(lossy(&value))),
+                sled::Event::Remove { key } => (0, lossy(&key), None),
+            }),
+        };
+        match change {
+            Ok((seq, key, value)) => Ok(Some(WatchEvent { seq, key, value })),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(KVStoreError::Other("the store was closed".to_owned()))
+            }
+        }
+    }
+}
+
+/// the open watches of an engine without native ones, it notifies them of every change
+#[derive(Default)]
+pub struct Watchers(Vec<(String, Sender<Change>)>);
+
+impl Watchers {
+    pub fn subscribe(&mut self, prefix: String) -> Watcher {
+        let (sender, receiver) = mpsc::channel();
+        self.0.push((prefix, sender));
+        Watcher {
+            source: Source::Channel(receiver),
+        }
+    }
+
+    /// send the change numbered `seq` to every watch of a matching prefix, and forget the
+    /// closed ones
+    pub fn notify(&mut self, seq: u64, key: &str, value: Option<&str>) {
+        self.0.retain(|(prefix, sender)| {
+            !key.starts_with(prefix.as_str())
+                || sender
+                    .send((seq, key.to_owned(), value.map(str::to_owned)))
+                    .is_ok()
+        });
+    }
+}
+
+// keys and values are written as strings, so they are valid UTF-8
+fn lossy(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
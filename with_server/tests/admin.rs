@@ -0,0 +1,159 @@
+mod common;
+
+use common::{bind, connect, start_server};
+use std::process::Command;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use with_server::{
+    hash_password_with_rounds, KVStore, KVStoreEngine, KVStoreError, Permission, Server, User,
+    Users,
+};
+
+/// few rounds, the tests are not built optimized
+const TEST_ROUNDS: u32 = 1000;
+
+fn user(name: &str, admin: bool) -> User {
+    User {
+        name: name.to_owned(),
+        password_hash: hash_password_with_rounds("secret", TEST_ROUNDS),
+        permissions: vec![Permission {
+            prefix: String::new(),
+            read: true,
+            write: true,
+        }],
+        admin,
+    }
+}
+
+fn is_denied<T>(result: with_server::Result<T>) -> bool {
+    matches!(result, Err(KVStoreError::PermissionDenied(_)))
+}
+
+#[test]
+fn admin_credential_without_users_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    let server = Server::new(Arc::new(Mutex::new(store))).with_admin(user("root", false));
+    let addr = start_server(server);
+
+    let mut client = connect(&addr);
+    // the other requests need no authentication
+    client.set("key".to_owned(), "value".to_owned()).unwrap();
+    assert!(is_denied(client.compact()));
+    assert!(is_denied(
+        client.authenticate("root".to_owned(), "wrong".to_owned())
+    ));
+    assert!(is_denied(client.flush()));
+
+    client
+        .authenticate("root".to_owned(), "secret".to_owned())
+        .unwrap();
+    client.compact().unwrap();
+    client.flush().unwrap();
+    assert_eq!(client.stats().unwrap().engine.compactions, 1);
+}
+
+#[test]
+fn admins_of_the_users_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    let users = Users::new(vec![user("operator", true), user("app", false)]);
+    let addr = start_server(Server::new(Arc::new(Mutex::new(store))).with_users(users));
+
+    let mut app = connect(&addr);
+    app.authenticate("app".to_owned(), "secret".to_owned())
+        .unwrap();
+    app.set("key".to_owned(), "value".to_owned()).unwrap();
+    assert!(is_denied(app.compact()));
+    assert!(is_denied(app.shutdown()));
+
+    let mut operator = connect(&addr);
+    operator
+        .authenticate("operator".to_owned(), "secret".to_owned())
+        .unwrap();
+    operator.compact().unwrap();
+}
+
+#[test]
+fn settings_change_at_runtime() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    let server = Server::new(Arc::new(Mutex::new(store))).with_admin(user("root", true));
+    let addr = start_server(server);
+    let mut client = connect(&addr);
+    client
+        .authenticate("root".to_owned(), "secret".to_owned())
+        .unwrap();
+
+    client
+        .set_config("compaction_threshold".to_owned(), "100".to_owned())
+        .unwrap();
+    for value in 0..10 {
+        client.set("key".to_owned(), value.to_string()).unwrap();
+    }
+    assert!(client.stats().unwrap().engine.compactions > 0);
+
+    assert!(client
+        .set_config("compaction_threshold".to_owned(), "many".to_owned())
+        .is_err());
+    assert!(client
+        .set_config("no_such_setting".to_owned(), "1".to_owned())
+        .is_err());
+    // the slow log is off, so the setting is unknown
+    assert!(client
+        .set_config("slow_log_ms".to_owned(), "10".to_owned())
+        .is_err());
+}
+
+#[test]
+fn shutdown_stops_the_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    let server = Server::new(Arc::new(Mutex::new(store))).with_admin(user("root", true));
+    let (listener, addr) = bind();
+    let (stopped, wait_stopped) = mpsc::channel();
+    thread::spawn(move || stopped.send(server.start_on(listener).is_ok()));
+
+    let mut client = connect(&addr);
+    client.set("key".to_owned(), "value".to_owned()).unwrap();
+    client
+        .authenticate("root".to_owned(), "secret".to_owned())
+        .unwrap();
+    client.shutdown().unwrap();
+    assert!(wait_stopped.recv_timeout(Duration::from_secs(5)).unwrap());
+
+    let mut store = KVStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+}
+
+#[test]
+fn admin_from_command_line() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    let server = Server::new(Arc::new(Mutex::new(store))).with_admin(user("root", true));
+    let addr = start_server(server);
+
+    let admin = |args: &[&str], credentials: bool| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_kvs-client"));
+        command.arg("admin").args(args).args(["--addr", &addr]);
+        if credentials {
+            command.args(["--user", "root", "--password", "secret"]);
+        }
+        command.output().unwrap()
+    };
+    let denied = admin(&["compact"], false);
+    assert!(!denied.status.success());
+    assert!(String::from_utf8_lossy(&denied.stderr).contains("admin"));
+    assert!(admin(&["compact"], true).status.success());
+    assert!(admin(&["flush"], true).status.success());
+    assert!(admin(&["set-config", "compaction_threshold", "4096"], true)
+        .status
+        .success());
+    assert!(admin(&["shutdown"], true).status.success());
+}
@@ -0,0 +1,158 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+use tempfile::TempDir;
+use with_server::{
+    hash_password_with_rounds, KVStore, KVStoreError, KvsClient, Permission, Server, User, Users,
+    MAX_FAILED_AUTHS,
+};
+
+/// few rounds, the tests are not built optimized
+const TEST_ROUNDS: u32 = 1000;
+
+fn user(name: &str, password: &str, permissions: &[(&str, bool, bool)]) -> User {
+    User {
+        name: name.to_owned(),
+        password_hash: hash_password_with_rounds(password, TEST_ROUNDS),
+        permissions: permissions
+            .iter()
+            .map(|&(prefix, read, write)| Permission {
+                prefix: prefix.to_owned(),
+                read,
+                write,
+            })
+            .collect(),
+        admin: false,
+    }
+}
+
+fn start_server(temp_dir: &TempDir) -> String {
+    let users = Users::new(vec![
+        user("admin", "secret", &[("", true, true)]),
+        user("reader", "secret", &[("", true, false)]),
+        user("app", "secret", &[("app/", true, true)]),
+    ]);
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    common::start_server(Server::new(Arc::new(Mutex::new(store))).with_users(users))
+}
+
+fn connect(addr: &str, user: &str) -> KvsClient {
+    let mut client = common::connect(addr);
+    client
+        .authenticate(user.to_owned(), "secret".to_owned())
+        .unwrap();
+    client
+}
+
+fn is_denied<T>(result: with_server::Result<T>) -> bool {
+    matches!(result, Err(KVStoreError::PermissionDenied(_)))
+}
+
+#[test]
+fn authentication_is_required() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start_server(&temp_dir);
+    connect(&addr, "admin");
+
+    let mut client = KvsClient::connect(addr.as_str()).unwrap();
+    assert!(is_denied(client.get("key1".to_owned())));
+    assert!(is_denied(
+        client.authenticate("admin".to_owned(), "wrong".to_owned())
+    ));
+    assert!(is_denied(
+        client.authenticate("nobody".to_owned(), "secret".to_owned())
+    ));
+    assert!(is_denied(
+        client.set("key1".to_owned(), "value1".to_owned())
+    ));
+
+    client
+        .authenticate("admin".to_owned(), "secret".to_owned())
+        .unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+#[test]
+fn connection_is_closed_after_failed_logins() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start_server(&temp_dir);
+
+    let mut client = KvsClient::connect(addr.as_str()).unwrap();
+    for _ in 0..MAX_FAILED_AUTHS {
+        assert!(is_denied(
+            client.authenticate("admin".to_owned(), "wrong".to_owned())
+        ));
+    }
+    let closed = client.authenticate("admin".to_owned(), "secret".to_owned());
+    assert!(closed.is_err() && !is_denied(closed));
+
+    // another connection starts over
+    connect(&addr, "admin")
+        .set("key1".to_owned(), "value1".to_owned())
+        .unwrap();
+}
+
+#[test]
+fn read_only_user() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start_server(&temp_dir);
+    let mut admin = connect(&addr, "admin");
+    admin.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    let mut reader = connect(&addr, "reader");
+    assert_eq!(
+        reader.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert!(is_denied(
+        reader.set("key1".to_owned(), "value2".to_owned())
+    ));
+    assert!(is_denied(reader.remove("key1".to_owned())));
+}
+
+#[test]
+fn prefix_user() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start_server(&temp_dir);
+    let mut admin = connect(&addr, "admin");
+    admin.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    let mut app = connect(&addr, "app");
+    app.set("app/key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        app.get("app/key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    app.remove("app/key1".to_owned()).unwrap();
+    assert!(is_denied(app.get("key1".to_owned())));
+    assert!(is_denied(app.set("key1".to_owned(), "value2".to_owned())));
+}
+
+#[test]
+fn passwords_are_hashed_with_pbkdf2() {
+    let password_hash = hash_password_with_rounds("secret", TEST_ROUNDS);
+    assert!(password_hash.starts_with("pbkdf2-sha256$1000$"));
+    let users = |password_hash: &str| {
+        let mut alice = user("alice", "", &[]);
+        alice.password_hash = password_hash.to_owned();
+        Users::new(vec![alice])
+    };
+    assert!(users(&password_hash)
+        .authenticate("alice", "secret")
+        .is_some());
+    assert!(users(&password_hash)
+        .authenticate("alice", "other")
+        .is_none());
+    // the rounds are part of the hash
+    let fewer_rounds = password_hash.replace("$1000$", "$999$");
+    assert!(users(&fewer_rounds)
+        .authenticate("alice", "secret")
+        .is_none());
+    // a single SHA-256 round is not accepted
+    let (_, salted) = password_hash.split_once("$1000$").unwrap();
+    assert!(users(salted).authenticate("alice", "secret").is_none());
+}
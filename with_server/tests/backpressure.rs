@@ -0,0 +1,252 @@
+mod common;
+
+use common::{connect, spawn_server, start_server};
+use std::fs;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use with_server::{
+    slow_down_write, Backpressure, CompactionPolicy, KVStore, KVStoreEngine, KVStoreError,
+    KVStoreOptions, Result, Server, WriteStall,
+};
+
+/// a store the compaction policy never compacts, so only the backpressure does
+fn open(dir: &TempDir, backpressure: Backpressure, max_segment_bytes: u64) -> KVStore {
+    let options = KVStoreOptions {
+        compaction: CompactionPolicy {
+            dead_bytes: None,
+            ..CompactionPolicy::default()
+        },
+        backpressure,
+        max_segment_bytes,
+        ..KVStoreOptions::default()
+    };
+    KVStore::open_with_options(dir.path(), options).unwrap()
+}
+
+/// set a key the way the server does for a client, held back by the backlog
+fn client_set(store: &mut KVStore, key: String, value: String) -> Result<()> {
+    slow_down_write(store)?;
+    store.set(key, value)
+}
+
+/// overwrite one key `times` times
+fn overwrite(store: &mut KVStore, times: usize) {
+    for value in 0..times {
+        client_set(store, "key".to_owned(), value.to_string()).unwrap();
+    }
+}
+
+#[test]
+fn limits_slow_down_then_stall() {
+    let backpressure = Backpressure {
+        soft_dead_bytes: Some(100),
+        hard_dead_bytes: Some(1000),
+        soft_log_files: Some(4),
+        hard_log_files: Some(8),
+        ..Backpressure::default()
+    };
+    assert_eq!(backpressure.state(100, 4), WriteStall::Normal);
+    assert_eq!(backpressure.state(101, 1), WriteStall::Slowed);
+    assert_eq!(backpressure.state(0, 5), WriteStall::Slowed);
+    assert_eq!(backpressure.state(1001, 1), WriteStall::Stalled);
+    assert_eq!(backpressure.state(500, 9), WriteStall::Stalled);
+    assert_eq!(
+        Backpressure::default().state(u64::MAX, u64::MAX),
+        WriteStall::Normal
+    );
+}
+
+#[test]
+fn soft_limit_slows_writes_down() {
+    let temp_dir = TempDir::new().unwrap();
+    let backpressure = Backpressure {
+        soft_dead_bytes: Some(200),
+        ..Backpressure::default()
+    };
+    let mut store = open(&temp_dir, backpressure, u64::MAX);
+    overwrite(&mut store, 3);
+    assert_eq!(store.stats().unwrap().write_stall, WriteStall::Normal);
+    overwrite(&mut store, 20);
+    let stats = store.stats().unwrap();
+    assert_eq!(stats.write_stall, WriteStall::Slowed);
+    assert!(stats.slowed_writes > 0);
+    assert_eq!(stats.stalled_writes, 0);
+    assert_eq!(stats.compactions, 0);
+    assert_eq!(store.get("key".to_owned()).unwrap(), Some("19".to_owned()));
+}
+
+#[test]
+fn hard_limit_stalls_writes_until_maintenance_compacts() {
+    let temp_dir = TempDir::new().unwrap();
+    let backpressure = Backpressure {
+        hard_dead_bytes: Some(500),
+        ..Backpressure::default()
+    };
+    let mut store = open(&temp_dir, backpressure, u64::MAX);
+    let mut written = 0;
+    let err = loop {
+        match client_set(&mut store, "key".to_owned(), written.to_string()) {
+            Ok(()) => written += 1,
+            Err(err) => break err,
+        }
+        assert!(written < 100, "writes never stalled");
+    };
+    assert!(matches!(err, KVStoreError::WriteStalled(_)));
+    // the stalled write compacted nothing itself
+    let stats = store.stats().unwrap();
+    assert_eq!(stats.stalled_writes, 1);
+    assert_eq!(stats.compactions, 0);
+    // writes that do not come from clients, like replicated ones, are not held back
+    store
+        .set("key".to_owned(), "replicated".to_owned())
+        .unwrap();
+    assert_eq!(store.stats().unwrap().stalled_writes, 1);
+
+    store.maintain().unwrap();
+    let stats = store.stats().unwrap();
+    assert_eq!(stats.write_stall, WriteStall::Normal);
+    assert!(stats.compactions > 0);
+    client_set(&mut store, "key".to_owned(), "last".to_owned()).unwrap();
+    assert_eq!(
+        store.get("key".to_owned()).unwrap(),
+        Some("last".to_owned())
+    );
+}
+
+#[test]
+fn slowed_writes_wait_outside_the_engine_lock() {
+    let temp_dir = TempDir::new().unwrap();
+    let backpressure = Backpressure {
+        soft_dead_bytes: Some(0),
+        write_slowdown_ms: 0,
+        ..Backpressure::default()
+    };
+    let mut store = open(&temp_dir, backpressure, u64::MAX);
+    overwrite(&mut store, 2);
+    store.set_config("write_slowdown_ms", "1000").unwrap();
+    assert_eq!(store.hold_back_write().unwrap(), Duration::from_secs(1));
+    let engine = Arc::new(Mutex::new(store));
+    let addr = start_server(Server::new(Arc::clone(&engine)));
+
+    let mut writer = connect(&addr);
+    let mut reader = connect(&addr);
+    let write = thread::spawn(move || {
+        let started = Instant::now();
+        writer.set("key".to_owned(), "slow".to_owned()).unwrap();
+        started.elapsed()
+    });
+    thread::sleep(Duration::from_millis(200));
+    // the engine is free while the write waits
+    let started = Instant::now();
+    assert_eq!(reader.get("key".to_owned()).unwrap(), Some("1".to_owned()));
+    assert!(started.elapsed() < Duration::from_millis(500));
+    assert!(write.join().unwrap() >= Duration::from_secs(1));
+    assert_eq!(
+        reader.get("key".to_owned()).unwrap(),
+        Some("slow".to_owned())
+    );
+    assert!(engine.lock().unwrap().stats().unwrap().slowed_writes > 0);
+}
+
+#[test]
+fn stalled_writes_fail_while_compaction_cannot_help() {
+    let temp_dir = TempDir::new().unwrap();
+    let backpressure = Backpressure {
+        hard_log_files: Some(3),
+        ..Backpressure::default()
+    };
+    // every file holds two records, and no record is dead
+    let mut store = open(&temp_dir, backpressure, 100);
+    let mut written = 0;
+    let err = loop {
+        match client_set(&mut store, format!("key{}", written), written.to_string()) {
+            Ok(()) => written += 1,
+            Err(err) => break err,
+        }
+        assert!(written < 100, "writes never stalled");
+    };
+    assert!(matches!(err, KVStoreError::WriteStalled(_)));
+    assert_eq!(err.kind(), "write_stalled");
+    assert_eq!(store.stats().unwrap().write_stall, WriteStall::Stalled);
+    assert!(slow_down_write(&mut store).is_err());
+    // no dead bytes to compact
+    store.maintain().unwrap();
+    assert_eq!(store.stats().unwrap().compactions, 0);
+
+    store.set_config("hard_log_files", "none").unwrap();
+    client_set(&mut store, "more".to_owned(), "value".to_owned()).unwrap();
+    assert_eq!(store.stats().unwrap().write_stall, WriteStall::Normal);
+    for value in 0..written {
+        assert_eq!(
+            store.get(format!("key{}", value)).unwrap(),
+            Some(value.to_string())
+        );
+    }
+}
+
+#[test]
+fn limits_change_with_set_config() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open(&temp_dir, Backpressure::default(), u64::MAX);
+    store.set_config("soft_dead_bytes", "100").unwrap();
+    store.set_config("hard_dead_bytes", "1000").unwrap();
+    store.set_config("soft_log_files", "10").unwrap();
+    store.set_config("write_slowdown_ms", "0").unwrap();
+    assert_eq!(
+        store.backpressure,
+        Backpressure {
+            soft_dead_bytes: Some(100),
+            hard_dead_bytes: Some(1000),
+            soft_log_files: Some(10),
+            hard_log_files: None,
+            write_slowdown_ms: 0,
+        }
+    );
+    store.set_config("soft_log_files", "none").unwrap();
+    assert_eq!(store.backpressure.soft_log_files, None);
+    assert!(store.set_config("hard_dead_bytes", "lots").is_err());
+    assert!(store.set_config("write_slowdown_ms", "none").is_err());
+    // the compaction settings are still known
+    store.set_config("compaction_threshold", "none").unwrap();
+}
+
+#[test]
+fn server_takes_the_limits_from_config_and_flags() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = temp_dir.path().join("kvs.json");
+    fs::write(
+        &config,
+        r#"{"backpressure": {"soft_dead_bytes": 100, "write_slowdown_ms": 1000}}"#,
+    )
+    .unwrap();
+    let (_server, addr) = spawn_server(
+        temp_dir.path(),
+        &[
+            "--engine",
+            "kvs",
+            "--config",
+            config.to_str().unwrap(),
+            "--compaction-bytes",
+            "none",
+            "--write-slowdown",
+            "0",
+        ],
+    );
+    let mut client = connect(&addr);
+    for value in 0..10 {
+        client.set("key".to_owned(), value.to_string()).unwrap();
+    }
+    let stats = client.stats().unwrap().engine;
+    assert_eq!(stats.write_stall, WriteStall::Slowed);
+    assert!(stats.slowed_writes > 0);
+
+    let status = Command::new(env!("CARGO_BIN_EXE_kvs-server"))
+        .args(["--addr", "127.0.0.1:0", "--hard-log-files", "many"])
+        .current_dir(temp_dir.path())
+        .status()
+        .unwrap();
+    assert!(!status.success());
+}
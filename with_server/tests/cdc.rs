@@ -0,0 +1,72 @@
+mod common;
+
+use common::{connect, start_server};
+use std::sync::{Arc, Mutex};
+use tempfile::TempDir;
+use with_server::{KVStore, KVStoreEngine, KVStoreError, Server, WatchEvent};
+
+fn event(seq: u64, key: &str, value: Option<&str>) -> WatchEvent {
+    WatchEvent {
+        seq,
+        key: key.to_owned(),
+        value: value.map(str::to_owned),
+    }
+}
+
+#[test]
+fn changes_are_replayed_then_tailed() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KVStore::open(temp_dir.path()).unwrap();
+    store.set("a".to_owned(), "1".to_owned()).unwrap();
+    store.set("b".to_owned(), "2".to_owned()).unwrap();
+    store.remove("a".to_owned()).unwrap();
+    drop(store);
+
+    // sequence numbers go on after a restart
+    let mut store = KVStore::open(temp_dir.path()).unwrap();
+    store.set("c".to_owned(), "3".to_owned()).unwrap();
+    let addr = start_server(Server::new(Arc::new(Mutex::new(store))));
+
+    let mut changes = connect(&addr).changes_since(1).unwrap();
+    for expected in [
+        event(2, "b", Some("2")),
+        event(3, "a", None),
+        event(4, "c", Some("3")),
+    ] {
+        assert_eq!(changes.next().unwrap().unwrap(), expected);
+    }
+    connect(&addr).set("d".to_owned(), "4".to_owned()).unwrap();
+    assert_eq!(changes.next().unwrap().unwrap(), event(5, "d", Some("4")));
+}
+
+#[test]
+fn compacted_changes_are_an_error() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KVStore::open(temp_dir.path()).unwrap();
+    for value in 0..10 {
+        store.set("key".to_owned(), value.to_string()).unwrap();
+    }
+    store.compact().unwrap();
+    store.set("key".to_owned(), "after".to_owned()).unwrap();
+
+    match store.changes_since(5, 10) {
+        Err(KVStoreError::HistoryCompacted(first)) => assert_eq!(first, 11),
+        other => panic!("unexpected {:?}", other.map(|_| ())),
+    }
+    assert_eq!(
+        store.changes_since(10, 10).unwrap(),
+        vec![event(11, "key", Some("after"))]
+    );
+    drop(store);
+
+    // the discarded range is remembered across a restart
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    let addr = start_server(Server::new(Arc::new(Mutex::new(store))));
+    let err = connect(&addr).changes_since(0).err().unwrap();
+    assert!(err.to_string().contains("discarded by compaction"));
+    let mut changes = connect(&addr).changes_since(10).unwrap();
+    assert_eq!(
+        changes.next().unwrap().unwrap(),
+        event(11, "key", Some("after"))
+    );
+}
@@ -0,0 +1,80 @@
+//! servers and clients the integration tests share, each test uses only some of them
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader};
+use std::net::TcpListener;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use with_server::{KVStoreEngine, KvsClient, Server};
+
+/// the log line of kvs-server that tells the address it is bound to
+const LISTENING: &str = "Listening on ";
+
+/// a port of localhost the system picks, kept bound until a server takes the listener
+pub fn bind() -> (TcpListener, String) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    (listener, addr)
+}
+
+/// serve `server` on a port of localhost the system picks, return the address
+///
+/// the port is bound before this returns, clients can connect right away
+pub fn start_server<E: KVStoreEngine + Clone + Send + 'static>(server: Server<E>) -> String {
+    let (listener, addr) = bind();
+    thread::spawn(move || server.start_on(listener));
+    addr
+}
+
+pub fn connect(addr: &str) -> KvsClient {
+    KvsClient::connect(addr).unwrap_or_else(|err| panic!("cannot connect to {}: {}", addr, err))
+}
+
+/// a kvs-server process, killed when dropped
+pub struct ServerProcess(pub Child);
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// run kvs-server in `dir` on a port it picks, return it once it listens and its address
+pub fn spawn_server(dir: &Path, args: &[&str]) -> (ServerProcess, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_kvs-server"))
+        .args(["--addr", "127.0.0.1:0"])
+        .args(args)
+        .current_dir(dir)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stderr = BufReader::new(child.stderr.take().unwrap());
+    let process = ServerProcess(child);
+    let (sender, receiver) = mpsc::channel();
+    // keep reading the log, a full pipe would block the server
+    thread::spawn(move || {
+        for line in stderr.lines().map_while(Result::ok) {
+            if let Some((_, addr)) = line.split_once(LISTENING) {
+                let _ = sender.send(addr.trim().to_owned());
+            }
+            eprintln!("{}", line);
+        }
+    });
+    match receiver.recv_timeout(Duration::from_secs(10)) {
+        Ok(addr) => (process, addr),
+        Err(_) => panic!("kvs-server did not start listening"),
+    }
+}
+
+/// addresses for the nodes of a cluster, which must know each other before they start
+///
+/// unlike `bind`, nothing holds the ports until the nodes bind them
+pub fn cluster_addrs(nodes: usize) -> Vec<String> {
+    // bound all at once, so no two nodes get the same port
+    let listeners: Vec<(TcpListener, String)> = (0..nodes).map(|_| bind()).collect();
+    listeners.into_iter().map(|(_, addr)| addr).collect()
+}
@@ -0,0 +1,202 @@
+mod common;
+
+use common::{connect, spawn_server};
+use std::fs;
+use std::process::Command;
+use tempfile::TempDir;
+use with_server::{
+    minute_of_day, now_millis, CompactionPolicy, KVStore, KVStoreEngine, KVStoreOptions,
+    TimeWindow, COMPACTION_THRESHOLD,
+};
+
+fn open(dir: &TempDir, compaction: CompactionPolicy) -> KVStore {
+    let options = KVStoreOptions {
+        compaction,
+        ..KVStoreOptions::default()
+    };
+    KVStore::open_with_options(dir.path(), options).unwrap()
+}
+
+/// overwrite one key `times` times
+fn overwrite(store: &mut KVStore, times: usize) {
+    for value in 0..times {
+        store.set("key".to_owned(), value.to_string()).unwrap();
+    }
+}
+
+fn bytes(dead_bytes: u64) -> CompactionPolicy {
+    CompactionPolicy {
+        dead_bytes: Some(dead_bytes),
+        ..CompactionPolicy::default()
+    }
+}
+
+#[test]
+fn either_threshold_makes_a_compaction_due() {
+    let policy = CompactionPolicy {
+        dead_bytes: Some(1000),
+        dead_ratio: Some(0.5),
+        ..CompactionPolicy::default()
+    };
+    assert!(!policy.should_compact(400, 1000, None, 0));
+    assert!(policy.should_compact(600, 1000, None, 0));
+    assert!(policy.should_compact(1001, 100_000, None, 0));
+    assert!(!CompactionPolicy::default().should_compact(COMPACTION_THRESHOLD, 0, None, 0));
+    assert!(CompactionPolicy::default().should_compact(COMPACTION_THRESHOLD + 1, 0, None, 0));
+}
+
+#[test]
+fn interval_and_window_hold_a_due_compaction() {
+    let policy = CompactionPolicy {
+        min_interval_secs: Some(60),
+        window: Some("22:00-02:00".parse().unwrap()),
+        ..bytes(10)
+    };
+    assert!(policy.should_compact(11, 0, None, 23 * 60));
+    assert!(policy.should_compact(11, 0, Some(60), 60));
+    assert!(!policy.should_compact(11, 0, Some(59), 60));
+    assert!(!policy.should_compact(11, 0, None, 12 * 60));
+    assert!(!policy.should_compact(11, 0, None, 2 * 60));
+}
+
+#[test]
+fn time_windows_parse() {
+    let window: TimeWindow = "01:30-05:00".parse().unwrap();
+    assert_eq!(
+        window,
+        TimeWindow {
+            start: 90,
+            end: 300
+        }
+    );
+    assert_eq!(window.to_string(), "01:30-05:00");
+    assert!(window.contains(90) && !window.contains(300));
+    assert!("25:00-01:00".parse::<TimeWindow>().is_err());
+    assert!("01:60-02:00".parse::<TimeWindow>().is_err());
+    assert!("01:00".parse::<TimeWindow>().is_err());
+}
+
+#[test]
+fn dead_ratio_compacts_a_small_log() {
+    let temp_dir = TempDir::new().unwrap();
+    let policy = CompactionPolicy {
+        dead_bytes: None,
+        dead_ratio: Some(4.0),
+        ..CompactionPolicy::default()
+    };
+    let mut store = open(&temp_dir, policy);
+    overwrite(&mut store, 3);
+    assert_eq!(store.compactions().unwrap(), 0);
+    overwrite(&mut store, 10);
+    assert!(store.compactions().unwrap() > 0);
+    assert_eq!(store.get("key".to_owned()).unwrap(), Some("9".to_owned()));
+}
+
+#[test]
+fn min_interval_spaces_compactions() {
+    let temp_dir = TempDir::new().unwrap();
+    let policy = CompactionPolicy {
+        min_interval_secs: Some(3600),
+        ..bytes(50)
+    };
+    let mut store = open(&temp_dir, policy);
+    overwrite(&mut store, 50);
+    assert_eq!(store.compactions().unwrap(), 1);
+}
+
+#[test]
+fn no_compaction_outside_the_window() {
+    let temp_dir = TempDir::new().unwrap();
+    let now = minute_of_day(now_millis());
+    let policy = CompactionPolicy {
+        window: Some(TimeWindow {
+            start: (now + 60) % 1440,
+            end: (now + 120) % 1440,
+        }),
+        ..bytes(50)
+    };
+    let mut store = open(&temp_dir, policy);
+    overwrite(&mut store, 50);
+    assert_eq!(store.compactions().unwrap(), 0);
+    // an admin compaction ignores the policy
+    KVStoreEngine::compact(&mut store).unwrap();
+    assert_eq!(store.compactions().unwrap(), 1);
+}
+
+#[test]
+fn policy_changes_with_set_config() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open(&temp_dir, CompactionPolicy::default());
+    store.set_config("compaction_threshold", "none").unwrap();
+    store.set_config("compaction_ratio", "0.5").unwrap();
+    store.set_config("compaction_interval_secs", "10").unwrap();
+    store
+        .set_config("compaction_window", "03:00-04:00")
+        .unwrap();
+    assert_eq!(
+        store.compaction,
+        CompactionPolicy {
+            dead_bytes: None,
+            dead_ratio: Some(0.5),
+            min_interval_secs: Some(10),
+            window: Some(TimeWindow {
+                start: 180,
+                end: 240
+            }),
+        }
+    );
+    assert!(store.set_config("compaction_ratio", "half").is_err());
+    assert!(store.set_config("compaction_window", "3-4").is_err());
+}
+
+#[test]
+fn server_takes_the_policy_from_config_and_flags() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = temp_dir.path().join("kvs.json");
+    // the flag overrides the threshold of the file, so only the ratio is left
+    fs::write(
+        &config,
+        r#"{"compaction": {"dead_bytes": 100000000, "dead_ratio": 2.0}}"#,
+    )
+    .unwrap();
+    let (_server, addr) = spawn_server(
+        temp_dir.path(),
+        &[
+            "--engine",
+            "kvs",
+            "--config",
+            config.to_str().unwrap(),
+            "--compaction-bytes",
+            "none",
+        ],
+    );
+    let mut client = connect(&addr);
+    for value in 0..10 {
+        client.set("key".to_owned(), value.to_string()).unwrap();
+    }
+    assert!(client.stats().unwrap().engine.compactions > 0);
+}
+
+#[test]
+fn server_rejects_a_bad_policy() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = temp_dir.path().join("kvs.json");
+    fs::write(&config, r#"{"compaction": {"window": "noon"}}"#).unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_kvs-server"))
+        .args([
+            "--addr",
+            "127.0.0.1:0",
+            "--config",
+            config.to_str().unwrap(),
+        ])
+        .current_dir(temp_dir.path())
+        .status()
+        .unwrap();
+    assert!(!status.success());
+    let status = Command::new(env!("CARGO_BIN_EXE_kvs-server"))
+        .args(["--addr", "127.0.0.1:0", "--compaction-ratio", "lots"])
+        .current_dir(temp_dir.path())
+        .status()
+        .unwrap();
+    assert!(!status.success());
+}
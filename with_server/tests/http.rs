@@ -0,0 +1,80 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use tempfile::TempDir;
+use with_server::{HttpServer, KVStore};
+
+fn start_server(temp_dir: &TempDir) -> String {
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    let (listener, addr) = common::bind();
+    thread::spawn(move || HttpServer::new(store).start_on(listener).unwrap());
+    addr
+}
+
+/// send one request, return status code and body
+fn call(addr: &str, method: &str, path: &str, body: &str) -> (u16, serde_json::Value) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        addr,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response[9..12].parse().unwrap();
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    (status, serde_json::from_str(body).unwrap())
+}
+
+#[test]
+fn put_get_delete() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start_server(&temp_dir);
+
+    assert_eq!(call(&addr, "PUT", "/kv/key1", "value1").0, 200);
+    let (status, body) = call(&addr, "GET", "/kv/key1", "");
+    assert_eq!(status, 200);
+    assert_eq!(body["value"], "value1");
+
+    assert_eq!(call(&addr, "DELETE", "/kv/key1", "").0, 200);
+    assert_eq!(call(&addr, "GET", "/kv/key1", "").0, 404);
+    assert_eq!(call(&addr, "DELETE", "/kv/key1", "").0, 404);
+}
+
+#[test]
+fn list_by_prefix() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start_server(&temp_dir);
+
+    for key in ["a%201", "a%202", "a%203", "b1"] {
+        call(&addr, "PUT", &format!("/kv/{}", key), "value");
+    }
+    let (status, body) = call(&addr, "GET", "/kv?prefix=a+&limit=2", "");
+    assert_eq!(status, 200);
+    let keys: Vec<&str> = body
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|item| item["key"].as_str().unwrap())
+        .collect();
+    assert_eq!(keys, ["a 1", "a 2"]);
+    assert_eq!(call(&addr, "GET", "/kv?limit=x", "").0, 400);
+}
+
+#[test]
+fn health() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start_server(&temp_dir);
+
+    let (status, body) = call(&addr, "GET", "/health", "");
+    assert_eq!(status, 200);
+    assert_eq!(body["status"], "ok");
+    assert_eq!(call(&addr, "GET", "/other", "").0, 404);
+}
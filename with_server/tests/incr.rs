@@ -0,0 +1,103 @@
+mod common;
+
+use common::{connect, start_server};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tempfile::TempDir;
+use with_server::{KVStore, KVStoreEngine, KVStoreError, Server, SledKVStore};
+
+fn incr_counts<E: KVStoreEngine>(mut store: E) {
+    assert_eq!(store.incr("hits".to_owned(), 5).unwrap(), 5);
+    assert_eq!(store.incr("hits".to_owned(), -7).unwrap(), -2);
+    assert_eq!(store.get("hits".to_owned()).unwrap(), Some("-2".to_owned()));
+
+    store.set("name".to_owned(), "kvs".to_owned()).unwrap();
+    match store.incr("name".to_owned(), 1) {
+        Err(KVStoreError::NotAnInteger(key)) => assert_eq!(key, "name"),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(
+        store.get("name".to_owned()).unwrap(),
+        Some("kvs".to_owned())
+    );
+}
+
+fn concurrent_increments(addr: &str) {
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let mut client = connect(addr);
+            thread::spawn(move || {
+                for _ in 0..25 {
+                    client.incr("hits".to_owned(), 1).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in threads {
+        handle.join().unwrap();
+    }
+    assert_eq!(connect(addr).incr("hits".to_owned(), 0).unwrap(), 100);
+}
+
+#[test]
+fn incr_kvs() {
+    let temp_dir = TempDir::new().unwrap();
+    incr_counts(KVStore::open(temp_dir.path()).unwrap());
+}
+
+#[test]
+fn incr_sled() {
+    let temp_dir = TempDir::new().unwrap();
+    incr_counts(SledKVStore::open(sled::open(temp_dir.path()).unwrap()));
+}
+
+#[test]
+fn concurrent_incr_kvs() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    concurrent_increments(&start_server(Server::new(Arc::new(Mutex::new(store)))));
+}
+
+#[test]
+fn concurrent_incr_sled() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = SledKVStore::open(sled::open(temp_dir.path()).unwrap());
+    concurrent_increments(&start_server(Server::new(store)));
+}
+
+#[test]
+fn incr_error_reaches_the_client() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    let mut client = connect(&start_server(Server::new(Arc::new(Mutex::new(store)))));
+    client.set("name".to_owned(), "kvs".to_owned()).unwrap();
+    match client.incr("name".to_owned(), 1) {
+        Err(KVStoreError::NotAnInteger(key)) => assert_eq!(key, "name"),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn incr_from_command_line() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    let addr = start_server(Server::new(Arc::new(Mutex::new(store))));
+    connect(&addr)
+        .set("name".to_owned(), "kvs".to_owned())
+        .unwrap();
+
+    let incr = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_kvs-client"))
+            .arg("incr")
+            .args(args)
+            .args(["--addr", &addr])
+            .output()
+            .unwrap()
+    };
+    assert_eq!(incr(&["hits"]).stdout, b"1\n");
+    assert_eq!(incr(&["hits", "-3"]).stdout, b"-2\n");
+    let output = incr(&["name"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("not an integer"));
+}
@@ -0,0 +1,196 @@
+mod common;
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::thread;
+use tempfile::TempDir;
+use with_server::{KVStore, KVStoreEngine, MemcachedServer, MAX_ITEM_BYTES};
+
+/// memcached client speaking over one connection
+struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Client {
+    fn connect(addr: &str) -> Client {
+        let stream = TcpStream::connect(addr).unwrap();
+        Client {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        }
+    }
+
+    /// send `request` and read reply lines until one of them equals `last`
+    fn call(&mut self, request: &str, last: &[&str]) -> Vec<String> {
+        self.writer.write_all(request.as_bytes()).unwrap();
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            let line = line.trim_end().to_owned();
+            let done = last.contains(&line.as_str()) || line.starts_with("SERVER_ERROR");
+            lines.push(line);
+            if done {
+                return lines;
+            }
+        }
+    }
+}
+
+fn start_server(temp_dir: &TempDir) -> String {
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    let server = MemcachedServer::new(std::sync::Arc::new(std::sync::Mutex::new(store)));
+    let (listener, addr) = common::bind();
+    thread::spawn(move || server.start_on(listener).unwrap());
+    addr
+}
+
+const REPLIES: &[&str] = &[
+    "STORED",
+    "NOT_STORED",
+    "EXISTS",
+    "NOT_FOUND",
+    "DELETED",
+    "END",
+];
+
+#[test]
+fn set_get_delete() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut client = Client::connect(&start_server(&temp_dir));
+
+    assert_eq!(
+        client.call("set key1 5 0 6\r\nvalue1\r\n", REPLIES),
+        ["STORED"]
+    );
+    assert_eq!(
+        client.call("get key1 key2\r\n", REPLIES),
+        ["VALUE key1 5 6", "value1", "END"]
+    );
+    assert_eq!(client.call("delete key1\r\n", REPLIES), ["DELETED"]);
+    assert_eq!(client.call("delete key1\r\n", REPLIES), ["NOT_FOUND"]);
+    assert_eq!(client.call("get key1\r\n", REPLIES), ["END"]);
+}
+
+#[test]
+fn add_and_replace() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut client = Client::connect(&start_server(&temp_dir));
+
+    assert_eq!(
+        client.call("replace key1 0 0 1\r\na\r\n", REPLIES),
+        ["NOT_STORED"]
+    );
+    assert_eq!(client.call("add key1 0 0 1\r\nb\r\n", REPLIES), ["STORED"]);
+    assert_eq!(
+        client.call("add key1 0 0 1\r\nc\r\n", REPLIES),
+        ["NOT_STORED"]
+    );
+    assert_eq!(
+        client.call("replace key1 0 0 1\r\nd\r\n", REPLIES),
+        ["STORED"]
+    );
+    assert_eq!(
+        client.call("get key1\r\n", REPLIES),
+        ["VALUE key1 0 1", "d", "END"]
+    );
+}
+
+#[test]
+fn gets_and_cas() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut client = Client::connect(&start_server(&temp_dir));
+
+    assert_eq!(
+        client.call("cas key1 0 0 1 1\r\na\r\n", REPLIES),
+        ["NOT_FOUND"]
+    );
+    client.call("set key1 0 0 1\r\na\r\n", REPLIES);
+    let reply = client.call("gets key1\r\n", REPLIES);
+    let cas_unique = reply[0].rsplit(' ').next().unwrap().to_owned();
+
+    // a write in between changes the cas unique
+    client.call("set key1 0 0 1\r\nb\r\n", REPLIES);
+    let request = format!("cas key1 0 0 1 {}\r\nc\r\n", cas_unique);
+    assert_eq!(client.call(&request, REPLIES), ["EXISTS"]);
+
+    let reply = client.call("gets key1\r\n", REPLIES);
+    let cas_unique = reply[0].rsplit(' ').next().unwrap().to_owned();
+    let request = format!("cas key1 0 0 1 {}\r\nc\r\n", cas_unique);
+    assert_eq!(client.call(&request, REPLIES), ["STORED"]);
+    assert_eq!(
+        client.call("get key1\r\n", REPLIES),
+        ["VALUE key1 0 1", "c", "END"]
+    );
+}
+
+#[test]
+fn expired_item_is_missing() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut client = Client::connect(&start_server(&temp_dir));
+
+    assert_eq!(client.call("set key1 0 -1 1\r\na\r\n", REPLIES), ["STORED"]);
+    assert_eq!(client.call("get key1\r\n", REPLIES), ["END"]);
+    assert_eq!(client.call("add key1 0 0 1\r\nb\r\n", REPLIES), ["STORED"]);
+}
+
+#[test]
+fn stats_and_errors() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut client = Client::connect(&start_server(&temp_dir));
+
+    assert_eq!(client.call("bogus\r\n", &["ERROR"]), ["ERROR"]);
+    assert_eq!(
+        client.call(
+            "set key1 0 0 1\r\nabc\r\n",
+            &["CLIENT_ERROR bad data chunk"]
+        ),
+        ["CLIENT_ERROR bad data chunk"]
+    );
+    client.call("get key1\r\n", REPLIES);
+    let stats = client.call("stats\r\n", REPLIES);
+    assert!(stats.contains(&"STAT get_misses 1".to_owned()));
+    assert!(stats.contains(&"STAT curr_connections 1".to_owned()));
+}
+
+#[test]
+fn too_large_items_are_refused() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut client = Client::connect(&start_server(&temp_dir));
+
+    let data = "x".repeat(MAX_ITEM_BYTES + 1);
+    assert_eq!(
+        client.call(
+            &format!("set big 0 0 {}\r\n{}\r\n", data.len(), data),
+            REPLIES
+        ),
+        ["SERVER_ERROR object too large for cache"]
+    );
+    let request = format!("set big 0 0 {} noreply\r\n{}\r\n", data.len(), data);
+    client.writer.write_all(request.as_bytes()).unwrap();
+    // the connection goes on after the skipped blocks
+    assert_eq!(client.call("get big\r\n", REPLIES), ["END"]);
+    let data = "x".repeat(MAX_ITEM_BYTES);
+    assert_eq!(
+        client.call(
+            &format!("set big 0 0 {}\r\n{}\r\n", data.len(), data),
+            REPLIES
+        ),
+        ["STORED"]
+    );
+}
+
+#[test]
+fn plain_values_are_readable() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KVStore::open(temp_dir.path()).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    drop(store);
+
+    let mut client = Client::connect(&start_server(&temp_dir));
+    assert_eq!(
+        client.call("get key1\r\n", REPLIES),
+        ["VALUE key1 0 6", "value1", "END"]
+    );
+}
@@ -0,0 +1,82 @@
+mod common;
+
+use common::{bind, connect, start_server};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tempfile::TempDir;
+use with_server::{Counters, KVStore, MetricsServer, Server};
+
+/// send one GET, return status code and body
+fn get(addr: &str, path: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, addr
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response[9..12].parse().unwrap();
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    (status, body.to_owned())
+}
+
+/// the value of the sample with exactly this name and labels
+fn value(metrics: &str, sample: &str) -> f64 {
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(sample)?.strip_prefix(' '))
+        .unwrap_or_else(|| panic!("no {} in\n{}", sample, metrics))
+        .parse()
+        .unwrap()
+}
+
+#[test]
+fn metrics_of_requests_and_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = Arc::new(Mutex::new(KVStore::open(temp_dir.path()).unwrap()));
+    let counters: Arc<Counters> = Arc::default();
+    let addr = start_server(Server::new(Arc::clone(&store)).with_counters(Arc::clone(&counters)));
+    let metrics = MetricsServer::new(Arc::clone(&store), counters);
+    let (listener, metrics_addr) = bind();
+    thread::spawn(move || metrics.start_on(listener));
+
+    let mut client = connect(&addr);
+    for value in 0..10 {
+        client.set("a".to_owned(), value.to_string()).unwrap();
+    }
+    client.get("a".to_owned()).unwrap();
+    assert!(client.remove("missing".to_owned()).is_err());
+    store.lock().unwrap().compact().unwrap();
+
+    let (status, body) = get(&metrics_addr, "/metrics");
+    assert_eq!(status, 200);
+    assert_eq!(value(&body, "kvs_requests_total{request=\"Set\"}"), 10.0);
+    assert_eq!(value(&body, "kvs_requests_total{request=\"Get\"}"), 1.0);
+    assert_eq!(
+        value(&body, "kvs_request_duration_seconds_count{request=\"Set\"}"),
+        10.0
+    );
+    assert_eq!(
+        value(
+            &body,
+            "kvs_request_duration_seconds_bucket{request=\"Set\",le=\"+Inf\"}"
+        ),
+        10.0
+    );
+    assert_eq!(
+        value(&body, "kvs_errors_total{kind=\"key_not_found\"}"),
+        1.0
+    );
+    assert_eq!(value(&body, "kvs_compactions_total"), 1.0);
+    assert!(value(&body, "kvs_compaction_reclaimed_bytes_total") > 0.0);
+    assert_eq!(value(&body, "kvs_index_keys"), 1.0);
+    assert!(value(&body, "kvs_log_files") >= 1.0);
+    assert_eq!(value(&body, "kvs_connections"), 1.0);
+    assert!(body.contains("# TYPE kvs_request_duration_seconds histogram"));
+
+    assert_eq!(get(&metrics_addr, "/other").0, 404);
+}
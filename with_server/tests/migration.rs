@@ -0,0 +1,287 @@
+mod common;
+
+use common::connect;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tempfile::TempDir;
+use with_server::{
+    hash_password_with_rounds, HashRange, KVStore, KVStoreEngine, KVStoreError, Permission, Server,
+    ShardEngine, ShardedClient, User, Users,
+};
+
+/// few rounds, the tests are not built optimized
+const TEST_ROUNDS: u32 = 1000;
+
+fn user(name: &str, admin: bool) -> User {
+    User {
+        name: name.to_owned(),
+        password_hash: hash_password_with_rounds("secret", TEST_ROUNDS),
+        permissions: vec![Permission {
+            prefix: String::new(),
+            read: true,
+            write: true,
+        }],
+        admin,
+    }
+}
+
+/// a client of `nodes` that may move keys
+fn admin_client(nodes: Vec<String>) -> ShardedClient {
+    ShardedClient::new(nodes).with_credentials("root".to_owned(), "secret".to_owned())
+}
+
+fn open_shard(temp_dir: &TempDir) -> ShardEngine<Arc<Mutex<KVStore>>> {
+    let store = Arc::new(Mutex::new(KVStore::open(temp_dir.path()).unwrap()));
+    ShardEngine::open(store, temp_dir.path().join("shards.moved")).unwrap()
+}
+
+fn start_server(temp_dir: &TempDir) -> String {
+    start_server_with(temp_dir, |server| server.with_admin(user("root", true)))
+}
+
+fn start_server_with(
+    temp_dir: &TempDir,
+    setup: impl FnOnce(
+        Server<ShardEngine<Arc<Mutex<KVStore>>>>,
+    ) -> Server<ShardEngine<Arc<Mutex<KVStore>>>>,
+) -> String {
+    common::start_server(setup(Server::new(open_shard(temp_dir))))
+}
+
+fn stored_keys(addr: &str) -> Vec<String> {
+    let mut client = connect(addr);
+    let pairs = client.scan(String::new(), None).unwrap();
+    pairs.into_iter().map(|(key, _)| key).collect()
+}
+
+#[test]
+fn added_server_takes_over_its_keys() {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let nodes: Vec<String> = dirs.iter().map(start_server).collect();
+    let mut client = admin_client(nodes[..2].to_vec());
+    let pairs: Vec<(String, String)> = (0..300)
+        .map(|i| (format!("key{:03}", i), format!("value{}", i)))
+        .collect();
+    client.set_many(pairs).unwrap();
+
+    // a client that does not know about the new server keeps writing during the move
+    let old_nodes = nodes[..2].to_vec();
+    let writer = thread::spawn(move || {
+        let mut client = ShardedClient::new(old_nodes);
+        for i in 0..300 {
+            client
+                .set(format!("key{:03}", i), format!("updated{}", i))
+                .unwrap();
+        }
+        client
+    });
+    let moved = client.add_node_and_migrate(nodes[2].to_owned()).unwrap();
+    let mut old_client = writer.join().unwrap();
+
+    let on_new = stored_keys(&nodes[2]);
+    assert!(moved > 0);
+    assert!(!on_new.is_empty());
+    for key in &on_new {
+        assert_eq!(client.node_for(key), Some(nodes[2].as_str()));
+    }
+    // the old servers no longer hold the moved keys
+    for node in &nodes[..2] {
+        for key in stored_keys(node) {
+            assert_eq!(client.node_for(&key), Some(node.as_str()));
+        }
+    }
+    for i in 0..300 {
+        let key = format!("key{:03}", i);
+        assert_eq!(
+            client.get(key.to_owned()).unwrap(),
+            Some(format!("updated{}", i))
+        );
+        // an old server redirects to the new owner
+        assert_eq!(old_client.get(key).unwrap(), Some(format!("updated{}", i)));
+    }
+    assert_eq!(client.scan("key".to_owned(), None).unwrap().len(), 300);
+}
+
+#[test]
+fn moved_key_is_redirected() {
+    let dirs: Vec<TempDir> = (0..2).map(|_| TempDir::new().unwrap()).collect();
+    let nodes: Vec<String> = dirs.iter().map(start_server).collect();
+    let mut client = admin_client(vec![nodes[0].to_owned()]);
+    for i in 0..50 {
+        client.set(format!("key{}", i), "value".to_owned()).unwrap();
+    }
+    client.add_node_and_migrate(nodes[1].to_owned()).unwrap();
+
+    let moved = stored_keys(&nodes[1]);
+    let mut direct = connect(&nodes[0]);
+    match direct.set(moved[0].to_owned(), "value".to_owned()) {
+        Err(KVStoreError::Redirect(owner)) => assert_eq!(owner, nodes[1]),
+        result => panic!("expected a redirect, got {:?}", result),
+    }
+}
+
+#[test]
+fn stale_client_follows_redirects_of_many_keys() {
+    let dirs: Vec<TempDir> = (0..2).map(|_| TempDir::new().unwrap()).collect();
+    let nodes: Vec<String> = dirs.iter().map(start_server).collect();
+    // this client never learns about the second server
+    let mut stale = ShardedClient::new(vec![nodes[0].to_owned()]);
+    let keys: Vec<String> = (0..50).map(|i| format!("key{}", i)).collect();
+    let pairs: Vec<(String, String)> = keys
+        .iter()
+        .map(|key| (key.to_owned(), "value".to_owned()))
+        .collect();
+    stale.set_many(pairs).unwrap();
+    admin_client(vec![nodes[0].to_owned()])
+        .add_node_and_migrate(nodes[1].to_owned())
+        .unwrap();
+    let moved = stored_keys(&nodes[1]);
+    assert!(!moved.is_empty());
+
+    let updated: Vec<(String, String)> = keys
+        .iter()
+        .map(|key| (key.to_owned(), "updated".to_owned()))
+        .collect();
+    stale.set_many(updated).unwrap();
+    assert_eq!(
+        stale.get_many(keys.clone()).unwrap(),
+        vec![Some("updated".to_owned()); keys.len()]
+    );
+    let mut direct = connect(&nodes[1]);
+    for key in moved {
+        assert_eq!(direct.get(key).unwrap(), Some("updated".to_owned()));
+    }
+}
+
+#[test]
+fn moved_ranges_survive_a_restart() {
+    let dirs: Vec<TempDir> = (0..2).map(|_| TempDir::new().unwrap()).collect();
+    let target = start_server(&dirs[1]);
+    let mut shard = open_shard(&dirs[0]);
+    for i in 0..50 {
+        shard.set(format!("key{}", i), "value".to_owned()).unwrap();
+    }
+    let everything = HashRange {
+        start: u64::MAX,
+        end: u64::MAX,
+    };
+    assert_eq!(
+        shard.migrate(target.to_owned(), vec![everything]).unwrap(),
+        50
+    );
+    drop(shard);
+
+    let mut shard = open_shard(&dirs[0]);
+    match shard.get("key0".to_owned()) {
+        Err(KVStoreError::Redirect(owner)) => assert_eq!(owner, target),
+        result => panic!("expected a redirect, got {:?}", result),
+    }
+    assert_eq!(stored_keys(&target).len(), 50);
+}
+
+#[test]
+fn source_authenticates_to_the_target() {
+    let dirs: Vec<TempDir> = (0..2).map(|_| TempDir::new().unwrap()).collect();
+    let target = start_server_with(&dirs[1], |server| {
+        server.with_users(Users::new(vec![user("mover", false)]))
+    });
+    let everything = HashRange {
+        start: u64::MAX,
+        end: u64::MAX,
+    };
+    let mut shard = open_shard(&dirs[0]);
+    shard.set("key".to_owned(), "value".to_owned()).unwrap();
+    assert!(matches!(
+        shard.migrate(target.to_owned(), vec![everything]),
+        Err(KVStoreError::PermissionDenied(_))
+    ));
+    assert_eq!(
+        shard.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+
+    let mut shard = shard.with_credentials("mover".to_owned(), "secret".to_owned());
+    assert_eq!(
+        shard.migrate(target.to_owned(), vec![everything]).unwrap(),
+        1
+    );
+    let mut client = connect(&target);
+    client
+        .authenticate("mover".to_owned(), "secret".to_owned())
+        .unwrap();
+    assert_eq!(
+        client.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+}
+
+#[test]
+fn failed_migration_leaves_nothing_on_the_target() {
+    let dirs: Vec<TempDir> = (0..2).map(|_| TempDir::new().unwrap()).collect();
+    // the target takes the keys starting with "a", and refuses the others
+    let mover = User {
+        permissions: vec![Permission {
+            prefix: "a".to_owned(),
+            read: true,
+            write: true,
+        }],
+        ..user("mover", false)
+    };
+    let target = start_server_with(&dirs[1], |server| {
+        server.with_users(Users::new(vec![mover, user("root", true)]))
+    });
+    let everything = HashRange {
+        start: u64::MAX,
+        end: u64::MAX,
+    };
+    let mut shard = open_shard(&dirs[0]).with_credentials("mover".to_owned(), "secret".to_owned());
+    let keys: Vec<String> = (0..20)
+        .flat_map(|i| [format!("a{}", i), format!("b{}", i)])
+        .collect();
+    for key in &keys {
+        shard.set(key.to_owned(), "value".to_owned()).unwrap();
+    }
+
+    assert!(shard.migrate(target.to_owned(), vec![everything]).is_err());
+    let mut client = connect(&target);
+    client
+        .authenticate("root".to_owned(), "secret".to_owned())
+        .unwrap();
+    assert_eq!(client.scan(String::new(), None).unwrap(), vec![]);
+    // the keys are still served here
+    for key in keys {
+        assert_eq!(shard.get(key).unwrap(), Some("value".to_owned()));
+    }
+}
+
+#[test]
+fn only_an_admin_may_move_keys() {
+    let dirs: Vec<TempDir> = (0..2).map(|_| TempDir::new().unwrap()).collect();
+    let source = start_server_with(&dirs[0], |server| {
+        server.with_users(Users::new(vec![user("alice", false), user("root", true)]))
+    });
+    let target = start_server(&dirs[1]);
+    let everything = HashRange {
+        start: u64::MAX,
+        end: u64::MAX,
+    };
+
+    let mut client = connect(&source);
+    client
+        .authenticate("alice".to_owned(), "secret".to_owned())
+        .unwrap();
+    client.set("key".to_owned(), "value".to_owned()).unwrap();
+    match client.migrate(target.to_owned(), vec![everything]) {
+        Err(KVStoreError::PermissionDenied(_)) => {}
+        result => panic!("expected a denial, got {:?}", result),
+    }
+    assert!(stored_keys(&target).is_empty());
+
+    client
+        .authenticate("root".to_owned(), "secret".to_owned())
+        .unwrap();
+    assert_eq!(
+        client.migrate(target.to_owned(), vec![everything]).unwrap(),
+        1
+    );
+}
@@ -0,0 +1,96 @@
+mod common;
+
+use common::{connect, start_server};
+use std::sync::{Arc, Mutex};
+use tempfile::TempDir;
+use with_server::{KVStore, KVStoreEngine, Server, SledKVStore};
+
+fn keys(keys: &[&str]) -> Vec<String> {
+    keys.iter().map(|key| key.to_string()).collect()
+}
+
+fn multi_get_and_set<E: KVStoreEngine>(mut store: E) {
+    let pairs = (0..10)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    let results = store.multi_set(pairs).unwrap();
+    assert_eq!(results.len(), 10);
+    assert!(results.iter().all(|result| result.is_ok()));
+    // rewrite some keys so their latest values are in a later position of the log
+    store.set("key3".to_owned(), "new3".to_owned()).unwrap();
+    store.set("key1".to_owned(), "new1".to_owned()).unwrap();
+
+    let values: Vec<Option<String>> = store
+        .multi_get(keys(&["key9", "missing", "key1", "key3", "key0", "key1"]))
+        .unwrap()
+        .into_iter()
+        .map(|value| value.unwrap())
+        .collect();
+    assert_eq!(
+        values,
+        vec![
+            Some("value9".to_owned()),
+            None,
+            Some("new1".to_owned()),
+            Some("new3".to_owned()),
+            Some("value0".to_owned()),
+            Some("new1".to_owned()),
+        ]
+    );
+}
+
+#[test]
+fn multi_kvs() {
+    let temp_dir = TempDir::new().unwrap();
+    multi_get_and_set(KVStore::open(temp_dir.path()).unwrap());
+}
+
+#[test]
+fn multi_sled() {
+    let temp_dir = TempDir::new().unwrap();
+    multi_get_and_set(SledKVStore::open(sled::open(temp_dir.path()).unwrap()));
+}
+
+#[test]
+fn multi_set_survives_restart() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KVStore::open(temp_dir.path()).unwrap();
+    store
+        .multi_set(vec![
+            ("a".to_owned(), "1".to_owned()),
+            ("b".to_owned(), "2".to_owned()),
+        ])
+        .unwrap();
+    drop(store);
+
+    let mut store = KVStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("a".to_owned()).unwrap(), Some("1".to_owned()));
+    assert_eq!(store.get("b".to_owned()).unwrap(), Some("2".to_owned()));
+}
+
+#[test]
+fn multi_over_network() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    let addr = start_server(Server::new(Arc::new(Mutex::new(store))));
+
+    let mut client = connect(&addr);
+    let results = client
+        .multi_set(vec![
+            ("a".to_owned(), "1".to_owned()),
+            ("b".to_owned(), "2".to_owned()),
+        ])
+        .unwrap();
+    assert!(results.iter().all(|result| result.is_ok()));
+    let values: Vec<Option<String>> = client
+        .multi_get(keys(&["b", "c", "a"]))
+        .unwrap()
+        .into_iter()
+        .map(|value| value.unwrap())
+        .collect();
+    assert_eq!(
+        values,
+        vec![Some("2".to_owned()), None, Some("1".to_owned())]
+    );
+    assert!(client.multi_get(Vec::new()).unwrap().is_empty());
+}
@@ -0,0 +1,131 @@
+mod common;
+
+use common::{connect, start_server};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tempfile::TempDir;
+use with_server::{KVStore, KVStoreEngine, KVStoreError, Retention, Server};
+
+fn keep_versions(versions: usize) -> Retention {
+    Retention {
+        versions,
+        window: None,
+    }
+}
+
+fn values<E: KVStoreEngine>(store: &mut E, key: &str) -> Vec<(u64, Option<String>)> {
+    store
+        .history(key.to_owned())
+        .unwrap()
+        .into_iter()
+        .map(|version| (version.seq, version.value))
+        .collect()
+}
+
+#[test]
+fn last_versions_are_kept() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KVStore::open_with_retention(temp_dir.path(), keep_versions(3)).unwrap();
+    for value in 1..=4 {
+        store.set("key".to_owned(), format!("v{}", value)).unwrap();
+    }
+    assert_eq!(
+        values(&mut store, "key"),
+        vec![
+            (2, Some("v2".to_owned())),
+            (3, Some("v3".to_owned())),
+            (4, Some("v4".to_owned())),
+        ]
+    );
+    assert_eq!(
+        store.get_at("key".to_owned(), 3).unwrap(),
+        Some("v3".to_owned())
+    );
+    match store.get_at("key".to_owned(), 1) {
+        Err(KVStoreError::HistoryCompacted(oldest)) => assert_eq!(oldest, 2),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(store.get_at("other".to_owned(), 3).unwrap(), None);
+
+    // a removal is a version too
+    store.remove("key".to_owned()).unwrap();
+    assert_eq!(store.get_at("key".to_owned(), 5).unwrap(), None);
+    assert_eq!(
+        store.get_at("key".to_owned(), 4).unwrap(),
+        Some("v4".to_owned())
+    );
+    assert_eq!(values(&mut store, "key").last(), Some(&(5, None)));
+}
+
+#[test]
+fn versions_survive_compaction_and_restart() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KVStore::open_with_retention(temp_dir.path(), keep_versions(2)).unwrap();
+    store.set("a".to_owned(), "1".to_owned()).unwrap();
+    store.set("b".to_owned(), "2".to_owned()).unwrap();
+    store.set("a".to_owned(), "3".to_owned()).unwrap();
+    store.set("a".to_owned(), "4".to_owned()).unwrap();
+    store.compact().unwrap();
+    assert_eq!(
+        store.get_at("a".to_owned(), 3).unwrap(),
+        Some("3".to_owned())
+    );
+    drop(store);
+
+    let mut store = KVStore::open_with_retention(temp_dir.path(), keep_versions(2)).unwrap();
+    assert_eq!(
+        values(&mut store, "a"),
+        vec![(3, Some("3".to_owned())), (4, Some("4".to_owned()))]
+    );
+    assert_eq!(store.get("a".to_owned()).unwrap(), Some("4".to_owned()));
+    assert_eq!(
+        store.get_at("b".to_owned(), 3).unwrap(),
+        Some("2".to_owned())
+    );
+}
+
+#[test]
+fn versions_in_the_window_are_kept() {
+    let temp_dir = TempDir::new().unwrap();
+    let retention = Retention {
+        versions: 1,
+        window: Some(Duration::from_secs(60)),
+    };
+    let mut store = KVStore::open_with_retention(temp_dir.path(), retention).unwrap();
+    for value in 1..=5 {
+        store.set("key".to_owned(), value.to_string()).unwrap();
+    }
+    store.compact().unwrap();
+    assert_eq!(values(&mut store, "key").len(), 5);
+    assert!(store
+        .history("key".to_owned())
+        .unwrap()
+        .iter()
+        .all(|version| version.timestamp > 0));
+}
+
+#[test]
+fn only_latest_value_by_default() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KVStore::open(temp_dir.path()).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    assert!(store.get_at("key".to_owned(), 1).is_err());
+}
+
+#[test]
+fn versions_over_network() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KVStore::open_with_retention(temp_dir.path(), keep_versions(10)).unwrap();
+    let addr = start_server(Server::new(Arc::new(Mutex::new(store))));
+
+    let mut client = connect(&addr);
+    client.set("key".to_owned(), "old".to_owned()).unwrap();
+    client.set("key".to_owned(), "new".to_owned()).unwrap();
+    assert_eq!(
+        client.get_at("key".to_owned(), 1).unwrap(),
+        Some("old".to_owned())
+    );
+    let history = client.history("key".to_owned()).unwrap();
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[1].value, Some("new".to_owned()));
+}
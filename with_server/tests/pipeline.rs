@@ -0,0 +1,137 @@
+mod common;
+
+use common::{connect, start_server};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use with_server::{KVStore, Request, Server, SledKVStore, MAX_PIPELINE_BYTES};
+
+fn pipelined_requests(addr: &str) {
+    let mut pipeline = connect(addr).pipeline();
+    let sets: Vec<_> = (0..100)
+        .map(|i| pipeline.set(format!("key{}", i), i.to_string()).unwrap())
+        .collect();
+    let gets: Vec<_> = (0..100)
+        .map(|i| pipeline.get(format!("key{}", i)).unwrap())
+        .collect();
+    let missing = pipeline.get("missing".to_owned()).unwrap();
+
+    // handles are waited for in any order
+    assert_eq!(missing.wait().unwrap(), None);
+    for (i, get) in gets.into_iter().enumerate().rev() {
+        assert_eq!(get.wait().unwrap(), Some(i.to_string()));
+    }
+    for set in sets {
+        set.wait().unwrap();
+    }
+}
+
+#[test]
+fn pipeline_kvs() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    pipelined_requests(&start_server(Server::new(Arc::new(Mutex::new(store)))));
+}
+
+#[test]
+fn pipeline_sled() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = SledKVStore::open(sled::open(temp_dir.path()).unwrap());
+    pipelined_requests(&start_server(Server::new(store)));
+}
+
+#[test]
+fn errors_belong_to_their_request() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    let addr = start_server(Server::new(Arc::new(Mutex::new(store))));
+    let mut pipeline = connect(&addr).pipeline();
+
+    let set = pipeline.set("name".to_owned(), "kvs".to_owned()).unwrap();
+    let not_integer = pipeline.incr("name".to_owned(), 1).unwrap();
+    let remove_missing = pipeline.remove("missing".to_owned()).unwrap();
+    let watch = pipeline
+        .send(Request::Watch {
+            key_or_prefix: String::new(),
+        })
+        .unwrap();
+    let hits = pipeline.incr("hits".to_owned(), 2).unwrap();
+
+    set.wait().unwrap();
+    assert!(not_integer.wait().is_err());
+    assert!(remove_missing.wait().is_err());
+    assert!(watch.wait().is_err());
+    assert_eq!(hits.wait().unwrap(), 2);
+}
+
+#[test]
+fn deep_pipeline_and_dropped_handles() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    let addr = start_server(Server::new(Arc::new(Mutex::new(store))));
+    let mut pipeline = connect(&addr).pipeline();
+
+    // nobody waits for these
+    for _ in 0..5000 {
+        pipeline.incr("count".to_owned(), 1).unwrap();
+    }
+    assert_eq!(
+        pipeline
+            .incr("count".to_owned(), 0)
+            .unwrap()
+            .wait()
+            .unwrap(),
+        5000
+    );
+    assert_eq!(
+        connect(&addr).get("count".to_owned()).unwrap(),
+        Some("5000".to_owned())
+    );
+}
+
+#[test]
+fn pipeline_past_the_byte_budget_without_waiting() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    let addr = start_server(Server::new(Arc::new(Mutex::new(store))));
+    let value = "v".repeat(256 * 1024);
+    let count = 4 * MAX_PIPELINE_BYTES / value.len();
+    connect(&addr).set("big".to_owned(), value.clone()).unwrap();
+
+    // large requests and large responses, far more than the socket buffers hold, and no
+    // handle is waited for until the end: the server must never wait on the client
+    let (done, finished) = mpsc::channel();
+    let pipeline_addr = addr.clone();
+    let pipeline_value = value.clone();
+    thread::spawn(move || {
+        let mut pipeline = connect(&pipeline_addr).pipeline();
+        let mut gets = Vec::new();
+        let mut sets = Vec::new();
+        for i in 0..count {
+            gets.push(pipeline.get("big".to_owned()).unwrap());
+            sets.push(
+                pipeline
+                    .set(format!("key{}", i), pipeline_value.clone())
+                    .unwrap(),
+            );
+        }
+        let values: Vec<_> = gets.into_iter().map(|get| get.wait().unwrap()).collect();
+        for set in sets {
+            set.wait().unwrap();
+        }
+        done.send(values).unwrap();
+    });
+    let values = finished
+        .recv_timeout(Duration::from_secs(60))
+        .expect("pipeline deadlocked");
+    assert_eq!(values.len(), count);
+    assert!(values
+        .iter()
+        .all(|got| got.as_deref() == Some(value.as_str())));
+    assert_eq!(
+        connect(&addr).get(format!("key{}", count - 1)).unwrap(),
+        Some(value)
+    );
+}
@@ -0,0 +1,325 @@
+mod common;
+
+use common::{bind, cluster_addrs, ServerProcess};
+use std::io;
+use std::net::TcpListener;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use with_server::{
+    KVStore, KVStoreEngine, KVStoreError, KvsClient, RaftEngine, Result, Server, Version, Watcher,
+};
+
+fn spawn_node(dir: &TempDir, addr: &str, nodes: &[String]) -> ServerProcess {
+    let child = Command::new(env!("CARGO_BIN_EXE_kvs-server"))
+        .args([
+            "--engine",
+            "kvs",
+            "--addr",
+            addr,
+            "--cluster",
+            &nodes.join(","),
+        ])
+        .current_dir(dir.path())
+        .spawn()
+        .unwrap();
+    ServerProcess(child)
+}
+
+/// a store whose sets fail while `failing` is on, like a disk that is full
+#[derive(Clone)]
+struct FailingStore {
+    store: Arc<Mutex<KVStore>>,
+    failing: Arc<AtomicBool>,
+}
+
+impl KVStoreEngine for FailingStore {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        if self.failing.load(Ordering::SeqCst) {
+            return Err(io::Error::other("no space left").into());
+        }
+        self.store.set(key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.store.get(key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.store.remove(key)
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.store.compare_and_swap(key, expected, new)
+    }
+
+    fn commit(
+        &mut self,
+        reads: Vec<(String, Version)>,
+        writes: Vec<(String, Option<String>)>,
+    ) -> Result<bool> {
+        self.store.commit(reads, writes)
+    }
+
+    fn scan(&mut self, prefix: String, limit: Option<usize>) -> Result<Vec<(String, String)>> {
+        self.store.scan(prefix, limit)
+    }
+
+    fn watch(&mut self, prefix: String) -> Result<Watcher> {
+        self.store.watch(prefix)
+    }
+}
+
+/// send a request to the leader, following redirects and retrying while there is none
+///
+/// return the address of the node that answered
+fn call<T>(nodes: &[String], mut request: impl FnMut(&mut KvsClient) -> Result<T>) -> (String, T) {
+    let deadline = Instant::now() + Duration::from_secs(20);
+    let mut addr = nodes[0].to_owned();
+    let mut next = 0;
+    while Instant::now() < deadline {
+        match KvsClient::connect(addr.as_str()).and_then(|mut client| request(&mut client)) {
+            Ok(value) => return (addr, value),
+            Err(KVStoreError::Redirect(leader)) => {
+                addr = leader;
+                continue;
+            }
+            // the node is down, has no leader yet, or lost the write to an election
+            Err(_) => {
+                next = (next + 1) % nodes.len();
+                addr = nodes[next].to_owned();
+            }
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("no leader answered");
+}
+
+fn set(nodes: &[String], key: &str, value: &str) -> String {
+    call(nodes, |client| client.set(key.to_owned(), value.to_owned())).0
+}
+
+fn get(nodes: &[String], key: &str) -> Option<String> {
+    call(nodes, |client| client.get(key.to_owned())).1
+}
+
+#[test]
+fn cluster_survives_losing_the_leader() {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let nodes = cluster_addrs(3);
+    let mut processes: Vec<Option<ServerProcess>> = dirs
+        .iter()
+        .zip(&nodes)
+        .map(|(dir, addr)| Some(spawn_node(dir, addr, &nodes)))
+        .collect();
+
+    let mut leader = String::new();
+    for i in 0..40 {
+        leader = set(&nodes, &format!("key{}", i), &format!("value{}", i));
+        if i == 20 {
+            // kill the leader partway through the workload
+            let index = nodes.iter().position(|node| *node == leader).unwrap();
+            processes[index] = None;
+        }
+    }
+    for i in 0..40 {
+        assert_eq!(
+            get(&nodes, &format!("key{}", i)),
+            Some(format!("value{}", i))
+        );
+    }
+
+    // a node that comes back catches up, then is enough for a majority on its own log
+    let old = processes.iter().position(Option::is_none).unwrap();
+    processes[old] = Some(spawn_node(&dirs[old], &nodes[old], &nodes));
+    set(&nodes, "key40", "value40");
+    thread::sleep(Duration::from_secs(1));
+    let current = nodes.iter().position(|node| *node == leader).unwrap();
+    processes[current] = None;
+    for i in 0..41 {
+        assert_eq!(
+            get(&nodes, &format!("key{}", i)),
+            Some(format!("value{}", i))
+        );
+    }
+}
+
+#[test]
+fn followers_redirect_to_the_leader() {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let nodes = cluster_addrs(3);
+    let _processes: Vec<ServerProcess> = dirs
+        .iter()
+        .zip(&nodes)
+        .map(|(dir, addr)| spawn_node(dir, addr, &nodes))
+        .collect();
+
+    let leader = set(&nodes, "key1", "value1");
+    let follower = nodes.iter().find(|node| **node != leader).unwrap();
+    let mut client = KvsClient::connect(follower.as_str()).unwrap();
+    match client.get("key1".to_owned()) {
+        Err(KVStoreError::Redirect(addr)) => assert_eq!(addr, leader),
+        result => panic!("expected a redirect, got {:?}", result),
+    }
+    match client.remove("key1".to_owned()) {
+        Err(KVStoreError::Redirect(addr)) => assert_eq!(addr, leader),
+        result => panic!("expected a redirect, got {:?}", result),
+    }
+}
+
+#[test]
+fn lagging_node_gets_a_snapshot() {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let (listeners, nodes): (Vec<TcpListener>, Vec<String>) = (0..3).map(|_| bind()).unzip();
+    let mut listeners: Vec<Option<TcpListener>> = listeners.into_iter().map(Some).collect();
+    let mut start = |i: usize| {
+        let store = Arc::new(Mutex::new(KVStore::open(dirs[i].path()).unwrap()));
+        let peers = nodes
+            .iter()
+            .filter(|node| **node != nodes[i])
+            .cloned()
+            .collect();
+        let state_path = dirs[i].path().join("raft.state");
+        let engine = RaftEngine::start(
+            Arc::clone(&store),
+            nodes[i].to_owned(),
+            peers,
+            state_path,
+            5,
+        )
+        .unwrap();
+        let listener = listeners[i].take().unwrap();
+        thread::spawn(move || Server::new(engine).start_on(listener));
+        store
+    };
+    // two of three nodes are a majority
+    start(0);
+    start(1);
+    // the port of the third is bound already, a client of it would wait for an answer
+    let running = &nodes[..2];
+    for i in 0..30 {
+        set(running, &format!("key{}", i), &format!("value{}", i));
+    }
+    call(running, |client| client.remove("key0".to_owned()));
+
+    // the entries it misses were dropped from the log of the others
+    let mut lagging = start(2);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while lagging.get("key29".to_owned()).unwrap().is_none() {
+        assert!(Instant::now() < deadline, "the snapshot never arrived");
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(lagging.get("key0".to_owned()).unwrap(), None);
+    assert_eq!(
+        lagging.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+#[test]
+fn leader_cut_off_from_the_others_refuses_reads() {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let nodes = cluster_addrs(3);
+    let mut processes: Vec<Option<ServerProcess>> = dirs
+        .iter()
+        .zip(&nodes)
+        .map(|(dir, addr)| Some(spawn_node(dir, addr, &nodes)))
+        .collect();
+
+    let leader = set(&nodes, "key1", "value1");
+    let mut client = KvsClient::connect(leader.as_str()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    // the others may have elected a new leader and taken writes since
+    for (node, process) in nodes.iter().zip(processes.iter_mut()) {
+        if *node != leader {
+            *process = None;
+        }
+    }
+    assert!(client.get("key1".to_owned()).is_err());
+}
+
+#[test]
+fn restarted_cluster_replays_its_log() {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let nodes = cluster_addrs(3);
+    let spawn_all = || -> Vec<ServerProcess> {
+        dirs.iter()
+            .zip(&nodes)
+            .map(|(dir, addr)| spawn_node(dir, addr, &nodes))
+            .collect()
+    };
+    let processes = spawn_all();
+    for i in 0..10 {
+        set(&nodes, &format!("key{}", i), &format!("value{}", i));
+    }
+    drop(processes);
+    for dir in &dirs {
+        // the entries are appended to the log file, not kept in the state file
+        let state = std::fs::read_to_string(dir.path().join("raft.state")).unwrap();
+        assert!(!state.contains("entries"));
+        assert!(dir.path().join("raft.log").exists());
+    }
+
+    let _processes = spawn_all();
+    set(&nodes, "key10", "value10");
+    for i in 0..11 {
+        assert_eq!(
+            get(&nodes, &format!("key{}", i)),
+            Some(format!("value{}", i))
+        );
+    }
+}
+
+#[test]
+fn entry_that_fails_to_apply_is_applied_again() {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let (listeners, nodes): (Vec<TcpListener>, Vec<String>) = (0..3).map(|_| bind()).unzip();
+    let mut stores = Vec::new();
+    let mut failing = Vec::new();
+    for (i, listener) in listeners.into_iter().enumerate() {
+        let engine = FailingStore {
+            store: Arc::new(Mutex::new(KVStore::open(dirs[i].path()).unwrap())),
+            failing: Arc::new(AtomicBool::new(false)),
+        };
+        stores.push(Arc::clone(&engine.store));
+        failing.push(Arc::clone(&engine.failing));
+        let peers = nodes.iter().filter(|node| **node != nodes[i]).cloned();
+        let engine = RaftEngine::start(
+            engine,
+            nodes[i].to_owned(),
+            peers.collect(),
+            dirs[i].path().join("raft.state"),
+            100,
+        )
+        .unwrap();
+        thread::spawn(move || Server::new(engine).start_on(listener));
+    }
+    let leader = set(&nodes, "key0", "value0");
+
+    // a follower cannot write, the other two still commit
+    let follower = nodes.iter().position(|node| *node != leader).unwrap();
+    let mut store = Arc::clone(&stores[follower]);
+    failing[follower].store(true, Ordering::SeqCst);
+    set(&nodes, "key1", "value1");
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(store.get("key1".to_owned()).unwrap(), None);
+
+    // the entry was not skipped, it goes in once the node can write again
+    failing[follower].store(false, Ordering::SeqCst);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while store.get("key1".to_owned()).unwrap().is_none() {
+        assert!(Instant::now() < deadline, "the entry was never applied");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,81 @@
+mod common;
+
+use common::{connect, spawn_server, start_server};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use with_server::{FollowerEngine, KVStore, KVStoreError, Replica, Server};
+
+/// poll the follower until `key` has `expected`
+fn wait_for(addr: &str, key: &str, expected: Option<&str>) {
+    for _ in 0..250 {
+        if let Ok(value) = connect(addr).get(key.to_owned()) {
+            if value.as_deref() == expected {
+                return;
+            }
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("{} never became {:?} on {}", key, expected, addr);
+}
+
+#[test]
+fn follower_applies_the_log_of_the_leader() {
+    let leader_dir = TempDir::new().unwrap();
+    let follower_dir = TempDir::new().unwrap();
+    let leader = Arc::new(Mutex::new(KVStore::open(leader_dir.path()).unwrap()));
+    let leader_addr = start_server(Server::new(leader));
+    let mut client = connect(&leader_addr);
+    // written before the follower connects, replayed from the start of the log
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    let follower = Arc::new(Mutex::new(KVStore::open(follower_dir.path()).unwrap()));
+    let replica = Replica::new(
+        Arc::clone(&follower),
+        leader_addr.as_str(),
+        follower_dir.path().join("replication.pos"),
+    );
+    thread::spawn(move || replica.run());
+    let follower_addr = start_server(Server::new(FollowerEngine::new(
+        follower,
+        leader_addr.clone(),
+    )));
+
+    wait_for(&follower_addr, "key1", Some("value1"));
+
+    // shipped while the follower stays connected
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    client.remove("key1".to_owned()).unwrap();
+    wait_for(&follower_addr, "key2", Some("value2"));
+    wait_for(&follower_addr, "key1", None);
+    assert!(follower_dir.path().join("replication.pos").exists());
+
+    match connect(&follower_addr).set("key3".to_owned(), "value3".to_owned()) {
+        Err(KVStoreError::Redirect(addr)) => assert_eq!(addr, leader_addr),
+        result => panic!("expected a redirect, got {:?}", result),
+    }
+}
+
+#[test]
+fn restarted_follower_catches_up() {
+    let leader_dir = TempDir::new().unwrap();
+    let follower_dir = TempDir::new().unwrap();
+    let (_leader, leader_addr) = spawn_server(leader_dir.path(), &["--engine", "kvs"]);
+    let follower_args = ["--engine", "kvs", "--replica-of", &leader_addr];
+    let (follower, follower_addr) = spawn_server(follower_dir.path(), &follower_args);
+
+    let mut client = connect(&leader_addr);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    wait_for(&follower_addr, "key1", Some("value1"));
+    drop(follower);
+
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    client.remove("key1".to_owned()).unwrap();
+
+    // it listens on another port now
+    let (_follower, follower_addr) = spawn_server(follower_dir.path(), &follower_args);
+    wait_for(&follower_addr, "key2", Some("value2"));
+    wait_for(&follower_addr, "key1", None);
+    assert!(follower_dir.path().join("replication.pos").exists());
+}
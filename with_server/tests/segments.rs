@@ -0,0 +1,350 @@
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+use with_server::{
+    CompactionPolicy, KVStore, KVStoreEngine, KVStoreError, KVStoreOptions, LogBatch, LogPosition,
+    LogRecord, Retention,
+};
+
+const MAX_SEGMENT_BYTES: u64 = 512;
+
+fn open(dir: &Path, retention: Retention) -> KVStore {
+    let options = KVStoreOptions {
+        retention,
+        max_segment_bytes: MAX_SEGMENT_BYTES,
+        ..KVStoreOptions::default()
+    };
+    KVStore::open_with_options(dir, options).unwrap()
+}
+
+/// open without compacting on its own
+fn open_uncompacted(dir: &Path, retention: Retention) -> KVStore {
+    let options = KVStoreOptions {
+        retention,
+        compaction: CompactionPolicy {
+            dead_bytes: None,
+            ..CompactionPolicy::default()
+        },
+        max_segment_bytes: MAX_SEGMENT_BYTES,
+        ..KVStoreOptions::default()
+    };
+    KVStore::open_with_options(dir, options).unwrap()
+}
+
+/// overwrite one key `times` times, then fill newer files with keys that stay live
+///
+/// return the sealed files that hold only the live keys
+fn hot_then_cold(store: &mut KVStore, dir: &Path, times: usize) -> Vec<(u64, u64)> {
+    overwrite(store, 0..times);
+    let last_hot = segments(dir).last().unwrap().0;
+    set_keys(store, 30, "cold");
+    let mut cold: Vec<(u64, u64)> = segments(dir)
+        .into_iter()
+        .filter(|(file_number, _)| *file_number > last_hot)
+        .collect();
+    // the last one is current
+    cold.pop();
+    assert!(!cold.is_empty());
+    cold
+}
+
+fn overwrite(store: &mut KVStore, values: std::ops::Range<usize>) {
+    for value in values {
+        store.set("hot".to_owned(), value.to_string()).unwrap();
+    }
+}
+
+/// read the log from `from` on like a follower, return where it ends and the records read
+fn read_to_tip(store: &mut KVStore, mut from: LogPosition) -> (LogPosition, Vec<LogRecord>) {
+    let mut read = Vec::new();
+    loop {
+        match store.read_log(from, 100).unwrap() {
+            LogBatch::Records(records) if records.is_empty() => return (from, read),
+            LogBatch::Records(records) => {
+                for (next, record) in records {
+                    read.push(record);
+                    from = next;
+                }
+            }
+            LogBatch::Snapshot { .. } => panic!("expected records"),
+        }
+    }
+}
+
+/// sizes of the log files, by file number
+fn segments(dir: &Path) -> Vec<(u64, u64)> {
+    let mut segments: Vec<(u64, u64)> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter_map(|path| {
+            let number = path
+                .file_name()?
+                .to_str()?
+                .strip_suffix(".log")?
+                .parse()
+                .ok()?;
+            Some((number, fs::metadata(&path).unwrap().len()))
+        })
+        .collect();
+    segments.sort_unstable();
+    segments
+}
+
+fn set_keys(store: &mut KVStore, keys: usize, value: &str) {
+    for key in 0..keys {
+        store.set(format!("key{}", key), value.to_owned()).unwrap();
+    }
+}
+
+fn check_keys(store: &mut KVStore, keys: usize, value: &str) {
+    for key in 0..keys {
+        assert_eq!(
+            store.get(format!("key{}", key)).unwrap(),
+            Some(value.to_owned())
+        );
+    }
+}
+
+#[test]
+fn writes_roll_over_to_new_segments() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open(temp_dir.path(), Retention::default());
+    set_keys(&mut store, 100, "value");
+    let written = segments(temp_dir.path());
+    assert!(written.len() > 2);
+    // a segment is only sealed once a record takes it past the limit
+    for (_, bytes) in &written[..written.len() - 1] {
+        assert!(*bytes >= MAX_SEGMENT_BYTES && *bytes < 2 * MAX_SEGMENT_BYTES);
+    }
+    assert_eq!(store.stats().unwrap().log_files, written.len() as u64);
+    check_keys(&mut store, 100, "value");
+
+    drop(store);
+    let mut store = open(temp_dir.path(), Retention::default());
+    check_keys(&mut store, 100, "value");
+    assert_eq!(store.stats().unwrap().dead_bytes, 0);
+}
+
+#[test]
+fn compaction_writes_bounded_segments() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open(temp_dir.path(), Retention::default());
+    set_keys(&mut store, 100, "old");
+    set_keys(&mut store, 100, "new");
+    let before = segments(temp_dir.path());
+    KVStoreEngine::compact(&mut store).unwrap();
+    let after = segments(temp_dir.path());
+    assert!(after.len() > 2);
+    assert!(after.first().unwrap().0 > before.last().unwrap().0);
+    for (_, bytes) in &after {
+        assert!(*bytes < 2 * MAX_SEGMENT_BYTES);
+    }
+    check_keys(&mut store, 100, "new");
+
+    // every segment of the compaction is kept on open
+    drop(store);
+    let mut store = open(temp_dir.path(), Retention::default());
+    assert_eq!(segments(temp_dir.path())[..after.len()], after[..]);
+    check_keys(&mut store, 100, "new");
+    assert_eq!(store.scan("key".to_owned(), None).unwrap().len(), 100);
+}
+
+#[test]
+fn versions_survive_segmented_compaction() {
+    let temp_dir = TempDir::new().unwrap();
+    let retention = Retention {
+        versions: 2,
+        window: None,
+    };
+    let mut store = open(temp_dir.path(), retention);
+    for round in 0..3 {
+        set_keys(&mut store, 30, &format!("v{}", round));
+    }
+    KVStoreEngine::compact(&mut store).unwrap();
+    drop(store);
+
+    let mut store = open(temp_dir.path(), retention);
+    for key in 0..30 {
+        let values: Vec<Option<String>> = store
+            .history(format!("key{}", key))
+            .unwrap()
+            .into_iter()
+            .map(|version| version.value)
+            .collect();
+        assert_eq!(values, vec![Some("v1".to_owned()), Some("v2".to_owned())]);
+    }
+}
+
+#[test]
+fn changes_are_read_across_segments() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open(temp_dir.path(), Retention::default());
+    set_keys(&mut store, 50, "value");
+    assert!(segments(temp_dir.path()).len() > 2);
+    let events = store.changes_since(0, 100).unwrap();
+    assert_eq!(events.len(), 50);
+    assert_eq!(events.last().unwrap().key, "key49");
+}
+
+#[test]
+fn segment_compaction_leaves_newer_files_alone() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open_uncompacted(temp_dir.path(), Retention::default());
+    let cold = hot_then_cold(&mut store, temp_dir.path(), 100);
+    let before = segments(temp_dir.path());
+    let dead_before = store.stats().unwrap().dead_bytes;
+
+    store.compact_segments().unwrap();
+    let after = segments(temp_dir.path());
+    assert!(cold.iter().all(|file| after.contains(file)));
+    assert!(after.len() < before.len());
+    let stats = store.stats().unwrap();
+    assert_eq!(stats.compactions, 1);
+    assert!(stats.dead_bytes < dead_before / 4);
+    assert!(stats.reclaimed_bytes > 0);
+    check_keys(&mut store, 30, "cold");
+    assert_eq!(store.get("hot".to_owned()).unwrap(), Some("99".to_owned()));
+
+    drop(store);
+    let mut store = open_uncompacted(temp_dir.path(), Retention::default());
+    assert_eq!(store.stats().unwrap().dead_bytes, stats.dead_bytes);
+    check_keys(&mut store, 30, "cold");
+    assert_eq!(store.get("hot".to_owned()).unwrap(), Some("99".to_owned()));
+}
+
+#[test]
+fn policy_compacts_segments() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open(temp_dir.path(), Retention::default());
+    store.compaction.dead_bytes = Some(2048);
+    let cold = hot_then_cold(&mut store, temp_dir.path(), 200);
+    assert!(store.stats().unwrap().compactions > 0);
+    let after = segments(temp_dir.path());
+    assert!(cold.iter().all(|file| after.contains(file)));
+    check_keys(&mut store, 30, "cold");
+}
+
+#[test]
+fn versions_survive_segment_compaction() {
+    let temp_dir = TempDir::new().unwrap();
+    let retention = Retention {
+        versions: 2,
+        window: None,
+    };
+    let mut store = open_uncompacted(temp_dir.path(), retention);
+    for round in 0..3 {
+        set_keys(&mut store, 30, &format!("v{}", round));
+    }
+    store.compact_segments().unwrap();
+    store.set("key0".to_owned(), "v3".to_owned()).unwrap();
+    drop(store);
+
+    let mut store = open_uncompacted(temp_dir.path(), retention);
+    for key in 0..30 {
+        let values: Vec<Option<String>> = store
+            .history(format!("key{}", key))
+            .unwrap()
+            .into_iter()
+            .map(|version| version.value)
+            .collect();
+        let expected = if key == 0 { ["v2", "v3"] } else { ["v1", "v2"] };
+        assert_eq!(values, expected.map(|value| Some(value.to_owned())));
+    }
+    assert_eq!(store.get("key0".to_owned()).unwrap(), Some("v3".to_owned()));
+}
+
+#[test]
+fn segment_compaction_keeps_the_history_of_newer_files() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open_uncompacted(temp_dir.path(), Retention::default());
+    hot_then_cold(&mut store, temp_dir.path(), 100);
+    store.compact_segments().unwrap();
+    store.set("hot".to_owned(), "after".to_owned()).unwrap();
+
+    // the changes of the deleted files are gone, the cold keys set after them are not
+    let first = match store.changes_since(5, 10) {
+        Err(KVStoreError::HistoryCompacted(first)) => first,
+        other => panic!("unexpected {:?}", other.map(|_| ())),
+    };
+    assert!(first > 5 && first <= 101);
+    let events = store.changes_since(first - 1, 200).unwrap();
+    let seqs: Vec<u64> = events.iter().map(|event| event.seq).collect();
+    assert_eq!(seqs, (first..=131).collect::<Vec<u64>>());
+    assert_eq!(events.last().unwrap().value, Some("after".to_owned()));
+
+    // a follower that read part of the deleted files has to start over
+    let from = LogPosition {
+        file_number: 1,
+        offset: 0,
+    };
+    match store.read_log(from, 10).unwrap() {
+        LogBatch::Snapshot { pairs, .. } => assert_eq!(pairs.len(), 31),
+        _ => panic!("expected a snapshot"),
+    }
+}
+
+#[test]
+fn follower_at_the_tip_reads_on_after_segment_compaction() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open_uncompacted(temp_dir.path(), Retention::default());
+    let mut tip = LogPosition::default();
+    // every change is in the files compacted, then only some of them are
+    for cold in [false, true] {
+        if cold {
+            hot_then_cold(&mut store, temp_dir.path(), 100);
+        } else {
+            overwrite(&mut store, 0..100);
+        }
+        tip = read_to_tip(&mut store, tip).0;
+        store.compact_segments().unwrap();
+        let (next, read) = read_to_tip(&mut store, tip);
+        assert!(read.is_empty());
+
+        // the copied records are not shipped again
+        store.set("hot".to_owned(), "after".to_owned()).unwrap();
+        let (next, read) = read_to_tip(&mut store, next);
+        assert_eq!(read.len(), 1);
+        assert!(matches!(&read[0], LogRecord::Set(key, value) if key == "hot" && value == "after"));
+        tip = next;
+    }
+}
+
+#[test]
+fn removed_keys_stay_removed_after_segment_compaction() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open_uncompacted(temp_dir.path(), Retention::default());
+    // the file of the removed key is mostly live, the one of its removal mostly dead
+    store.set("removed".to_owned(), "old".to_owned()).unwrap();
+    set_keys(&mut store, 30, "cold");
+    overwrite(&mut store, 0..20);
+    store.remove("removed".to_owned()).unwrap();
+    overwrite(&mut store, 20..40);
+    store.compact_segments().unwrap();
+    assert_eq!(store.get("removed".to_owned()).unwrap(), None);
+
+    drop(store);
+    let mut store = open_uncompacted(temp_dir.path(), Retention::default());
+    assert_eq!(store.get("removed".to_owned()).unwrap(), None);
+    assert_eq!(store.get("hot".to_owned()).unwrap(), Some("39".to_owned()));
+    check_keys(&mut store, 30, "cold");
+}
+
+#[test]
+fn segment_compaction_skips_files_of_snapshots() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open_uncompacted(temp_dir.path(), Retention::default());
+    for value in 0..50 {
+        store.set("hot".to_owned(), value.to_string()).unwrap();
+    }
+    let mut snapshot = store.snapshot().unwrap();
+    let pinned = segments(temp_dir.path()).last().unwrap().0;
+    for value in 50..100 {
+        store.set("hot".to_owned(), value.to_string()).unwrap();
+    }
+    store.compact_segments().unwrap();
+    assert!(segments(temp_dir.path())
+        .iter()
+        .any(|(file_number, _)| *file_number == pinned));
+    assert_eq!(snapshot.get("hot").unwrap(), Some("49".to_owned()));
+    assert_eq!(store.get("hot".to_owned()).unwrap(), Some("99".to_owned()));
+}
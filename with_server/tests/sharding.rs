@@ -0,0 +1,130 @@
+mod common;
+
+use common::connect;
+use std::sync::{Arc, Mutex};
+use tempfile::TempDir;
+use with_server::{Counters, KVStore, Server, ShardedClient};
+
+fn start_server(temp_dir: &TempDir) -> String {
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    common::start_server(Server::new(Arc::new(Mutex::new(store))))
+}
+
+#[test]
+fn keys_are_spread_across_servers() {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let nodes: Vec<String> = dirs.iter().map(start_server).collect();
+    let mut client = ShardedClient::new(nodes.clone());
+
+    let pairs: Vec<(String, String)> = (0..100)
+        .map(|i| (format!("key{:03}", i), format!("value{}", i)))
+        .collect();
+    client.set_many(pairs.clone()).unwrap();
+    client.set("other".to_owned(), "value".to_owned()).unwrap();
+
+    // every key is on the server it hashes to, and every server has some
+    for node in &nodes {
+        let mut direct = connect(node);
+        let stored = direct.scan(String::new(), None).unwrap();
+        assert!(!stored.is_empty());
+        for (key, _) in stored {
+            assert_eq!(client.node_for(&key), Some(node.as_str()));
+        }
+    }
+
+    assert_eq!(
+        client.get("key007".to_owned()).unwrap(),
+        Some("value7".to_owned())
+    );
+    let values = client
+        .get_many(vec![
+            "key099".to_owned(),
+            "missing".to_owned(),
+            "key000".to_owned(),
+        ])
+        .unwrap();
+    assert_eq!(
+        values,
+        vec![Some("value99".to_owned()), None, Some("value0".to_owned())]
+    );
+
+    // the scan merges every server in key order
+    assert_eq!(client.scan("key".to_owned(), None).unwrap(), pairs);
+    assert_eq!(client.scan("key".to_owned(), Some(5)).unwrap(), pairs[..5]);
+
+    client.remove("key007".to_owned()).unwrap();
+    assert_eq!(client.get("key007".to_owned()).unwrap(), None);
+    assert!(client.remove("key007".to_owned()).is_err());
+}
+
+#[test]
+fn many_keys_take_one_request_per_server() {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let counters: Vec<Arc<Counters>> = (0..3).map(|_| Arc::default()).collect();
+    let nodes: Vec<String> = dirs
+        .iter()
+        .zip(&counters)
+        .map(|(temp_dir, counters)| {
+            let store = KVStore::open(temp_dir.path()).unwrap();
+            let server = Server::new(Arc::new(Mutex::new(store)));
+            common::start_server(server.with_counters(Arc::clone(counters)))
+        })
+        .collect();
+    let mut client = ShardedClient::new(nodes);
+
+    let pairs: Vec<(String, String)> = (0..100)
+        .map(|i| (format!("key{:03}", i), format!("value{}", i)))
+        .collect();
+    client.set_many(pairs.clone()).unwrap();
+    let keys = pairs.iter().map(|(key, _)| key.to_owned()).collect();
+    let values = client.get_many(keys).unwrap();
+    let expected: Vec<Option<String>> = pairs.into_iter().map(|(_, value)| Some(value)).collect();
+    assert_eq!(values, expected);
+
+    for counters in &counters {
+        let requests = counters.requests().unwrap();
+        assert_eq!(requests["MultiSet"].total, 1);
+        assert_eq!(requests["MultiGet"].total, 1);
+        assert!(!requests.contains_key("Set"));
+        assert!(!requests.contains_key("Get"));
+    }
+}
+
+#[test]
+fn changing_servers_moves_few_keys() {
+    let nodes: Vec<String> = (0..4).map(|i| format!("127.0.0.1:{}", 4000 + i)).collect();
+    let mut client = ShardedClient::new(nodes.clone());
+    let keys: Vec<String> = (0..10_000).map(|i| format!("key{}", i)).collect();
+    let before: Vec<String> = keys
+        .iter()
+        .map(|key| client.node_for(key).unwrap().to_owned())
+        .collect();
+
+    // only the keys of the removed server move
+    client.remove_node(&nodes[1]);
+    let mut moved = 0;
+    for (key, owner) in keys.iter().zip(&before) {
+        let now = client.node_for(key).unwrap();
+        if owner == &nodes[1] {
+            assert_ne!(now, nodes[1]);
+            moved += 1;
+        } else {
+            assert_eq!(now, owner);
+        }
+    }
+    assert!(moved > 1500 && moved < 3500, "{} keys moved", moved);
+
+    // an added server only takes keys, about a fifth of them
+    client.add_node(nodes[1].to_owned());
+    client.add_node("127.0.0.1:4004");
+    let mut taken = 0;
+    for (key, owner) in keys.iter().zip(&before) {
+        let now = client.node_for(key).unwrap();
+        if now == "127.0.0.1:4004" {
+            taken += 1;
+        } else {
+            assert_eq!(now, owner);
+        }
+    }
+    assert!(taken > 1000 && taken < 3000, "{} keys taken", taken);
+}
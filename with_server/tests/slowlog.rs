@@ -0,0 +1,117 @@
+mod common;
+
+use common::connect;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tempfile::TempDir;
+use with_server::{
+    hash_password_with_rounds, KVStore, KVStoreError, KvsClient, Server, SlowEntry, SlowLog, User,
+};
+
+/// few rounds, the tests are not built optimized
+const TEST_ROUNDS: u32 = 1000;
+
+fn start_server(temp_dir: &TempDir, slow_log: Option<SlowLog>) -> String {
+    let store = KVStore::open(temp_dir.path().join("db")).unwrap();
+    let admin = User {
+        name: "root".to_owned(),
+        password_hash: hash_password_with_rounds("secret", TEST_ROUNDS),
+        permissions: Vec::new(),
+        admin: true,
+    };
+    let mut server = Server::new(Arc::new(Mutex::new(store))).with_admin(admin);
+    if let Some(slow_log) = slow_log {
+        server = server.with_slow_log(slow_log);
+    }
+    common::start_server(server)
+}
+
+/// a connection of the admin, its `Auth` is the first request of the slow log
+fn admin(addr: &str) -> KvsClient {
+    let mut client = connect(addr);
+    client
+        .authenticate("root".to_owned(), "secret".to_owned())
+        .unwrap();
+    client
+}
+
+#[test]
+fn slow_requests_are_logged() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("slow.log");
+    let slow_log = SlowLog::new(Duration::ZERO, Some(path.clone())).unwrap();
+    let addr = start_server(&temp_dir, Some(slow_log));
+    let mut admin = admin(&addr);
+    let mut client = connect(&addr);
+    client.set("key".to_owned(), "value".to_owned()).unwrap();
+    client.get("key".to_owned()).unwrap();
+
+    let entries = admin.slow_log().unwrap();
+    let requests: Vec<&str> = entries.iter().map(|entry| entry.request.as_str()).collect();
+    assert_eq!(requests, vec!["Auth", "Set", "Get"]);
+    assert_eq!(entries[1].key.as_deref(), Some("key"));
+    assert_eq!(entries[1].value_bytes, 5);
+    assert_eq!(entries[2].value_bytes, 0);
+    assert!(entries[1].client.starts_with("127.0.0.1:"));
+    assert!(!entries[1].compacted);
+
+    let logged: Vec<SlowEntry> = fs::read_to_string(&path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    // the `SlowLog` request is logged once answered
+    assert_eq!(logged.len(), 4);
+    assert_eq!(logged[3].request, "SlowLog");
+}
+
+#[test]
+fn compaction_inside_a_request_is_flagged() {
+    let temp_dir = TempDir::new().unwrap();
+    let slow_log = SlowLog::new(Duration::ZERO, None).unwrap();
+    let addr = start_server(&temp_dir, Some(slow_log));
+    let mut client = admin(&addr);
+    let big = "x".repeat(600 * 1024);
+    for _ in 0..3 {
+        client.set("big".to_owned(), big.to_owned()).unwrap();
+    }
+
+    let entries = client.slow_log().unwrap();
+    let compacted: Vec<bool> = entries.iter().map(|entry| entry.compacted).collect();
+    assert_eq!(compacted, vec![false, false, false, true]);
+}
+
+#[test]
+fn fast_requests_are_not_logged() {
+    let temp_dir = TempDir::new().unwrap();
+    let slow_log = SlowLog::new(Duration::from_secs(60), None).unwrap();
+    let addr = start_server(&temp_dir, Some(slow_log));
+    let mut client = admin(&addr);
+    client.set("key".to_owned(), "value".to_owned()).unwrap();
+    assert!(client.slow_log().unwrap().is_empty());
+}
+
+#[test]
+fn slow_log_is_off_by_default() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start_server(&temp_dir, None);
+    assert!(matches!(
+        admin(&addr).slow_log(),
+        Err(KVStoreError::Other(_))
+    ));
+}
+
+#[test]
+fn only_an_admin_may_read_the_slow_log() {
+    let temp_dir = TempDir::new().unwrap();
+    let slow_log = SlowLog::new(Duration::ZERO, None).unwrap();
+    let addr = start_server(&temp_dir, Some(slow_log));
+    let mut client = connect(&addr);
+    client.set("secret".to_owned(), "value".to_owned()).unwrap();
+    assert!(matches!(
+        client.slow_log(),
+        Err(KVStoreError::PermissionDenied(_))
+    ));
+    assert_eq!(admin(&addr).slow_log().unwrap().len(), 3);
+}
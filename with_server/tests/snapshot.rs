@@ -0,0 +1,114 @@
+mod common;
+
+use common::{connect, start_server};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use with_server::{KVStore, KVStoreEngine, Server, SledKVStore};
+
+fn log_files(path: &Path) -> usize {
+    fs::read_dir(path)
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+        .count()
+}
+
+fn snapshot_is_frozen<E: KVStoreEngine>(mut store: E) {
+    store.set("a".to_owned(), "1".to_owned()).unwrap();
+    store.set("b".to_owned(), "2".to_owned()).unwrap();
+    let mut snapshot = store.snapshot().unwrap();
+    store.set("a".to_owned(), "3".to_owned()).unwrap();
+    store.remove("b".to_owned()).unwrap();
+    store.set("c".to_owned(), "4".to_owned()).unwrap();
+
+    assert_eq!(snapshot.get("a").unwrap(), Some("1".to_owned()));
+    assert_eq!(snapshot.get("c").unwrap(), None);
+    assert_eq!(
+        snapshot.scan("", None).unwrap(),
+        vec![
+            ("a".to_owned(), "1".to_owned()),
+            ("b".to_owned(), "2".to_owned()),
+        ]
+    );
+    assert_eq!(store.get("a".to_owned()).unwrap(), Some("3".to_owned()));
+}
+
+#[test]
+fn snapshot_kvs() {
+    let temp_dir = TempDir::new().unwrap();
+    snapshot_is_frozen(KVStore::open(temp_dir.path()).unwrap());
+}
+
+#[test]
+fn snapshot_sled() {
+    let temp_dir = TempDir::new().unwrap();
+    snapshot_is_frozen(SledKVStore::open(sled::open(temp_dir.path()).unwrap()));
+}
+
+#[test]
+fn compaction_keeps_files_of_snapshots() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KVStore::open(temp_dir.path()).unwrap();
+    store.set("key".to_owned(), "old".to_owned()).unwrap();
+    let mut snapshot = store.snapshot().unwrap();
+    store.set("key".to_owned(), "new".to_owned()).unwrap();
+    store.compact().unwrap();
+
+    // the file read by the snapshot, the compacted file and the current one
+    assert_eq!(log_files(temp_dir.path()), 3);
+    assert_eq!(snapshot.get("key").unwrap(), Some("old".to_owned()));
+    drop(snapshot);
+    assert_eq!(log_files(temp_dir.path()), 2);
+    assert_eq!(store.get("key".to_owned()).unwrap(), Some("new".to_owned()));
+}
+
+#[test]
+fn files_left_by_snapshots_are_deleted_on_open() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KVStore::open(temp_dir.path()).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    let snapshot = store.snapshot().unwrap();
+    store.compact().unwrap();
+    store.remove("key".to_owned()).unwrap();
+    store.compact().unwrap();
+    // as if the process died with the snapshot open
+    std::mem::forget(snapshot);
+    drop(store);
+
+    let mut store = KVStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key".to_owned()).unwrap(), None);
+    assert!(!temp_dir.path().join("1.log").exists());
+}
+
+#[test]
+fn snapshot_over_network() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    let addr = start_server(Server::new(Arc::new(Mutex::new(store))));
+    let mut client = connect(&addr);
+    client.set("a".to_owned(), "1".to_owned()).unwrap();
+    let id = client.snapshot(Duration::from_secs(60)).unwrap();
+    client.set("a".to_owned(), "2".to_owned()).unwrap();
+    client.set("ab".to_owned(), "3".to_owned()).unwrap();
+
+    // the id is usable from any connection
+    let mut reader = connect(&addr);
+    assert_eq!(
+        reader.snapshot_get(id, "a".to_owned()).unwrap(),
+        Some("1".to_owned())
+    );
+    assert_eq!(
+        reader.snapshot_scan(id, "a".to_owned(), None).unwrap(),
+        vec![("a".to_owned(), "1".to_owned())]
+    );
+    reader.release_snapshot(id).unwrap();
+    assert!(reader.snapshot_get(id, "a".to_owned()).is_err());
+
+    let id = client.snapshot(Duration::from_millis(100)).unwrap();
+    thread::sleep(Duration::from_millis(300));
+    let err = client.snapshot_get(id, "a".to_owned()).unwrap_err();
+    assert!(err.to_string().contains("expired"));
+}
@@ -0,0 +1,87 @@
+mod common;
+
+use common::{connect, start_server};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use tempfile::TempDir;
+use with_server::{KVStore, KVStoreEngine, Server, ServerStats, SledKVStore};
+
+#[test]
+fn kvs_stats() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KVStore::open(temp_dir.path()).unwrap();
+    for i in 0..10 {
+        store.set(format!("key{}", i % 5), i.to_string()).unwrap();
+    }
+    let stats = store.stats().unwrap();
+    assert_eq!(stats.engine, "kvs");
+    assert_eq!(stats.keys, 5);
+    assert!(stats.live_bytes > 0);
+    assert!(stats.dead_bytes > 0);
+    assert_eq!(stats.compactions, 0);
+    assert!(stats.last_compaction.is_none());
+
+    store.compact().unwrap();
+    let compacted = store.stats().unwrap();
+    assert_eq!(compacted.keys, 5);
+    assert_eq!(compacted.dead_bytes, 0);
+    assert_eq!(compacted.compactions, 1);
+    let last = compacted.last_compaction.unwrap();
+    assert!(last.reclaimed_bytes > 0);
+    assert_eq!(compacted.reclaimed_bytes, last.reclaimed_bytes);
+}
+
+#[test]
+fn sled_stats() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = SledKVStore::open(sled::open(temp_dir.path()).unwrap());
+    store.set("a".to_owned(), "1".to_owned()).unwrap();
+    store.set("b".to_owned(), "2".to_owned()).unwrap();
+    let stats = store.stats().unwrap();
+    assert_eq!(stats.engine, "sled");
+    assert_eq!(stats.keys, 2);
+}
+
+#[test]
+fn server_stats() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    let addr = start_server(Server::new(Arc::new(Mutex::new(store))));
+    let mut client = connect(&addr);
+    client.set("a".to_owned(), "1".to_owned()).unwrap();
+    for _ in 0..3 {
+        client.get("a".to_owned()).unwrap();
+    }
+
+    let stats = client.stats().unwrap();
+    assert_eq!(stats.engine.keys, 1);
+    assert_eq!(stats.connections, 1);
+    assert!(stats.ops_per_sec["Get"] > 0.0);
+    assert!(stats.ops_per_sec["Get"] > stats.ops_per_sec["Set"]);
+    assert!(!stats.ops_per_sec.contains_key("Remove"));
+}
+
+#[test]
+fn stats_from_command_line() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    let addr = start_server(Server::new(Arc::new(Mutex::new(store))));
+    connect(&addr).set("a".to_owned(), "1".to_owned()).unwrap();
+
+    let stats = |json: bool| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_kvs-client"));
+        command.arg("stats").args(["--addr", &addr]);
+        if json {
+            command.arg("--json");
+        }
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let human = stats(false);
+    assert!(human.contains("engine: kvs"));
+    assert!(human.contains("keys: 1"));
+    let parsed: ServerStats = serde_json::from_str(&stats(true)).unwrap();
+    assert_eq!(parsed.engine.engine, "kvs");
+    assert_eq!(parsed.engine.keys, 1);
+}
@@ -0,0 +1,192 @@
+mod common;
+
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa, KeyPair};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use with_server::{
+    Address, HashRange, KVStore, KVStoreEngine, KvsClient, Listener, Result, Server, ShardEngine,
+    Stream, TlsAcceptor, TlsConnector,
+};
+
+/// certificate authority generated for one test
+struct TestCa {
+    cert: Certificate,
+    key: KeyPair,
+}
+
+impl TestCa {
+    fn new(dir: &Path, name: &str) -> TestCa {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, name);
+        let cert = params.self_signed(&key).unwrap();
+        fs::write(dir.join(format!("{}.pem", name)), cert.pem()).unwrap();
+        TestCa { cert, key }
+    }
+
+    /// issue a certificate for `names`, return paths of certificate and key
+    fn issue(&self, dir: &Path, name: &str, names: &[&str]) -> (PathBuf, PathBuf) {
+        let key = KeyPair::generate().unwrap();
+        let names = names
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        let cert = CertificateParams::new(names)
+            .unwrap()
+            .signed_by(&key, &self.cert, &self.key)
+            .unwrap();
+        let cert_path = dir.join(format!("{}.crt", name));
+        let key_path = dir.join(format!("{}.key", name));
+        fs::write(&cert_path, cert.pem()).unwrap();
+        fs::write(&key_path, key.serialize_pem()).unwrap();
+        (cert_path, key_path)
+    }
+}
+
+fn start_server(temp_dir: &TempDir, tls: TlsAcceptor) -> String {
+    let store = KVStore::open(temp_dir.path().join("db")).unwrap();
+    common::start_server(Server::new(Arc::new(Mutex::new(store))).with_tls(tls))
+}
+
+fn round_trip(addr: &str, tls: &TlsConnector) -> Result<Option<String>> {
+    let mut client = KvsClient::connect_tls(addr, tls)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.get("key1".to_owned())
+}
+
+#[test]
+fn tls_round_trip() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    let ca = TestCa::new(dir, "ca");
+    let (cert, key) = ca.issue(dir, "server", &["localhost", "127.0.0.1"]);
+    let addr = start_server(&temp_dir, TlsAcceptor::new(&cert, &key, None).unwrap());
+
+    let tls = TlsConnector::new(&dir.join("ca.pem"), None).unwrap();
+    assert_eq!(round_trip(&addr, &tls).unwrap(), Some("value1".to_owned()));
+
+    // a plain client cannot talk to a TLS server
+    let mut client = KvsClient::connect(addr.as_str()).unwrap();
+    assert!(client.get("key1".to_owned()).is_err());
+}
+
+#[test]
+fn untrusted_server_is_rejected() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    let ca = TestCa::new(dir, "ca");
+    TestCa::new(dir, "other-ca");
+    let (cert, key) = ca.issue(dir, "server", &["localhost", "127.0.0.1"]);
+    let addr = start_server(&temp_dir, TlsAcceptor::new(&cert, &key, None).unwrap());
+
+    let tls = TlsConnector::new(&dir.join("other-ca.pem"), None).unwrap();
+    assert!(round_trip(&addr, &tls).is_err());
+}
+
+#[test]
+fn mutual_tls() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    let ca = TestCa::new(dir, "ca");
+    let client_ca = TestCa::new(dir, "client-ca");
+    let (cert, key) = ca.issue(dir, "server", &["localhost", "127.0.0.1"]);
+    let (client_cert, client_key) = client_ca.issue(dir, "client", &["client"]);
+    let (rogue_cert, rogue_key) = ca.issue(dir, "rogue", &["client"]);
+    let tls = TlsAcceptor::new(&cert, &key, Some(&dir.join("client-ca.pem"))).unwrap();
+    let addr = start_server(&temp_dir, tls);
+
+    let ca_path = dir.join("ca.pem");
+    let tls = TlsConnector::new(&ca_path, Some((&client_cert, &client_key))).unwrap();
+    assert_eq!(round_trip(&addr, &tls).unwrap(), Some("value1".to_owned()));
+
+    let tls = TlsConnector::new(&ca_path, None).unwrap();
+    assert!(round_trip(&addr, &tls).is_err());
+
+    let tls = TlsConnector::new(&ca_path, Some((&rogue_cert, &rogue_key))).unwrap();
+    assert!(round_trip(&addr, &tls).is_err());
+}
+
+#[test]
+fn keys_move_to_a_tls_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    let ca = TestCa::new(dir, "ca");
+    let (cert, key) = ca.issue(dir, "server", &["localhost", "127.0.0.1"]);
+    let target = start_server(&temp_dir, TlsAcceptor::new(&cert, &key, None).unwrap());
+    let tls = TlsConnector::new(&dir.join("ca.pem"), None).unwrap();
+
+    let store = Arc::new(Mutex::new(KVStore::open(dir.join("source")).unwrap()));
+    let mut shard = ShardEngine::open(store, dir.join("shards.moved"))
+        .unwrap()
+        .with_tls(tls.clone());
+    shard.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let everything = HashRange {
+        start: u64::MAX,
+        end: u64::MAX,
+    };
+    assert_eq!(
+        shard.migrate(target.to_owned(), vec![everything]).unwrap(),
+        1
+    );
+
+    let mut client = KvsClient::connect_tls(target.as_str(), &tls).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+/// write `data` on one clone of `stream` while reading as much on another
+fn exchange(stream: Stream, data: Vec<u8>) -> Vec<u8> {
+    let mut reader = stream.try_clone().unwrap();
+    let len = data.len();
+    let reading = thread::spawn(move || {
+        let mut received = vec![0; len];
+        reader.read_exact(&mut received).unwrap();
+        received
+    });
+    let mut writer = stream;
+    writer.write_all(&data).unwrap();
+    writer.flush().unwrap();
+    reading.join().unwrap()
+}
+
+#[test]
+fn reads_and_writes_proceed_concurrently() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    let ca = TestCa::new(dir, "ca");
+    let (cert, key) = ca.issue(dir, "server", &["localhost", "127.0.0.1"]);
+    let acceptor = TlsAcceptor::new(&cert, &key, None).unwrap();
+    let connector = TlsConnector::new(&dir.join("ca.pem"), None).unwrap();
+    let listener = Listener::bind(&Address::from("127.0.0.1:0")).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    // both ends read on one thread and write on another, far more than the socket
+    // buffers hold, so neither write can finish unless the reads keep going
+    let len = 8 * 1024 * 1024;
+    let (done, finished) = mpsc::channel();
+    thread::spawn(move || {
+        let server = thread::spawn(move || {
+            let stream = acceptor.accept(listener.accept().unwrap()).unwrap();
+            exchange(stream, vec![b's'; len])
+        });
+        let stream = connector
+            .connect(&addr, Stream::connect(&addr).unwrap())
+            .unwrap();
+        let received = exchange(stream, vec![b'c'; len]);
+        done.send((received, server.join().unwrap())).unwrap();
+    });
+    let (client_received, server_received) = finished
+        .recv_timeout(Duration::from_secs(30))
+        .expect("concurrent reads and writes deadlocked");
+    assert!(client_received.iter().all(|&byte| byte == b's'));
+    assert!(server_received.iter().all(|&byte| byte == b'c'));
+}
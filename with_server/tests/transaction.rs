@@ -0,0 +1,198 @@
+mod common;
+
+use common::{connect, start_server};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tempfile::TempDir;
+use with_server::{
+    Backpressure, KVStore, KVStoreEngine, KVStoreError, KVStoreOptions, Server, SledKVStore,
+    Version, WriteStall,
+};
+
+/// the versions of `keys` now
+fn read<E: KVStoreEngine>(store: &mut E, keys: &[&str]) -> Vec<(String, Version)> {
+    keys.iter()
+        .map(|key| (key.to_string(), store.get_version(key.to_string()).unwrap()))
+        .collect()
+}
+
+fn commit_checks_reads<E: KVStoreEngine>(mut store: E) {
+    store.set("from".to_owned(), "other".to_owned()).unwrap();
+    let stale = read(&mut store, &["from", "to"]);
+    store.set("from".to_owned(), "value".to_owned()).unwrap();
+    let fresh = read(&mut store, &["from", "to"]);
+    assert_eq!(fresh[0].1.value, Some("value".to_owned()));
+    assert_eq!(fresh[1].1.value, None);
+    let moved = vec![
+        ("from".to_owned(), None),
+        ("to".to_owned(), Some("value".to_owned())),
+    ];
+
+    assert!(!store.commit(stale, moved.clone()).unwrap());
+    assert_eq!(store.get("to".to_owned()).unwrap(), None);
+    assert!(store.commit(fresh, moved).unwrap());
+    assert_eq!(store.get("from".to_owned()).unwrap(), None);
+    assert_eq!(
+        store.get("to".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+}
+
+fn concurrent_increments(addr: &str) {
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let mut client = connect(addr);
+            thread::spawn(move || {
+                for _ in 0..10 {
+                    client
+                        .transact(|transaction| {
+                            let count: u64 = transaction
+                                .get("count".to_owned())?
+                                .map_or(0, |count| count.parse().unwrap());
+                            transaction.set("count".to_owned(), (count + 1).to_string());
+                            Ok(())
+                        })
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in threads {
+        handle.join().unwrap();
+    }
+    assert_eq!(
+        connect(addr).get("count".to_owned()).unwrap(),
+        Some("40".to_owned())
+    );
+}
+
+#[test]
+fn commit_kvs() {
+    let temp_dir = TempDir::new().unwrap();
+    commit_checks_reads(KVStore::open(temp_dir.path()).unwrap());
+}
+
+#[test]
+fn commit_sled() {
+    let temp_dir = TempDir::new().unwrap();
+    commit_checks_reads(SledKVStore::open(sled::open(temp_dir.path()).unwrap()));
+}
+
+#[test]
+fn transactions_kvs() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    concurrent_increments(&start_server(Server::new(Arc::new(Mutex::new(store)))));
+}
+
+#[test]
+fn transactions_sled() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = SledKVStore::open(sled::open(temp_dir.path()).unwrap());
+    concurrent_increments(&start_server(Server::new(store)));
+}
+
+#[test]
+fn conflicting_transaction_writes_nothing() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    let addr = start_server(Server::new(Arc::new(Mutex::new(store))));
+    let mut client = connect(&addr);
+    let mut other = connect(&addr);
+    client.set("a".to_owned(), "1".to_owned()).unwrap();
+
+    let mut transaction = client.transaction();
+    assert_eq!(
+        transaction.get("a".to_owned()).unwrap(),
+        Some("1".to_owned())
+    );
+    transaction.set("b".to_owned(), "1".to_owned());
+    // a buffered write is read back before it is committed
+    assert_eq!(
+        transaction.get("b".to_owned()).unwrap(),
+        Some("1".to_owned())
+    );
+    other.set("a".to_owned(), "2".to_owned()).unwrap();
+    assert!(matches!(transaction.commit(), Err(KVStoreError::Conflict)));
+    assert_eq!(client.get("b".to_owned()).unwrap(), None);
+}
+
+#[test]
+fn changes_undone_conflict_kvs() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KVStore::open(temp_dir.path()).unwrap();
+    let write = vec![("other".to_owned(), Some("value".to_owned()))];
+    store.set("key".to_owned(), "1".to_owned()).unwrap();
+    let reads = read(&mut store, &["key", "absent"]);
+    store.set("key".to_owned(), "2".to_owned()).unwrap();
+    store.set("key".to_owned(), "1".to_owned()).unwrap();
+    assert!(!store.commit(reads, write.clone()).unwrap());
+
+    let reads = read(&mut store, &["key", "absent"]);
+    store.set("absent".to_owned(), "1".to_owned()).unwrap();
+    store.remove("absent".to_owned()).unwrap();
+    assert!(!store.commit(reads, write.clone()).unwrap());
+    assert_eq!(store.get("other".to_owned()).unwrap(), None);
+
+    // the version of a key outlives a reopen and a compaction
+    let reads = read(&mut store, &["key"]);
+    drop(store);
+    let mut store = KVStore::open(temp_dir.path()).unwrap();
+    store.compact().unwrap();
+    assert!(store.commit(reads, write).unwrap());
+    assert_eq!(
+        store.get("other".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+}
+
+#[test]
+fn transaction_conflicts_with_a_change_undone() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    let addr = start_server(Server::new(Arc::new(Mutex::new(store))));
+    let mut client = connect(&addr);
+    let mut other = connect(&addr);
+    client.set("a".to_owned(), "1".to_owned()).unwrap();
+
+    let mut transaction = client.transaction();
+    transaction.get("a".to_owned()).unwrap();
+    transaction.set("b".to_owned(), "1".to_owned());
+    other.set("a".to_owned(), "2".to_owned()).unwrap();
+    other.set("a".to_owned(), "1".to_owned()).unwrap();
+    assert!(matches!(transaction.commit(), Err(KVStoreError::Conflict)));
+    assert_eq!(client.get("b".to_owned()).unwrap(), None);
+}
+
+#[test]
+fn stalled_commit_writes_nothing() {
+    let temp_dir = TempDir::new().unwrap();
+    let options = KVStoreOptions {
+        backpressure: Backpressure {
+            hard_log_files: Some(3),
+            ..Backpressure::default()
+        },
+        max_segment_bytes: 100,
+        ..KVStoreOptions::default()
+    };
+    let mut store = KVStore::open_with_options(temp_dir.path(), options).unwrap();
+    let mut written = 0;
+    while store.stats().unwrap().write_stall != WriteStall::Stalled {
+        store
+            .set(format!("key{}", written), written.to_string())
+            .unwrap();
+        written += 1;
+        assert!(written < 100, "writes never stalled");
+    }
+    let addr = start_server(Server::new(Arc::new(Mutex::new(store))));
+    let mut client = connect(&addr);
+    let mut transaction = client.transaction();
+    transaction.set("a".to_owned(), "1".to_owned());
+    transaction.remove("key0".to_owned());
+    assert!(matches!(
+        transaction.commit(),
+        Err(KVStoreError::WriteStalled(_))
+    ));
+    assert_eq!(client.get("a".to_owned()).unwrap(), None);
+    assert_eq!(client.get("key0".to_owned()).unwrap(), Some("0".to_owned()));
+}
@@ -0,0 +1,112 @@
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use with_server::{KVStore, KVStoreError, KvsClient, Server};
+
+fn start_server(temp_dir: &TempDir, path: PathBuf) {
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    thread::spawn(move || Server::new(Arc::new(Mutex::new(store))).start_unix(path));
+}
+
+fn connect(path: &Path) -> KvsClient {
+    for _ in 0..50 {
+        if let Ok(client) = KvsClient::connect_unix(path) {
+            return client;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("cannot connect to {}", path.display());
+}
+
+#[test]
+fn requests_over_unix_socket() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("kvs.sock");
+    start_server(&temp_dir, path.clone());
+
+    let mut client = connect(&path);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    client.remove("key1".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(KVStoreError::KeyNotFound)
+    ));
+
+    // a second connection is served while the first one stays open
+    let mut other = connect(&path);
+    other.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key2".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+}
+
+#[test]
+fn stale_socket_is_removed() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("kvs.sock");
+    // a dropped listener leaves its socket file behind
+    drop(UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+
+    start_server(&temp_dir, path.clone());
+    let mut client = connect(&path);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+#[test]
+fn live_socket_is_kept() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("kvs.sock");
+    let _listener = UnixListener::bind(&path).unwrap();
+
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    let result = Server::new(Arc::new(Mutex::new(store))).start_unix(&path);
+    assert!(result.is_err());
+    assert!(path.exists());
+}
+
+#[test]
+fn unix_address_string() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("kvs.sock");
+    start_server(&temp_dir, path.clone());
+    connect(&path);
+
+    let mut client = KvsClient::connect_to(format!("unix:{}", path.display())).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+#[test]
+fn requests_over_tcp() {
+    let temp_dir = TempDir::new().unwrap();
+    // any address `ToSocketAddrs` takes, here a host and port
+    let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    thread::spawn(move || Server::new(Arc::new(Mutex::new(store))).start_on(listener));
+
+    let mut client = KvsClient::connect(("127.0.0.1", port)).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
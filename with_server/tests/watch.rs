@@ -0,0 +1,95 @@
+mod common;
+
+use common::{connect, start_server};
+use std::sync::{Arc, Mutex};
+use tempfile::TempDir;
+use with_server::{KVStore, Server, SledKVStore, WatchEvent};
+
+fn event(seq: u64, key: &str, value: Option<&str>) -> WatchEvent {
+    WatchEvent {
+        seq,
+        key: key.to_owned(),
+        value: value.map(str::to_owned),
+    }
+}
+
+/// `seqs` are the numbers the store gives the changes of the watched keys
+fn changes_are_pushed(addr: &str, seqs: [u64; 3]) {
+    let watch = connect(addr).watch("config/".to_owned()).unwrap();
+    let mut client = connect(addr);
+    client.set("config/a".to_owned(), "1".to_owned()).unwrap();
+    client.set("other".to_owned(), "2".to_owned()).unwrap();
+    client.remove("config/a".to_owned()).unwrap();
+    client.set("config/b".to_owned(), "3".to_owned()).unwrap();
+
+    let events: Vec<WatchEvent> = watch.take(3).map(Result::unwrap).collect();
+    assert_eq!(
+        events,
+        vec![
+            event(seqs[0], "config/a", Some("1")),
+            event(seqs[1], "config/a", None),
+            event(seqs[2], "config/b", Some("3")),
+        ]
+    );
+}
+
+#[test]
+fn watch_kvs() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    // the change of "other" is numbered too
+    changes_are_pushed(
+        &start_server(Server::new(Arc::new(Mutex::new(store)))),
+        [1, 3, 4],
+    );
+}
+
+#[test]
+fn watch_sled() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = SledKVStore::open(sled::open(temp_dir.path()).unwrap());
+    // sled numbers no changes
+    changes_are_pushed(&start_server(Server::new(store)), [0, 0, 0]);
+}
+
+#[test]
+fn several_watches_of_one_key() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    let addr = start_server(Server::new(Arc::new(Mutex::new(store))));
+    let first = connect(&addr).watch("key".to_owned()).unwrap();
+    let second = connect(&addr).watch("key".to_owned()).unwrap();
+    // a closed watch does not get in the way of the others
+    drop(connect(&addr).watch("key".to_owned()).unwrap());
+
+    let mut client = connect(&addr);
+    client.set("key".to_owned(), "value".to_owned()).unwrap();
+    for mut watch in [first, second] {
+        assert_eq!(
+            watch.next().unwrap().unwrap(),
+            event(1, "key", Some("value"))
+        );
+    }
+}
+
+#[test]
+fn changes_since_resumes_a_watch() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KVStore::open(temp_dir.path()).unwrap();
+    let addr = start_server(Server::new(Arc::new(Mutex::new(store))));
+    let mut watch = connect(&addr).watch("key".to_owned()).unwrap();
+    let mut client = connect(&addr);
+    client.set("other".to_owned(), "value".to_owned()).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let last = watch.next().unwrap().unwrap();
+    assert_eq!(last, event(2, "key1", Some("value1")));
+    drop(watch);
+
+    // missed while no watch was open
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    let mut changes = connect(&addr).changes_since(last.seq).unwrap();
+    assert_eq!(
+        changes.next().unwrap().unwrap(),
+        event(3, "key2", Some("value2"))
+    );
+}